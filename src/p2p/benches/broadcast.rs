@@ -0,0 +1,176 @@
+// 广播序列化开销对比：每个接收者各自序列化 vs. 只序列化一次并共享缓冲区；
+// 以及安装 1ms 钩子时，不同工作线程数下服务器端到端的广播吞吐量；
+// 以及开启/关闭 TCP_NODELAY 时经服务器转发的私聊消息往返延迟
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use p2p::common::{serialize_message, Message, MessageType, PeerInfo, BROADCAST_TARGET};
+use p2p::hooks::{HookDecision, MessageHook};
+use p2p::server::{P2PServer, ServerConfig};
+use p2p::socket::SocketOptions;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+const CONNECTIONS: usize = 1000;
+
+fn chat() -> Message {
+    Message::new(MessageType::Chat, "alice".to_string())
+        .with_content("hello everyone, this is a broadcast chat message".to_string())
+        .with_generated_msg_id()
+}
+
+fn broadcast(c: &mut Criterion) {
+    let message = chat();
+    let mut group = c.benchmark_group("broadcast_1k");
+
+    group.bench_function("serialize_per_recipient", |b| {
+        let mut queues: Vec<VecDeque<Vec<u8>>> = (0..CONNECTIONS).map(|_| VecDeque::new()).collect();
+        b.iter(|| {
+            for queue in queues.iter_mut() {
+                queue.push_back(serialize_message(black_box(&message)).unwrap());
+            }
+            queues.iter_mut().for_each(VecDeque::clear);
+        });
+    });
+
+    group.bench_function("serialize_once_shared", |b| {
+        let mut queues: Vec<VecDeque<Arc<Vec<u8>>>> = (0..CONNECTIONS).map(|_| VecDeque::new()).collect();
+        b.iter(|| {
+            let frame = Arc::new(serialize_message(black_box(&message)).unwrap());
+            for queue in queues.iter_mut() {
+                queue.push_back(frame.clone());
+            }
+            queues.iter_mut().for_each(VecDeque::clear);
+        });
+    });
+
+    group.finish();
+}
+
+/// 模拟耗时处理（过滤、压缩、持久化等）的钩子
+struct SlowHook;
+
+impl MessageHook for SlowHook {
+    fn on_inbound(&mut self, _msg: &Message, _from: &PeerInfo) -> HookDecision {
+        std::thread::sleep(Duration::from_millis(1));
+        HookDecision::Allow
+    }
+}
+
+const SENDERS: usize = 4;
+const MESSAGES_PER_SENDER: usize = 10;
+
+struct BenchClient {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl BenchClient {
+    fn join(addr: std::net::SocketAddr, user_id: &str) -> Self {
+        Self::join_with_nodelay(addr, user_id, false)
+    }
+
+    fn join_with_nodelay(addr: std::net::SocketAddr, user_id: &str, nodelay: bool) -> Self {
+        let writer = TcpStream::connect(addr).unwrap();
+        writer.set_nodelay(nodelay).unwrap();
+        let reader = BufReader::new(writer.try_clone().unwrap());
+        let mut client = Self { writer, reader };
+        let join = Message::new(MessageType::Join, user_id.to_string()).with_peer_info("127.0.0.1".to_string(), 9000);
+        client.send(&join);
+        client.wait_for(MessageType::Welcome, 1);
+        client
+    }
+
+    fn send(&mut self, message: &Message) {
+        self.writer.write_all(&serialize_message(message).unwrap()).unwrap();
+    }
+
+    fn wait_for(&mut self, msg_type: MessageType, count: usize) {
+        let mut seen = 0;
+        let mut line = String::new();
+        while seen < count {
+            line.clear();
+            assert!(self.reader.read_line(&mut line).unwrap() > 0, "server closed the connection");
+            if p2p::common::deserialize_message(line.trim_end().as_bytes()).unwrap().msg_type == msg_type {
+                seen += 1;
+            }
+        }
+    }
+}
+
+/// 多个发送方同时广播，等待每个接收方都收到全部消息
+fn broadcast_through_server(c: &mut Criterion) {
+    let mut group = c.benchmark_group("broadcast_1ms_hook");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements((SENDERS * MESSAGES_PER_SENDER) as u64));
+
+    for workers in [0, 2, 4] {
+        let config = ServerConfig { worker_threads: workers, ..ServerConfig::default() };
+        let mut server = P2PServer::new_with_config("127.0.0.1:0", config).unwrap();
+        server.add_hook_factory(|| Box::new(SlowHook));
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let handle = std::thread::spawn(move || server.start());
+        let mut clients: Vec<BenchClient> = (0..SENDERS).map(|i| BenchClient::join(addr, &format!("user{}", i))).collect();
+        for (i, client) in clients.iter_mut().enumerate() {
+            client.wait_for(MessageType::UserJoined, SENDERS - 1 - i);
+        }
+
+        group.bench_with_input(BenchmarkId::from_parameter(workers), &workers, |b, _| {
+            b.iter(|| {
+                for round in 0..MESSAGES_PER_SENDER {
+                    for (i, client) in clients.iter_mut().enumerate() {
+                        let chat = Message::new(MessageType::Chat, format!("user{}", i))
+                            .with_content(format!("message {}", round))
+                            .with_target(BROADCAST_TARGET.to_string());
+                        client.send(&chat);
+                    }
+                }
+                for client in clients.iter_mut() {
+                    client.wait_for(MessageType::Chat, (SENDERS - 1) * MESSAGES_PER_SENDER);
+                }
+            });
+        });
+
+        shutdown.shutdown();
+        handle.join().unwrap().unwrap();
+    }
+    group.finish();
+}
+
+/// alice 给 bob 发一条私聊，bob 收到后立即回复一条（双方都持续发消息，不会被服务器判定超时）；
+/// 客户端和服务器两侧的 nodelay 设置一致
+fn single_message_latency(c: &mut Criterion) {
+    let mut group = c.benchmark_group("single_message_latency");
+    group.sample_size(20);
+
+    for nodelay in [false, true] {
+        let socket = SocketOptions { nodelay, ..SocketOptions::default() };
+        let config = ServerConfig { socket, ..ServerConfig::default() };
+        let mut server = P2PServer::new_with_config("127.0.0.1:0", config).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let handle = std::thread::spawn(move || server.start());
+        let mut alice = BenchClient::join_with_nodelay(addr, "alice", nodelay);
+        let mut bob = BenchClient::join_with_nodelay(addr, "bob", nodelay);
+        alice.wait_for(MessageType::UserJoined, 1);
+
+        group.bench_with_input(BenchmarkId::new("nodelay", nodelay), &nodelay, |b, _| {
+            b.iter(|| {
+                // 每轮新建消息，带新的 msg_id，否则会被服务器当作重发去重
+                alice.send(&Message::chat("alice".to_string(), Some("bob".to_string()), "ping".to_string()));
+                bob.wait_for(MessageType::Chat, 1);
+                bob.send(&Message::chat("bob".to_string(), Some("alice".to_string()), "pong".to_string()));
+                alice.wait_for(MessageType::Chat, 1);
+            });
+        });
+
+        shutdown.shutdown();
+        handle.join().unwrap().unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, broadcast, broadcast_through_server, single_message_latency);
+criterion_main!(benches);
@@ -1,32 +1,166 @@
-use p2p::client::{P2PClient, PendingMessage, ClientCommand};
+use p2p::client::{ClientConfig, ClientEvent, P2PClient, PendingMessage, ClientCommand};
 use p2p::common::P2PError;
-use std::io::{self, BufRead};
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Write};
 use std::env;
+use std::net::{IpAddr, SocketAddr};
 use std::thread;
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+
+const USAGE: &str = "\
+用法: client [服务器地址] [选项]
+
+选项:
+  --server <地址>   服务器地址                    (环境变量 P2P_SERVER，默认 127.0.0.1:8080)
+  --user <用户ID>   用户ID                        (环境变量 P2P_USER，未设置时交互式输入)
+  --bind <IP>       本地P2P监听IP                 (环境变量 P2P_BIND，默认 127.0.0.1)
+  --port <端口>     本地P2P监听端口，0 为随机端口 (环境变量 P2P_PORT，默认 0)
+  --log-messages <文件>  把收到的每条消息以 JSON Lines 格式追加到文件，- 表示标准输出
+  --seeds <地址,...>  纯 P2P 模式：不连接服务器，直连这些节点 (环境变量 P2P_SEEDS；命令行给出空值时不带种子节点启动)
+  -h, --help        显示本帮助
+
+优先级: 命令行参数 > 环境变量 > 交互式输入 > 默认值";
+
+/// 输入线程检查退出标志的间隔
+const INPUT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 客户端启动参数
+#[derive(Debug)]
+struct Settings {
+    server: String,
+    user: Option<String>,  // None 时需要交互式输入
+    bind: IpAddr,
+    port: u16,
+    log_messages: Option<String>,  // 收到的消息的 JSON Lines 输出路径
+    seeds: Option<Vec<SocketAddr>>,  // Some 时以纯 P2P 模式运行
+}
+
+/// 按 命令行参数 > 环境变量 > 默认值 的优先级解析启动参数。
+/// `env` 用于查询环境变量（便于在容器以外的场景中替换数据来源）。
+fn resolve_settings(
+    args: impl IntoIterator<Item = String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Settings, String> {
+    let mut server = None;
+    let mut user = None;
+    let mut bind = None;
+    let mut port = None;
+    let mut log_messages = None;
+    let mut seeds = None;
+    
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let slot = match arg.as_str() {
+            "--server" => &mut server,
+            "--user" => &mut user,
+            "--bind" => &mut bind,
+            "--port" => &mut port,
+            "--log-messages" => &mut log_messages,
+            "--seeds" => &mut seeds,
+            flag if flag.starts_with("--") => return Err(format!("未知参数: {}", flag)),
+            _ => {
+                // 兼容旧用法：第一个位置参数为服务器地址
+                server = Some(arg);
+                continue;
+            }
+        };
+        *slot = Some(args.next().ok_or_else(|| format!("{} 需要一个值", arg))?);
+    }
+    
+    let pick = |flag: Option<String>, var: &str| flag.or_else(|| env(var)).filter(|v| !v.trim().is_empty());
+    let bind = match pick(bind, "P2P_BIND") {
+        Some(ip) => ip.trim().parse().map_err(|e| format!("无效的监听IP {}: {}", ip, e))?,
+        None => IpAddr::from([127, 0, 0, 1]),
+    };
+    let port = match pick(port, "P2P_PORT") {
+        Some(p) => p.trim().parse().map_err(|e| format!("无效的端口 {}: {}", p, e))?,
+        None => 0,
+    };
+    // 命令行中的空列表也表示纯 P2P 模式（第一个节点没有可连接的种子）
+    let seeds = match seeds.or_else(|| env("P2P_SEEDS").filter(|v| !v.trim().is_empty())) {
+        Some(list) => Some(list.split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| addr.parse().map_err(|e| format!("无效的种子节点地址 {}: {}", addr, e)))
+            .collect::<Result<Vec<SocketAddr>, String>>()?),
+        None => None,
+    };
+    
+    Ok(Settings {
+        server: pick(server, "P2P_SERVER").unwrap_or_else(|| "127.0.0.1:8080".to_string()),
+        user: pick(user, "P2P_USER").map(|u| u.trim().to_string()),
+        bind,
+        port,
+        log_messages,
+        seeds,
+    })
+}
 
 fn main() -> Result<(), P2PError> {
-    let server_addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8080".to_string());
-    println!("正在连接到P2P服务器: {}...", server_addr);
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let settings = match resolve_settings(args, |name| env::var(name).ok()) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            return Ok(());
+        }
+    };
+    let server_addr = settings.server;
+    match &settings.seeds {
+        Some(seeds) => println!("以纯 P2P 模式启动，种子节点: {:?}", seeds),
+        None => println!("正在连接到P2P服务器: {}...", server_addr),
+    }
     
-    // 获取用户ID
-    print!("请输入您的用户ID: ");
-    io::Write::flush(&mut io::stdout()).ok();
-    let mut user_id = String::new();
-    io::stdin().read_line(&mut user_id)?;
-    let user_id = user_id.trim().to_string();
+    // 获取用户ID（参数和环境变量都未提供时交互式输入）
+    let user_id = match settings.user {
+        Some(user_id) => user_id,
+        None => {
+            print!("请输入您的用户ID: ");
+            io::Write::flush(&mut io::stdout()).ok();
+            let mut user_id = String::new();
+            io::stdin().read_line(&mut user_id)?;
+            user_id.trim().to_string()
+        }
+    };
     
     if user_id.is_empty() {
         println!("用户ID不能为空！");
         return Ok(());
     }
     
-    // 创建、连接P2P客户端（使用随机端口）
-    let mut client = P2PClient::new(&server_addr, 0, user_id.clone())?;
+    // 创建、连接P2P客户端
+    let mesh = settings.seeds.is_some();
+    let config = ClientConfig {
+        listen_ip: settings.bind,
+        seed_peers: settings.seeds.unwrap_or_default(),
+        ..ClientConfig::default()
+    };
+    let mut client = if mesh {
+        P2PClient::new_mesh(settings.port, user_id.clone(), config)?
+    } else {
+        P2PClient::new_with_config(&server_addr, settings.port, user_id.clone(), config)?
+    };
+    let message_log = match settings.log_messages {
+        Some(path) => {
+            let events = client.take_event_receiver().expect("事件接收器只会被取出一次");
+            Some(spawn_message_log(&path, events)?)
+        }
+        None => None,
+    };
     client.connect()?;
-    client.request_peer_list()?;
-    
-    println!("已连接到服务器！用户: {}", user_id);
+    if mesh {
+        println!("已启动纯 P2P 节点！用户: {}", user_id);
+    } else {
+        client.request_peer_list()?;
+        println!("已连接到服务器！用户: {}", user_id);
+    }
     println!("\n使用说明:");
     println!("  直接输入消息发送公共消息");
     println!("  @<用户名> <消息> 发送私聊消息");
@@ -35,6 +169,7 @@ fn main() -> Result<(), P2PError> {
     println!("  /status 显示连接状态");
     println!("  /p2p <用户名> 建立直接P2P连接");
     println!("  /direct <用户名> <消息> 发送直接P2P消息");
+    println!("  /send <用户名> <消息> 优先直连发送，失败时经服务器转发");
     println!("  /exit 退出客户端\n");
     
     // 获取通道发送器
@@ -45,92 +180,109 @@ fn main() -> Result<(), P2PError> {
     let client_for_input = message_sender.clone();
     let control_for_input = control_sender.clone();
     let user_id_for_input = user_id.clone();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_for_input = Arc::clone(&shutdown);
     
-    thread::spawn(move || {
-        let stdin = io::stdin();
-        let mut handle = stdin.lock();
-        
+    // Ctrl+C 时与 /exit 一样先通知客户端停止（离开服务器），再由下面的收尾逻辑结束输入线程
+    let control_for_signal = control_sender.clone();
+    ctrlc::set_handler(move || {
+        println!("\n收到 Ctrl+C，正在退出...");
+        let _ = control_for_signal.send(ClientCommand::Stop);
+    }).map_err(|e| P2PError::Connection(format!("无法注册 Ctrl+C 处理器: {}", e)))?;
+    
+    let input_thread = thread::spawn(move || {
         println!("输入线程已启动，可以开始聊天\n");
+        let lines = spawn_stdin_reader();
         
-        loop {
-            let mut input = String::new();
-            match handle.read_line(&mut input) {
-                Ok(0) => {
+        while !shutdown_for_input.load(Ordering::SeqCst) {
+            let line = match lines.recv_timeout(INPUT_POLL_INTERVAL) {
+                Ok(Ok(line)) => line,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
                     // EOF - 通常是 Ctrl+D
                     println!("\n检测到输入结束，正在退出...");
                     let _ = control_for_input.send(ClientCommand::Stop);
                     break;
                 }
-                Ok(_) => {
-                    let input = input.trim();
-                    
-                    if input.is_empty() {
-                        continue;
-                    }
-                    
-                    // 检查退出命令
-                    if input.eq_ignore_ascii_case("/exit") {
-                        println!("正在退出...");
-                        let _ = control_for_input.send(ClientCommand::Stop);
-                        break;
-                    }
-                    
-                    // 检查列表命令
-                    if input.eq_ignore_ascii_case("/list") {
-                        let _ = control_for_input.send(ClientCommand::ListPeers);
-                        continue;
-                    }
-                    
-                    // 检查状态命令
-                    if input.eq_ignore_ascii_case("/status") {
-                        let _ = control_for_input.send(ClientCommand::ShowStatus);
-                        continue;
-                    }
-                    
-                    // 检查刷新命令
-                    if input.eq_ignore_ascii_case("/refresh") {
-                        let _ = control_for_input.send(ClientCommand::RefreshPeers);
-                        continue;
-                    }
-                    
-                    // 检查P2P连接命令
-                    if let Some(peer_id) = input.strip_prefix("/p2p ") {
-                        let peer_id = peer_id.trim();
-                        if !peer_id.is_empty() {
-                            println!("🔗 正在建立P2P连接到: {}", peer_id);
-                            let _ = control_for_input.send(ClientCommand::ConnectToPeer(peer_id.to_string()));
-                        } else {
-                            println!("格式: /p2p <用户名>");
-                        }
-                        continue;
-                    }
-                    
-                    // 检查直接消息命令
-                    if let Some(direct_msg) = input.strip_prefix("/direct ") {
-                        if let Some((peer_id, content)) = direct_msg.split_once(' ') {
-                            let peer_id = peer_id.trim();
-                            let content = content.trim();
-                            if !peer_id.is_empty() && !content.is_empty() {
-                                let _ = control_for_input.send(ClientCommand::SendDirectMessage(peer_id.to_string(), content.to_string()));
-                            } else {
-                                println!("格式: /direct <用户名> <消息>");
-                            }
-                        } else {
-                            println!("格式: /direct <用户名> <消息>");
-                        }
-                        continue;
-                    }
-                    
-                    // 处理消息发送
-                    handle_user_input(&client_for_input, input, &user_id_for_input);
-                }
-                Err(e) => {
+                Ok(Err(e)) => {
                     eprintln!("读取输入错误: {}", e);
                     println!("输入出错，正在退出...");
                     let _ = control_for_input.send(ClientCommand::Stop);
                     break;
                 }
+            };
+            let input = line.trim();
+            
+            if input.is_empty() {
+                continue;
+            }
+            
+            // 检查退出命令
+            if input.eq_ignore_ascii_case("/exit") {
+                println!("正在退出...");
+                let _ = control_for_input.send(ClientCommand::Stop);
+                break;
+            }
+            
+            // 检查列表命令
+            if input.eq_ignore_ascii_case("/list") {
+                let _ = control_for_input.send(ClientCommand::ListPeers);
+                continue;
+            }
+            
+            // 检查状态命令
+            if input.eq_ignore_ascii_case("/status") {
+                let _ = control_for_input.send(ClientCommand::ShowStatus);
+                continue;
+            }
+            
+            // 检查刷新命令
+            if input.eq_ignore_ascii_case("/refresh") {
+                let _ = control_for_input.send(ClientCommand::RefreshPeers);
+                continue;
+            }
+            
+            // 检查P2P连接命令
+            if let Some(peer_id) = input.strip_prefix("/p2p ") {
+                let peer_id = peer_id.trim();
+                if !peer_id.is_empty() {
+                    println!("🔗 正在建立P2P连接到: {}", peer_id);
+                    let _ = control_for_input.send(ClientCommand::ConnectToPeer(peer_id.to_string()));
+                } else {
+                    println!("格式: /p2p <用户名>");
+                }
+                continue;
             }
+            
+            // 检查直接消息命令
+            if let Some(direct_msg) = input.strip_prefix("/direct ") {
+                if let Some((peer_id, content)) = direct_msg.split_once(' ') {
+                    let peer_id = peer_id.trim();
+                    let content = content.trim();
+                    if !peer_id.is_empty() && !content.is_empty() {
+                        let _ = control_for_input.send(ClientCommand::SendDirectMessage(peer_id.to_string(), content.to_string()));
+                    } else {
+                        println!("格式: /direct <用户名> <消息>");
+                    }
+                } else {
+                    println!("格式: /direct <用户名> <消息>");
+                }
+                continue;
+            }
+            
+            // 优先直连、失败时经服务器转发
+            if let Some(send_msg) = input.strip_prefix("/send ") {
+                match send_msg.split_once(' ') {
+                    Some((peer_id, content)) if !peer_id.trim().is_empty() && !content.trim().is_empty() => {
+                        let _ = control_for_input.send(ClientCommand::SendWithFallback(peer_id.trim().to_string(), content.trim().to_string()));
+                    }
+                    _ => println!("格式: /send <用户名> <消息>"),
+                }
+                continue;
+            }
+            
+            // 处理消息发送
+            handle_user_input(&client_for_input, input, &user_id_for_input);
         }
         println!("输入线程已结束");
     });
@@ -143,9 +295,71 @@ fn main() -> Result<(), P2PError> {
             println!("客户端已断开连接。");
         }
     }
+    
+    // 客户端停止后通知输入线程退出并等待它结束
+    shutdown.store(true, Ordering::SeqCst);
+    let _ = input_thread.join();
+    // 释放客户端后事件通道断开，日志线程写完剩余消息后结束
+    drop(client);
+    if let Some(message_log) = message_log {
+        let _ = message_log.join();
+    }
     Ok(())
 }
 
+/// 在后台线程中把收到的每条消息写成一行 JSON（JSON Lines），与控制台输出互不影响。
+/// 文件以追加方式打开，不做轮转；时间戳按 serde 对 SystemTime 的固定格式输出为
+/// `{"secs_since_epoch":…,"nanos_since_epoch":…}`
+fn spawn_message_log(path: &str, events: mpsc::Receiver<ClientEvent>) -> io::Result<thread::JoinHandle<()>> {
+    let mut writer: Box<dyn Write + Send> = if path == "-" {
+        Box::new(io::stdout())
+    } else {
+        Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+    };
+    Ok(thread::spawn(move || {
+        for event in events {
+            let ClientEvent::MessageReceived(message) = event else {
+                continue;
+            };
+            let written = serde_json::to_writer(&mut writer, &message).map_err(io::Error::from)
+                .and_then(|_| writer.write_all(b"\n"))
+                .and_then(|_| writer.flush());
+            if let Err(e) = written {
+                eprintln!("❌ 写入消息日志失败: {}", e);
+                break;
+            }
+        }
+    }))
+}
+
+/// 在后台线程中逐行读取标准输入，通过通道转发。
+/// 阻塞在 `read_line` 上的线程无法被中断，因此这个线程不做 join：
+/// 输入线程以超时方式接收，可以及时响应退出标志，而读取线程随进程结束。
+/// 读到 EOF 时线程结束、通道断开。
+fn spawn_stdin_reader() -> mpsc::Receiver<io::Result<String>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut handle = stdin.lock();
+        loop {
+            let mut line = String::new();
+            match handle.read_line(&mut line) {
+                Ok(0) => break,
+                Ok(_) => {
+                    if sender.send(Ok(line)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    break;
+                }
+            }
+        }
+    });
+    receiver
+}
+
 /// 处理用户输入的函数（完全基于通道）
 fn handle_user_input(
     message_sender: &mpsc::Sender<PendingMessage>, 
@@ -1,13 +1,265 @@
-use p2p::server::P2PServer;
+use p2p::server::{P2PServer, ServerCommand, ServerConfig};
 use p2p::common::P2PError;
+use log::LevelFilter;
+use log4rs::append::console::ConsoleAppender;
+use log4rs::config::{Appender, Config, Logger, Root};
+use log4rs::encode::pattern::PatternEncoder;
 use std::env;
+use std::io::{self, BufRead};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: server [addr] [options]
+
+Options:
+  --bind <addr>                listen address, may be repeated (the first replaces bind_addr, the rest listen as well)
+  --listen <addr>              additional listen address
+  --config <file.toml>         load the server configuration from a TOML file; the options below override it
+  --log-level <filter>         log filter such as debug or p2p=trace (overrides RUST_LOG, default info)
+  --max-connections <n>        maximum number of concurrent connections
+  --heartbeat-interval <secs>  keepalive heartbeat interval in seconds (must stay below peer_timeout)
+  -h, --help                   show this help
+
+A single positional address is still accepted and means the same as --bind <addr>.";
+
+/// 服务器启动参数，未给出的选项沿用配置文件或 ServerConfig 默认值
+#[derive(Debug, Default)]
+struct Args {
+    binds: Vec<String>,
+    extra_binds: Vec<String>,
+    config_path: Option<String>,
+    log_level: Option<String>,
+    max_connections: Option<usize>,
+    heartbeat_interval: Option<Duration>,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            // 兼容旧用法：位置参数为监听地址
+            parsed.binds.push(arg);
+            continue;
+        }
+        let value = args.next().ok_or_else(|| format!("{} requires a value", arg))?;
+        match arg.as_str() {
+            "--bind" => parsed.binds.push(value),
+            "--listen" => parsed.extra_binds.push(value),
+            "--config" => parsed.config_path = Some(value),
+            "--log-level" => parsed.log_level = Some(value),
+            "--max-connections" => {
+                parsed.max_connections = Some(value.parse().map_err(|e| format!("invalid --max-connections {}: {}", value, e))?);
+            }
+            "--heartbeat-interval" => {
+                let secs: u64 = value.parse().map_err(|e| format!("invalid --heartbeat-interval {}: {}", value, e))?;
+                if secs == 0 {
+                    return Err("--heartbeat-interval must be at least 1 second".to_string());
+                }
+                parsed.heartbeat_interval = Some(Duration::from_secs(secs));
+            }
+            _ => return Err(format!("unknown option {}", arg)),
+        }
+    }
+    Ok(parsed)
+}
+
+/// 把 `info` 或 `p2p=trace,p2p::router=debug` 形式的过滤串转换为 log4rs 配置，
+/// 不带目标的一项设置根级别，其余按模块路径设置
+fn log_config(filter: &str) -> Result<Config, String> {
+    let parse_level = |level: &str| level.trim().parse::<LevelFilter>().map_err(|_| format!("invalid log level {}", level));
+    let mut root = LevelFilter::Info;
+    let mut loggers = Vec::new();
+    for directive in filter.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+        match directive.split_once('=') {
+            Some((target, level)) => loggers.push(Logger::builder().build(target.trim(), parse_level(level)?)),
+            None => root = parse_level(directive)?,
+        }
+    }
+    let stdout = ConsoleAppender::builder()
+        .encoder(Box::new(PatternEncoder::new("{d(%Y-%m-%d %H:%M:%S)} {h({l})} {t} - {m}{n}")))
+        .build();
+    Config::builder()
+        .appender(Appender::builder().build("stdout", Box::new(stdout)))
+        .loggers(loggers)
+        .build(Root::builder().appender("stdout").build(root))
+        .map_err(|e| format!("invalid log configuration: {}", e))
+}
 
 fn main() -> Result<(), P2PError> {
-    let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:8080".to_string());
-    println!("Starting P2P server on {}...", addr);
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let args = match parse_args(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    
+    // 默认 info 级别，可通过 RUST_LOG 或 --log-level 调整（如 p2p=trace），--log-level 优先
+    let filter = args.log_level.clone().or_else(|| env::var("RUST_LOG").ok()).unwrap_or_else(|| "info".to_string());
+    let log_config = match log_config(&filter) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    log4rs::init_config(log_config).map_err(|e| P2PError::Connection(format!("Failed to initialize logging: {}", e)))?;
+    
+    // 命令行选项优先于配置文件：第一个 --bind（或位置参数）替换 bind_addr，其余地址追加为额外的监听地址
+    let mut config = match args.config_path {
+        Some(path) => ServerConfig::from_file(path)?,
+        None => ServerConfig::default(),
+    };
+    let mut binds = args.binds.into_iter();
+    if let Some(addr) = binds.next() {
+        config.bind_addr = addr;
+    }
+    config.extra_bind_addrs.extend(binds.chain(args.extra_binds));
+    if let Some(max_connections) = args.max_connections {
+        config.max_connections = max_connections;
+    }
+    if let Some(interval) = args.heartbeat_interval {
+        config.heartbeat_interval = interval;
+    }
+    println!("Starting P2P server on {}...", config.bind_addr);
+    
+    let mut server = P2PServer::from_config(config)?;
+    for addr in server.local_addrs()? {
+        println!("Server started successfully on {}!", addr);
+    }
+    
+    // Ctrl+C 时通知客户端并优雅关闭
+    let shutdown = server.shutdown_handle();
+    ctrlc::set_handler(move || {
+        println!("\nReceived Ctrl+C, shutting down...");
+        shutdown.shutdown();
+    }).map_err(|e| P2PError::Connection(format!("Failed to install Ctrl+C handler: {}", e)))?;
+    
+    println!("Admin commands:");
+    println!("  /list                 list connected users");
+    println!("  /stats                show server statistics");
+    println!("  /audit                show the audit log file and its size");
+    println!("  /whois <user>         show a user's registry record (including quota usage)");
+    println!("  /resetquota <user>    reset a user's message quota");
+    println!("  /kick <user>          kick a user");
+    println!("  /ban <user> [secs]    ban a user (permanently if no duration)");
+    println!("  /unban <user>         lift a ban");
+    println!("  /banip <ip>           ban an IP address and drop its connections");
+    println!("  /unbanip <ip>         lift an IP ban");
+    println!("  /broadcast <message>  send a system announcement");
+    println!("  /motd [message]       set the message shown to new users (clears it if empty)");
+    println!("  /shutdown             stop the server\n");
     
-    let mut server = P2PServer::new(&addr)?;
-    println!("Server started successfully on {}!", addr);
+    // 在单独线程中读取管理指令
+    let control = server.get_control_sender();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let Ok(line) = line else { break };
+            let input = line.trim();
+            
+            let command = if input.eq_ignore_ascii_case("/list") {
+                ServerCommand::ListPeers
+            } else if input.eq_ignore_ascii_case("/stats") {
+                let (reply, stats) = mpsc::channel();
+                if control.send(ServerCommand::Stats(reply)).is_err() {
+                    break;
+                }
+                if let Ok(stats) = stats.recv_timeout(Duration::from_secs(1)) {
+                    println!("{}", stats.summary());
+                    for (user_id, user) in &stats.per_user {
+                        println!("  - {}: {} messages, {} bytes", user_id, user.messages_sent, user.bytes_sent);
+                    }
+                }
+                continue;
+            } else if input.eq_ignore_ascii_case("/audit") {
+                let (reply, info) = mpsc::channel();
+                if control.send(ServerCommand::AuditInfo(reply)).is_err() {
+                    break;
+                }
+                match info.recv_timeout(Duration::from_secs(1)) {
+                    Ok(Some(info)) => println!("Audit log: {} ({} bytes)", info.path.display(), info.size),
+                    Ok(None) => println!("Audit log is disabled (set audit_log_path in the config file)"),
+                    Err(_) => println!("Server did not respond"),
+                }
+                continue;
+            } else if input.eq_ignore_ascii_case("/shutdown") {
+                ServerCommand::Shutdown
+            } else if let Some(user_id) = input.strip_prefix("/whois ") {
+                let (reply, record) = mpsc::channel();
+                if control.send(ServerCommand::QueryUser(user_id.trim().to_string(), reply)).is_err() {
+                    break;
+                }
+                match record.recv_timeout(Duration::from_secs(1)) {
+                    Ok(Some(record)) => println!("{:#?}", record),
+                    Ok(None) => println!("No record for {}", user_id.trim()),
+                    Err(_) => println!("Server did not respond"),
+                }
+                continue;
+            } else if let Some(user_id) = input.strip_prefix("/resetquota ") {
+                ServerCommand::ResetQuota(user_id.trim().to_string())
+            } else if let Some(user_id) = input.strip_prefix("/kick ") {
+                ServerCommand::Kick(user_id.trim().to_string())
+            } else if let Some(args) = input.strip_prefix("/ban").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+                // 目标为空时不发送 Ban("")；时长写错时也不能悄悄变成永久封禁
+                let mut parts = args.split_whitespace();
+                let Some(user_id) = parts.next() else {
+                    println!("Usage: /ban <user> [secs]");
+                    continue;
+                };
+                let duration = match parts.next().map(str::parse) {
+                    None => None,
+                    Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+                    Some(Err(e)) => {
+                        println!("Invalid ban duration: {}", e);
+                        println!("Usage: /ban <user> [secs]");
+                        continue;
+                    }
+                };
+                ServerCommand::Ban(user_id.to_string(), duration)
+            } else if let Some(ip) = input.strip_prefix("/banip ") {
+                match ip.trim().parse() {
+                    Ok(ip) => ServerCommand::BanIp(ip),
+                    Err(e) => {
+                        println!("Invalid IP address {}: {}", ip.trim(), e);
+                        continue;
+                    }
+                }
+            } else if let Some(ip) = input.strip_prefix("/unbanip ") {
+                match ip.trim().parse() {
+                    Ok(ip) => ServerCommand::UnbanIp(ip),
+                    Err(e) => {
+                        println!("Invalid IP address {}: {}", ip.trim(), e);
+                        continue;
+                    }
+                }
+            } else if let Some(user_id) = input.strip_prefix("/unban ") {
+                ServerCommand::Unban(user_id.trim().to_string())
+            } else if let Some(motd) = input.strip_prefix("/motd").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+                let motd = motd.trim();
+                ServerCommand::SetMotd((!motd.is_empty()).then(|| motd.to_string()))
+            } else if let Some(content) = input.strip_prefix("/broadcast ") {
+                ServerCommand::Broadcast(content.trim().to_string())
+            } else {
+                if !input.is_empty() {
+                    println!("Unknown command: {}", input);
+                }
+                continue;
+            };
+            
+            if control.send(command).is_err() {
+                break;
+            }
+        }
+    });
     
     // Start the server event loop
     server.start()
@@ -0,0 +1,178 @@
+// 审计日志：加入、离开、踢出、封禁和认证失败，每个事件一行 JSON 追加到文件。
+// 写文件在单独的线程中进行，事件循环只把记录放进通道，不会被磁盘 IO 阻塞
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use crate::common::{DisconnectReason, P2PError};
+
+/// 审计事件（序列化为带 "event" 字段的 JSON 对象）
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// 加入成功。remote_addr 为 accept 时观察到的地址，listen_port 为客户端声明的 P2P 端口
+    Join { user_id: String, remote_addr: Option<SocketAddr>, listen_port: u16 },
+    Leave { user_id: String, reason: DisconnectReason },
+    /// 管理员踢人（随后还会有 reason 为 Kicked 的 Leave）
+    Kick { user_id: String },
+    /// 管理员封禁，duration_ms 为 None 表示永久
+    Ban { user_id: String, duration_ms: Option<u64> },
+    Unban { user_id: String },
+    BanIp { ip: IpAddr },
+    UnbanIp { ip: IpAddr },
+    /// 因封禁或身份公钥不匹配被拒绝的加入
+    AuthFailure { user_id: String, remote_addr: Option<SocketAddr>, reason: String },
+}
+
+/// 审计文件中的一行
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub timestamp: SystemTime,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+/// 当前审计文件的位置和大小（见 `ServerCommand::AuditInfo`）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditFileInfo {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// 审计日志。超过 max_bytes 后把当前文件轮转为 `<path>.1`（原有的依次后移），
+/// 最多保留 max_files 个旧文件。Drop 时等待写线程把剩余记录写完
+pub struct AuditLog {
+    path: PathBuf,
+    sender: Option<mpsc::Sender<AuditRecord>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AuditLog {
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64, max_files: usize) -> Result<Self, P2PError> {
+        let path = path.into();
+        let mut writer = AuditWriter { file: RotatingFile::open(path.clone(), max_bytes, max_files)? };
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("audit-log".to_string())
+            .spawn(move || writer.run(receiver))?;
+        Ok(Self { path, sender: Some(sender), writer: Some(handle) })
+    }
+
+    /// 以当前时间记录一个事件
+    pub fn record(&self, event: AuditEvent) {
+        let record = AuditRecord { timestamp: SystemTime::now(), event };
+        if let Some(Err(e)) = self.sender.as_ref().map(|sender| sender.send(record)) {
+            warn!("audit writer has stopped, dropping {:?}", e.0.event);
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 当前文件的路径和大小（尚在写线程缓冲中的记录不计入）
+    pub fn info(&self) -> AuditFileInfo {
+        let size = fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0);
+        AuditFileInfo { path: self.path.clone(), size }
+    }
+}
+
+impl Drop for AuditLog {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+struct AuditWriter {
+    file: RotatingFile,
+}
+
+impl AuditWriter {
+    /// 每批记录写完后 flush；发送端全部关闭后退出
+    fn run(&mut self, receiver: mpsc::Receiver<AuditRecord>) {
+        while let Ok(record) = receiver.recv() {
+            self.write(&record);
+            while let Ok(record) = receiver.try_recv() {
+                self.write(&record);
+            }
+            self.file.flush();
+        }
+    }
+
+    fn write(&mut self, record: &AuditRecord) {
+        match serde_json::to_vec(record) {
+            Ok(line) => self.file.write_line(&line),
+            Err(e) => error!("failed to serialize audit record {:?}: {}", record.event, e),
+        }
+    }
+}
+
+/// 按大小轮转的追加写文件，审计日志和聊天记录共用。超过 max_bytes 后把当前文件轮转为 `<path>.1`
+/// （原有的依次后移），最多保留 max_files 个旧文件。写入和轮转失败只记录日志，不中断写线程
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(path: PathBuf, max_bytes: u64, max_files: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file: BufWriter::new(file), size, max_bytes, max_files })
+    }
+
+    /// 追加一行（line 不含换行符），写入后超过上限的先轮转
+    pub(crate) fn write_line(&mut self, line: &[u8]) {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            if let Err(e) = self.rotate() {
+                error!("failed to rotate {}: {}", self.path.display(), e);
+            }
+        }
+        match self.file.write_all(line).and_then(|()| self.file.write_all(b"\n")) {
+            Ok(()) => self.size += len,
+            Err(e) => error!("failed to write {}: {}", self.path.display(), e),
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        if let Err(e) = self.file.flush() {
+            error!("failed to flush {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        if self.max_files > 0 {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        let file = OpenOptions::new().create(true).write(true).truncate(true).open(&self.path)?;
+        self.file = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+}
@@ -1,16 +1,23 @@
-use crate::common::*;
 use mio::{Events, Interest, Poll, Token};
 use mio::net::{TcpStream, TcpListener};
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::time::{Duration, SystemTime, Instant};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::io::{Read, Write};
 use std::sync::mpsc;
-use crate::common::{Message, MessageType, PeerInfo, P2PError, serialize_message, deserialize_message, MessageSource};
+use log::debug;
+use crate::socket::SocketOptions;
+use crate::health::{Health, LagMonitor, LoopLag};
+use crate::stats::ConnStats;
+use crate::room::{RoomConfig, RoomInfo};
+use crate::common::{ErrorCode, ErrorContext, Framing, GossipEntry, Message, MessageType, PeerEntry, PeerInfo, PeerGossip, PeerListDelta, PeerReachability, P2PError, Result, ServerInfo, SignaturePolicy, TokenAllocator, serialize_message, deserialize_message_lenient, sign_message, verify_message, MessageSource, DEFAULT_CONTENT_TYPE};
 
 const SERVER: Token = Token(0);
 const LISTENER: Token = Token(1); // 客户端监听器token
 
+// 最近收到的聊天消息去重记录上限
+const SEEN_MESSAGES_CAPACITY: usize = 1024;
+
 /// 待发送的消息
 #[derive(Debug, Clone)]
 pub struct PendingMessage {
@@ -25,12 +32,203 @@ pub enum MessageTarget {
     Peer(Token),
 }
 
+/// 客户端事件，供外部应用（UI、脚本）订阅
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// 对等节点列表已更新（收到服务器下发的完整列表）
+    PeerListUpdated { version: u64, peers: Vec<PeerInfo> },
+    /// 服务器系统公告
+    SystemMessage(String),
+    /// 加入后服务器发送的欢迎信息（版本、在线人数、公告）
+    ServerInfo(ServerInfo),
+    /// 收到聊天消息，content_type 供界面决定按纯文本还是 markdown 渲染
+    ChatReceived {
+        msg_id: Option<String>,  // 回应和编辑通过它引用这条消息
+        sender_id: String,
+        target_id: Option<String>,  // 私聊目标，公共消息为 None
+        content: String,
+        content_type: String,
+        source: MessageSource,
+        late: bool,  // 比同一会话中已显示的消息发出得早（例如经服务器转发慢于直连），界面可按 msg_id 重新排序
+        room: Option<String>,  // 房间聊天所在的房间
+        verified: Option<bool>,  // 签名校验结果，未配置 signing_key 时为 None
+    },
+    /// 所在房间的成员或配置发生变化
+    RoomUpdated(RoomInfo),
+    /// 被房间成员邀请加入房间
+    RoomInvited { room: String, inviter: String },
+    /// 被房主移出房间
+    RemovedFromRoom { room: String, by: String },
+    /// 收到对某条消息的表情回应。known 为 false 表示本地没有见过被回应的消息（如在加入前发出），界面可以忽略
+    ReactionReceived { sender_id: String, target_msg_id: String, emoji: String, known: bool },
+    /// 收到对某条消息的编辑，known 的含义同上；作者不符的编辑会被直接丢弃，不产生事件
+    MessageEdited { sender_id: String, target_msg_id: String, new_content: String, known: bool },
+    /// `send_with_fallback` 发出的消息实际走的路径
+    MessageSent { target_id: String, path: DeliveryPath },
+    /// 服务器回报的私聊投递结果（目前只有失败回执，如目标不在线）
+    SendResult { msg_id: Option<String>, target_id: String, result: Result<(), ErrorCode> },
+    /// 服务器返回的 Error：code 为错误码（旧服务器可能没有），detail 为可读说明，reply_to 指向出错的消息。
+    /// 目标不在线的错误同时还会发出 SendResult
+    ServerError { code: Option<ErrorCode>, detail: String, reply_to: Option<String> },
+    /// 收到的原始消息（来自服务器或对等节点），在对应的其他事件之前发出，便于记录或转发
+    MessageReceived(Message),
+    /// 重连服务器时逐个检查 P2P 直连的结果，每个直连节点一条
+    PeerLinkChecked { peer_id: String, status: PeerLinkStatus },
+    /// 已知节点的直连状态发生变化，last_seen 为最近一次收到该节点消息的时间，界面可据此显示在线情况
+    PeerStatusChanged { peer_id: String, status: PeerReachability, last_seen: Option<SystemTime> },
+    /// 客户端本身不处理的消息类型（如 RelayAck、来自对等节点的 ConnectRequest），
+    /// 应用可以据此实现自己的消息处理而无需修改本库。签名无效被丢弃的消息不会出现在这里
+    Unhandled(Message),
+}
+
+/// 重连时一条 P2P 直连的检查结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerLinkStatus {
+    /// 直连仍然可用
+    Alive(Token),
+    /// 直连已断开并被清理（未开启 `redial_peers_on_reconnect`）
+    Dropped,
+    /// 直连已断开，重新拨号成功，之后使用新的 token
+    Redialed(Token),
+    /// 直连已断开，重新拨号失败
+    RedialFailed(String),
+}
+
+/// 消息的投递路径
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryPath {
+    Direct,  // P2P 直连
+    Server,  // 经服务器转发
+}
+
+/// `connect_to_peer` 的结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerConnection {
+    /// 调用前已有直连，没有新建连接
+    AlreadyConnected(Token),
+    /// 新建立的直连和实际连接的地址（测试模式下只登记映射，地址为 None）
+    Connected { token: Token, addr: Option<SocketAddr> },
+}
+
+impl PeerConnection {
+    /// 该节点直连使用的 token，可用于之后的 P2P 发送
+    pub fn token(&self) -> Token {
+        match self {
+            PeerConnection::AlreadyConnected(token) | PeerConnection::Connected { token, .. } => *token,
+        }
+    }
+}
+
+/// 客户端状态快照，见 `P2PClient::status`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientStatus {
+    pub user_id: String,
+    pub listen_port: u16,
+    pub server_addr: SocketAddr,
+    pub connected: bool,
+    pub seconds_since_heartbeat: u64,
+    pub server_rtt: Option<Duration>,
+    pub known_peer_count: usize,
+    pub active_p2p_count: usize,
+    /// 客户端创建以来的时间
+    pub uptime: Duration,
+    /// 事件循环每轮处理耗时的统计
+    pub loop_lag: LoopLag,
+    pub health: Health,
+}
+
+/// 客户端配置
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// 向服务器发送心跳的间隔。间隔内与服务器双向都有其他消息往来时跳过这次心跳
+    pub heartbeat_interval: Duration,
+    /// 连续多少次心跳未收到 HeartbeatAck 即认为服务器连接已失效
+    pub max_missed_heartbeat_acks: u32,
+    /// 本地 P2P 监听器绑定的 IP
+    pub listen_ip: IpAddr,
+    /// 单条消息序列化后的最大字节数，超过时在发送前直接拒绝（应与服务器的 max_message_size 一致）。
+    /// P2P 直连收到的帧同样受此限制：超长的帧不解析直接丢弃，未收完就已超长的连接会被断开
+    pub max_message_bytes: usize,
+    /// 在 Join 中按偏好顺序声明的帧格式，实际使用的格式由服务器在欢迎消息中确定
+    pub framings: Vec<Framing>,
+    /// P2P 直连时绑定的本地地址（多网卡、VPN 分流时指定出口网络；端口为 0 由系统分配），None 使用默认路由
+    pub outbound_bind_addr: Option<SocketAddr>,
+    /// 单轮事件循环处理耗时超过该值时输出告警；平均耗时超过它即为 Degraded，超过 4 倍为 Unhealthy
+    pub lag_warn_threshold: Duration,
+    /// 纯 P2P 模式（`new_mesh`）下 `connect` 时直连的节点地址，其余节点通过它们转告得知
+    pub seed_peers: Vec<SocketAddr>,
+    /// 纯 P2P 模式下定期向直连节点转告全部已知节点的间隔
+    pub gossip_interval: Duration,
+    /// 转告最多经过的跳数（发出时的 ttl），限制传播范围
+    pub gossip_ttl: u8,
+    /// 重连服务器时自动重新拨号断开前已直连、但直连也已失效的节点
+    pub redial_peers_on_reconnect: bool,
+    /// 监听器、服务器连接和 P2P 直连的套接字选项（默认开启 TCP_NODELAY）
+    pub socket: SocketOptions,
+    /// 聊天、回应和编辑内容的最大字符数，None 不限制（仍受 `MAX_CONTENT_BYTES` 约束）。
+    /// 与 max_message_bytes 不同，这是产品上的消息长度限制；服务器可以配置同样的上限拒绝超长内容
+    pub max_content_chars: Option<usize>,
+    /// 内容超过 max_content_chars 时的处理方式
+    pub content_limit_policy: ContentLimitPolicy,
+    /// 与服务器和其他客户端共享的签名密钥。配置后发出的聊天、回应和编辑带 HMAC 签名，
+    /// 收到的同类消息校验签名，用于在没有 TLS 时发现被篡改的转发消息
+    pub signing_key: Option<String>,
+    /// 收到签名无效的消息时的处理方式
+    pub signature_policy: SignaturePolicy,
+    /// 聊天消息的重排窗口。经服务器和 P2P 两条路径到达的消息可能乱序，配置后每条聊天消息最多暂存
+    /// 该时间，同一发送方的消息按发送顺序放行（ChatReceived 按该顺序发出）；None 为收到即投递
+    pub reorder_window: Option<Duration>,
+}
+
+/// 发送的内容超过 `ClientConfig::max_content_chars` 时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContentLimitPolicy {
+    /// 不发送，返回 `P2PError::ContentTooLong`
+    #[default]
+    Reject,
+    /// 截断到上限后发送
+    Truncate,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(30),
+            max_missed_heartbeat_acks: 3,
+            listen_ip: IpAddr::from([127, 0, 0, 1]),
+            max_message_bytes: 64 * 1024,
+            framings: vec![Framing::Newline],
+            outbound_bind_addr: None,
+            lag_warn_threshold: Duration::from_millis(250),
+            seed_peers: Vec::new(),
+            gossip_interval: Duration::from_secs(30),
+            gossip_ttl: 3,
+            redial_peers_on_reconnect: false,
+            socket: SocketOptions::default(),
+            max_content_chars: None,
+            content_limit_policy: ContentLimitPolicy::Reject,
+            signing_key: None,
+            signature_policy: SignaturePolicy::Mark,
+            reorder_window: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// 设置 P2P 直连绑定的本地地址（见 `outbound_bind_addr`）
+    pub fn with_outbound_bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.outbound_bind_addr = Some(addr);
+        self
+    }
+}
+
 /// 客户端控制指令
 #[derive(Debug, Clone)]
 pub enum ClientCommand {
     Stop,
     ConnectToPeer(String),  // 连接到指定的peer
     SendDirectMessage(String, String),  // (peer_id, content)
+    SendWithFallback(String, String),  // (peer_id, content)，直连失败时改由服务器转发
     SmartSendMessage(Option<String>, String),  // 智能发送消息（自动P2P或服务器）
     ListPeers,  // 显示已知对等节点列表
     ShowStatus,  // 显示连接状态
@@ -41,71 +239,198 @@ pub struct P2PClient {
     poll: Poll,
     events: Events,
     server_stream: Option<TcpStream>,
+    server_connecting: bool,  // 非阻塞connect尚未完成
+    held_server_messages: Vec<Message>,  // 连接建立前（或帧格式协商完成前）暂存的发往服务器的消息
+    server_framing: Framing,  // 与服务器之间使用的帧格式
+    server_write_buffer: Vec<u8>,  // 尚未写出的发往服务器的数据（遇到 WouldBlock 时保留）
+    server_writable_interest: bool,  // 服务器连接当前是否关注 WRITABLE
+    awaiting_welcome: bool,  // 已提议非 Newline 帧格式，等待欢迎消息确认
     listener: Option<TcpListener>,  // 客户端监听器
     listen_port: u16,  // 实际监听端口
     streams: HashMap<Token, TcpStream>,
     buffers: HashMap<Token, Vec<u8>>,
+    conn_stats: HashMap<Token, ConnStats>,  // 各连接（包括服务器连接 SERVER）的读写计数
     user_id: String,
     server_addr: SocketAddr,
     known_peers: HashMap<String, PeerInfo>,
     // P2P连接管理
     peer_to_token: HashMap<String, Token>,  // peer_id -> token 映射
-    next_peer_token: Token,  // 下一个可用的peer token
+    peer_tokens: TokenAllocator,  // peer token 分配（断开后回收复用）
     // 消息发送通道
     message_sender: mpsc::Sender<PendingMessage>,
     message_receiver: mpsc::Receiver<PendingMessage>,
     // 控制指令通道
     control_sender: mpsc::Sender<ClientCommand>,
     control_receiver: mpsc::Receiver<ClientCommand>,
+    // 事件通道（接收端被取走后才会发送事件）
+    event_sender: mpsc::Sender<ClientEvent>,
+    event_receiver: Option<mpsc::Receiver<ClientEvent>>,
     // 心跳管理
     last_heartbeat: Instant,
+    started_at: Instant,
+    heartbeat_nonce: u64,
+    pending_heartbeat: Option<(u64, Instant)>,  // 尚未收到 ack 的心跳 (nonce, 发送时间)
+    missed_heartbeat_acks: u32,
+    last_heartbeat_ack: Option<Instant>,
+    // 最近一次向服务器发出和从服务器收到消息的时间，双向都有往来时不必发送心跳
+    last_server_send: Instant,
+    last_server_receive: Instant,
+    server_rtt: Option<Duration>,
+    lag_monitor: LagMonitor,
+    config: ClientConfig,
+    // 本地缓存的对等节点列表版本号
+    peer_list_version: u64,
+    // 正在拼装的分页完整列表 (版本, 总页数, 已收到的页)，收齐后整体替换 known_peers
+    peer_list_pages: Option<(u64, u32, BTreeMap<u32, Vec<PeerEntry>>)>,
+    // 已应用的完整列表数（含所有分页收齐的列表），request_peer_list_blocking 据此判断响应已处理
+    full_peer_lists_applied: u64,
+    // 最近收到的聊天消息去重键（同一消息可能经服务器和 P2P 两条路径到达）
+    seen_messages: HashSet<String>,
+    seen_order: VecDeque<String>,
+    // 最近收发的聊天消息 msg_id -> 作者，用于识别回应和编辑引用的消息
+    message_authors: HashMap<String, String>,
+    author_order: VecDeque<String>,
+    // 每个会话（发送方 + 投递范围）中已显示的最新消息序号，见 `reconcile_conversation`
+    conversation_heads: HashMap<String, (u128, u64)>,
+    // 等待按发送顺序放行的聊天消息，按发送方区分，每个发送方内已按顺序排列，见 `hold_chat`
+    reorder_buffer: HashMap<String, Vec<HeldChat>>,
+    // 测试模式（`new_testing`）下代替 socket 记录所有发送
+    sent_log: Option<Vec<PendingMessage>>,
+    // 纯 P2P 模式：不连接服务器，发往服务器的消息改为直接发给直连节点
+    mesh: bool,
+    // 已发送过 Hello 的直连 token（主动连接时立即发送，被动接受的在收到对方 Hello 后回复）
+    hello_sent: HashSet<Token>,
+    last_gossip: Instant,
 }
 
 impl P2PClient {
-    pub fn new(server_addr: &str, local_port: u16, user_id: String) -> Result<Self, P2PError> {
-        let server_addr: SocketAddr = server_addr.parse().map_err(|e: std::net::AddrParseError| P2PError::ConnectionError(e.to_string()))?;
+    pub fn new(server_addr: &str, local_port: u16, user_id: String) -> Result<Self> {
+        Self::new_with_config(server_addr, local_port, user_id, ClientConfig::default())
+    }
+    
+    pub fn new_with_config(server_addr: &str, local_port: u16, user_id: String, config: ClientConfig) -> Result<Self> {
+        let server_addr: SocketAddr = server_addr.parse()?;
         let poll = Poll::new()?;
         
-        // 创建客户端监听器
-        let listen_addr = if local_port == 0 {
-            "127.0.0.1:0".parse().unwrap() // 系统分配端口
-        } else {
-            format!("127.0.0.1:{}", local_port).parse().map_err(|e: std::net::AddrParseError| P2PError::ConnectionError(e.to_string()))?
-        };
+        // 创建客户端监听器（端口为0时由系统分配）
+        let listen_addr = SocketAddr::new(config.listen_ip, local_port);
         
-        let mut listener = TcpListener::bind(listen_addr)?;
+        let mut listener = config.socket.bind(listen_addr)
+            .map_err(|e| P2PError::Connection(format!("绑定本地监听地址 {} 失败: {}", listen_addr, e)))?;
         let actual_addr = listener.local_addr()?;
         let listen_port = actual_addr.port();
         
         // 注册监听器
         poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
         
+        println!("🚀 客户端监听端口: {}", listen_port);
+        Ok(Self::build(poll, server_addr, Some(listener), listen_port, user_id, config))
+    }
+    
+    /// 测试模式的客户端：不绑定监听端口、不连接服务器，所有发送都记录到内存中，
+    /// 通过 `sent_messages` / `sent_pending` 查询。`connect_to_peer` 只登记映射，不建立连接
+    pub fn new_testing(user_id: String) -> Result<Self> {
+        Self::new_testing_with_config(user_id, ClientConfig::default())
+    }
+    
+    /// 使用指定配置的测试模式客户端，见 `new_testing`
+    pub fn new_testing_with_config(user_id: String, config: ClientConfig) -> Result<Self> {
+        let poll = Poll::new()?;
+        let server_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut client = Self::build(poll, server_addr, None, 0, user_id, config);
+        client.sent_log = Some(Vec::new());
+        Ok(client)
+    }
+    
+    /// 纯 P2P 模式的客户端：从不连接服务器。`connect` 直连 `config.seed_peers` 中的节点，
+    /// 双方通过 Hello 交换身份，之后节点之间互相转告已知节点（PeerGossip）并自动建立直连。
+    /// 公共消息发给所有直连节点，私聊只能发给已直连的节点
+    pub fn new_mesh(local_port: u16, user_id: String, config: ClientConfig) -> Result<Self> {
+        let mut client = Self::new_with_config("127.0.0.1:0", local_port, user_id, config)?;
+        client.mesh = true;
+        Ok(client)
+    }
+    
+    fn build(poll: Poll, server_addr: SocketAddr, listener: Option<TcpListener>, listen_port: u16, user_id: String, config: ClientConfig) -> Self {
         // 创建消息发送通道
         let (message_sender, message_receiver) = mpsc::channel();
         // 创建控制指令通道
         let (control_sender, control_receiver) = mpsc::channel();
+        // 创建事件通道
+        let (event_sender, event_receiver) = mpsc::channel();
         
-        println!("🚀 客户端监听端口: {}", listen_port);
-        
-        Ok(Self {
+        Self {
             poll,
             events: Events::with_capacity(1024),
             server_stream: None,
-            listener: Some(listener),
+            server_connecting: false,
+            held_server_messages: Vec::new(),
+            server_framing: Framing::Newline,
+            server_write_buffer: Vec::new(),
+            server_writable_interest: false,
+            awaiting_welcome: false,
+            listener,
             listen_port,
             streams: HashMap::new(),
             buffers: HashMap::new(),
+            conn_stats: HashMap::new(),
             user_id,
             server_addr,
             known_peers: HashMap::new(),
             peer_to_token: HashMap::new(),
-            next_peer_token: Token(1000), // 从1000开始为peer分配（避开LISTENER的token）
+            peer_tokens: TokenAllocator::new(Token(1000)), // 从1000开始为peer分配（避开LISTENER的token）
             message_sender,
             message_receiver,
             control_sender,
             control_receiver,
+            event_sender,
+            event_receiver: Some(event_receiver),
             last_heartbeat: Instant::now(),
-        })
+            started_at: Instant::now(),
+            heartbeat_nonce: 0,
+            pending_heartbeat: None,
+            missed_heartbeat_acks: 0,
+            last_heartbeat_ack: None,
+            last_server_send: Instant::now(),
+            last_server_receive: Instant::now(),
+            server_rtt: None,
+            lag_monitor: LagMonitor::new(),
+            config,
+            peer_list_version: 0,
+            peer_list_pages: None,
+            full_peer_lists_applied: 0,
+            seen_messages: HashSet::new(),
+            seen_order: VecDeque::new(),
+            message_authors: HashMap::new(),
+            author_order: VecDeque::new(),
+            conversation_heads: HashMap::new(),
+            reorder_buffer: HashMap::new(),
+            sent_log: None,
+            mesh: false,
+            hello_sent: HashSet::new(),
+            last_gossip: Instant::now(),
+        }
+    }
+    
+    /// 测试模式下已发送的消息（按发送顺序）；非测试模式返回空列表
+    pub fn sent_messages(&mut self) -> Result<Vec<Message>> {
+        Ok(self.sent_pending()?.into_iter().map(|pending| pending.message).collect())
+    }
+    
+    /// 测试模式下已发送的消息及其投递目标。会先处理通道中排队的消息
+    pub fn sent_pending(&mut self) -> Result<Vec<PendingMessage>> {
+        self.process_pending_messages()?;
+        Ok(self.sent_log.clone().unwrap_or_default())
+    }
+    
+    /// 测试模式下模拟收到一条消息（按消息自带的 source 处理），非测试模式返回错误
+    pub fn inject_received(&mut self, message: Message) -> Result<()> {
+        if self.sent_log.is_none() {
+            return Err(P2PError::ConfigError("inject_received 只能在测试模式下使用".to_string()));
+        }
+        self.emit_event(ClientEvent::MessageReceived(message.clone()));
+        self.record_peer_seen(&message.sender_id);
+        self.handle_message(&message)
     }
     
     /// 获取消息发送器的克隆，用于在其他线程中发送消息
@@ -118,21 +443,25 @@ impl P2PClient {
         self.control_sender.clone()
     }
     
+    /// 取走事件接收器（只能取一次），之后客户端会通过它推送 `ClientEvent`
+    pub fn take_event_receiver(&mut self) -> Option<mpsc::Receiver<ClientEvent>> {
+        self.event_receiver.take()
+    }
+    
+    /// 发送事件（没有订阅者时直接丢弃，避免事件无限堆积）
+    fn emit_event(&self, event: ClientEvent) {
+        if self.event_receiver.is_none() {
+            let _ = self.event_sender.send(event);
+        }
+    }
+    
     /// 创建智能路由的聊天消息（供外部使用）
     pub fn create_smart_chat_message(&self, target_id: Option<String>, content: String) -> PendingMessage {
         // 如果有目标用户且已建立P2P连接，则通过P2P发送
         if let Some(ref target) = target_id {
             if let Some(&peer_token) = self.peer_to_token.get(target) {
-                let message = Message {
-                    msg_type: MessageType::Chat,
-                    sender_id: self.user_id.clone(),
-                    target_id: target_id.clone(),
-                    content: Some(content),
-                    sender_peer_address: "127.0.0.1".to_string(),
-                    sender_listen_port: self.listen_port,
-                    timestamp: SystemTime::now(),
-                    source: MessageSource::Peer,
-                };
+                let message = Message::chat(self.user_id.clone(), target_id, content)
+                    .with_source(MessageSource::Peer);
                 
                 return PendingMessage {
                     target: MessageTarget::Peer(peer_token),
@@ -142,16 +471,7 @@ impl P2PClient {
         }
         
         // 否则通过服务器发送
-        let message = Message {
-            msg_type: MessageType::Chat,
-            sender_id: self.user_id.clone(),
-            target_id,
-            content: Some(content),
-            sender_peer_address: "127.0.0.1".to_string(),
-            sender_listen_port: 0,
-            timestamp: SystemTime::now(),
-            source: MessageSource::Server,
-        };
+        let message = Message::chat(self.user_id.clone(), target_id, content);
         
         PendingMessage {
             target: MessageTarget::Server,
@@ -161,16 +481,7 @@ impl P2PClient {
     
     /// 静态方法：创建聊天消息（不需要客户端实例） - 始终通过服务器
     pub fn create_chat_message_static(user_id: String, target_id: Option<String>, content: String) -> PendingMessage {
-        let message = Message {
-            msg_type: MessageType::Chat,
-            sender_id: user_id,
-            target_id,
-            content: Some(content),
-            sender_peer_address: "127.0.0.1".to_string(),
-            sender_listen_port: 0,
-            timestamp: SystemTime::now(),
-            source: MessageSource::Server,
-        };
+        let message = Message::chat(user_id, target_id, content);
         
         PendingMessage {
             target: MessageTarget::Server,
@@ -179,8 +490,10 @@ impl P2PClient {
     }
     
     /// 智能发送消息（自动选择P2P或服务器）
-    pub fn send_smart_message(&self, target_id: Option<String>, content: String) -> Result<(), P2PError> {
-        let pending_message = self.create_smart_chat_message(target_id.clone(), content.clone());
+    pub fn send_smart_message(&self, target_id: Option<String>, content: String) -> Result<()> {
+        let mut pending_message = self.create_smart_chat_message(target_id.clone(), content);
+        self.check_outgoing(&mut pending_message.message)?;
+        let content = pending_message.message.content.clone().unwrap_or_default();
         
         // 根据消息目标显示不同的提示
         match &pending_message.target {
@@ -199,63 +512,264 @@ impl P2PClient {
         }
         
         self.message_sender.send(pending_message)
-            .map_err(|_| P2PError::ConnectionError("消息发送通道已关闭".to_string()))?;
+            .map_err(|_| P2PError::ChannelClosed("消息发送通道".to_string()))?;
         Ok(())
     }
 
-    pub fn connect(&mut self) -> Result<(), P2PError> {
-        let mut stream = TcpStream::connect(self.server_addr)?;
+    /// 对 target_msg_id 指向的消息添加表情回应。target_id 应与原消息的投递范围一致（公共消息为 None）
+    pub fn react(&self, target_id: Option<String>, target_msg_id: String, emoji: String) -> Result<()> {
+        self.send_reference(MessageType::React, target_id, target_msg_id, emoji)
+    }
+    
+    /// 修改自己发出的 target_msg_id 消息的内容，接收方会丢弃他人冒名的编辑
+    pub fn edit_message(&self, target_id: Option<String>, target_msg_id: String, new_content: String) -> Result<()> {
+        self.send_reference(MessageType::Edit, target_id, target_msg_id, new_content)
+    }
+    
+    /// 回应和编辑与聊天走相同的路径（已有P2P连接时直发），只是类型不同并带上被引用的 msg_id
+    fn send_reference(&self, msg_type: MessageType, target_id: Option<String>, target_msg_id: String, content: String) -> Result<()> {
+        let mut pending_message = self.create_smart_chat_message(target_id, content);
+        pending_message.message.msg_type = msg_type;
+        pending_message.message.reply_to = Some(target_msg_id);
+        self.check_outgoing(&mut pending_message.message)?;
+        self.message_sender.send(pending_message)
+            .map_err(|_| P2PError::ChannelClosed("消息发送通道".to_string()))?;
+        Ok(())
+    }
+
+    pub fn connect(&mut self) -> Result<()> {
+        if self.mesh {
+            self.connect_seed_peers();
+            return Ok(());
+        }
+        let mut stream = self.config.socket.connect(self.server_addr, None)
+            .with_context(|| format!("连接服务器 {}", self.server_addr))?;
         self.poll.registry()
-            .register(&mut stream, SERVER, Interest::READABLE | Interest::WRITABLE)?;
+            .register(&mut stream, SERVER, Interest::READABLE | Interest::WRITABLE)
+            .context("注册服务器连接")?;
         
         self.server_stream = Some(stream);
+        self.server_connecting = true;
+        self.held_server_messages.clear();
+        self.server_framing = Framing::Newline;
+        self.server_write_buffer.clear();
+        self.server_writable_interest = true;
+        self.awaiting_welcome = false;
+        self.pending_heartbeat = None;
+        self.missed_heartbeat_acks = 0;
         self.buffers.insert(SERVER, Vec::new());
+        self.conn_stats.insert(SERVER, ConnStats::default());
 
         // 使用通道发送join消息，包含真实的监听端口
-        let join_message = Message {
-            msg_type: MessageType::Join,
-            sender_id: self.user_id.clone(),
-            target_id: None,
-            content: None,
-            sender_peer_address: "127.0.0.1".to_string(),
-            sender_listen_port: self.listen_port,  // 发送真实的监听端口
-            timestamp: SystemTime::now(),
-            source: MessageSource::Server,
-        };
+        let join_message = self.join_message();
 
         self.queue_message(MessageTarget::Server, join_message)?;
         Ok(())
     }
 
+    /// 纯 P2P 模式下直连所有种子节点。种子节点可能尚未启动，连接失败只记录，之后仍可被其他节点发现
+    fn connect_seed_peers(&mut self) {
+        for addr in self.config.seed_peers.clone() {
+            match self.dial(addr) {
+                Ok(token) => println!("🌱 已连接种子节点 {} (Token: {:?})，等待对方 Hello", addr, token),
+                Err(e) => eprintln!("❌ 无法连接种子节点 {}: {}", addr, e),
+            }
+        }
+    }
+    
+    /// 加入房间，房间不存在时创建并成为房主
+    pub fn join_room(&self, room: &str) -> Result<()> {
+        self.send_room_request(MessageType::JoinRoom, room, None, None)
+    }
+    
+    pub fn leave_room(&self, room: &str) -> Result<()> {
+        self.send_room_request(MessageType::LeaveRoom, room, None, None)
+    }
+    
+    /// 邀请用户加入房间（仅限受邀的房间需要），自己必须是房间成员
+    pub fn invite_to_room(&self, room: &str, user_id: &str) -> Result<()> {
+        self.send_room_request(MessageType::RoomInvite, room, Some(user_id), None)
+    }
+    
+    /// 把成员移出房间，只有房主可以执行
+    pub fn kick_from_room(&self, room: &str, user_id: &str) -> Result<()> {
+        self.send_room_request(MessageType::KickFromRoom, room, Some(user_id), None)
+    }
+    
+    /// 替换房间配置，只有房主可以执行
+    pub fn set_room_config(&self, room: &str, config: &RoomConfig) -> Result<()> {
+        let content = serde_json::to_string(config)?;
+        self.send_room_request(MessageType::SetRoomConfig, room, None, Some(content))
+    }
+    
+    /// 发送房间聊天，始终经服务器转发给房间成员
+    pub fn send_room_message(&self, room: &str, content: String) -> Result<()> {
+        let mut pending_message = Self::create_chat_message_static(self.user_id.clone(), None, content);
+        pending_message.message.room = Some(room.to_string());
+        self.check_outgoing(&mut pending_message.message)?;
+        self.message_sender.send(pending_message)
+            .map_err(|_| P2PError::ChannelClosed("消息发送通道".to_string()))?;
+        Ok(())
+    }
+    
+    fn send_room_request(&self, msg_type: MessageType, room: &str, target_id: Option<&str>, content: Option<String>) -> Result<()> {
+        let mut message = Message::new(msg_type, self.user_id.clone()).with_room(room.to_string());
+        message.target_id = target_id.map(str::to_string);
+        message.content = content;
+        self.queue_message(MessageTarget::Server, message)
+    }
+    
     /// 请求对等节点列表
-    pub fn request_peer_list(&self) -> Result<(), P2PError> {
-        let request_message = Message {
-            msg_type: MessageType::PeerListRequest,
-            sender_id: self.user_id.clone(),
-            target_id: None,
-            content: None,
-            sender_peer_address: "127.0.0.1".to_string(),
-            sender_listen_port: 0,
-            timestamp: SystemTime::now(),
-            source: MessageSource::Server,
-        };
+    pub fn request_peer_list(&self) -> Result<()> {
+        self.send_peer_list_request(None)
+    }
+
+    /// 请求对等节点列表并驱动事件循环，直到完整列表（分页时为所有页）处理完毕，返回按 user_id 排序的已知节点。
+    /// 超时返回 `P2PError::Timeout`，此时已收到的部分响应仍会在之后的轮询中继续处理
+    pub fn request_peer_list_blocking(&mut self, timeout: Duration) -> Result<Vec<PeerInfo>> {
+        if !self.is_connected() {
+            return Err(P2PError::Connection("未连接到服务器".to_string()));
+        }
+        let applied = self.full_peer_lists_applied;
+        self.request_peer_list()?;
+        let deadline = Instant::now() + timeout;
+        while self.full_peer_lists_applied == applied {
+            if Instant::now() >= deadline {
+                return Err(P2PError::Timeout("等待对等节点列表".to_string()));
+            }
+            self.poll_once()?;
+        }
+        Ok(self.peers())
+    }
+
+    /// 按 user_id 排序的已知节点，包含本地记录的直连状态（`reachability`）和最近活动时间（`last_seen`）
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self.known_peers.values().cloned().collect();
+        peers.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        peers
+    }
+
+    /// 只请求分页列表中的某一页（页码从 0 开始），用于补齐丢失的页
+    pub fn request_peer_list_page(&self, page: u32) -> Result<()> {
+        self.send_peer_list_request(Some(page))
+    }
+
+    fn send_peer_list_request(&self, page: Option<u32>) -> Result<()> {
+        let mut request_message = Message::new(MessageType::PeerListRequest, self.user_id.clone());
+        request_message.page = page;
         
         self.queue_message(MessageTarget::Server, request_message)?;
         Ok(())
     }
 
+    /// Join 消息：真实的 P2P 监听端口，以及按偏好顺序声明的帧格式
+    fn join_message(&self) -> Message {
+        let mut join = Message::join(self.user_id.clone(), "127.0.0.1".to_string(), self.listen_port);
+        join.framings = Some(self.config.framings.clone());
+        join
+    }
+
     /// 将消息加入发送队列（内部方法）
-    fn queue_message(&self, target: MessageTarget, message: Message) -> Result<(), P2PError> {
+    fn queue_message(&self, target: MessageTarget, mut message: Message) -> Result<()> {
+        self.check_outgoing(&mut message)?;
         let pending_message = PendingMessage { target, message };
         self.message_sender.send(pending_message)
-            .map_err(|_| P2PError::ConnectionError("消息发送通道已关闭".to_string()))?;
+            .map_err(|_| P2PError::ChannelClosed("消息发送通道".to_string()))?;
+        Ok(())
+    }
+
+    /// 入队前检查内容长度、消息字段（`Message::validate`）和帧长度，在调用方就拒绝不合法的消息，
+    /// 而不是发出后被对方默默忽略。内容超长且策略为截断时直接修改消息
+    fn check_outgoing(&self, message: &mut Message) -> Result<()> {
+        self.apply_content_limit(message)?;
+        if let Some(key) = self.config.signing_key.as_deref().filter(|_| message.msg_type.is_user_content()) {
+            message.signature = Some(sign_message(message, key.as_bytes()));
+        }
+        message.validate()?;
+        self.check_message_size(message)
+    }
+    
+    /// 按 max_content_chars 和 content_limit_policy 处理聊天、回应和编辑的内容
+    fn apply_content_limit(&self, message: &mut Message) -> Result<()> {
+        let Some(max) = self.config.max_content_chars else {
+            return Ok(());
+        };
+        if !message.msg_type.is_user_content() {
+            return Ok(());
+        }
+        let Some(content) = message.content.as_mut() else {
+            return Ok(());
+        };
+        let chars = content.chars().count();
+        if chars <= max {
+            return Ok(());
+        }
+        match self.config.content_limit_policy {
+            ContentLimitPolicy::Reject => Err(P2PError::ContentTooLong { chars, max }),
+            ContentLimitPolicy::Truncate => {
+                let end = content.char_indices().nth(max).map_or(content.len(), |(index, _)| index);
+                content.truncate(end);
+                println!("✂️ 消息内容 {} 个字符，超过上限 {}，已截断", chars, max);
+                Ok(())
+            }
+        }
+    }
+    
+    /// 发送前检查消息长度，超过 max_message_bytes 时返回 MessageTooLarge
+    pub fn check_message_size(&self, message: &Message) -> Result<()> {
+        let size = message.size_bytes();
+        let max = self.config.max_message_bytes;
+        if size > max {
+            return Err(P2PError::MessageTooLarge { size, max });
+        }
         Ok(())
     }
 
     /// 单次事件轮询（非阻塞）
-    pub fn poll_once(&mut self) -> Result<(), P2PError> {
-        self.poll.poll(&mut self.events, Some(Duration::from_millis(100)))?;
-        self.process_events()
+    pub fn poll_once(&mut self) -> Result<()> {
+        let timeout = self.poll_timeout(Duration::from_millis(100));
+        self.poll.poll(&mut self.events, Some(timeout))?;
+        let started = Instant::now();
+        self.process_events()?;
+        self.check_and_send_heartbeat();
+        self.check_and_send_gossip();
+        self.release_reordered(false);
+        self.record_loop_lag(started);
+        Ok(())
+    }
+    
+    /// 记录本轮（poll 返回之后）的处理耗时，过慢时告警
+    fn record_loop_lag(&mut self, started: Instant) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(started);
+        if self.lag_monitor.record(elapsed, self.config.lag_warn_threshold, now) {
+            eprintln!("⚠️ 事件循环单轮耗时 {} ms，超过告警阈值 {} ms", elapsed.as_millis(), self.config.lag_warn_threshold.as_millis());
+        }
+    }
+    
+    /// 健康状态：与服务器断开为 Unhealthy（纯 P2P 模式下改为没有直连时 Degraded），有心跳未收到确认至少为 Degraded，其余按事件循环延迟判断
+    pub fn health(&self) -> Health {
+        // 纯 P2P 模式没有服务器，没有任何直连时为 Degraded
+        if self.mesh && self.peer_to_token.is_empty() {
+            return Health::Degraded;
+        }
+        if !self.mesh && !self.is_connected() {
+            return Health::Unhealthy;
+        }
+        match self.lag_monitor.lag().health(self.config.lag_warn_threshold) {
+            Health::Healthy if self.missed_heartbeat_acks > 0 => Health::Degraded,
+            health => health,
+        }
+    }
+    
+    /// 各连接读写计数的快照，服务器连接的 token 为 `Token(0)`（重连后重新计数），对等节点连接断开后移除
+    pub fn connection_stats(&self) -> HashMap<Token, ConnStats> {
+        self.conn_stats.clone()
+    }
+    
+    /// 当前使用的 user_id（访客加入后为服务器分配的 id）
+    pub fn user_id(&self) -> &str {
+        &self.user_id
     }
     
     /// 检查是否连接到服务器
@@ -263,33 +777,38 @@ impl P2PClient {
         self.server_stream.is_some()
     }
     
-    /// 尝试重新连接到服务器
-    pub fn try_reconnect(&mut self) -> Result<(), P2PError> {
+    /// 尝试重新连接到服务器。
+    ///
+    /// 导致服务器连接断开的网络故障通常也会影响 P2P 直连，所以先检查每条直连：
+    /// 已失效的被清理，按 `redial_peers_on_reconnect` 决定是否重新拨号，结果通过
+    /// `ClientEvent::PeerLinkChecked` 发出
+    pub fn try_reconnect(&mut self) -> Result<()> {
         if self.is_connected() {
             return Ok(()); // 已经连接
         }
         
+        self.check_peer_links();
         println!("尝试重新连接到服务器...");
         
-        match TcpStream::connect(self.server_addr) {
+        match self.config.socket.connect(self.server_addr, None) {
             Ok(mut stream) => {
                 self.poll.registry()
                     .register(&mut stream, SERVER, Interest::READABLE | Interest::WRITABLE)?;
                 
                 self.server_stream = Some(stream);
+                self.server_connecting = true;
+                self.held_server_messages.clear();
+                self.server_framing = Framing::Newline;
+                self.server_write_buffer.clear();
+                self.server_writable_interest = true;
+                self.awaiting_welcome = false;
+                self.pending_heartbeat = None;
+                self.missed_heartbeat_acks = 0;
                 self.buffers.insert(SERVER, Vec::new());
+                self.conn_stats.insert(SERVER, ConnStats::default());
                 
                 // 重新发送join消息，包含真实的监听端口
-                let join_message = Message {
-                    msg_type: MessageType::Join,
-                    sender_id: self.user_id.clone(),
-                    target_id: None,
-                    content: None,
-                    sender_peer_address: "127.0.0.1".to_string(),
-                    sender_listen_port: self.listen_port,  // 发送真实的监听端口
-                    timestamp: SystemTime::now(),
-                    source: MessageSource::Server,
-                };
+                let join_message = self.join_message();
                 
                 self.queue_message(MessageTarget::Server, join_message)?;
                 println!("重新连接成功！");
@@ -297,22 +816,22 @@ impl P2PClient {
             }
             Err(e) => {
                 eprintln!("重新连接失败: {}", e);
-                Err(P2PError::IoError(e))
+                Err(P2PError::Io(e).context(format!("重新连接服务器 {}", self.server_addr)))
             }
         }
     }
     
     /// 运行客户端（纯粹的网络事件循环）
     /// 使用通道接收外部指令和消息
-    pub fn run(&mut self) -> Result<(), P2PError> {
+    pub fn run(&mut self) -> Result<()> {
         println!("客户端开始运行，按 Ctrl+C 或输入 /exit 退出");
         let mut reconnect_attempts = 0;
         let max_reconnect_attempts = 5;
         
         loop {
             // 检查连接状态，如果断开则尝试重连
-            if !self.is_connected() && reconnect_attempts < max_reconnect_attempts {
-                if let Err(_) = self.try_reconnect() {
+            if !self.mesh && !self.is_connected() && reconnect_attempts < max_reconnect_attempts {
+                if self.try_reconnect().is_err() {
                     reconnect_attempts += 1;
                     println!("重连尝试 {}/{}", reconnect_attempts, max_reconnect_attempts);
                     std::thread::sleep(Duration::from_secs(2)); // 等待一段时间再重试
@@ -323,7 +842,10 @@ impl P2PClient {
             }
             
             // 处理网络事件和待发送消息
-            match self.poll.poll(&mut self.events, Some(Duration::from_millis(50))) {
+            let timeout = self.poll_timeout(Duration::from_millis(50));
+            let poll_result = self.poll.poll(&mut self.events, Some(timeout));
+            let started = Instant::now();
+            match poll_result {
                 Ok(_) => {
                     if let Err(e) = self.process_events() {
                         eprintln!("处理事件时出错: {}", e);
@@ -341,12 +863,36 @@ impl P2PClient {
             
             // 检查是否需要发送心跳
             self.check_and_send_heartbeat();
+            self.check_and_send_gossip();
+            self.release_reordered(false);
+            
+            // 处理本轮之前积累的全部控制指令
+            if !self.process_commands() {
+                break;
+            }
             
-            // 检查控制指令
+            self.record_loop_lag(started);
+            
+            // 如果重连尝试过多，给出提示
+            if reconnect_attempts >= max_reconnect_attempts {
+                eprintln!("达到最大重连尝试次数，客户端将在断线模式下继续运行");
+                reconnect_attempts = 0; // 重置以便稍后再次尝试
+                std::thread::sleep(Duration::from_secs(5));
+            }
+        }
+        // 退出前投递重排缓冲中剩余的消息
+        self.release_reordered(true);
+        Ok(())
+    }
+    
+    /// 依次处理通道中已有的全部控制指令（外部线程一次投递多条时不必每轮只处理一条）。
+    /// 收到停止指令或通道断开时返回 false，之后的指令不再处理
+    fn process_commands(&mut self) -> bool {
+        loop {
             match self.control_receiver.try_recv() {
                 Ok(ClientCommand::Stop) => {
                     println!("收到停止指令，正在关闭客户端...");
-                    break;
+                    return false;
                 }
                 Ok(ClientCommand::ConnectToPeer(peer_id)) => {
                     if let Err(e) = self.connect_to_peer(&peer_id) {
@@ -358,6 +904,11 @@ impl P2PClient {
                         eprintln!("发送直接消息失败: {}", e);
                     }
                 }
+                Ok(ClientCommand::SendWithFallback(peer_id, content)) => {
+                    if let Err(e) = self.send_with_fallback(&peer_id, content) {
+                        eprintln!("发送消息失败: {}", e);
+                    }
+                }
                 Ok(ClientCommand::SmartSendMessage(target_id, content)) => {
                     if let Err(e) = self.send_smart_message(target_id, content) {
                         eprintln!("发送消息失败: {}", e);
@@ -376,42 +927,41 @@ impl P2PClient {
                         println!("🔄 已请求刷新对等节点列表...");
                     }
                 }
-                Err(mpsc::TryRecvError::Empty) => {
-                    // 没有指令，继续运行
-                }
+                Err(mpsc::TryRecvError::Empty) => return true,
                 Err(mpsc::TryRecvError::Disconnected) => {
                     println!("控制通道已断开，客户端退出");
-                    break;
+                    return false;
                 }
             }
-            
-            // 如果重连尝试过多，给出提示
-            if reconnect_attempts >= max_reconnect_attempts {
-                eprintln!("达到最大重连尝试次数，客户端将在断线模式下继续运行");
-                reconnect_attempts = 0; // 重置以便稍后再次尝试
-                std::thread::sleep(Duration::from_secs(5));
-            }
         }
-        Ok(())
     }
     
     /// 处理网络事件（内部方法）
-    fn process_events(&mut self) -> Result<(), P2PError> {
+    fn process_events(&mut self) -> Result<()> {
         // 先处理待发送的消息
         self.process_pending_messages()?;
         
         // 再处理网络事件
-        let event_tokens: Vec<Token> = self.events.iter().map(|e| e.token()).collect();
+        let events: Vec<(Token, bool, bool)> = self.events.iter()
+            .map(|e| (e.token(), e.is_readable(), e.is_writable()))
+            .collect();
         
-        for token in event_tokens {
+        for (token, readable, writable) in events {
             match token {
-                SERVER => self.handle_server_event()?,
+                SERVER => {
+                    if writable && self.server_connecting {
+                        self.finish_server_connect()?;
+                    } else if writable {
+                        self.flush_server_writes()?;
+                    }
+                    if readable {
+                        self.handle_server_event()?;
+                    }
+                }
                 LISTENER => self.handle_listener_event()?,
                 token => {
-                    if let Some(event) = self.events.iter().find(|e| e.token() == token) {
-                        if event.is_readable() {
-                            self.handle_readable(token)?;
-                        }
+                    if readable {
+                        self.handle_readable(token)?;
                     }
                 }
             }
@@ -420,10 +970,22 @@ impl P2PClient {
     }
     
     /// 处理待发送的消息
-    fn process_pending_messages(&mut self) -> Result<(), P2PError> {
+    fn process_pending_messages(&mut self) -> Result<()> {
         // 处理所有待发送的消息
-        while let Ok(pending_message) = self.message_receiver.try_recv() {
+        while let Ok(mut pending_message) = self.message_receiver.try_recv() {
+            // 外部直接通过通道投递的消息在这里补做字段和长度检查
+            if let Err(e) = self.check_outgoing(&mut pending_message.message) {
+                eprintln!("❌ 消息未发送: {}", e);
+                continue;
+            }
             match pending_message.target {
+                MessageTarget::Server if self.mesh => {
+                    self.send_over_mesh(&pending_message.message)?;
+                }
+                MessageTarget::Server if self.server_connecting || self.awaiting_welcome => {
+                    // 连接尚未建立或帧格式尚未确定，暂存到之后再发送
+                    self.held_server_messages.push(pending_message.message);
+                }
                 MessageTarget::Server => {
                     self.send_message_to_server(&pending_message.message)?;
                 }
@@ -435,9 +997,56 @@ impl P2PClient {
         Ok(())
     }
 
-    fn handle_server_event(&mut self) -> Result<(), P2PError> {
-        if let Some(stream) = &mut self.server_stream {
-            let mut buffer = [0; 1024];
+    /// 非阻塞 connect 完成（收到 WRITABLE）后确认连接状态，并发送暂存的消息
+    fn finish_server_connect(&mut self) -> Result<()> {
+        let Some(stream) = &self.server_stream else {
+            return Ok(());
+        };
+        
+        match stream.take_error() {
+            Ok(None) => {}
+            Ok(Some(e)) | Err(e) => {
+                eprintln!("⚠️ 连接服务器失败: {}，将尝试重新连接...", e);
+                self.server_stream = None;
+                self.server_connecting = false;
+                self.buffers.remove(&SERVER);
+                return Ok(());
+            }
+        }
+        
+        match stream.peer_addr() {
+            Ok(_) => {}
+            // 虚假唤醒：连接仍在进行中
+            Err(e) if e.kind() == std::io::ErrorKind::NotConnected => return Ok(()),
+            Err(e) => {
+                eprintln!("⚠️ 连接服务器失败: {}，将尝试重新连接...", e);
+                self.server_stream = None;
+                self.server_connecting = false;
+                self.buffers.remove(&SERVER);
+                return Ok(());
+            }
+        }
+        
+        self.server_connecting = false;
+        self.flush_held_server_messages()
+    }
+    
+    /// 按顺序发送暂存的消息；发出提议新帧格式的 Join 后，其余消息继续暂存到欢迎消息到达
+    fn flush_held_server_messages(&mut self) -> Result<()> {
+        for message in std::mem::take(&mut self.held_server_messages) {
+            if self.awaiting_welcome {
+                self.held_server_messages.push(message);
+            } else {
+                self.send_message_to_server(&message)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_server_event(&mut self) -> Result<()> {
+        // 与 handle_readable 相同，必须读到 WouldBlock 为止，否则紧跟在数据后面的 EOF 不会再触发事件
+        let mut buffer = [0; 1024];
+        while let Some(stream) = &mut self.server_stream {
             match stream.read(&mut buffer) {
                 Ok(0) => {
                     println!("⚠️ 服务器主动断开连接，将尝试重新连接...");
@@ -446,6 +1055,7 @@ impl P2PClient {
                     return Ok(());
                 }
                 Ok(n) => {
+                    self.conn_stats.entry(SERVER).or_default().bytes_read += n as u64;
                     if let Some(peer_buffer) = self.buffers.get_mut(&SERVER) {
                         peer_buffer.extend_from_slice(&buffer[..n]);
                     }
@@ -453,7 +1063,9 @@ impl P2PClient {
                 }
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     // 这是正常的非阻塞状态，不用处理
+                    break;
                 }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
                 Err(e) if e.kind() == std::io::ErrorKind::ConnectionReset || 
                          e.kind() == std::io::ErrorKind::ConnectionAborted ||
                          e.kind() == std::io::ErrorKind::BrokenPipe => {
@@ -466,6 +1078,7 @@ impl P2PClient {
                     // 其他类型的错误，记录但不立即断开连接
                     eprintln!("⚠️ 服务器连接出现错误: {}，继续监听...", e);
                     // 只有在持续错误时才断开连接
+                    break;
                 }
             }
         }
@@ -473,16 +1086,19 @@ impl P2PClient {
     }
 
     /// 处理监听器事件，接受其他客户端的P2P连接
-    fn handle_listener_event(&mut self) -> Result<(), P2PError> {
+    fn handle_listener_event(&mut self) -> Result<()> {
         if let Some(listener) = &self.listener {
             loop {
                 match listener.accept() {
                     Ok((mut stream, addr)) => {
-                        let peer_token = self.next_peer_token;
-                        self.next_peer_token = Token(self.next_peer_token.0 + 1);
+                        if let Err(e) = self.config.socket.apply(&stream) {
+                            eprintln!("⚠️ 设置P2P连接 {} 的套接字选项失败: {}", addr, e);
+                        }
+                        let peer_token = self.peer_tokens.allocate();
                         
                         self.poll.registry()
-                            .register(&mut stream, peer_token, Interest::READABLE | Interest::WRITABLE)?;
+                            .register(&mut stream, peer_token, Interest::READABLE | Interest::WRITABLE)
+                            .with_context(|| format!("注册来自 {} 的P2P连接", addr))?;
                         
                         self.streams.insert(peer_token, stream);
                         self.buffers.insert(peer_token, Vec::new());
@@ -491,7 +1107,7 @@ impl P2PClient {
                     }
                     Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => {
                         eprintln!("接受P2P连接错误: {}", e);
-                        return Err(P2PError::IoError(e));
+                        return Err(P2PError::Io(e).context("接受P2P连接"));
                     }
                     _ => break,
                 }
@@ -500,144 +1116,496 @@ impl P2PClient {
         Ok(())
     }
 
-    fn handle_readable(&mut self, token: Token) -> Result<(), P2PError> {
-        if let Some(stream) = self.streams.get_mut(&token) {
-            let mut buffer = [0; 1024];
+    fn handle_readable(&mut self, token: Token) -> Result<()> {
+        // mio 是边沿触发，必须读到 WouldBlock 为止，否则剩余数据要等到对方再次发送才会被读取
+        let mut buffer = [0; 1024];
+        while let Some(stream) = self.streams.get_mut(&token) {
             match stream.read(&mut buffer) {
                 Ok(0) => {
                     println!("对等节点 {:?} 已断开连接", token);
                     self.remove_peer(token);
+                    break;
                 }
                 Ok(n) => {
+                    self.conn_stats.entry(token).or_default().bytes_read += n as u64;
                     if let Some(peer_buffer) = self.buffers.get_mut(&token) {
                         peer_buffer.extend_from_slice(&buffer[..n]);
                     }
                     self.try_parse_messages(token)?;
                 }
-                Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => {
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
                     eprintln!("对等节点 {:?} 连接错误: {}", token, e);
                     self.remove_peer(token);
-                    return Ok(()); // 不要因为一个对等节点的错误就退出
+                    break; // 不要因为一个对等节点的错误就退出
                 }
-                _ => {}
             }
         }
         Ok(())
     }
 
-    fn try_parse_messages(&mut self, token: Token) -> Result<(), P2PError> {
-        let mut messages = Vec::new();
-        
-        if let Some(buffer) = self.buffers.get_mut(&token) {
-            while let Some(delimiter_pos) = buffer.iter().position(|&b| b == b'\n') {
-                let message_data = buffer.drain(..=delimiter_pos).collect::<Vec<_>>();
-                let message_data = &message_data[..message_data.len() - 1];
-                
-                if let Ok(mut message) = deserialize_message(message_data) {
-                    // 根据token来源设置消息来源标识
-                    message.source = if token == SERVER {
-                        MessageSource::Server
+    fn try_parse_messages(&mut self, token: Token) -> Result<()> {
+        // 逐帧处理：欢迎消息之后的服务器数据可能要按协商出的新格式解析
+        let max = self.config.max_message_bytes;
+        loop {
+            let framing = if token == SERVER { self.server_framing } else { Framing::Newline };
+            let Some(buffer) = self.buffers.get_mut(&token) else {
+                break;
+            };
+            let Some(message_data) = framing.take_frame(buffer) else {
+                // 服务器连接同样不能无限缓存：断开后由重连逻辑重新连接
+                if buffer.len() > max {
+                    if token == SERVER {
+                        eprintln!("❌ 服务器 {} 发送的消息超过 {} 字节，断开连接，将尝试重新连接...", self.server_addr, max);
+                        self.server_stream = None;
+                        self.buffers.remove(&SERVER);
                     } else {
-                        MessageSource::Peer
-                    };
-                    messages.push(message);
+                        eprintln!("❌ 对等节点 {:?} 发送的消息超过 {} 字节，断开连接", token, max);
+                        self.remove_peer(token);
+                    }
+                }
+                break;
+            };
+            if message_data.len() > max {
+                self.conn_stats.entry(token).or_default().parse_failures += 1;
+                eprintln!("❌ 丢弃来自 {:?} 的超长消息: {} 字节", token, message_data.len());
+                continue;
+            }
+            let parsed = deserialize_message_lenient(&message_data).and_then(|message| message.validate().map(|()| message));
+            let conn = self.conn_stats.entry(token).or_default();
+            match &parsed {
+                Ok(_) => conn.messages_parsed += 1,
+                // 对方不是本协议的节点，继续解析只会不停报错，直接断开
+                Err(P2PError::NotP2PProtocol(preview)) => {
+                    conn.parse_failures += 1;
+                    if token == SERVER {
+                        eprintln!("❌ 服务器 {} 发来的数据不是P2P协议（开头为 \"{}\"），断开连接，请检查服务器地址和端口", self.server_addr, preview);
+                        self.server_stream = None;
+                        self.buffers.remove(&SERVER);
+                    } else {
+                        eprintln!("❌ 对等节点 {:?} 发来的数据不是P2P协议（开头为 \"{}\"），断开连接", token, preview);
+                        self.remove_peer(token);
+                    }
+                    break;
+                }
+                Err(e) => {
+                    conn.parse_failures += 1;
+                    eprintln!("❌ 丢弃来自 {:?} 的无效消息: {}", token, e);
+                }
+            }
+            if let Ok(mut message) = parsed {
+                debug!("received {} on token={:?}", message.redacted(), token);
+                if token == SERVER {
+                    self.last_server_receive = Instant::now();
+                }
+                // 根据token来源设置消息来源标识
+                message.source = if token == SERVER {
+                    MessageSource::Server
+                } else {
+                    MessageSource::Peer
+                };
+                self.emit_event(ClientEvent::MessageReceived(message.clone()));
+                self.record_peer_seen(&message.sender_id);
+                match message.msg_type {
+                    // 身份交换和节点转告需要知道来自哪条直连
+                    MessageType::Hello if token != SERVER => self.handle_hello(token, &message)?,
+                    MessageType::PeerGossip if token != SERVER => self.handle_peer_gossip(token, &message),
+                    _ => self.handle_message(&message)?,
                 }
             }
-        }
-        
-        for message in messages {
-            self.handle_message(&message)?;
         }
         
         Ok(())
     }
 
-    fn handle_message(&mut self, message: &Message) -> Result<(), P2PError> {
+    /// 校验聊天、回应和编辑的签名；未配置 signing_key 或不是这几种消息时返回 None
+    fn check_signature(&self, message: &Message) -> Option<bool> {
+        let key = self.config.signing_key.as_deref()?;
+        message.msg_type.is_user_content().then(|| verify_message(message, key.as_bytes()))
+    }
+
+    /// 显示一条聊天消息并发出 ChatReceived（启用 reorder_window 时在放行后调用）
+    fn deliver_chat(&mut self, message: &Message, verified: Option<bool>) {
+        let late = !self.reconcile_conversation(message);
+        if let Some(content) = &message.content {
+            // 根据消息来源显示不同的标识；与已直连的节点的私聊即使经服务器到达也归入 P2P 会话
+            let source_tag = match message.source {
+                MessageSource::Server if message.direct_target().is_some()
+                    && self.peer_to_token.contains_key(&message.sender_id) => "[P2P·服务器转发]",
+                MessageSource::Server => "[服务器]",
+                MessageSource::Peer => "[P2P]",
+            };
+            let late_tag = if late { "（较早发出，迟到）" } else { "" };
+            let late_tag = if verified == Some(false) { format!("{}（签名无效）", late_tag) } else { late_tag.to_string() };
+            
+            // 检查是否为私聊消息
+            if let Some(room) = &message.room {
+                println!("{}房间[{}][{}]{}: {}", source_tag, room, message.sender_id, late_tag, content);
+            } else if message.direct_target().is_some() {
+                println!("{}私聊[{}]{}: {}", source_tag, message.sender_id, late_tag, content);
+            } else {
+                println!("{}公共[{}]{}: {}", source_tag, message.sender_id, late_tag, content);
+            }
+            
+            self.emit_event(ClientEvent::ChatReceived {
+                msg_id: message.msg_id.clone(),
+                sender_id: message.sender_id.clone(),
+                target_id: message.direct_target().map(str::to_string),
+                content: content.clone(),
+                content_type: message.content_type.clone().unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_string()),
+                source: message.source.clone(),
+                late,
+                room: message.room.clone(),
+                verified,
+            });
+        }
+    }
+
+    /// 把聊天消息放进按发送方区分的重排缓冲，等待 reorder_window 内可能更早发出、但经另一条路径
+    /// 晚到的消息。比会话中已放行的消息更早发出的消息等待也无法恢复顺序，直接投递（标记为迟到）
+    fn hold_chat(&mut self, message: &Message, verified: Option<bool>) {
+        if self.is_behind_conversation(message) {
+            self.deliver_chat(message, verified);
+            return;
+        }
+        let held = self.reorder_buffer.entry(message.sender_id.clone()).or_default();
+        let chat = HeldChat { arrived: Instant::now(), order: reorder_key(message), message: message.clone(), verified };
+        let index = held.partition_point(|other| other.order <= chat.order);
+        held.insert(index, chat);
+    }
+
+    /// 放行暂存已满 reorder_window 的消息（force 时放行全部）。每个发送方按顺序放行到最后一条到期的
+    /// 消息为止，排在它前面、尚未到期的消息也一并放行，因此缺少某个序号时也不会一直等待
+    fn release_reordered(&mut self, force: bool) {
+        let Some(window) = self.config.reorder_window else {
+            return;
+        };
+        let now = Instant::now();
+        let mut ready = Vec::new();
+        for held in self.reorder_buffer.values_mut() {
+            let due = held.iter().rposition(|chat| force || now.duration_since(chat.arrived) >= window);
+            if let Some(last) = due {
+                ready.extend(held.drain(..=last));
+            }
+        }
+        self.reorder_buffer.retain(|_, held| !held.is_empty());
+        for chat in ready {
+            self.deliver_chat(&chat.message, chat.verified);
+        }
+    }
+
+    /// 本轮 poll 的超时：重排缓冲中有消息时不超过最早一条到期的剩余时间
+    fn poll_timeout(&self, default: Duration) -> Duration {
+        let Some(window) = self.config.reorder_window else {
+            return default;
+        };
+        self.reorder_buffer.values()
+            .flatten()
+            .map(|chat| (chat.arrived + window).saturating_duration_since(Instant::now()))
+            .min()
+            .map_or(default, |wait| wait.min(default))
+    }
+
+    fn handle_message(&mut self, message: &Message) -> Result<()> {
+        let verified = self.check_signature(message);
+        if verified == Some(false) && self.config.signature_policy == SignaturePolicy::Reject {
+            eprintln!("❌ 丢弃来自 {} 的签名无效的 {} 消息", message.sender_id, message.msg_type);
+            return Ok(());
+        }
         match message.msg_type {
             MessageType::Chat => {
-                if let Some(content) = &message.content {
-                    // 根据消息来源显示不同的标识
-                    let source_tag = match message.source {
-                        MessageSource::Server => "[服务器]",
-                        MessageSource::Peer => "[P2P]",
-                    };
-                    
-                    // 检查是否为私聊消息
-                    if message.target_id.is_some() {
-                        println!("{}私聊[{}]: {}", source_tag, message.sender_id, content);
-                    } else {
-                        println!("{}公共[{}]: {}", source_tag, message.sender_id, content);
-                    }
+                if !self.remember_message(message) {
+                    return Ok(());
+                }
+                self.remember_author(message);
+                match self.config.reorder_window {
+                    Some(_) => self.hold_chat(message, verified),
+                    None => self.deliver_chat(message, verified),
                 }
             }
+            MessageType::React | MessageType::Edit => {
+                if !self.remember_message(message) {
+                    return Ok(());
+                }
+                self.handle_reference(message);
+            }
             MessageType::PeerList => {
                 if let Some(content) = &message.content {
-                    println!("📄 收到对等节点列表: {}", content);
-                    if let Ok(peer_list) = serde_json::from_str::<Vec<(String, String, u16)>>(content) {
-                        println!("🗺️ 解析到 {} 个对等节点:", peer_list.len());
-                        for (user_id, address, port) in peer_list {
-                            if user_id != self.user_id {
-                                let peer_info = PeerInfo::new(user_id.clone(), address.clone(), port);
-                                self.known_peers.insert(peer_info.user_id.clone(), peer_info);
-                                println!("  ✅ 添加对等节点: {} ({}:{})", user_id, address, port);
-                            } else {
-                                println!("  ℹ️ 跳过自己: {} ({}:{})", user_id, address, port);
-                            }
-                        }
-                        println!("📊 当前已知对等节点数量: {}", self.known_peers.len());
+                    if let Ok(peer_list) = serde_json::from_str::<Vec<PeerEntry>>(content) {
+                        self.handle_peer_list_page(message, peer_list);
                     } else {
                         eprintln!("❌ 无法解析对等节点列表");
                     }
                 }
             }
-            _ => {}
+            MessageType::PeerListDelta => {
+                match message.content.as_deref().map(serde_json::from_str::<PeerListDelta>) {
+                    Some(Ok(delta)) => self.apply_peer_list_delta(delta)?,
+                    _ => eprintln!("❌ 无法解析对等节点列表增量"),
+                }
+            }
+            MessageType::Heartbeat => {
+                self.check_peer_list_version(message)?;
+            }
+            MessageType::HeartbeatAck => {
+                let nonce = message.content.as_deref().and_then(|c| c.parse::<u64>().ok());
+                if let Some((pending_nonce, sent_at)) = self.pending_heartbeat {
+                    if nonce == Some(pending_nonce) {
+                        let now = Instant::now();
+                        self.server_rtt = Some(now.duration_since(sent_at));
+                        self.last_heartbeat_ack = Some(now);
+                        self.pending_heartbeat = None;
+                        self.missed_heartbeat_acks = 0;
+                    }
+                }
+                self.check_peer_list_version(message)?;
+            }
+            MessageType::UserLeft => {
+                let reason = message.content.as_deref().unwrap_or("Left");
+                println!("👋 用户 {} 已离开 ({})", message.sender_id, reason);
+                self.set_peer_reachability(&message.sender_id, PeerReachability::Unreachable);
+                self.known_peers.remove(&message.sender_id);
+            }
+            MessageType::Welcome => {
+                match message.content.as_deref().map(serde_json::from_str::<ServerInfo>) {
+                    Some(Ok(info)) => {
+                        if let Some(assigned) = &info.assigned_user_id {
+                            println!("🪪 以访客身份加入，服务器分配的用户ID: {}", assigned);
+                            self.user_id = assigned.clone();
+                            // 对等节点列表先于欢迎消息到达，其中包含自己
+                            self.known_peers.remove(assigned);
+                        }
+                        println!("🎉 欢迎加入！服务器版本 {}，当前在线 {} 人", info.version, info.connected_users);
+                        if let Some(motd) = &info.motd {
+                            println!("📌 [公告] {}", motd);
+                        }
+                        self.server_framing = info.framing;
+                        if self.awaiting_welcome {
+                            self.awaiting_welcome = false;
+                            self.flush_held_server_messages()?;
+                        }
+                        self.emit_event(ClientEvent::ServerInfo(info));
+                    }
+                    _ => eprintln!("❌ 无法解析欢迎消息"),
+                }
+            }
+            MessageType::RoomUpdate => {
+                match message.content.as_deref().map(serde_json::from_str::<RoomInfo>) {
+                    Some(Ok(info)) => {
+                        println!("🏠 房间 {}（房主 {}）成员: {}", info.name, info.config.owner, info.members.join(", "));
+                        self.emit_event(ClientEvent::RoomUpdated(info));
+                    }
+                    _ => eprintln!("❌ 无法解析房间信息"),
+                }
+            }
+            MessageType::RoomInvite => {
+                if let Some(room) = message.room.clone() {
+                    println!("📨 {} 邀请你加入房间 {}", message.sender_id, room);
+                    self.emit_event(ClientEvent::RoomInvited { room, inviter: message.sender_id.clone() });
+                }
+            }
+            MessageType::KickFromRoom => {
+                if let Some(room) = message.room.clone() {
+                    println!("🚪 你已被 {} 移出房间 {}", message.sender_id, room);
+                    self.emit_event(ClientEvent::RemovedFromRoom { room, by: message.sender_id.clone() });
+                }
+            }
+            MessageType::System => {
+                let content = message.content.clone().unwrap_or_default();
+                println!("📢 [系统公告] {}", content);
+                self.emit_event(ClientEvent::SystemMessage(content));
+            }
+            MessageType::Error => {
+                let detail = message.content.clone().unwrap_or_default();
+                match (message.error_code, message.target_id.clone()) {
+                    (Some(ErrorCode::TargetOffline), Some(target_id)) => {
+                        eprintln!("❌ {} is offline", target_id);
+                        self.emit_event(ClientEvent::SendResult {
+                            msg_id: message.reply_to.clone(),
+                            target_id,
+                            result: Err(ErrorCode::TargetOffline),
+                        });
+                    }
+                    (Some(code), _) => eprintln!("❌ 服务器错误 [{:?}]: {}", code, detail),
+                    (None, _) => eprintln!("❌ 服务器错误: {}", detail),
+                }
+                self.emit_event(ClientEvent::ServerError { code: message.error_code, detail, reply_to: message.reply_to.clone() });
+            }
+            MessageType::JoinRejected => {
+                eprintln!("🚫 加入被拒绝: {}", message.content.as_deref().unwrap_or(""));
+            }
+            MessageType::Kick => {
+                println!("⚠️ 已被服务器踢出: {}", message.content.as_deref().unwrap_or(""));
+            }
+            MessageType::ServerShutdown => {
+                println!("⚠️ 服务器即将关闭: {}", message.content.as_deref().unwrap_or(""));
+            }
+            _ => {
+                debug!("no built-in handling for {}, passing it to the application", message.msg_type);
+                self.emit_event(ClientEvent::Unhandled(message.clone()));
+            }
         }
         Ok(())
     }
 
+    /// 处理回应或编辑。被引用的消息本地未知时仍然发出事件，由界面决定是否忽略
+    fn handle_reference(&mut self, message: &Message) {
+        let (Some(target_msg_id), Some(content)) = (message.reply_to.clone(), message.content.clone()) else {
+            return;
+        };
+        let author = self.message_authors.get(&target_msg_id);
+        let known = author.is_some();
+        
+        if message.msg_type == MessageType::React {
+            println!("💬 {} 回应了消息 {}: {}", message.sender_id, target_msg_id, content);
+            self.emit_event(ClientEvent::ReactionReceived {
+                sender_id: message.sender_id.clone(),
+                target_msg_id,
+                emoji: content,
+                known,
+            });
+            return;
+        }
+        
+        if author.is_some_and(|author| *author != message.sender_id) {
+            eprintln!("⚠️ 忽略 {} 对他人消息 {} 的编辑", message.sender_id, target_msg_id);
+            return;
+        }
+        println!("✏️ {} 编辑了消息 {}: {}", message.sender_id, target_msg_id, content);
+        self.emit_event(ClientEvent::MessageEdited {
+            sender_id: message.sender_id.clone(),
+            target_msg_id,
+            new_content: content,
+            known,
+        });
+    }
+
     /// 发送消息到服务器
-    fn send_message_to_server(&mut self, message: &Message) -> Result<(), P2PError> {
-        if let Some(stream) = &mut self.server_stream {
-            let data = serialize_message(message)?;
-            stream.write_all(&data)?;
+    fn send_message_to_server(&mut self, message: &Message) -> Result<()> {
+        debug!("sending {} to server", message.redacted());
+        self.remember_author(message);
+        if let Some(log) = &mut self.sent_log {
+            log.push(PendingMessage { target: MessageTarget::Server, message: message.clone() });
+            return Ok(());
+        }
+        if self.server_stream.is_some() {
+            let data = self.server_framing.encode(message)
+                .with_context(|| format!("编码发往服务器的 {} 消息", message.msg_type))?;
+            // 排在已缓冲的数据之后，保证顺序
+            self.server_write_buffer.extend_from_slice(&data);
+            self.last_server_send = Instant::now();
+            if self.server_write_buffer.len() == data.len() {
+                self.flush_server_writes()?;
+            }
+            if message.msg_type == MessageType::Join
+                && message.framings.iter().flatten().any(|framing| *framing != Framing::Newline) {
+                self.awaiting_welcome = true;
+            }
+        }
+        Ok(())
+    }
+    
+    /// 尽量写出发往服务器的缓冲数据。遇到 WouldBlock 时保留剩余数据并关注 WRITABLE，
+    /// 可写后继续；全部写出后切回只读关注。写错误按连接断开处理，由重连逻辑恢复
+    fn flush_server_writes(&mut self) -> Result<()> {
+        let Some(stream) = &mut self.server_stream else {
+            self.server_write_buffer.clear();
+            return Ok(());
+        };
+        
+        let mut written = 0;
+        let result = loop {
+            if written == self.server_write_buffer.len() {
+                break Ok(true);
+            }
+            match stream.write(&self.server_write_buffer[written..]) {
+                Ok(0) => break Err(std::io::Error::from(std::io::ErrorKind::WriteZero)),
+                Ok(n) => {
+                    written += n;
+                    self.conn_stats.entry(SERVER).or_default().bytes_written += n as u64;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break Ok(false),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => break Err(e),
+            }
+        };
+        self.server_write_buffer.drain(..written);
+        
+        match result {
+            Ok(drained) => {
+                if drained == self.server_writable_interest {
+                    let interest = if drained { Interest::READABLE } else { Interest::READABLE | Interest::WRITABLE };
+                    self.poll.registry().reregister(stream, SERVER, interest)?;
+                    self.server_writable_interest = !drained;
+                }
+            }
+            Err(e) => {
+                eprintln!("⚠️ 写入服务器失败: {}，将尝试重新连接...", e);
+                self.server_stream = None;
+                self.server_write_buffer.clear();
+                self.buffers.remove(&SERVER);
+            }
         }
         Ok(())
     }
     
     /// 发送消息到对等节点
-    fn send_message_to_peer(&mut self, token: Token, message: &Message) -> Result<(), P2PError> {
+    fn send_message_to_peer(&mut self, token: Token, message: &Message) -> Result<()> {
+        self.write_to_peer(token, message)
+            .with_context(|| format!("向对等节点 {} 发送 {} 消息", self.peer_label(token), message.msg_type))
+    }
+
+    /// 日志和错误说明中使用的对等节点名称：已表明身份的用 user_id，否则用 token
+    fn peer_label(&self, token: Token) -> String {
+        self.peer_to_token.iter()
+            .find(|(_, &t)| t == token)
+            .map(|(id, _)| id.clone())
+            .unwrap_or_else(|| format!("{:?}", token))
+    }
+
+    fn write_to_peer(&mut self, token: Token, message: &Message) -> Result<()> {
+        debug!("sending {} to peer token={:?}", message.redacted(), token);
+        self.remember_author(message);
+        if let Some(log) = &mut self.sent_log {
+            log.push(PendingMessage { target: MessageTarget::Peer(token), message: message.clone() });
+            return Ok(());
+        }
         if let Some(stream) = self.streams.get_mut(&token) {
             let data = serialize_message(message)?;
             match stream.write_all(&data) {
                 Ok(_) => {
                     // 消息发送成功
+                    self.conn_stats.entry(token).or_default().bytes_written += data.len() as u64;
                     Ok(())
                 }
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     // 非阻塞错误，稍后重试
                     eprintln!("⚠️ 连接忙碌，稍后重试...");
                     std::thread::sleep(Duration::from_millis(50));
-                    stream.write_all(&data).map_err(P2PError::IoError)
+                    stream.write_all(&data).map_err(P2PError::Io)?;
+                    self.conn_stats.entry(token).or_default().bytes_written += data.len() as u64;
+                    Ok(())
                 }
                 Err(e) if e.kind() == std::io::ErrorKind::NotConnected => {
                     eprintln!("❌ 连接未建立或已断开: {}", e);
-                    Err(P2PError::IoError(e))
+                    Err(P2PError::Io(e))
                 }
                 Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe || 
                          e.kind() == std::io::ErrorKind::ConnectionReset => {
                     eprintln!("❌ P2P连接已断开: {}", e);
                     // 清理断开的连接
                     self.remove_peer(token);
-                    Err(P2PError::IoError(e))
+                    Err(P2PError::Io(e))
                 }
                 Err(e) => {
                     eprintln!("❌ 发送P2P消息错误: {}", e);
-                    Err(P2PError::IoError(e))
+                    Err(P2PError::Io(e))
                 }
             }
         } else {
             eprintln!("❌ 找不到对等节点连接 (Token: {:?})", token);
-            Err(P2PError::PeerNotFound)
+            Err(P2PError::PeerNotFound(format!("{:?}", token)))
         }
     }
 
@@ -650,14 +1618,47 @@ impl P2PClient {
         if let Some(peer_id) = peer_id {
             self.peer_to_token.remove(&peer_id);
             println!("🚫 P2P连接已断开: {}", peer_id);
+            self.set_peer_reachability(&peer_id, PeerReachability::Unreachable);
         }
         
-        self.streams.remove(&token);
+        self.hello_sent.remove(&token);
+        if self.streams.remove(&token).is_some() {
+            self.peer_tokens.release(token);
+        }
         self.buffers.remove(&token);
+        self.conn_stats.remove(&token);
+    }
+
+    /// 检查所有 P2P 直连，清理已失效的连接，按配置重新拨号
+    fn check_peer_links(&mut self) {
+        let mut links: Vec<(String, Token)> = self.peer_to_token.iter()
+            .map(|(peer_id, &token)| (peer_id.clone(), token))
+            .collect();
+        links.sort();
+        
+        for (peer_id, token) in links {
+            // 测试模式下没有真实连接，只登记了映射
+            let alive = self.sent_log.is_some() || self.streams.get(&token).is_some_and(peer_link_alive);
+            let status = if alive {
+                PeerLinkStatus::Alive(token)
+            } else {
+                println!("🔌 与 {} 的直连已失效", peer_id);
+                self.remove_peer(token);
+                if !self.config.redial_peers_on_reconnect {
+                    PeerLinkStatus::Dropped
+                } else {
+                    match self.connect_to_peer(&peer_id) {
+                        Ok(connection) => PeerLinkStatus::Redialed(connection.token()),
+                        Err(e) => PeerLinkStatus::RedialFailed(e.to_string()),
+                    }
+                }
+            };
+            self.emit_event(ClientEvent::PeerLinkChecked { peer_id, status });
+        }
     }
 
     /// 直接连接到指定的对等节点
-    pub fn connect_to_peer(&mut self, peer_id: &str) -> Result<(), P2PError> {
+    pub fn connect_to_peer(&mut self, peer_id: &str) -> Result<PeerConnection> {
         println!("🔍 尝试连接到对等节点: {}", peer_id);
         println!("📋 当前已知对等节点数量: {}", self.known_peers.len());
         
@@ -668,161 +1669,619 @@ impl P2PClient {
         // 检查是否尝试连接到自己
         if peer_id == self.user_id {
             eprintln!("❌ 不能连接到自己！");
-            return Err(P2PError::ConnectionError("不能连接到自己".to_string()));
+            return Err(P2PError::Connection("不能连接到自己".to_string()));
         }
         
         // 检查是否已经连接
-        if self.peer_to_token.contains_key(peer_id) {
+        if let Some(&peer_token) = self.peer_to_token.get(peer_id) {
             println!("ℹ️ 已经与对等节点 {} 建立了直接连接", peer_id);
-            return Ok(());
+            return Ok(PeerConnection::AlreadyConnected(peer_token));
+        }
+        
+        // 测试模式：只登记映射，之后发往该节点的消息记录为 Peer 目标
+        if self.sent_log.is_some() {
+            let peer_token = self.peer_tokens.allocate();
+            self.peer_to_token.insert(peer_id.to_string(), peer_token);
+            self.set_peer_reachability(peer_id, PeerReachability::Connected);
+            return Ok(PeerConnection::Connected { token: peer_token, addr: None });
         }
         
         if let Some(peer_info) = self.known_peers.get(peer_id) {
             let peer_addr = peer_info.socket_addr()?;
             println!("🌐 尝试连接到 {}", peer_addr);
+            self.set_peer_reachability(peer_id, PeerReachability::Connecting);
             
-            match TcpStream::connect(peer_addr) {
-                Ok(mut stream) => {
-                    let peer_token = self.next_peer_token;
-                    self.next_peer_token = Token(self.next_peer_token.0 + 1);
-                    
-                    // 先注册到事件循环
-                    self.poll.registry()
-                        .register(&mut stream, peer_token, Interest::READABLE | Interest::WRITABLE)?;
-                    
-                    self.streams.insert(peer_token, stream);
-                    self.buffers.insert(peer_token, Vec::new());
+            match self.dial(peer_addr) {
+                Ok(peer_token) => {
                     self.peer_to_token.insert(peer_id.to_string(), peer_token);
-                    
                     println!("✨ 已直接连接到对等节点: {} (Token: {:?})", peer_id, peer_token);
-                    
-                    // 等待一小段时间确保连接稳定
-                    std::thread::sleep(Duration::from_millis(100));
-                    
-                    Ok(())
+                    Ok(PeerConnection::Connected { token: peer_token, addr: Some(peer_addr) })
                 }
                 Err(e) => {
                     eprintln!("❌ 无法连接到对等节点 {}: {}", peer_id, e);
-                    Err(P2PError::IoError(e))
+                    self.set_peer_reachability(peer_id, PeerReachability::Unreachable);
+                    Err(e.context(format!("连接对等节点 {}", peer_id)))
                 }
             }
         } else {
             eprintln!("❌ 未知的对等节点: {} (请检查对等节点是否在线)", peer_id);
-            Err(P2PError::PeerNotFound)
+            Err(P2PError::PeerNotFound(peer_id.to_string()))
+        }
+    }
+    
+    /// 建立一条出站直连并发送 Hello，返回分配的 token（尚未与 peer_id 关联）
+    fn dial(&mut self, peer_addr: SocketAddr) -> Result<Token> {
+        let mut stream = self.config.socket.connect(peer_addr, self.config.outbound_bind_addr)
+            .with_context(|| format!("拨号 {}", peer_addr))?;
+        let peer_token = self.peer_tokens.allocate();
+        
+        // 先注册到事件循环
+        if let Err(e) = self.poll.registry().register(&mut stream, peer_token, Interest::READABLE | Interest::WRITABLE) {
+            self.peer_tokens.release(peer_token);
+            return Err(P2PError::Io(e).context(format!("注册到 {} 的连接", peer_addr)));
+        }
+        self.streams.insert(peer_token, stream);
+        self.buffers.insert(peer_token, Vec::new());
+        
+        // 等待一小段时间确保连接稳定
+        std::thread::sleep(Duration::from_millis(100));
+        
+        if let Err(e) = self.send_hello(peer_token) {
+            self.remove_peer(peer_token);
+            return Err(e);
+        }
+        Ok(peer_token)
+    }
+    
+    fn send_hello(&mut self, token: Token) -> Result<()> {
+        let hello = Message::new(MessageType::Hello, self.user_id.clone())
+            .with_peer_info("127.0.0.1".to_string(), self.listen_port)
+            .with_source(MessageSource::Peer);
+        self.hello_sent.insert(token);
+        self.send_message_to_peer(token, &hello)
+    }
+    
+    /// 对方在直连上表明身份：登记 user_id 与 token 的映射（已有直连时保留原来的），
+    /// 被动接受的连接回复自己的 Hello；纯 P2P 模式下再把已知节点转告给对方
+    fn handle_hello(&mut self, token: Token, message: &Message) -> Result<()> {
+        let peer_id = message.sender_id.clone();
+        if peer_id.is_empty() || peer_id == self.user_id {
+            eprintln!("⚠️ 忽略无效的 Hello: {:?} (Token: {:?})", peer_id, token);
+            return Ok(());
         }
+        // 对方的监听地址：IP 取自连接本身，端口取自 Hello
+        let address = self.streams.get(&token)
+            .and_then(|stream| stream.peer_addr().ok())
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|| message.sender_peer_address.clone());
+        let entry = (peer_id.clone(), address.clone(), message.sender_listen_port);
+        let changed = self.known_peers.get(&peer_id).is_none_or(|info| info.address != entry.1 || info.port != entry.2);
+        self.upsert_known_peer(PeerEntry::new(peer_id.clone(), address, message.sender_listen_port));
+        self.peer_to_token.entry(peer_id.clone()).or_insert(token);
+        println!("🤝 对等节点 {} 已表明身份 (Token: {:?})", peer_id, token);
+        self.record_peer_seen(&peer_id);
+        self.set_peer_reachability(&peer_id, PeerReachability::Connected);
+        
+        if !self.hello_sent.contains(&token) {
+            self.send_hello(token)?;
+        }
+        if self.mesh {
+            // 把已知节点转告给新直连的节点，再把它转告给其他直连节点
+            let known = self.gossip_entries(Some(&peer_id));
+            if !known.is_empty() {
+                self.send_peer_gossip(token, &PeerGossip { ttl: self.config.gossip_ttl, peers: known });
+            }
+            if changed {
+                let entry = GossipEntry { user_id: peer_id, address: entry.1, port: entry.2, heard_ms_ago: 0 };
+                self.forward_gossip(&PeerGossip { ttl: self.config.gossip_ttl, peers: vec![entry] }, token);
+            }
+        }
+        Ok(())
+    }
+    
+    /// 合并直连节点转告的已知节点，同一 user_id 只处理第一项：
+    /// 1. 新节点直接加入，由 user_id 较小的一方主动连接，避免双方同时连接对方；
+    /// 2. 已知节点的地址不同时，以最近得知的为准（已直连的节点以直连时得到的地址为准）；
+    /// 3. 新增或有变化的项在 ttl 未耗尽时减一跳后转告给其他直连节点，没有变化的不再转告，因此会收敛
+    fn handle_peer_gossip(&mut self, token: Token, message: &Message) {
+        if !self.mesh {
+            return;
+        }
+        let Some(Ok(gossip)) = message.content.as_deref().map(serde_json::from_str::<PeerGossip>) else {
+            eprintln!("❌ 无法解析 {} 转告的节点列表", message.sender_id);
+            return;
+        };
+        let now = Instant::now();
+        let mut seen = HashSet::new();
+        let mut learned = Vec::new();
+        let mut updated = Vec::new();
+        for entry in gossip.peers {
+            if entry.user_id == self.user_id || !seen.insert(entry.user_id.clone()) {
+                continue;
+            }
+            // 早于客户端启动的时间无法用 Instant 表示，按启动时间处理
+            let heard_at = now.checked_sub(Duration::from_millis(entry.heard_ms_ago)).unwrap_or(self.started_at);
+            match self.known_peers.get_mut(&entry.user_id) {
+                None => {
+                    println!("🗺️ 从 {} 得知对等节点: {} ({}:{})", message.sender_id, entry.user_id, entry.address, entry.port);
+                    let mut info = PeerInfo::new(entry.user_id.clone(), entry.address.clone(), entry.port);
+                    info.last_heartbeat = heard_at;
+                    self.known_peers.insert(entry.user_id.clone(), info);
+                    learned.push(entry.user_id.clone());
+                    updated.push(entry);
+                }
+                Some(_) if self.peer_to_token.contains_key(&entry.user_id) => {}
+                // 客户端一侧的 last_heartbeat 记录最近一次得知该节点的时间
+                Some(info) if heard_at > info.last_heartbeat => {
+                    info.last_heartbeat = heard_at;
+                    if info.address != entry.address || info.port != entry.port {
+                        println!("🔀 {} 的地址更新为 {}:{} (来自 {})", entry.user_id, entry.address, entry.port, message.sender_id);
+                        info.address = entry.address.clone();
+                        info.port = entry.port;
+                        updated.push(entry);
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+        if updated.is_empty() {
+            return;
+        }
+        self.emit_event(ClientEvent::PeerListUpdated {
+            version: self.peer_list_version,
+            peers: self.known_peers.values().cloned().collect(),
+        });
+        if gossip.ttl > 0 {
+            self.forward_gossip(&PeerGossip { ttl: gossip.ttl - 1, peers: updated }, token);
+        }
+        
+        for user_id in &learned {
+            if self.user_id < *user_id && !self.peer_to_token.contains_key(user_id) {
+                if let Err(e) = self.connect_to_peer(user_id) {
+                    eprintln!("连接到对等节点 {} 失败: {}", user_id, e);
+                }
+            }
+        }
+    }
+    
+    /// 本地已知节点（可排除一个）的转告项，已直连的节点视为刚刚得知
+    fn gossip_entries(&self, exclude: Option<&str>) -> Vec<GossipEntry> {
+        let now = Instant::now();
+        self.known_peers.values()
+            .filter(|info| Some(info.user_id.as_str()) != exclude)
+            .map(|info| GossipEntry {
+                user_id: info.user_id.clone(),
+                address: info.address.clone(),
+                port: info.port,
+                heard_ms_ago: if self.peer_to_token.contains_key(&info.user_id) {
+                    0
+                } else {
+                    now.duration_since(info.last_heartbeat).as_millis() as u64
+                },
+            })
+            .collect()
+    }
+    
+    /// 把转告发给除来源以外的所有已表明身份的直连节点
+    fn forward_gossip(&mut self, gossip: &PeerGossip, from: Token) {
+        let tokens: Vec<Token> = self.peer_to_token.values().copied().filter(|token| *token != from).collect();
+        for token in tokens {
+            self.send_peer_gossip(token, gossip);
+        }
+    }
+    
+    fn send_peer_gossip(&mut self, token: Token, gossip: &PeerGossip) {
+        let content = match serde_json::to_string(gossip) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("❌ 序列化节点列表失败: {}", e);
+                return;
+            }
+        };
+        let message = Message::new(MessageType::PeerGossip, self.user_id.clone())
+            .with_content(content)
+            .with_source(MessageSource::Peer);
+        if let Err(e) = self.send_message_to_peer(token, &message) {
+            eprintln!("⚠️ 向 {:?} 转告节点列表失败: {}", token, e);
+        }
+    }
+    
+    /// 纯 P2P 模式下定期把全部已知节点转告给每个直连节点，弥补丢失的转告和之后的地址变化
+    fn check_and_send_gossip(&mut self) {
+        if !self.mesh || self.last_gossip.elapsed() < self.config.gossip_interval {
+            return;
+        }
+        self.last_gossip = Instant::now();
+        let links: Vec<(String, Token)> = self.peer_to_token.iter().map(|(id, token)| (id.clone(), *token)).collect();
+        for (peer_id, token) in links {
+            let peers = self.gossip_entries(Some(&peer_id));
+            if !peers.is_empty() {
+                self.send_peer_gossip(token, &PeerGossip { ttl: self.config.gossip_ttl, peers });
+            }
+        }
+    }
+    
+    /// 纯 P2P 模式下代替服务器投递：公共消息发给所有直连节点，私聊发给已直连的目标，
+    /// 其余只有服务器能处理的请求（房间、节点列表等）直接丢弃
+    fn send_over_mesh(&mut self, message: &Message) -> Result<()> {
+        if !message.msg_type.is_user_content() || message.room.is_some() {
+            eprintln!("⚠️ 纯 P2P 模式下没有服务器，忽略 {} 请求", message.msg_type);
+            return Ok(());
+        }
+        let tokens: Vec<Token> = match message.direct_target() {
+            Some(target) => match self.peer_to_token.get(target) {
+                Some(&token) => vec![token],
+                None => {
+                    eprintln!("❌ 没有与 {} 的直连，消息未发送", target);
+                    return Ok(());
+                }
+            },
+            None => self.peer_to_token.values().copied().collect(),
+        };
+        let mut message = message.clone();
+        message.source = MessageSource::Peer;
+        for token in tokens {
+            if let Err(e) = self.send_message_to_peer(token, &message) {
+                eprintln!("⚠️ 发送到 {:?} 失败: {}", token, e);
+            }
+        }
+        Ok(())
     }
     
     /// 发送直接P2P消息
-    pub fn send_direct_message(&mut self, peer_id: &str, content: String) -> Result<(), P2PError> {
+    pub fn send_direct_message(&mut self, peer_id: &str, content: String) -> Result<()> {
         // 检查是否尝试连接到自己
         if peer_id == self.user_id {
             eprintln!("❌ 不能发送消息给自己！");
-            return Err(P2PError::ConnectionError("不能发送消息给自己".to_string()));
+            return Err(P2PError::Connection("不能发送消息给自己".to_string()));
         }
         
-        // 查找是否已经有直接连接
-        let peer_token = self.find_peer_token(peer_id);
-        
-        if peer_token.is_none() {
-            // 如果没有直接连接，尝试建立连接
-            println!("🔗 正在为 {} 建立 P2P 连接...", peer_id);
-            self.connect_to_peer(peer_id)?;
-            
-            // 重新查找连接
-            let peer_token = self.find_peer_token(peer_id).ok_or(P2PError::PeerNotFound)?;
-            
-            // 等待连接稳定后发送消息
-            println!("⏳ 等待连接稳定...");
-            std::thread::sleep(Duration::from_millis(200));
-            
-            return self.send_p2p_message_with_retry(peer_token, peer_id, content);
-        }
+        // 查找是否已经有直接连接，没有则先建立连接
+        let peer_token = match self.find_peer_token(peer_id) {
+            Some(peer_token) => peer_token,
+            None => {
+                println!("🔗 正在为 {} 建立 P2P 连接...", peer_id);
+                let peer_token = self.connect_to_peer(peer_id)?.token();
+                
+                // 等待连接稳定后发送消息
+                println!("⏳ 等待连接稳定...");
+                std::thread::sleep(Duration::from_millis(200));
+                peer_token
+            }
+        };
         
-        let peer_token = peer_token.unwrap();
         self.send_p2p_message_with_retry(peer_token, peer_id, content)
     }
     
+    /// 先尝试 P2P 直连发送（含重试），失败后自动改由服务器转发，返回实际使用的路径。
+    /// 需要确定路径的调用者仍可直接使用 `send_direct_message` 或 `send_smart_message`
+    pub fn send_with_fallback(&mut self, peer_id: &str, content: String) -> Result<DeliveryPath> {
+        let path = match self.send_direct_message(peer_id, content.clone()) {
+            Ok(()) => DeliveryPath::Direct,
+            Err(e @ (P2PError::MessageTooLarge { .. } | P2PError::ContentTooLong { .. })) => return Err(e),
+            Err(e) => {
+                println!("↩️ 直连 {} 失败 ({})，改由服务器转发", peer_id, e);
+                // 丢弃失败的直连，下次重新建立
+                if let Some(token) = self.find_peer_token(peer_id) {
+                    self.remove_peer(token);
+                }
+                let pending_message = Self::create_chat_message_static(self.user_id.clone(), Some(peer_id.to_string()), content.clone());
+                self.queue_message(MessageTarget::Server, pending_message.message)?;
+                println!("📡 [你 -> {}]: {}", peer_id, content);
+                DeliveryPath::Server
+            }
+        };
+        self.emit_event(ClientEvent::MessageSent { target_id: peer_id.to_string(), path });
+        Ok(path)
+    }
+    
     /// 查找对等节点的token
     fn find_peer_token(&self, peer_id: &str) -> Option<Token> {
         self.peer_to_token.get(peer_id).copied()
     }
     
+    /// 用列表或 Hello 中的地址更新已知节点，保留本地记录的直连状态和最近活动时间
+    fn upsert_known_peer(&mut self, entry: PeerEntry) {
+        let mut info = PeerInfo::from(entry);
+        if let Some(previous) = self.known_peers.get(&info.user_id) {
+            info.reachability = previous.reachability;
+            info.last_seen = previous.last_seen;
+        }
+        self.known_peers.insert(info.user_id.clone(), info);
+    }
+
+    /// 记录收到已知节点消息的时间，不是已知节点（如 SERVER）时忽略
+    fn record_peer_seen(&mut self, peer_id: &str) {
+        if let Some(info) = self.known_peers.get_mut(peer_id) {
+            info.last_seen = Some(SystemTime::now());
+        }
+    }
+
+    /// 更新已知节点的直连状态，有变化时发出 PeerStatusChanged
+    fn set_peer_reachability(&mut self, peer_id: &str, status: PeerReachability) {
+        let Some(info) = self.known_peers.get_mut(peer_id).filter(|info| info.reachability != status) else {
+            return;
+        };
+        info.reachability = status;
+        let last_seen = info.last_seen;
+        debug!("peer {} is now {:?}", peer_id, status);
+        self.emit_event(ClientEvent::PeerStatusChanged { peer_id: peer_id.to_string(), status, last_seen });
+    }
+
     /// 显示已知对等节点列表
     fn list_known_peers(&self) {
         println!("🗺️ 已知对等节点列表 ({} 个):", self.known_peers.len());
         if self.known_peers.is_empty() {
             println!("  ℹ️ 暂无已知对等节点");
         } else {
+            let now = SystemTime::now();
             for (id, info) in &self.known_peers {
-                let connection_status = if self.peer_to_token.contains_key(id) {
-                    "✅ 已连接"
-                } else {
-                    "❌ 未连接"
+                let connection_status = match info.reachability {
+                    PeerReachability::Connected => "✅ 已连接",
+                    PeerReachability::Connecting => "⏳ 连接中",
+                    PeerReachability::Unreachable => "⚠️ 无法直连",
+                    PeerReachability::Known => "❌ 未连接",
                 };
-                println!("  {} {}: {}:{}", connection_status, id, info.address, info.port);
+                let last_seen = info.last_seen
+                    .map(|seen| format!("，{} 秒前活跃", now.duration_since(seen).unwrap_or_default().as_secs()))
+                    .unwrap_or_default();
+                println!("  {} {}: {}:{}{}", connection_status, id, info.address, info.port, last_seen);
             }
         }
         println!("🔗 当前活跃P2P连接数: {}", self.peer_to_token.len());
     }
     
+    /// 记录消息的去重键，返回 false 表示该消息已经收到过
+    fn remember_message(&mut self, message: &Message) -> bool {
+        let Some(key) = message.dedup_key() else {
+            return true;
+        };
+        if !self.seen_messages.insert(key.clone()) {
+            return false;
+        }
+        self.seen_order.push_back(key);
+        if self.seen_order.len() > SEEN_MESSAGES_CAPACITY {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen_messages.remove(&oldest);
+            }
+        }
+        true
+    }
+    
+    /// 合并经服务器和 P2P 两条路径到达的同一会话的消息。规则：
+    /// 1. 会话按 发送方 + 投递范围 区分（私聊即对方与自己之间的对话），与传输路径无关；
+    /// 2. 同一条消息（相同 msg_id）无论走哪条路径只处理第一次到达的，见 `remember_message`；
+    /// 3. 先后以 msg_id 中发送方的时间和序号为准，而不是到达顺序：
+    ///    比会话中已显示的最新消息更早发出的消息照常投递，但标记为迟到，由界面决定是否重新排序；
+    /// 4. 没有 msg_id 的旧版本消息不参与排序，总是视为按序到达；
+    /// 5. 配置了 reorder_window 时消息先在重排缓冲中按发送顺序排好（见 `hold_chat`），窗口内的乱序不会被标记为迟到。
+    ///
+    /// 返回 false 表示该消息迟到
+    fn reconcile_conversation(&mut self, message: &Message) -> bool {
+        if self.is_behind_conversation(message) {
+            return false;
+        }
+        if let Some(sequence) = message.msg_sequence() {
+            self.conversation_heads.insert(conversation_key(message), sequence);
+        }
+        true
+    }
+
+    /// 消息是否比所在会话中已显示的最新消息更早发出
+    fn is_behind_conversation(&self, message: &Message) -> bool {
+        message.msg_sequence().is_some_and(|sequence| {
+            self.conversation_heads.get(&conversation_key(message)).is_some_and(|head| *head > sequence)
+        })
+    }
+    
+    /// 记录聊天消息的作者（包括自己发出的），只保留最近 SEEN_MESSAGES_CAPACITY 条
+    fn remember_author(&mut self, message: &Message) {
+        let Some(msg_id) = message.msg_id.as_ref().filter(|_| message.msg_type == MessageType::Chat) else {
+            return;
+        };
+        if self.message_authors.insert(msg_id.clone(), message.sender_id.clone()).is_none() {
+            self.author_order.push_back(msg_id.clone());
+        }
+        if self.author_order.len() > SEEN_MESSAGES_CAPACITY {
+            if let Some(oldest) = self.author_order.pop_front() {
+                self.message_authors.remove(&oldest);
+            }
+        }
+    }
+    
+    /// 收集完整列表的分页：同一版本的页收齐后整体应用；旧版本的页直接忽略。
+    /// 服务器一次推送的各页版本相同，只有逐页请求（`request_peer_list_page`）期间列表发生变化时
+    /// 才会收到更新版本的页：这时丢弃未收齐的旧版本，并重新请求完整列表，而不是拼出混合两个版本的列表。
+    /// 不带分页信息（旧服务器）或只有一页的列表立即应用
+    fn handle_peer_list_page(&mut self, message: &Message, entries: Vec<PeerEntry>) {
+        let version = message.peer_list_version.unwrap_or(self.peer_list_version);
+        let total_pages = message.total_pages.unwrap_or(1);
+        let page = message.page.unwrap_or(0);
+        if total_pages <= 1 {
+            self.peer_list_pages = None;
+            self.apply_full_peer_list(entries, version);
+            return;
+        }
+        if page >= total_pages {
+            return;
+        }
+        
+        match &self.peer_list_pages {
+            Some((pending_version, _, _)) if *pending_version > version => return,
+            Some((pending_version, pending_total, _)) if *pending_version == version && *pending_total == total_pages => {}
+            Some((pending_version, _, pending)) if !pending.is_empty() => {
+                println!("🔄 对等节点列表在分页期间从 v{} 变为 v{}，重新请求完整列表", pending_version, version);
+                self.peer_list_pages = Some((version, total_pages, BTreeMap::new()));
+                if let Err(e) = self.send_peer_list_request(None) {
+                    eprintln!("重新请求对等节点列表失败: {}", e);
+                }
+            }
+            _ => self.peer_list_pages = Some((version, total_pages, BTreeMap::new())),
+        }
+        let Some((_, _, pages)) = self.peer_list_pages.as_mut() else {
+            return;
+        };
+        pages.insert(page, entries);
+        println!("📄 收到对等节点列表 v{} 第 {}/{} 页", version, page + 1, total_pages);
+        if pages.len() as u32 == total_pages {
+            if let Some((_, _, pages)) = self.peer_list_pages.take() {
+                self.apply_full_peer_list(pages.into_values().flatten().collect(), version);
+            }
+        }
+    }
+    
+    /// 用完整列表替换本地缓存：移除已不在列表中的节点
+    fn apply_full_peer_list(&mut self, peer_list: Vec<PeerEntry>, version: u64) {
+        println!("🗺️ 解析到 {} 个对等节点:", peer_list.len());
+        let listed: HashSet<&str> = peer_list.iter().map(|entry| entry.user_id.as_str()).collect();
+        self.known_peers.retain(|id, _| listed.contains(id.as_str()));
+        for entry in peer_list {
+            if entry.user_id != self.user_id {
+                println!("  ✅ 添加对等节点: {} ({}:{})", entry.user_id, entry.address, entry.port);
+                self.upsert_known_peer(entry);
+            } else {
+                println!("  ℹ️ 跳过自己: {} ({}:{})", entry.user_id, entry.address, entry.port);
+            }
+        }
+        println!("📊 当前已知对等节点数量: {}", self.known_peers.len());
+        
+        self.peer_list_version = version;
+        self.full_peer_lists_applied += 1;
+        self.emit_event(ClientEvent::PeerListUpdated {
+            version: self.peer_list_version,
+            peers: self.known_peers.values().cloned().collect(),
+        });
+    }
+    
+    /// 按版本顺序应用增量：已经包含的旧增量直接忽略，发现漏掉了中间的增量时重新拉取完整列表
+    fn apply_peer_list_delta(&mut self, delta: PeerListDelta) -> Result<()> {
+        if delta.epoch <= self.peer_list_version {
+            return Ok(());
+        }
+        if delta.base_epoch > self.peer_list_version {
+            println!("🔄 对等节点列表增量不连续 (本地 v{}，增量 v{}..v{})，重新获取完整列表...",
+                     self.peer_list_version, delta.base_epoch, delta.epoch);
+            return self.request_peer_list();
+        }
+        
+        for user_id in &delta.removed {
+            self.known_peers.remove(user_id);
+        }
+        for entry in delta.added {
+            if entry.user_id != self.user_id {
+                self.upsert_known_peer(entry);
+            }
+        }
+        self.peer_list_version = delta.epoch;
+        println!("🗺️ 对等节点列表已更新到 v{}，当前已知 {} 个节点", self.peer_list_version, self.known_peers.len());
+        self.emit_event(ClientEvent::PeerListUpdated {
+            version: self.peer_list_version,
+            peers: self.known_peers.values().cloned().collect(),
+        });
+        Ok(())
+    }
+    
+    /// 服务器心跳/心跳确认携带对等节点列表版本号，本地缓存落后时自动刷新
+    fn check_peer_list_version(&mut self, message: &Message) -> Result<()> {
+        if let Some(version) = message.peer_list_version {
+            if version > self.peer_list_version {
+                println!("🔄 对等节点列表已过期 (本地 v{} < 服务器 v{})，自动刷新...", self.peer_list_version, version);
+                self.request_peer_list()?;
+            }
+        }
+        Ok(())
+    }
+    
     /// 检查并发送心跳消息
     fn check_and_send_heartbeat(&mut self) {
         let now = Instant::now();
-        if now.duration_since(self.last_heartbeat) > Duration::from_secs(30) {
-            if self.is_connected() {
-                let heartbeat_message = Message {
-                    msg_type: MessageType::Heartbeat,
-                    sender_id: self.user_id.clone(),
-                    target_id: None,
-                    content: None,
-                    sender_peer_address: "127.0.0.1".to_string(),
-                    sender_listen_port: self.listen_port,
-                    timestamp: SystemTime::now(),
-                    source: MessageSource::Server,
-                };
-                
-                if let Ok(_) = self.queue_message(MessageTarget::Server, heartbeat_message) {
-                    self.last_heartbeat = now;
-                    println!("💓 发送心跳到服务器");
+        let interval = self.config.heartbeat_interval;
+        if now.duration_since(self.last_heartbeat) > interval && self.is_connected() {
+            // 一个心跳间隔内双向都有其他消息往来、且上一次心跳已经确认，连接显然存活，不必再发心跳
+            if self.pending_heartbeat.is_none()
+                && now.duration_since(self.last_server_send) <= interval
+                && now.duration_since(self.last_server_receive) <= interval {
+                return;
+            }
+            // 上一次心跳还没有收到 ack
+            if self.pending_heartbeat.is_some() {
+                self.missed_heartbeat_acks += 1;
+                if self.missed_heartbeat_acks >= self.config.max_missed_heartbeat_acks {
+                    eprintln!("⚠️ 连续 {} 次心跳未收到服务器响应，判定连接已失效，将尝试重新连接...", self.missed_heartbeat_acks);
+                    self.server_stream = None;
+                    self.server_connecting = false;
+                    self.buffers.remove(&SERVER);
+                    self.pending_heartbeat = None;
+                    self.missed_heartbeat_acks = 0;
+                    return;
                 }
             }
+            
+            self.heartbeat_nonce += 1;
+            let heartbeat_message = Message::heartbeat(self.user_id.clone())
+                .with_content(self.heartbeat_nonce.to_string());
+            
+            if self.queue_message(MessageTarget::Server, heartbeat_message).is_ok() {
+                self.last_heartbeat = now;
+                self.pending_heartbeat = Some((self.heartbeat_nonce, now));
+                println!("💓 发送心跳到服务器");
+            }
+        }
+    }
+    
+    /// 当前状态快照，供界面或监控使用
+    pub fn status(&self) -> ClientStatus {
+        let now = Instant::now();
+        ClientStatus {
+            user_id: self.user_id.clone(),
+            listen_port: self.listen_port,
+            server_addr: self.server_addr,
+            connected: self.is_connected(),
+            seconds_since_heartbeat: now.duration_since(self.last_heartbeat).as_secs(),
+            server_rtt: self.server_rtt,
+            known_peer_count: self.known_peers.len(),
+            active_p2p_count: self.peer_to_token.len(),
+            uptime: now.duration_since(self.started_at),
+            loop_lag: self.lag_monitor.lag(),
+            health: self.health(),
         }
     }
     
     /// 显示连接状态
     fn show_status(&self) {
+        let status = self.status();
         println!("📋 ==========  连接状态  ===========");
-        println!("👤 用户ID: {}", self.user_id);
-        println!("🏠 本地监听端口: {}", self.listen_port);
-        println!("🌐 服务器地址: {}", self.server_addr);
+        println!("👤 用户ID: {}", status.user_id);
+        println!("🏠 本地监听端口: {}", status.listen_port);
+        println!("🌐 服务器地址: {}", status.server_addr);
         
-        let server_status = if self.is_connected() {
+        let server_status = if status.connected {
             "✅ 已连接"
         } else {
             "❌ 已断开"
         };
         println!("🖥️ 服务器连接: {}", server_status);
         
-        let time_since_heartbeat = Instant::now().duration_since(self.last_heartbeat).as_secs();
-        println!("💓 上次心跳: {} 秒前", time_since_heartbeat);
+        println!("💓 上次心跳: {} 秒前", status.seconds_since_heartbeat);
+        match status.server_rtt {
+            Some(rtt) => println!("⏱️ 服务器RTT: {} ms", rtt.as_millis()),
+            None => println!("⏱️ 服务器RTT: 未知"),
+        }
         
-        println!("🗺️ 已知对等节点: {} 个", self.known_peers.len());
-        println!("🔗 活跃P2P连接: {} 个", self.peer_to_token.len());
+        println!("🗺️ 已知对等节点: {} 个", status.known_peer_count);
+        println!("🔗 活跃P2P连接: {} 个", status.active_p2p_count);
+        println!("⏳ 运行时间: {} 秒", status.uptime.as_secs());
+        println!("🩺 健康状态: {:?} (事件循环平均耗时 {} ms，最近一分钟最大 {} ms)",
+                 status.health, status.loop_lag.ewma.as_millis(), status.loop_lag.max_last_minute.as_millis());
         println!("========================================");
     }
     
     /// 发送P2P消息的内部方法（带重试机制）
-    fn send_p2p_message_with_retry(&mut self, peer_token: Token, peer_id: &str, content: String) -> Result<(), P2PError> {
-        let message = Message {
-            msg_type: MessageType::Chat,
-            sender_id: self.user_id.clone(),
-            target_id: Some(peer_id.to_string()),
-            content: Some(content.clone()),
-            sender_peer_address: "127.0.0.1".to_string(),
-            sender_listen_port: 0,
-            timestamp: SystemTime::now(),
-            source: MessageSource::Peer,
-        };
+    fn send_p2p_message_with_retry(&mut self, peer_token: Token, peer_id: &str, content: String) -> Result<()> {
+        let mut message = Message::chat(self.user_id.clone(), Some(peer_id.to_string()), content)
+            .with_source(MessageSource::Peer);
+        self.check_outgoing(&mut message)?;
+        let content = message.content.clone().unwrap_or_default();
         
         // 尝试发送，如果失败则重试
         for attempt in 1..=3 {
@@ -844,24 +2303,40 @@ impl P2PClient {
             }
         }
         
-        Err(P2PError::ConnectionError("消息发送超过最大重试次数".to_string()))
+        Err(P2PError::Timeout(format!("向 {} 发送消息超过最大重试次数", peer_id)))
     }
-    
-    /// 发送P2P消息的内部方法（旧版本，保留兼容）
-    fn send_p2p_message(&mut self, peer_token: Token, peer_id: &str, content: String) -> Result<(), P2PError> {
-        let message = Message {
-            msg_type: MessageType::Chat,
-            sender_id: self.user_id.clone(),
-            target_id: Some(peer_id.to_string()),
-            content: Some(content.clone()),
-            sender_peer_address: "127.0.0.1".to_string(),
-            sender_listen_port: 0,
-            timestamp: SystemTime::now(),
-            source: MessageSource::Peer,
-        };
-        
-        self.send_message_to_peer(peer_token, &message)?;
-        println!("🚀 [P2P直发 -> {}]: {}", peer_id, content);
-        Ok(())
+}
+
+/// 会话键：发送方 + 投递范围
+fn conversation_key(message: &Message) -> String {
+    format!("{}|{}", message.sender_id, message.delivery_scope())
+}
+
+/// 重排缓冲中暂存的一条聊天消息
+struct HeldChat {
+    arrived: Instant,
+    order: (u128, u64),
+    message: Message,
+    verified: Option<bool>,
+}
+
+/// 重排顺序：msg_id 中发送方的时间和序号；没有 msg_id 时使用发送方的时间戳
+/// （经服务器转发的消息为 client_timestamp）
+fn reorder_key(message: &Message) -> (u128, u64) {
+    message.msg_sequence().unwrap_or_else(|| {
+        let sent_at = message.client_timestamp.unwrap_or(message.timestamp);
+        (sent_at.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default(), 0)
+    })
+}
+
+/// 直连是否仍然可用：socket 上没有挂起的错误，且没有读到 EOF（只窥视，不消费数据）
+fn peer_link_alive(stream: &TcpStream) -> bool {
+    if !matches!(stream.take_error(), Ok(None)) {
+        return false;
+    }
+    match stream.peek(&mut [0; 1]) {
+        Ok(0) => false,
+        Ok(_) => true,
+        Err(e) => matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted),
     }
-}
\ No newline at end of file
+}
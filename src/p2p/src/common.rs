@@ -1,6 +1,12 @@
+use mio::Token;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
-use std::time::{SystemTime, Instant};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, Instant, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
 // 消息来源枚举
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -10,7 +16,7 @@ pub enum MessageSource {
 }
 
 // 消息类型枚举
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub enum MessageType {
     Join,
     Chat,
@@ -20,13 +26,267 @@ pub enum MessageType {
     ConnectRequest,
     ConnectResponse,
     Heartbeat,
+    HeartbeatAck,
     UserJoined,
-    UserLeft
+    UserLeft,
+    ServerShutdown,
+    Error,
+    Kick,
+    JoinRejected,
+    System,
+    React,  // 对 reply_to 指向的消息添加表情回应，content 为表情
+    Edit,   // 修改 reply_to 指向的消息，content 为新内容
+    Welcome,  // 加入成功后服务器发送的欢迎信息，content 为 JSON 编码的 ServerInfo
+    // 房间（room 字段为房间名）
+    JoinRoom,       // 加入房间，房间不存在时创建并成为房主
+    LeaveRoom,      // 离开房间
+    RoomInvite,     // 成员邀请 target_id 加入；服务器记录后转发给被邀请人
+    KickFromRoom,   // 房主把 target_id 移出房间；服务器转发给被移出的用户
+    SetRoomConfig,  // 房主修改房间配置，content 为 JSON 编码的 RoomConfig
+    RoomUpdate,     // 房间成员或配置变化后服务器发给全体成员，content 为 JSON 编码的 RoomInfo
+    PeerListDelta,  // 成员变化的增量推送，content 为 JSON 编码的 PeerListDelta
+    RelayAck,       // 服务器已接收 reply_to 指向的用户消息（重复发送的也会确认），发送方据此停止重试
+    // 对等节点之间（不经服务器）
+    Hello,       // 直连建立后双方交换身份，sender_listen_port 为发送方的 P2P 监听端口
+    PeerGossip,  // 纯 P2P 模式下向直连节点转告已知节点，content 为 JSON 编码的 PeerGossip
+}
+
+impl std::fmt::Display for MessageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // 派生的 Debug 输出的就是变体名
+        write!(f, "{:?}", self)
+    }
+}
+
+impl MessageType {
+    /// 用户发出、由服务器按 target_id 转发的消息：聊天以及对聊天的回应和编辑
+    pub fn is_user_content(&self) -> bool {
+        matches!(self, MessageType::Chat | MessageType::React | MessageType::Edit)
+    }
+}
+
+// 断开连接原因（随 UserLeft 消息的 content 下发）
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DisconnectReason {
+    Left,      // 主动离开（发送了 Leave）
+    Disconnected,  // 对方没有发送 Leave 就关闭了连接（读到 EOF）
+    Timeout,   // 心跳超时
+    Idle,      // 长时间没有实际活动（只有心跳）
+    Error,     // 读写错误
+    Kicked,    // 被管理员踢出或被新会话顶替
+    Rejected,  // 加入被拒绝
+    SlowConsumer,  // 写队列超过上限（客户端长时间不读取）
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::Left => "Left",
+            DisconnectReason::Disconnected => "Disconnected",
+            DisconnectReason::Timeout => "Timeout",
+            DisconnectReason::Idle => "Idle",
+            DisconnectReason::Error => "Error",
+            DisconnectReason::Kicked => "Kicked",
+            DisconnectReason::Rejected => "Rejected",
+            DisconnectReason::SlowConsumer => "SlowConsumer",
+        }
+    }
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// 错误码（随 Error 消息的 error_code 下发，content 为可读的说明）。
+// 序列化后的名称是协议的一部分，只能追加新的错误码，不能改名或删除
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCode {
+    TargetOffline,    // 私聊目标不在线
+    MissingTarget,    // 聊天消息没有指定目标
+    UserIdInUse,      // user_id 已被其他连接占用
+    RateLimited,      // 超出每秒消息数限制
+    MessageTooLarge,  // 超过最大消息长度
+    Rejected,         // 被服务器端消息钩子拒绝，或签名无效
+    ServerFull,       // 达到连接上限
+    InvalidUserId,    // user_id 为空且服务器不允许访客
+    NotRoomMember,    // 不是该房间的成员
+    NotRoomOwner,     // 只有房主可以执行该操作
+    NotInvited,       // 房间仅限受邀用户加入
+    RoomFull,         // 房间已达成员上限
+    InvalidRoomConfig,  // 房间配置无法解析或不合法
+    QuotaExceeded,    // 超出消息配额，content 中包含配额重置时间
+    MalformedMessage, // 无法解析的消息，已被丢弃
+    NotJoined,        // 连接尚未 Join，不能执行该操作
+}
+
+/// 对等节点列表（PeerList 的 content、PeerListDelta 的 added）中的一项。
+/// 反序列化时也接受旧版本的位置元组 `[user_id, 地址, 端口]`，见 `PeerEntryWire`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(from = "PeerEntryWire")]
+pub struct PeerEntry {
+    pub user_id: String,
+    pub address: String,
+    pub port: u16,
+    pub status: PeerStatus,
+    /// 节点声明的可选功能，旧版本没有该字段
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+}
+
+impl PeerEntry {
+    pub fn new(user_id: String, address: String, port: u16) -> Self {
+        PeerEntry { user_id, address, port, status: PeerStatus::default(), capabilities: Vec::new() }
+    }
+}
+
+/// 对等节点的在线状态
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerStatus {
+    #[default]
+    Online,
+    Away,  // 在线但暂时离开，由客户端自行声明
+}
+
+/// 客户端视角下能否直连某个对等节点，只在本地维护，不在线路上传输
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerReachability {
+    #[default]
+    Known,        // 从列表或转告得知，还没有尝试直连
+    Connecting,   // 已发起直连，等待对方 Hello
+    Connected,    // 直连已完成身份交换
+    Unreachable,  // 直连失败、已断开，或对方已离开
+}
+
+/// PeerEntry 在线路上的两种格式。旧格式只再兼容一个版本，之后删除 Legacy 分支
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PeerEntryWire {
+    Current {
+        user_id: String,
+        address: String,
+        port: u16,
+        #[serde(default)]
+        status: PeerStatus,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
+    /// 旧版本的 (user_id, 地址, 监听端口)
+    Legacy(String, String, u16),
+}
+
+impl From<PeerEntryWire> for PeerEntry {
+    fn from(wire: PeerEntryWire) -> Self {
+        match wire {
+            PeerEntryWire::Current { user_id, address, port, status, capabilities } => {
+                PeerEntry { user_id, address, port, status, capabilities }
+            }
+            PeerEntryWire::Legacy(user_id, address, port) => PeerEntry::new(user_id, address, port),
+        }
+    }
+}
+
+/// 对等节点列表的增量：把版本 base_epoch 到 epoch 之间的成员变化合并后一次推送。
+/// 每个用户只保留最后一次变化（加入/更新在 added，离开在 removed），因此本地版本在
+/// [base_epoch, epoch) 之间的客户端都可以直接应用；本地版本低于 base_epoch 说明漏掉了增量，需要重新拉取完整列表
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PeerListDelta {
+    pub base_epoch: u64,
+    pub epoch: u64,
+    pub added: Vec<PeerEntry>,
+    pub removed: Vec<String>,
+}
+
+/// 纯 P2P 模式下节点之间转告的已知节点。ttl 为收到后还能继续转发的跳数，每转发一次减一，为 0 时不再转发
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PeerGossip {
+    pub ttl: u8,
+    pub peers: Vec<GossipEntry>,
+}
+
+/// 转告中的一个节点。heard_ms_ago 为转告方最近一次得知该节点距今的毫秒数（直连的节点为 0），
+/// 同一 user_id 的地址有冲突时以最近得知的为准
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct GossipEntry {
+    pub user_id: String,
+    pub address: String,
+    pub port: u16,
+    pub heard_ms_ago: u64,
+}
+
+/// 欢迎消息携带的服务器信息
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct ServerInfo {
+    pub version: String,
+    /// 加入时的在线人数（包括自己）
+    pub connected_users: usize,
+    /// 当日公告/规则，服务器未配置时为 None
+    pub motd: Option<String>,
+    /// 访客加入（Join 的 sender_id 为空）时服务器分配的 user_id，客户端之后必须使用它
+    #[serde(default)]
+    pub assigned_user_id: Option<String>,
+    /// 协商出的帧格式：欢迎消息本身仍按 Newline 发送，之后双方都改用该格式
+    #[serde(default)]
+    pub framing: Framing,
+}
+
+/// 帧格式，在 Join 握手中协商
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Framing {
+    #[default]
+    Newline,         // 每条消息一行 JSON，以 \n 结尾（兼容旧版本）
+    LengthPrefixed,  // 4 字节大端长度 + JSON
+}
+
+impl Framing {
+    /// 按对方的偏好顺序选出第一个本端也支持的格式；对方没有声明或没有共同格式时使用 Newline
+    pub fn negotiate(offered: Option<&[Framing]>, supported: &[Framing]) -> Framing {
+        offered.unwrap_or_default().iter()
+            .find(|framing| supported.contains(framing))
+            .copied()
+            .unwrap_or_default()
+    }
+    
+    pub fn encode(&self, message: &Message) -> Result<Vec<u8>> {
+        match self {
+            Framing::Newline => serialize_message(message),
+            Framing::LengthPrefixed => {
+                message.validate()?;
+                let json = serde_json::to_vec(message)?;
+                let mut data = Vec::with_capacity(4 + json.len());
+                data.extend_from_slice(&(json.len() as u32).to_be_bytes());
+                data.extend_from_slice(&json);
+                Ok(data)
+            }
+        }
+    }
+    
+    /// 从缓冲区中取出一个完整的帧（不含分隔符或长度前缀）
+    pub fn take_frame(&self, buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+        match self {
+            Framing::Newline => take_frame(buffer),
+            Framing::LengthPrefixed => {
+                let header: [u8; 4] = buffer.get(..4)?.try_into().ok()?;
+                let len = u32::from_be_bytes(header) as usize;
+                // 不计算 4 + len：32 位平台上长度接近 u32::MAX 时会溢出
+                if buffer.len() - 4 < len {
+                    return None;
+                }
+                let frame = buffer[4..4 + len].to_vec();
+                buffer.drain(..4 + len);
+                Some(frame)
+            }
+        }
+    }
 }
 
 // 消息结构体
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Message {
+    // 消息结构的版本号，序列化时排在最前。没有该字段的旧消息视为版本 1，解码时先迁移到当前结构
+    #[serde(default = "legacy_schema_version")]
+    pub v: u16,
     pub msg_type: MessageType,
     pub sender_id: String,
     pub target_id: Option<String>,
@@ -36,6 +296,57 @@ pub struct Message {
     pub timestamp: SystemTime,
     #[serde(default = "default_message_source")]
     pub source: MessageSource,
+    // 服务器对等节点列表版本号（随 PeerList 和心跳下发，用于判断客户端缓存是否过期）
+    #[serde(default)]
+    pub peer_list_version: Option<u64>,
+    // 内容类型（MIME），服务器不解析、原样转发；缺省为 text/plain
+    #[serde(default = "default_content_type")]
+    pub content_type: Option<String>,
+    // 消息唯一标识（发送方生成），用于去重和引用；旧版本消息没有该字段
+    #[serde(default)]
+    pub msg_id: Option<String>,
+    // 引用的另一条消息的 msg_id（错误回执指向出错的原消息，React/Edit 指向被回应或修改的消息）
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    // 错误码，仅 Error 消息使用
+    #[serde(default)]
+    pub error_code: Option<ErrorCode>,
+    // Join 中声明本端支持的帧格式（按偏好排序），未声明视为只支持 Newline
+    #[serde(default)]
+    pub framings: Option<Vec<Framing>>,
+    // 服务器转发时保留的发送方原始时间戳（timestamp 被改写为服务器收到的时间）
+    #[serde(default)]
+    pub client_timestamp: Option<SystemTime>,
+    // 房间名：房间操作的对象；聊天消息带有房间名时只发给该房间的成员
+    #[serde(default)]
+    pub room: Option<String>,
+    // 分页的对等节点列表：PeerList 中为本页页码（从 0 开始），PeerListRequest 中为请求的页码（缺省请求全部页）
+    #[serde(default)]
+    pub page: Option<u32>,
+    // 分页的对等节点列表总页数，仅 PeerList 使用
+    #[serde(default)]
+    pub total_pages: Option<u32>,
+    // 发送方用共享密钥计算的 HMAC-SHA256 签名（见 `sign_message`），未配置密钥时为空
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+/// 当前的消息结构版本。修改 Message 的字段（新增必填字段、改名）时加一，并在 `SCHEMA_MIGRATIONS` 中追加迁移
+pub const MESSAGE_SCHEMA_VERSION: u16 = 2;
+
+fn legacy_schema_version() -> u16 {
+    1
+}
+
+/// 逐版本的迁移：下标 i 把版本 i + 1 的消息（JSON 对象）改写为版本 i + 2
+const SCHEMA_MIGRATIONS: [fn(&mut serde_json::Map<String, serde_json::Value>); (MESSAGE_SCHEMA_VERSION - 1) as usize] = [
+    migrate_v1,
+];
+
+/// 版本 1 的消息可能没有 source 和 content_type，补上当时的隐含取值
+fn migrate_v1(message: &mut serde_json::Map<String, serde_json::Value>) {
+    message.entry("source").or_insert_with(|| serde_json::json!(MessageSource::Server));
+    message.entry("content_type").or_insert_with(|| serde_json::json!(DEFAULT_CONTENT_TYPE));
 }
 
 // 默认消息来源为服务器（为了向后兼容）
@@ -43,9 +354,30 @@ fn default_message_source() -> MessageSource {
     MessageSource::Server
 }
 
+pub const DEFAULT_CONTENT_TYPE: &str = "text/plain";
+
+/// 公共聊天的目标：发给所有在线用户。聊天消息必须显式指定目标，target_id 为空会被服务器拒绝
+pub const BROADCAST_TARGET: &str = "*";
+
+/// 聊天、回应和编辑内容的最大字节数，与帧大小的配置无关
+pub const MAX_CONTENT_BYTES: usize = 64 * 1024;
+
+/// 收到的帧中 JSON 对象/数组允许的最大嵌套层数。Message 本身只有两层（时间戳、帧格式列表），
+/// 更深的嵌套一定不是合法消息，在交给 serde_json 之前直接拒绝
+pub const MAX_JSON_DEPTH: usize = 16;
+
+/// `P2PError::NotP2PProtocol` 中保留的帧开头字节数
+const NOT_P2P_PREVIEW_BYTES: usize = 16;
+
+// 默认内容类型为纯文本（旧版本消息不带该字段）
+pub fn default_content_type() -> Option<String> {
+    Some(DEFAULT_CONTENT_TYPE.to_string())
+}
+
 impl Message {
     pub fn new(msg_type: MessageType, sender_id: String) -> Self {
         Message {
+            v: MESSAGE_SCHEMA_VERSION,
             msg_type,
             sender_id,
             target_id: None,
@@ -54,6 +386,102 @@ impl Message {
             sender_listen_port: 0,
             timestamp: SystemTime::now(),
             source: MessageSource::Server,
+            page: None,
+            total_pages: None,
+            signature: None,
+            room: None,
+            client_timestamp: None,
+            framings: None,
+            error_code: None,
+            reply_to: None,
+            msg_id: None,
+            content_type: default_content_type(),
+            peer_list_version: None,
+        }
+    }
+    
+    /// 聊天消息，target_id 为 None 时发往所有人（`*`）。附带生成的 msg_id，来源为服务器转发；
+    /// P2P 直发时再用 `with_source(MessageSource::Peer)` 改为直连
+    pub fn chat(sender_id: String, target_id: Option<String>, content: String) -> Self {
+        Message::new(MessageType::Chat, sender_id)
+            .with_target(target_id.unwrap_or_else(|| BROADCAST_TARGET.to_string()))
+            .with_content(content)
+            .with_generated_msg_id()
+    }
+    
+    /// 加入请求：address 和 listen_port 为本地 P2P 监听地址。sender_id 为空表示以访客身份加入
+    pub fn join(sender_id: String, address: String, listen_port: u16) -> Self {
+        Message::new(MessageType::Join, sender_id).with_peer_info(address, listen_port)
+    }
+    
+    /// 心跳，content 可以带上 nonce 由服务器在 HeartbeatAck 中回显
+    pub fn heartbeat(sender_id: String) -> Self {
+        Message::new(MessageType::Heartbeat, sender_id)
+    }
+    
+    /// 服务器发出的对等节点列表（一页）。entries 为 `PeerEntry`，或序列化格式与之相同的 `PeerInfo`；
+    /// 分页信息和列表版本由调用方填写
+    pub fn peer_list<E: Serialize>(entries: &[E]) -> Self {
+        Message::new(MessageType::PeerList, "SERVER".to_string())
+            .with_content(serde_json::to_string(entries).unwrap_or_default())
+    }
+    
+    /// 按消息类型检查字段约束：必需的字段存在且非空、只属于某些类型的字段没有出现在其他类型上。
+    /// 发送前（`serialize_message`）和收到后（客户端、服务器解析帧之后）都会调用，
+    /// 不合法的消息在入口被拒绝，而不是在路由时表现为莫名其妙的行为
+    pub fn validate(&self) -> Result<()> {
+        use MessageType::*;
+        let invalid = |code: ErrorCode, reason: &str| Err(P2PError::InvalidMessage {
+            msg_type: self.msg_type.clone(),
+            code,
+            reason: reason.to_string(),
+        });
+        let present = |field: &Option<String>| field.as_deref().is_some_and(|value| !value.is_empty());
+        
+        if self.error_code.is_some() && self.msg_type != Error {
+            return invalid(ErrorCode::MalformedMessage, "error_code is only allowed on Error");
+        }
+        if self.framings.is_some() && self.msg_type != Join {
+            return invalid(ErrorCode::MalformedMessage, "framings are only allowed on Join");
+        }
+        if self.page.is_some() && !matches!(self.msg_type, PeerList | PeerListRequest) {
+            return invalid(ErrorCode::MalformedMessage, "page is only allowed on PeerList and PeerListRequest");
+        }
+        if self.total_pages.is_some() && self.msg_type != PeerList {
+            return invalid(ErrorCode::MalformedMessage, "total_pages is only allowed on PeerList");
+        }
+        if self.msg_type.is_user_content() {
+            if !present(&self.content) {
+                return invalid(ErrorCode::MalformedMessage, "content must not be empty");
+            }
+            if self.content.as_ref().is_some_and(|content| content.len() > MAX_CONTENT_BYTES) {
+                return invalid(ErrorCode::MessageTooLarge, &format!("content exceeds {} bytes", MAX_CONTENT_BYTES));
+            }
+            // 房间消息按房间投递，其余必须指定目标（公共消息为 `*`）
+            if !present(&self.target_id) && !present(&self.room) {
+                return invalid(ErrorCode::MissingTarget, &format!("needs a target_id (use \"{}\" to broadcast) or a room", BROADCAST_TARGET));
+            }
+        }
+        
+        match self.msg_type {
+            // sender_id 为空的 Join 是访客加入，由服务器分配 user_id，不要求监听端口
+            Join if !self.sender_id.is_empty() && self.sender_listen_port == 0 => {
+                invalid(ErrorCode::MalformedMessage, "Join needs a listen port")
+            }
+            React | Edit if !present(&self.reply_to) => invalid(ErrorCode::MalformedMessage, "needs reply_to"),
+            ConnectRequest | ConnectResponse | RoomInvite | KickFromRoom if !present(&self.target_id) => {
+                invalid(ErrorCode::MissingTarget, "needs a target_id")
+            }
+            JoinRoom | LeaveRoom | RoomInvite | KickFromRoom | SetRoomConfig | RoomUpdate if !present(&self.room) => {
+                invalid(ErrorCode::MissingTarget, "needs a room name")
+            }
+            RelayAck if !present(&self.reply_to) => invalid(ErrorCode::MalformedMessage, "needs reply_to"),
+            Hello | UserJoined | UserLeft if self.sender_id.is_empty() => invalid(ErrorCode::MalformedMessage, "needs a sender_id"),
+            // ConnectResponse 的 content 为目标的 `地址,端口`
+            ConnectResponse | SetRoomConfig | RoomUpdate | Error | Welcome | PeerList | PeerListDelta | PeerGossip if !present(&self.content) => {
+                invalid(ErrorCode::MalformedMessage, "content must not be empty")
+            }
+            _ => Ok(()),
         }
     }
     
@@ -67,6 +495,11 @@ impl Message {
         self
     }
     
+    pub fn with_room(mut self, room: String) -> Self {
+        self.room = Some(room);
+        self
+    }
+    
     pub fn with_peer_info(mut self, address: String, port: u16) -> Self {
         self.sender_peer_address = address;
         self.sender_listen_port = port;
@@ -77,25 +510,138 @@ impl Message {
         self.source = source;
         self
     }
+    
+    pub fn with_content_type(mut self, content_type: String) -> Self {
+        self.content_type = Some(content_type);
+        self
+    }
+    
+    /// 生成并设置 msg_id。
+    ///
+    /// id 中包含投递范围（公共 `*` 或私聊目标），同一发送方在同一时刻发出的
+    /// 公共消息和私聊消息即使内容相同也不会得到相同的 id。
+    pub fn with_generated_msg_id(mut self) -> Self {
+        static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+        let seq = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        let nanos = self.timestamp.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or_default();
+        self.msg_id = Some(format!("{}:{}:{:x}:{:x}", self.sender_id, self.delivery_scope(), nanos, seq));
+        self
+    }
+    
+    /// 序列化后的字节数（不含帧末尾的换行），与服务器的 max_message_size 比较的就是这个长度
+    pub fn size_bytes(&self) -> usize {
+        serde_json::to_vec(self).map(|data| data.len()).unwrap_or(0)
+    }
+    
+    /// 从 msg_id（`发送方:范围:时间:序号`）中取出发送时间和序号，用于比较同一发送方消息的先后；
+    /// 没有 msg_id 或格式不符时返回 None
+    pub fn msg_sequence(&self) -> Option<(u128, u64)> {
+        let mut parts = self.msg_id.as_deref()?.rsplitn(3, ':');
+        let seq = u64::from_str_radix(parts.next()?, 16).ok()?;
+        let nanos = u128::from_str_radix(parts.next()?, 16).ok()?;
+        parts.next()?;
+        Some((nanos, seq))
+    }
+    
+    /// 投递范围：私聊为目标 user_id，公共消息为 `*`
+    pub fn delivery_scope(&self) -> &str {
+        self.target_id.as_deref().unwrap_or(BROADCAST_TARGET)
+    }
+    
+    /// 私聊目标；公共消息（目标为 `*` 或未设置）返回 None
+    pub fn direct_target(&self) -> Option<&str> {
+        self.target_id.as_deref().filter(|target| *target != BROADCAST_TARGET)
+    }
+    
+    /// 去重键：发送方 + 投递范围 + msg_id，没有 msg_id 的消息不参与去重
+    pub fn dedup_key(&self) -> Option<String> {
+        self.msg_id.as_ref()
+            .map(|id| format!("{}|{}|{}", self.sender_id, self.delivery_scope(), id))
+    }
+}
+
+/// Display 中默认显示的最大内容字符数，超出部分以省略号代替；可以用精度指定，如 `{:.100}`
+pub const DISPLAY_CONTENT_CHARS: usize = 48;
+
+impl Message {
+    /// 与 Display 相同的单行摘要，但不包含内容本身，只显示字节数和哈希（同一内容的哈希相同，
+    /// 便于在日志中关联同一条消息；不是加密哈希）。用于可能写入日志的场合
+    pub fn redacted(&self) -> impl std::fmt::Display + '_ {
+        struct Redacted<'a>(&'a Message);
+        
+        impl std::fmt::Display for Redacted<'_> {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                self.0.fmt_header(f)?;
+                if let Some(content) = &self.0.content {
+                    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                    content.hash(&mut hasher);
+                    write!(f, ": <redacted> ({} bytes, hash {:08x})", content.len(), hasher.finish() as u32)?;
+                }
+                Ok(())
+            }
+        }
+        
+        Redacted(self)
+    }
+    
+    /// 摘要中内容之前的部分：类型、收发方、房间、msg_id 和发送时间（Unix 时间，毫秒精度）
+    fn fmt_header(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.msg_type, self.sender_id)?;
+        if let Some(target_id) = &self.target_id {
+            write!(f, " -> {}", target_id)?;
+        }
+        if let Some(room) = &self.room {
+            write!(f, " #{}", room)?;
+        }
+        if let Some(msg_id) = &self.msg_id {
+            write!(f, " id={}", msg_id)?;
+        }
+        let since_epoch = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(f, " @{}.{:03}", since_epoch.as_secs(), since_epoch.subsec_millis())
+    }
+}
+
+/// 单行摘要，如 `[Chat] alice -> bob id=… @1700000000.123: "hi" (2 bytes)`：目标为 `*` 表示公共消息，
+/// 括号内为完整内容的字节数。内容预览默认最多 `DISPLAY_CONTENT_CHARS` 个字符，可以用精度修改（`{:.0}` 不显示内容）。
+/// 消息内容可能包含隐私，写入日志请用 `redacted`，完整字段请用 Debug
+impl std::fmt::Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_header(f)?;
+        if let Some(content) = &self.content {
+            let max_chars = f.precision().unwrap_or(DISPLAY_CONTENT_CHARS);
+            let shown = match content.char_indices().nth(max_chars) {
+                Some((end, _)) => format!("{}…", &content[..end]),
+                None => content.clone(),
+            };
+            write!(f, ": {:?} ({} bytes)", shown, content.len())?;
+        }
+        Ok(())
+    }
 }
 
-// 节点信息结构体
-#[derive(Debug, Clone)]
+// 节点信息结构体。序列化格式与 PeerEntry 相同：时间字段只在本进程内有意义，序列化时跳过，反序列化时取当前时间
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerInfo {
     pub user_id: String,
     pub address: String,
     pub port: u16,
+    #[serde(default)]
+    pub status: PeerStatus,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub capabilities: Vec<String>,
+    #[serde(skip, default = "Instant::now")]
     pub last_heartbeat: Instant,
+    #[serde(skip, default = "Instant::now")]
+    pub last_activity: Instant,  // 最近一次实际活动（聊天、请求），心跳不计入
+    #[serde(skip)]
+    pub reachability: PeerReachability,  // 仅客户端使用：能否直连该节点
+    #[serde(skip)]
+    pub last_seen: Option<SystemTime>,  // 仅客户端使用：最近一次收到该节点消息的时间（直连或经服务器转发）
 }
 
 impl PeerInfo {
     pub fn new(user_id: String, address: String, port: u16) -> Self {
-        PeerInfo {
-            user_id,
-            address,
-            port,
-            last_heartbeat: Instant::now(),
-        }
+        PeerEntry::new(user_id, address, port).into()
     }
     
     pub fn socket_addr(&self) -> Result<SocketAddr, std::net::AddrParseError> {
@@ -103,51 +649,174 @@ impl PeerInfo {
     }
 }
 
-// 错误类型枚举
-#[derive(Debug)]
-pub enum P2PError {
-    IoError(std::io::Error),
-    SerializationError(serde_json::Error),
-    ConnectionError(String),
-    PeerNotFound,
+impl From<PeerEntry> for PeerInfo {
+    fn from(entry: PeerEntry) -> Self {
+        PeerInfo {
+            user_id: entry.user_id,
+            address: entry.address,
+            port: entry.port,
+            status: entry.status,
+            capabilities: entry.capabilities,
+            last_heartbeat: Instant::now(),
+            last_activity: Instant::now(),
+            reachability: PeerReachability::default(),
+            last_seen: None,
+        }
+    }
 }
 
-impl std::fmt::Display for P2PError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            P2PError::IoError(e) => write!(f, "IO error: {}", e),
-            P2PError::SerializationError(e) => write!(f, "Serialization error: {}", e),
-            P2PError::ConnectionError(s) => write!(f, "Connection error: {}", s),
-            P2PError::PeerNotFound => write!(f, "Peer not found"),
+impl From<&PeerInfo> for PeerEntry {
+    fn from(info: &PeerInfo) -> Self {
+        PeerEntry {
+            user_id: info.user_id.clone(),
+            address: info.address.clone(),
+            port: info.port,
+            status: info.status,
+            capabilities: info.capabilities.clone(),
         }
     }
 }
 
-impl std::error::Error for P2PError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+/// 连接 token 分配器：优先复用已释放的 token，没有可复用的才递增分配。
+/// 按释放顺序复用（先释放的先复用），让刚释放的 token 尽量晚一些再被使用
+#[derive(Debug)]
+pub struct TokenAllocator {
+    next: Token,
+    free: VecDeque<Token>,
+}
+
+impl TokenAllocator {
+    pub fn new(first: Token) -> Self {
+        Self { next: first, free: VecDeque::new() }
+    }
+    
+    pub fn allocate(&mut self) -> Token {
+        self.free.pop_front().unwrap_or_else(|| {
+            let token = self.next;
+            self.next = Token(token.0 + 1);
+            token
+        })
+    }
+    
+    /// 归还 token，调用方需保证该 token 已不再使用且只归还一次
+    pub fn release(&mut self, token: Token) {
+        self.free.push_back(token);
+    }
+}
+
+/// 错误类型。每个变体对应一类可以单独处理的失败，携带出错的对象（哪个节点、哪个操作）
+#[derive(Debug, thiserror::Error)]
+pub enum P2PError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Serialization error: {0}")]
+    Serialization(#[from] serde_json::Error),
+    /// 收到的帧不是合法的 UTF-8
+    #[error("Invalid UTF-8: {0}")]
+    InvalidUtf8(#[from] std::str::Utf8Error),
+    #[error("Invalid address: {0}")]
+    AddrParse(#[from] std::net::AddrParseError),
+    /// 建立或使用连接失败（绑定端口、连接到自己等），内容说明是哪个操作
+    #[error("Connection error: {0}")]
+    Connection(String),
+    /// 未知的对等节点（user_id 或 token）
+    #[error("Peer not found: {0}")]
+    PeerNotFound(String),
+    /// 操作在限定时间或次数内没有完成，内容为操作名称
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    /// 身份校验失败（如身份密钥不符），内容为原因
+    #[error("Authentication failed: {0}")]
+    AuthFailed(String),
+    /// 对方发来的数据不符合协议
+    #[error("Protocol error: {0}")]
+    Protocol(String),
+    /// 对方发来的数据根本不是本协议（见 `deserialize_message_lenient`），内容为数据开头的转义摘要。
+    /// 通常是其他协议的客户端连错了端口，应直接关闭连接
+    #[error("Not a P2P protocol frame, starts with \"{0}\"")]
+    NotP2PProtocol(String),
+    /// 内部通道的接收端已关闭，内容为通道名称
+    #[error("Channel closed: {0}")]
+    ChannelClosed(String),
+    /// 写队列已满，内容为受影响的连接
+    #[error("Backpressure: {0}")]
+    Backpressure(String),
+    #[error("Config error: {0}")]
+    ConfigError(String),
+    #[error("Message too large: {size} bytes (max {max})")]
+    MessageTooLarge { size: usize, max: usize },
+    /// 聊天、回应或编辑的内容超过配置的字符数上限（`max_content_chars`）
+    #[error("Content too long: {chars} characters (max {max})")]
+    ContentTooLong { chars: usize, max: usize },
+    /// 消息不满足其类型的字段约束（见 `Message::validate`），code 为回复给发送方的错误码
+    #[error("Invalid {msg_type} message: {reason}")]
+    InvalidMessage { msg_type: MessageType, code: ErrorCode, reason: String },
+    /// 附加了操作说明的错误（见 `ErrorContext`），显示为 `操作: 原始错误`，可以多层嵌套
+    #[error("{context}: {source}")]
+    Context { context: String, source: Box<P2PError> },
+}
+
+impl P2PError {
+    /// 用操作说明包装这个错误
+    pub fn context(self, context: impl Into<String>) -> Self {
+        P2PError::Context { context: context.into(), source: Box::new(self) }
+    }
+
+    /// 去掉所有操作说明后的原始错误，按错误类型分别处理时使用
+    pub fn root_cause(&self) -> &P2PError {
         match self {
-            P2PError::IoError(e) => Some(e),
-            P2PError::SerializationError(e) => Some(e),
-            _ => None,
+            P2PError::Context { source, .. } => source.root_cause(),
+            other => other,
         }
     }
 }
 
-impl From<std::io::Error> for P2PError {
-    fn from(error: std::io::Error) -> Self {
-        P2PError::IoError(error)
-    }
+/// 本 crate 的结果类型
+pub type Result<T, E = P2PError> = std::result::Result<T, E>;
+
+/// 给失败的结果附加操作说明，如 `.context("sending peer list to alice")`，
+/// 之后格式化为 `sending peer list to alice: IO error: Broken pipe`，能看出是哪个节点、哪个操作出错
+pub trait ErrorContext<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+
+    /// 只在出错时才生成说明，避免在成功路径上 format!
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T>;
 }
 
-impl From<serde_json::Error> for P2PError {
-    fn from(error: serde_json::Error) -> Self {
-        P2PError::SerializationError(error)
+impl<T, E: Into<P2PError>> ErrorContext<T> for std::result::Result<T, E> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|e| e.into().context(context))
+    }
+
+    fn with_context<C: Into<String>>(self, context: impl FnOnce() -> C) -> Result<T> {
+        self.map_err(|e| e.into().context(context()))
     }
 }
 
-impl From<std::net::AddrParseError> for P2PError {
-    fn from(error: std::net::AddrParseError) -> Self {
-        P2PError::ConnectionError(format!("Address parse error: {}", error))
+// io::Error 和 serde_json::Error 没有实现 PartialEq，分别比较 ErrorKind 和错误分类
+impl PartialEq for P2PError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (P2PError::Io(a), P2PError::Io(b)) => a.kind() == b.kind(),
+            (P2PError::Serialization(a), P2PError::Serialization(b)) => a.classify() == b.classify(),
+            (P2PError::InvalidUtf8(a), P2PError::InvalidUtf8(b)) => a == b,
+            (P2PError::AddrParse(a), P2PError::AddrParse(b)) => a == b,
+            (P2PError::Connection(a), P2PError::Connection(b))
+            | (P2PError::PeerNotFound(a), P2PError::PeerNotFound(b))
+            | (P2PError::Timeout(a), P2PError::Timeout(b))
+            | (P2PError::AuthFailed(a), P2PError::AuthFailed(b))
+            | (P2PError::Protocol(a), P2PError::Protocol(b))
+            | (P2PError::ChannelClosed(a), P2PError::ChannelClosed(b))
+            | (P2PError::Backpressure(a), P2PError::Backpressure(b))
+            | (P2PError::ConfigError(a), P2PError::ConfigError(b)) => a == b,
+            (P2PError::MessageTooLarge { size: a, max: x }, P2PError::MessageTooLarge { size: b, max: y })
+            | (P2PError::ContentTooLong { chars: a, max: x }, P2PError::ContentTooLong { chars: b, max: y }) => a == b && x == y,
+            (P2PError::InvalidMessage { msg_type: a, code: x, reason: r }, P2PError::InvalidMessage { msg_type: b, code: y, reason: s }) => {
+                a == b && x == y && r == s
+            }
+            (P2PError::Context { context: a, source: x }, P2PError::Context { context: b, source: y }) => a == b && x == y,
+            _ => false,
+        }
     }
 }
 
@@ -155,20 +824,202 @@ impl From<std::net::AddrParseError> for P2PError {
 pub const HEARTBEAT_INTERVAL: u64 = 5;
 
 // 消息序列化和反序列化函数
-pub fn serialize_message(message: &Message) -> Result<Vec<u8>, P2PError> {
+pub fn serialize_message(message: &Message) -> Result<Vec<u8>> {
+    message.validate()?;
     let json = serde_json::to_string(message)?;
     let mut data = json.into_bytes();
     data.push(b'\n');
     Ok(data)
 }
 
-pub fn deserialize_message(data: &[u8]) -> Result<Message, P2PError> {
-    let json_str = std::str::from_utf8(data)
-        .map_err(|_| P2PError::SerializationError(
-            serde_json::Error::io(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid UTF-8 sequence"
-            ))
-        ))?;
-    serde_json::from_str(json_str).map_err(P2PError::SerializationError)
+/// 从缓冲区中取出一个完整的帧（以 `\n` 结尾），去掉行尾的 `\n` 以及 CRLF 产生的 `\r`
+pub fn take_frame(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let delimiter_pos = buffer.iter().position(|&b| b == b'\n')?;
+    let mut frame: Vec<u8> = buffer.drain(..=delimiter_pos).collect();
+    frame.pop();
+    while frame.last() == Some(&b'\r') {
+        frame.pop();
+    }
+    Some(frame)
+}
+
+/// 解码一帧消息。帧的长度上限由调用方在取帧时检查，这里只拒绝嵌套过深的 JSON
+pub fn deserialize_message(data: &[u8]) -> Result<Message> {
+    let json_str = std::str::from_utf8(data)?;
+    if json_depth_exceeds(data, MAX_JSON_DEPTH) {
+        return Err(P2PError::Protocol(format!("JSON nested deeper than {} levels", MAX_JSON_DEPTH)));
+    }
+    Ok(decode_versioned(json_str)?)
+}
+
+/// 宽松模式解码：开头（跳过空白）不是 JSON 对象的 `{` 时不尝试解析，返回 `P2PError::NotP2PProtocol`，
+/// 调用方可以据此关闭连接，而不是对其他协议发来的每一帧都回复解析错误。以 `{` 开头的帧与 `deserialize_message` 相同
+pub fn deserialize_message_lenient(data: &[u8]) -> Result<Message> {
+    match data.iter().find(|byte| !byte.is_ascii_whitespace()) {
+        Some(b'{') | None => deserialize_message(data),
+        Some(_) => Err(P2PError::NotP2PProtocol(frame_preview(data))),
+    }
+}
+
+/// 帧开头最多 NOT_P2P_PREVIEW_BYTES 字节的转义形式，用于在日志中辨认对方说的是什么协议
+fn frame_preview(data: &[u8]) -> String {
+    data.iter()
+        .take(NOT_P2P_PREVIEW_BYTES)
+        .flat_map(|byte| std::ascii::escape_default(*byte))
+        .map(char::from)
+        .collect()
+}
+
+/// 不解析 JSON，只扫描括号判断嵌套是否超过 max 层（跳过字符串内的括号和转义字符）
+fn json_depth_exceeds(data: &[u8], max: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &byte in data {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'{' | b'[' => {
+                depth += 1;
+                if depth > max {
+                    return true;
+                }
+            }
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    false
+}
+
+/// 按消息携带的版本号解码：当前版本直接解析，旧版本先逐级迁移再解析；
+/// 比当前更新的版本按当前结构尽力解析（忽略不认识的字段）。解码结果总是标记为当前版本
+fn decode_versioned(json: &str) -> Result<Message, serde_json::Error> {
+    #[derive(Deserialize)]
+    struct SchemaTag {
+        #[serde(default = "legacy_schema_version")]
+        v: u16,
+    }
+    
+    let tag: SchemaTag = serde_json::from_str(json)?;
+    let mut message: Message = if tag.v >= MESSAGE_SCHEMA_VERSION {
+        serde_json::from_str(json)?
+    } else {
+        let mut value: serde_json::Value = serde_json::from_str(json)?;
+        if let Some(object) = value.as_object_mut() {
+            for migrate in &SCHEMA_MIGRATIONS[tag.v.saturating_sub(1) as usize..] {
+                migrate(object);
+            }
+        }
+        serde_json::from_value(value)?
+    };
+    message.v = MESSAGE_SCHEMA_VERSION;
+    Ok(message)
+}
+
+/// 签名校验失败（没有签名或签名不符）时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignaturePolicy {
+    /// 照常处理，只标记为未通过校验（客户端在 ChatReceived 中标记，服务器记录日志）
+    #[default]
+    Mark,
+    /// 丢弃该消息（服务器向发送方回复 Rejected 错误）
+    Reject,
+}
+
+/// 签名格式的版本标识，是签名数据的第一行
+pub const SIGNATURE_SCHEME: &str = "p2p-sig-v1";
+
+/// 计算签名的规范数据。依次为以下字段，每个字段单独一行（以 `\n` 结尾）：
+///
+/// 1. 固定的 `p2p-sig-v1`
+/// 2. `msg_type`：变体名，如 `Chat`
+/// 3. `sender_id`
+/// 4. `target_id`
+/// 5. `content`
+/// 6. 时间戳：发送方的原始时间，即 `client_timestamp`（服务器转发时写入），没有时为 `timestamp`；
+///    格式为 `秒.纳秒`，纳秒固定 9 位，如 `1700000000.000000001`
+/// 7. `msg_id`
+///
+/// 第 2 到 7 行的值编码为 `UTF-8 字节数:值`（如 `5:alice`），字段不存在时为 `-`；空字符串为 `0:`。
+/// 值本身可以包含换行，由长度前缀区分。其余字段（地址、来源、服务器改写的 timestamp 等）不参与签名
+pub fn canonical_signing_bytes(message: &Message) -> Vec<u8> {
+    fn field(out: &mut Vec<u8>, value: Option<&str>) {
+        match value {
+            Some(value) => {
+                out.extend_from_slice(value.len().to_string().as_bytes());
+                out.push(b':');
+                out.extend_from_slice(value.as_bytes());
+            }
+            None => out.push(b'-'),
+        }
+        out.push(b'\n');
+    }
+
+    let sent_at = message.client_timestamp.unwrap_or(message.timestamp);
+    let timestamp = sent_at.duration_since(UNIX_EPOCH)
+        .map(|elapsed| format!("{}.{:09}", elapsed.as_secs(), elapsed.subsec_nanos()));
+    let mut out = Vec::new();
+    out.extend_from_slice(SIGNATURE_SCHEME.as_bytes());
+    out.push(b'\n');
+    field(&mut out, Some(&message.msg_type.to_string()));
+    field(&mut out, Some(&message.sender_id));
+    field(&mut out, message.target_id.as_deref());
+    field(&mut out, message.content.as_deref());
+    field(&mut out, timestamp.as_deref().ok());
+    field(&mut out, message.msg_id.as_deref());
+    out
+}
+
+fn signing_mac(message: &Message, key: &[u8]) -> Hmac<Sha256> {
+    // HMAC 接受任意长度的密钥，new_from_slice 不会失败
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&canonical_signing_bytes(message));
+    mac
+}
+
+/// 用共享密钥对消息的规范数据（`canonical_signing_bytes`）计算 HMAC-SHA256，返回小写十六进制字符串。
+/// 结果放入 `Message::signature` 后发送
+pub fn sign_message(message: &Message, key: &[u8]) -> String {
+    hex::encode(signing_mac(message, key).finalize().into_bytes())
+}
+
+/// 校验 `Message::signature`：没有签名、不是合法的十六进制或与规范数据不符时返回 false。比较是常数时间的
+pub fn verify_message(message: &Message, key: &[u8]) -> bool {
+    let Some(signature) = message.signature.as_deref().and_then(|signature| hex::decode(signature).ok()) else {
+        return false;
+    };
+    signing_mac(message, key).verify_slice(&signature).is_ok()
+}
+
+// TOML 中的时间字段以毫秒整数表示
+pub(crate) mod duration_ms {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+    
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+    
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
+
+pub(crate) mod option_duration_ms {
+    use serde::{Deserialize, Deserializer};
+    use std::time::Duration;
+    
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Option::<u64>::deserialize(deserializer).map(|ms| ms.map(Duration::from_millis))
+    }
 }
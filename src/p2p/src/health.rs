@@ -0,0 +1,87 @@
+// 事件循环延迟统计和健康状态。服务器和客户端都在每轮事件循环结束时记录本轮的处理耗时
+// （不包括在 poll 中等待的时间），处理耗时过长说明进程过载，心跳和消息都会被推迟
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// 最大延迟的统计窗口
+const MAX_WINDOW: Duration = Duration::from_secs(60);
+/// EWMA 中最新一轮所占的权重
+const EWMA_WEIGHT: f64 = 0.2;
+/// 平均延迟达到阈值的多少倍时判定为 Unhealthy
+const UNHEALTHY_FACTOR: u32 = 4;
+
+/// 健康状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Health {
+    #[default]
+    Healthy,
+    /// 平均延迟超过阈值，或（客户端）有心跳未收到确认
+    Degraded,
+    /// 平均延迟远超阈值，或（客户端）与服务器的连接已断开
+    Unhealthy,
+}
+
+/// 事件循环延迟的快照
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoopLag {
+    /// 最近一轮的处理耗时
+    pub last: Duration,
+    /// 处理耗时的指数加权移动平均
+    pub ewma: Duration,
+    /// 最近一分钟内单轮的最大处理耗时
+    pub max_last_minute: Duration,
+    /// 超过告警阈值的轮数
+    pub slow_iterations: u64,
+}
+
+impl LoopLag {
+    /// 仅按延迟判断的健康状态，threshold 为单轮耗时的告警阈值
+    pub fn health(&self, threshold: Duration) -> Health {
+        if self.ewma >= threshold * UNHEALTHY_FACTOR {
+            Health::Unhealthy
+        } else if self.ewma >= threshold {
+            Health::Degraded
+        } else {
+            Health::Healthy
+        }
+    }
+}
+
+/// 记录每轮事件循环的耗时
+#[derive(Debug, Clone, Default)]
+pub struct LagMonitor {
+    lag: LoopLag,
+    // 一分钟内的 (时间, 耗时)，耗时单调递减，队首即为窗口内的最大值
+    recent_max: VecDeque<(Instant, Duration)>,
+}
+
+impl LagMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 记录一轮的耗时，超过 threshold 时返回 true，由调用方输出告警
+    pub fn record(&mut self, elapsed: Duration, threshold: Duration, now: Instant) -> bool {
+        self.lag.last = elapsed;
+        self.lag.ewma = self.lag.ewma.mul_f64(1.0 - EWMA_WEIGHT) + elapsed.mul_f64(EWMA_WEIGHT);
+
+        while self.recent_max.back().is_some_and(|&(_, lag)| lag <= elapsed) {
+            self.recent_max.pop_back();
+        }
+        self.recent_max.push_back((now, elapsed));
+        while self.recent_max.front().is_some_and(|&(at, _)| now.duration_since(at) > MAX_WINDOW) {
+            self.recent_max.pop_front();
+        }
+        self.lag.max_last_minute = self.recent_max.front().map_or(Duration::ZERO, |&(_, lag)| lag);
+
+        let slow = elapsed > threshold;
+        if slow {
+            self.lag.slow_iterations += 1;
+        }
+        slow
+    }
+
+    pub fn lag(&self) -> LoopLag {
+        self.lag
+    }
+}
@@ -0,0 +1,111 @@
+// 聊天记录：服务器转发的每条 Chat 一行 JSON 追加到文件，供审计和合规留存。
+// 写文件在单独的线程中进行并按 flush_interval 批量 flush，事件循环只把记录放进通道
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use crate::audit::RotatingFile;
+use crate::common::{Message, P2PError};
+
+/// 聊天记录文件中的一行
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChatRecord {
+    /// 服务器转发的时间
+    pub timestamp: SystemTime,
+    /// 发送方客户端声明的发送时间
+    pub sent_at: Option<SystemTime>,
+    pub sender_id: String,
+    /// 私聊目标，公共消息为 `*`，房间消息为 None
+    pub target_id: Option<String>,
+    pub room: Option<String>,
+    pub content: Option<String>,
+    pub msg_id: Option<String>,
+}
+
+impl ChatRecord {
+    /// 由服务器转发的副本生成（timestamp 为转发时间，client_timestamp 为发送时间）
+    pub fn from_relay(message: &Message) -> Self {
+        Self {
+            timestamp: message.timestamp,
+            sent_at: message.client_timestamp,
+            sender_id: message.sender_id.clone(),
+            target_id: if message.room.is_some() { None } else { message.target_id.clone() },
+            room: message.room.clone(),
+            content: message.content.clone(),
+            msg_id: message.msg_id.clone(),
+        }
+    }
+}
+
+/// 聊天记录，按大小轮转（规则与审计日志相同）。Drop 时等待写线程把剩余记录写完并 flush
+pub struct ChatHistory {
+    path: PathBuf,
+    sender: Option<mpsc::Sender<ChatRecord>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl ChatHistory {
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64, max_files: usize, flush_interval: Duration) -> Result<Self, P2PError> {
+        let path = path.into();
+        let file = RotatingFile::open(path.clone(), max_bytes, max_files)?;
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("chat-history".to_string())
+            .spawn(move || run_writer(file, receiver, flush_interval))?;
+        Ok(Self { path, sender: Some(sender), writer: Some(handle) })
+    }
+
+    /// 记录一条服务器转发的 Chat
+    pub fn record(&self, message: &Message) {
+        let record = ChatRecord::from_relay(message);
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if let Err(e) = sender.send(record) {
+            warn!("chat history writer has stopped, dropping msg_id={:?}", e.0.msg_id);
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ChatHistory {
+    fn drop(&mut self) {
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// 没有未 flush 的数据时阻塞等待；有数据时最多等到上次 flush 后 flush_interval 再 flush。
+/// 发送端全部关闭后 flush 并退出
+fn run_writer(mut file: RotatingFile, receiver: mpsc::Receiver<ChatRecord>, flush_interval: Duration) {
+    let mut dirty_since: Option<Instant> = None;
+    loop {
+        let received = match dirty_since {
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            Some(since) => receiver.recv_timeout((since + flush_interval).saturating_duration_since(Instant::now())),
+        };
+        match received {
+            Ok(record) => {
+                match serde_json::to_vec(&record) {
+                    Ok(line) => file.write_line(&line),
+                    Err(e) => error!("failed to serialize chat record msg_id={:?}: {}", record.msg_id, e),
+                }
+                dirty_since.get_or_insert_with(Instant::now);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if dirty_since.is_some_and(|since| since.elapsed() >= flush_interval) {
+            file.flush();
+            dirty_since = None;
+        }
+    }
+    file.flush();
+}
@@ -0,0 +1,152 @@
+// 服务器端消息钩子：在路由之前检查、修改或拒绝来自已加入用户的消息
+use std::sync::{Arc, Mutex};
+use crate::common::{Message, PeerInfo};
+
+/// 钩子对一条消息的处理结果。只在一次钩子调用中短暂存在，Modify 直接持有消息不装箱
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum HookDecision {
+    /// 原样放行
+    Allow,
+    /// 用新的消息替换（后续钩子和路由看到的是替换后的消息）
+    Modify(Message),
+    /// 拒绝：消息被丢弃，原因以 Error 消息回复给发送方
+    Reject(String),
+}
+
+/// 消息钩子。通过 `P2PServer::add_hook` 注册，按注册顺序依次调用，
+/// 任一钩子拒绝后不再调用后续钩子
+pub trait MessageHook: Send {
+    fn on_inbound(&mut self, msg: &Message, from: &PeerInfo) -> HookDecision;
+}
+
+/// 每个工作线程各创建一个钩子实例的工厂，见 `P2PServer::add_hook_factory`
+pub(crate) type HookFactory = Arc<dyn Fn() -> Box<dyn MessageHook> + Send + Sync>;
+
+/// 注册的钩子：单个实例（多个工作线程之间加锁共享），或按需创建实例的工厂
+pub(crate) enum HookRegistration {
+    Single(Arc<Mutex<Box<dyn MessageHook>>>),
+    Factory(HookFactory),
+}
+
+impl HookRegistration {
+    fn instantiate(&self) -> ChainHook {
+        match self {
+            HookRegistration::Single(hook) => ChainHook::Shared(hook.clone()),
+            HookRegistration::Factory(factory) => ChainHook::Owned(factory()),
+        }
+    }
+}
+
+enum ChainHook {
+    Shared(Arc<Mutex<Box<dyn MessageHook>>>),
+    Owned(Box<dyn MessageHook>),
+}
+
+/// 按注册顺序排列的一条钩子链。内联处理和每个工作线程各持有一条，工厂注册的钩子在每条链中各有一个实例
+#[derive(Default)]
+pub(crate) struct HookChain {
+    hooks: Vec<ChainHook>,
+}
+
+impl HookChain {
+    pub(crate) fn build(registrations: &[HookRegistration]) -> Self {
+        Self { hooks: registrations.iter().map(HookRegistration::instantiate).collect() }
+    }
+    
+    pub(crate) fn push(&mut self, registration: &HookRegistration) {
+        self.hooks.push(registration.instantiate());
+    }
+    
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+    
+    /// 依次调用钩子，返回最终的消息；被拒绝时返回原因，不再调用后续钩子
+    pub(crate) fn run(&mut self, mut message: Message, from: &PeerInfo) -> Result<Message, String> {
+        for hook in self.hooks.iter_mut() {
+            let decision = match hook {
+                // 共享的钩子在某个线程中 panic 后仍然可用，不让一次 panic 拖垮所有工作线程
+                ChainHook::Shared(hook) => hook.lock().unwrap_or_else(|e| e.into_inner()).on_inbound(&message, from),
+                ChainHook::Owned(hook) => hook.on_inbound(&message, from),
+            };
+            match decision {
+                HookDecision::Allow => {}
+                HookDecision::Modify(modified) => message = modified,
+                HookDecision::Reject(reason) => return Err(reason),
+            }
+        }
+        Ok(message)
+    }
+}
+
+/// 把 `FnMut(&PeerInfo, &mut Message) -> bool` 闭包包装成钩子，见 `P2PServer::on_message`。
+/// 闭包可以直接修改消息，返回 false 拒绝
+pub(crate) struct ClosureHook<F>(pub(crate) F);
+
+impl<F> MessageHook for ClosureHook<F>
+where
+    F: FnMut(&PeerInfo, &mut Message) -> bool + Send,
+{
+    fn on_inbound(&mut self, msg: &Message, from: &PeerInfo) -> HookDecision {
+        let mut message = msg.clone();
+        if (self.0)(from, &mut message) {
+            HookDecision::Modify(message)
+        } else {
+            HookDecision::Reject("message rejected by server".to_string())
+        }
+    }
+}
+
+/// 拒绝包含指定词语的聊天消息（包括回应和编辑，不区分大小写）
+pub struct ProfanityFilter {
+    words: Vec<String>,
+}
+
+impl ProfanityFilter {
+    pub fn new<I, S>(words: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self { words: words.into_iter().map(|word| word.into().to_lowercase()).collect() }
+    }
+}
+
+impl MessageHook for ProfanityFilter {
+    fn on_inbound(&mut self, msg: &Message, _from: &PeerInfo) -> HookDecision {
+        let Some(content) = msg.content.as_deref().filter(|_| msg.msg_type.is_user_content()) else {
+            return HookDecision::Allow;
+        };
+        let content = content.to_lowercase();
+        match self.words.iter().find(|word| content.contains(word.as_str())) {
+            Some(_) => HookDecision::Reject("message contains a blocked word".to_string()),
+            None => HookDecision::Allow,
+        }
+    }
+}
+
+/// 拒绝内容超过指定字符数的聊天消息
+pub struct MaxLengthFilter {
+    max_chars: usize,
+}
+
+impl MaxLengthFilter {
+    pub fn new(max_chars: usize) -> Self {
+        Self { max_chars }
+    }
+}
+
+impl MessageHook for MaxLengthFilter {
+    fn on_inbound(&mut self, msg: &Message, _from: &PeerInfo) -> HookDecision {
+        let chars = match &msg.content {
+            Some(content) if msg.msg_type.is_user_content() => content.chars().count(),
+            _ => return HookDecision::Allow,
+        };
+        if chars > self.max_chars {
+            HookDecision::Reject(format!("message is {} characters, limit is {}", chars, self.max_chars))
+        } else {
+            HookDecision::Allow
+        }
+    }
+}
@@ -1,4 +1,15 @@
 // p2p 包的主入口文件
 pub mod common;
 pub mod server;
-pub mod client;
\ No newline at end of file
+pub mod client;
+pub mod stats;
+pub mod health;
+pub mod registry;
+pub mod router;
+pub mod hooks;
+pub mod audit;
+#[cfg(feature = "chat-history")]
+pub mod history;
+pub mod room;
+pub mod socket;
+mod workers;
\ No newline at end of file
@@ -0,0 +1,229 @@
+// 用户注册表：记录曾经连接过的用户，持久化到 JSON 文件，服务器重启后仍然保留
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use crate::common::P2PError;
+
+/// 封禁信息：until 为 None 表示永久封禁
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BanRecord {
+    pub until: Option<SystemTime>,
+}
+
+/// 消息配额的使用情况：从 window_start 开始的一个配额周期内已发送的用户消息数
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    pub window_start: SystemTime,
+    pub used: u32,
+}
+
+/// 单个用户的持久化记录
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub user_id: String,
+    pub last_seen: SystemTime,
+    pub last_address: String,
+    pub last_port: u16,
+    /// 封禁状态（None 为未封禁）
+    #[serde(default)]
+    pub ban: Option<BanRecord>,
+    /// 首次加入时固定下来的身份公钥（之后的 Join 必须携带相同的 key）
+    #[serde(default)]
+    pub pinned_key: Option<String>,
+    /// 当前配额周期的使用情况（从未发送过消息或被管理员重置时为 None）
+    #[serde(default)]
+    pub quota: Option<QuotaUsage>,
+}
+
+impl UserRecord {
+    fn new(user_id: &str) -> Self {
+        Self {
+            user_id: user_id.to_string(),
+            last_seen: SystemTime::now(),
+            last_address: String::new(),
+            last_port: 0,
+            ban: None,
+            pinned_key: None,
+            quota: None,
+        }
+    }
+}
+
+/// 注册表文件内容。早期版本的文件只有用户记录数组，加载时仍然兼容
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RegistryFile {
+    Current {
+        users: Vec<UserRecord>,
+        #[serde(default)]
+        banned_ips: BTreeSet<IpAddr>,
+    },
+    Legacy(Vec<UserRecord>),
+}
+
+/// 用户注册表。修改只标记为脏，由事件循环定期调用 `flush_if_due` 合并写盘
+#[derive(Debug, Default)]
+pub struct Registry {
+    path: Option<PathBuf>,
+    records: HashMap<String, UserRecord>,
+    // 被封禁的 IP，来自这些地址的连接在 accept 时直接关闭
+    banned_ips: BTreeSet<IpAddr>,
+    dirty_since: Option<Instant>,
+}
+
+impl Registry {
+    /// 仅保存在内存中的注册表（不持久化）
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// 从文件加载注册表，文件不存在时创建空表
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, P2PError> {
+        let path = path.as_ref().to_path_buf();
+        let (list, banned_ips) = match std::fs::read(&path) {
+            Ok(data) => match serde_json::from_slice(&data)? {
+                RegistryFile::Current { users, banned_ips } => (users, banned_ips),
+                RegistryFile::Legacy(users) => (users, BTreeSet::new()),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (Vec::new(), BTreeSet::new()),
+            Err(e) => return Err(P2PError::Io(e)),
+        };
+        let records = list.into_iter().map(|record| (record.user_id.clone(), record)).collect();
+        Ok(Self { path: Some(path), records, banned_ips, dirty_since: None })
+    }
+
+    pub fn get(&self, user_id: &str) -> Option<&UserRecord> {
+        self.records.get(user_id)
+    }
+
+    pub fn records(&self) -> impl Iterator<Item = &UserRecord> {
+        self.records.values()
+    }
+
+    /// 记录一次加入：更新最后在线时间和地址
+    pub fn record_join(&mut self, user_id: &str, address: &str, port: u16) {
+        let record = self.records.entry(user_id.to_string()).or_insert_with(|| UserRecord::new(user_id));
+        record.last_seen = SystemTime::now();
+        record.last_address = address.to_string();
+        record.last_port = port;
+        self.mark_dirty();
+    }
+
+    /// 更新最后在线时间（离开、超时断开时调用）
+    pub fn touch(&mut self, user_id: &str) {
+        if let Some(record) = self.records.get_mut(user_id) {
+            record.last_seen = SystemTime::now();
+            self.mark_dirty();
+        }
+    }
+
+    pub fn set_ban(&mut self, user_id: &str, until: Option<SystemTime>) {
+        let record = self.records.entry(user_id.to_string()).or_insert_with(|| UserRecord::new(user_id));
+        record.ban = Some(BanRecord { until });
+        self.mark_dirty();
+    }
+
+    pub fn clear_ban(&mut self, user_id: &str) {
+        if let Some(record) = self.records.get_mut(user_id) {
+            if record.ban.take().is_some() {
+                self.mark_dirty();
+            }
+        }
+    }
+
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        self.banned_ips.contains(&ip)
+    }
+
+    pub fn banned_ips(&self) -> impl Iterator<Item = &IpAddr> {
+        self.banned_ips.iter()
+    }
+
+    pub fn ban_ip(&mut self, ip: IpAddr) {
+        if self.banned_ips.insert(ip) {
+            self.mark_dirty();
+        }
+    }
+
+    /// 解除 IP 封禁，返回该 IP 之前是否被封禁
+    pub fn unban_ip(&mut self, ip: IpAddr) -> bool {
+        let removed = self.banned_ips.remove(&ip);
+        if removed {
+            self.mark_dirty();
+        }
+        removed
+    }
+
+    pub fn pin_key(&mut self, user_id: &str, key: &str) {
+        let record = self.records.entry(user_id.to_string()).or_insert_with(|| UserRecord::new(user_id));
+        record.pinned_key = Some(key.to_string());
+        self.mark_dirty();
+    }
+
+    /// 消耗一条消息配额，返回本周期剩余条数。周期从上一个周期结束后的第一条消息开始，长度为 window；
+    /// 本周期已用完时不计数，返回周期结束（配额重置）的时间
+    pub fn consume_quota(&mut self, user_id: &str, limit: u32, window: Duration, now: SystemTime) -> Result<u32, SystemTime> {
+        let record = self.records.entry(user_id.to_string()).or_insert_with(|| UserRecord::new(user_id));
+        let usage = match record.quota {
+            Some(usage) if now < usage.window_start + window => usage,
+            _ => QuotaUsage { window_start: now, used: 0 },
+        };
+        if usage.used >= limit {
+            return Err(usage.window_start + window);
+        }
+        record.quota = Some(QuotaUsage { used: usage.used + 1, ..usage });
+        self.mark_dirty();
+        Ok(limit - usage.used - 1)
+    }
+
+    /// 清除用户的配额使用记录，返回之前是否有记录
+    pub fn reset_quota(&mut self, user_id: &str) -> bool {
+        let reset = self.records.get_mut(user_id).is_some_and(|record| record.quota.take().is_some());
+        if reset {
+            self.mark_dirty();
+        }
+        reset
+    }
+
+    fn mark_dirty(&mut self) {
+        if self.dirty_since.is_none() {
+            self.dirty_since = Some(Instant::now());
+        }
+    }
+
+    /// 距第一次未保存的修改超过 `debounce` 后写盘
+    pub fn flush_if_due(&mut self, debounce: Duration) -> Result<(), P2PError> {
+        match self.dirty_since {
+            Some(since) if since.elapsed() >= debounce => self.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    /// 立即写盘：先写临时文件再重命名，避免写到一半时崩溃损坏文件。
+    /// 写盘失败时修改仍然标记为未保存，下一个 debounce 周期后由 `flush_if_due` 重试
+    pub fn flush(&mut self) -> Result<(), P2PError> {
+        let Some(path) = &self.path else {
+            self.dirty_since = None;
+            return Ok(());
+        };
+        let result = self.write_file(path);
+        self.dirty_since = match result {
+            Ok(()) => None,
+            Err(_) => Some(Instant::now()),
+        };
+        result
+    }
+
+    fn write_file(&self, path: &Path) -> Result<(), P2PError> {
+        let mut users: Vec<UserRecord> = self.records.values().cloned().collect();
+        users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        let file = RegistryFile::Current { users, banned_ips: self.banned_ips.clone() };
+        let data = serde_json::to_vec_pretty(&file)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
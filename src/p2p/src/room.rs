@@ -0,0 +1,114 @@
+// 聊天房间：成员、房主和权限设置。房间状态只保存在服务器内存中，由路由器维护，
+// 第一个加入的用户创建房间并成为房主，最后一个成员离开后房间被删除
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// 房间配置。房主可以用 SetRoomConfig（content 为 JSON 编码的 RoomConfig）整体替换，
+/// 把 owner 改成其他成员即转让房主
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RoomConfig {
+    pub owner: String,
+    /// 只有被成员邀请（RoomInvite）过的用户才能加入
+    #[serde(default)]
+    pub invite_only: bool,
+    /// 成员上限，None 表示不限；调低上限不会移除已有成员
+    #[serde(default)]
+    pub max_members: Option<usize>,
+}
+
+/// 房间状态变化时服务器下发给成员的快照（RoomUpdate 的 content）
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RoomInfo {
+    pub name: String,
+    pub config: RoomConfig,
+    pub members: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Room {
+    name: String,
+    config: RoomConfig,
+    members: Vec<String>,  // 按加入顺序，房主离开后由最早加入的成员接任
+    invited: HashSet<String>,  // 已被邀请但尚未加入的用户，加入后移除
+}
+
+impl Room {
+    pub fn new(name: String, owner: String) -> Self {
+        Self {
+            name,
+            config: RoomConfig { owner: owner.clone(), invite_only: false, max_members: None },
+            members: vec![owner],
+            invited: HashSet::new(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn config(&self) -> &RoomConfig {
+        &self.config
+    }
+
+    pub fn members(&self) -> &[String] {
+        &self.members
+    }
+
+    pub fn is_member(&self, user_id: &str) -> bool {
+        self.members.iter().any(|member| member == user_id)
+    }
+
+    pub fn is_owner(&self, user_id: &str) -> bool {
+        self.config.owner == user_id
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.config.max_members.is_some_and(|max| self.members.len() >= max)
+    }
+
+    /// 该用户现在能否加入（不检查人数上限）
+    pub fn admits(&self, user_id: &str) -> bool {
+        !self.config.invite_only || self.invited.contains(user_id)
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo { name: self.name.clone(), config: self.config.clone(), members: self.members.clone() }
+    }
+
+    pub(crate) fn invite(&mut self, user_id: &str) {
+        if !self.is_member(user_id) {
+            self.invited.insert(user_id.to_string());
+        }
+    }
+
+    pub(crate) fn add_member(&mut self, user_id: &str) {
+        self.invited.remove(user_id);
+        if !self.is_member(user_id) {
+            self.members.push(user_id.to_string());
+        }
+    }
+
+    /// 移除成员，返回该用户原本是否是成员。房主离开时由最早加入的剩余成员接任
+    pub(crate) fn remove_member(&mut self, user_id: &str) -> bool {
+        let before = self.members.len();
+        self.members.retain(|member| member != user_id);
+        if self.is_owner(user_id) {
+            if let Some(next) = self.members.first() {
+                self.config.owner = next.clone();
+            }
+        }
+        self.members.len() != before
+    }
+
+    /// 替换配置；新房主必须是当前成员，成员上限不能为 0
+    pub(crate) fn set_config(&mut self, config: RoomConfig) -> Result<(), String> {
+        if !self.is_member(&config.owner) {
+            return Err(format!("{} is not a member of room {}", config.owner, self.name));
+        }
+        if config.max_members == Some(0) {
+            return Err("max_members must be at least 1".to_string());
+        }
+        self.config = config;
+        Ok(())
+    }
+}
@@ -0,0 +1,1175 @@
+// 消息路由：维护成员关系（在线用户、封禁、固定的身份公钥），根据收到的消息计算要投递的消息。
+// 路由器不接触 socket，P2PServer 负责把返回的投递写到连接上并执行状态变化事件。
+use mio::Token;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::BuildHasher;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime};
+use log::{debug, info, trace, warn};
+use crate::common::{DisconnectReason, ErrorCode, Framing, Message, MessageSource, MessageType, PeerEntry, PeerInfo, PeerListDelta, ServerInfo, SignaturePolicy, BROADCAST_TARGET, verify_message};
+use crate::audit::AuditEvent;
+use crate::registry::{BanRecord, Registry, UserRecord};
+use crate::room::{Room, RoomConfig};
+use crate::server::{DuplicateJoinPolicy, ServerConfig};
+
+/// 一次投递
+#[derive(Debug, Clone)]
+pub enum Delivery {
+    /// 发给单个连接
+    To(Token, Message),
+    /// 同一条消息发给一组连接（服务器只序列化一次）
+    Broadcast(Vec<Token>, Message),
+}
+
+impl Delivery {
+    pub fn message(&self) -> &Message {
+        match self {
+            Delivery::To(_, message) | Delivery::Broadcast(_, message) => message,
+        }
+    }
+
+    pub fn recipients(&self) -> &[Token] {
+        match self {
+            Delivery::To(token, _) => std::slice::from_ref(token),
+            Delivery::Broadcast(tokens, _) => tokens,
+        }
+    }
+}
+
+/// 路由产生的状态变化，由服务器在投递完成后执行
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouterEvent {
+    /// 关闭该连接（路由器已清理其成员状态）
+    Close(Token, DisconnectReason),
+    /// 该连接之后的收发改用协商出的帧格式（欢迎消息已按 Newline 排队）
+    SetFraming(Token, Framing),
+    /// 丢弃了一条重复的用户消息（只回复了确认），用于统计
+    DuplicateDropped,
+}
+
+/// 路由结果：按顺序排列的投递，以及投递之后要执行的事件
+#[derive(Debug, Default)]
+pub struct RouterOutput {
+    pub deliveries: Vec<Delivery>,
+    pub events: Vec<RouterEvent>,
+    /// 要写入审计日志的事件（加入、离开、认证失败）
+    pub audit: Vec<AuditEvent>,
+    /// 要写入聊天记录的已转发 Chat（服务器转发时的副本），未启用聊天记录时为空
+    pub chats: Vec<Message>,
+}
+
+impl RouterOutput {
+    /// 展开为逐个连接的 (token, 消息) 列表
+    pub fn pairs(&self) -> Vec<(Token, &Message)> {
+        self.deliveries.iter()
+            .flat_map(|delivery| delivery.recipients().iter().map(move |&token| (token, delivery.message())))
+            .collect()
+    }
+
+    /// 发往某个连接的全部消息（按投递顺序）
+    pub fn messages_to(&self, token: Token) -> Vec<&Message> {
+        self.pairs().into_iter()
+            .filter(|(recipient, _)| *recipient == token)
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deliveries.is_empty() && self.events.is_empty() && self.audit.is_empty() && self.chats.is_empty()
+    }
+
+    fn send(&mut self, token: Token, message: Message) {
+        self.deliveries.push(Delivery::To(token, message));
+    }
+
+    fn broadcast(&mut self, tokens: Vec<Token>, message: Message) {
+        self.deliveries.push(Delivery::Broadcast(tokens, message));
+    }
+
+    fn close(&mut self, token: Token, reason: DisconnectReason) {
+        self.events.push(RouterEvent::Close(token, reason));
+    }
+}
+
+pub struct Router {
+    peers: HashMap<Token, PeerInfo>,
+    user_to_token: HashMap<String, Token>,
+    addrs: HashMap<Token, SocketAddr>,  // accept 时观察到的远端地址
+    awaiting_join: HashMap<Token, Instant>,  // 已连接但尚未加入的连接 -> accept 时间
+    // 持久化的用户记录（最后在线时间、封禁、固定的身份公钥）
+    registry: Registry,
+    // 成员变化时递增，随心跳下发以便客户端检测过期的对等节点列表
+    peer_list_version: u64,
+    // 成员发生变化但尚未推送给所有人的时间点（用于合并短时间内的多次变化）
+    peer_list_changed_at: Option<Instant>,
+    // 上次推送以来每个用户的最新状态（None 表示已离开），合并窗口结束时作为增量推送
+    pending_delta: BTreeMap<String, Option<PeerEntry>>,
+    // 上次推送增量时的版本号，即下一次增量的 base_epoch
+    pushed_epoch: u64,
+    // 每个连接最近一次发出消息的时间（加入时和服务器写出时记录），空闲超过心跳间隔才发送保活心跳
+    last_sent: HashMap<Token, Instant>,
+    // 房间名 -> 房间，最后一个成员离开后删除
+    rooms: HashMap<String, Room>,
+    recent_relays: RecentRelays,
+    // 最近掉线用户的暂存私聊 user_id -> (暂存时间, 转发副本)，按发送顺序排列，下次加入时投递
+    offline_queue: HashMap<String, VecDeque<(Instant, Message)>>,
+    config: ServerConfig,
+}
+
+/// 最近转发过的用户消息 (发送方 user_id, msg_id)。按转发顺序淘汰：超过容量时丢弃最旧的，
+/// 周期维护时清理超过保留时间的
+#[derive(Debug, Default)]
+struct RecentRelays {
+    seen: HashSet<(String, String)>,
+    order: VecDeque<((String, String), Instant)>,
+}
+
+impl RecentRelays {
+    fn contains(&self, key: &(String, String)) -> bool {
+        self.seen.contains(key)
+    }
+
+    fn insert(&mut self, key: (String, String), now: Instant, capacity: usize) {
+        if !self.seen.insert(key.clone()) {
+            return;
+        }
+        self.order.push_back((key, now));
+        while self.order.len() > capacity {
+            if let Some((oldest, _)) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant, window: Duration) {
+        while let Some((key, _)) = self.order.front().filter(|(_, at)| now.saturating_duration_since(*at) >= window) {
+            self.seen.remove(key);
+            self.order.pop_front();
+        }
+    }
+}
+
+impl Router {
+    pub fn new(config: ServerConfig, registry: Registry) -> Self {
+        Self {
+            peers: HashMap::new(),
+            user_to_token: HashMap::new(),
+            addrs: HashMap::new(),
+            awaiting_join: HashMap::new(),
+            registry,
+            peer_list_version: 0,
+            peer_list_changed_at: None,
+            pending_delta: BTreeMap::new(),
+            pushed_epoch: 0,
+            last_sent: HashMap::new(),
+            rooms: HashMap::new(),
+            recent_relays: RecentRelays::default(),
+            offline_queue: HashMap::new(),
+            config,
+        }
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = (&Token, &PeerInfo)> {
+        self.peers.iter()
+    }
+
+    pub fn peer_info(&self, token: Token) -> Option<&PeerInfo> {
+        self.peers.get(&token)
+    }
+
+    pub fn token_of(&self, user_id: &str) -> Option<Token> {
+        self.user_to_token.get(user_id).copied()
+    }
+
+    pub fn room(&self, name: &str) -> Option<&Room> {
+        self.rooms.get(name)
+    }
+
+    pub fn peer_list_version(&self) -> u64 {
+        self.peer_list_version
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub fn registry_mut(&mut self) -> &mut Registry {
+        &mut self.registry
+    }
+
+    /// 查询用户的注册表记录
+    pub fn user_record(&self, user_id: &str) -> Option<&UserRecord> {
+        self.registry.get(user_id)
+    }
+
+    /// 新连接建立时记录观察到的远端地址
+    pub fn connection_opened(&mut self, token: Token, addr: SocketAddr) {
+        self.addrs.insert(token, addr);
+        self.awaiting_join.insert(token, Instant::now());
+    }
+
+    /// 连接已被服务器关闭（对端断开、读写错误等）：清理状态并通知剩余用户
+    pub fn connection_closed(&mut self, token: Token, reason: DisconnectReason) -> RouterOutput {
+        let mut out = RouterOutput::default();
+        self.remove_connection(token, reason, &mut out);
+        out
+    }
+
+    /// 服务器关闭时清空所有成员状态
+    pub fn clear(&mut self) {
+        self.peers.clear();
+        self.user_to_token.clear();
+        self.addrs.clear();
+        self.awaiting_join.clear();
+        self.last_sent.clear();
+    }
+
+    /// 服务器向 `token` 写出了一条消息，推迟该连接的下一次保活心跳
+    pub fn record_sent(&mut self, token: Token, now: Instant) {
+        if self.peers.contains_key(&token) {
+            self.last_sent.insert(token, now);
+        }
+    }
+
+    /// 路由一条来自 `token` 的消息
+    pub fn route(&mut self, message: &Message, token: Token) -> RouterOutput {
+        if self.config.log_content {
+            trace!("routing {} from user_id={} token={:?}: {:.*}",
+                   message.msg_type, message.sender_id, token, self.config.log_content_chars, message);
+        } else {
+            trace!("routing {} from user_id={} token={:?}: {}",
+                   message.msg_type, message.sender_id, token, message.redacted());
+        }
+
+        // 任何消息都说明连接存活（客户端有其他消息往来时不再发送心跳）；心跳本身不算作活动
+        if let Some(peer_info) = self.peers.get_mut(&token) {
+            let now = Instant::now();
+            peer_info.last_heartbeat = now;
+            if message.msg_type.is_user_content() || matches!(message.msg_type, MessageType::PeerListRequest | MessageType::ConnectRequest) {
+                peer_info.last_activity = now;
+            }
+        }
+
+        let mut out = RouterOutput::default();
+        match message.msg_type {
+            MessageType::Join => self.handle_join_message(message, token, &mut out),
+            MessageType::Leave => self.disconnect(token, DisconnectReason::Left, &mut out),
+            MessageType::Chat | MessageType::React | MessageType::Edit => self.handle_chat_message(message, token, &mut out),
+            MessageType::Heartbeat => self.handle_heartbeat_message(message, token, &mut out),
+            MessageType::PeerListRequest => self.handle_peer_list_request(message, token, &mut out),
+            MessageType::ConnectRequest => self.handle_connect_request(message, token, &mut out),
+            MessageType::JoinRoom | MessageType::LeaveRoom | MessageType::RoomInvite
+            | MessageType::KickFromRoom | MessageType::SetRoomConfig => self.handle_room_message(message, token, &mut out),
+            _ => warn!("unhandled message type {:?} from token={:?}", message.msg_type, token),
+        }
+        out
+    }
+
+    /// 修改之后加入的用户收到的公告
+    pub fn set_motd(&mut self, motd: Option<String>) {
+        self.config.motd = motd;
+    }
+
+    /// 踢出用户：通知对方、关闭连接，并告知其他用户
+    pub fn kick(&mut self, user_id: &str) -> RouterOutput {
+        let mut out = RouterOutput::default();
+        let Some(token) = self.token_of(user_id) else {
+            warn!("cannot kick user_id={}: not connected", user_id);
+            return out;
+        };
+
+        info!("kicking user_id={} token={:?}", user_id, token);
+        out.send(token, server_message(MessageType::Kick, "kicked by administrator".to_string()));
+        self.disconnect(token, DisconnectReason::Kicked, &mut out);
+        out
+    }
+
+    /// 封禁用户，使其在到期前无法重新加入；若在线则立即踢出
+    pub fn ban(&mut self, user_id: &str, duration: Option<Duration>) -> RouterOutput {
+        let expires_at = duration.map(|d| SystemTime::now() + d);
+        self.registry.set_ban(user_id, expires_at);
+        match duration {
+            Some(d) => info!("banned user_id={} for {:?}", user_id, d),
+            None => info!("banned user_id={} permanently", user_id),
+        }
+
+        if self.user_to_token.contains_key(user_id) {
+            self.kick(user_id)
+        } else {
+            RouterOutput::default()
+        }
+    }
+
+    pub fn reset_quota(&mut self, user_id: &str) {
+        if self.registry.reset_quota(user_id) {
+            info!("reset message quota of user_id={}", user_id);
+        }
+    }
+
+    pub fn unban(&mut self, user_id: &str) {
+        if self.registry.get(user_id).is_some_and(|record| record.ban.is_some()) {
+            self.registry.clear_ban(user_id);
+            info!("unbanned user_id={}", user_id);
+        }
+    }
+
+    /// 封禁 IP：之后来自该地址的连接在 accept 时直接关闭，当前来自该地址的连接（无论是否已加入）立即断开
+    pub fn ban_ip(&mut self, ip: IpAddr) -> RouterOutput {
+        self.registry.ban_ip(ip);
+        info!("banned ip={}", ip);
+
+        let mut out = RouterOutput::default();
+        let tokens: Vec<Token> = self.addrs.iter()
+            .filter(|(_, addr)| addr.ip() == ip)
+            .map(|(&token, _)| token)
+            .collect();
+        for token in tokens {
+            out.send(token, server_message(MessageType::Kick, "address banned by administrator".to_string()));
+            self.disconnect(token, DisconnectReason::Kicked, &mut out);
+        }
+        out
+    }
+
+    pub fn unban_ip(&mut self, ip: IpAddr) {
+        if self.registry.unban_ip(ip) {
+            info!("unbanned ip={}", ip);
+        }
+    }
+
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        self.registry.is_ip_banned(ip)
+    }
+
+    /// 向所有已加入的客户端推送系统公告（如维护通知）
+    pub fn system_broadcast(&mut self, content: String) -> RouterOutput {
+        info!("system broadcast: {}", content);
+        let mut out = RouterOutput::default();
+        out.broadcast(self.peer_tokens(None), server_message(MessageType::System, content));
+        out
+    }
+
+    /// 周期性任务：推送合并后的对等节点列表、向空闲连接发送心跳、清理超时和长时间无活动的连接
+    pub fn tick(&mut self, now: Instant) -> RouterOutput {
+        let mut out = RouterOutput::default();
+        self.push_peer_list_updates(now, &mut out);
+        self.check_heartbeat(now, &mut out);
+        self.check_peer_timeouts(now, &mut out);
+        self.check_idle_peers(now, &mut out);
+        self.check_join_timeouts(now, &mut out);
+        self.recent_relays.expire(now, self.config.relay_dedup_window);
+        self.expire_offline_queue(now, &mut out);
+        out
+    }
+
+    /// 检查用户是否处于封禁期（顺便清理已过期的封禁）
+    fn is_banned(&mut self, user_id: &str) -> bool {
+        match self.registry.get(user_id).and_then(|record| record.ban) {
+            Some(BanRecord { until: Some(expires_at) }) if SystemTime::now() >= expires_at => {
+                self.registry.clear_ban(user_id);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    fn peer_tokens(&self, exclude: Option<Token>) -> Vec<Token> {
+        self.peers.keys().filter(|&&t| Some(t) != exclude).cloned().collect()
+    }
+
+    /// 回复一条消息后关闭该连接
+    fn audit_auth_failure(&self, token: Token, user_id: &str, reason: &str, out: &mut RouterOutput) {
+        out.audit.push(AuditEvent::AuthFailure {
+            user_id: user_id.to_string(),
+            remote_addr: self.addrs.get(&token).copied(),
+            reason: reason.to_string(),
+        });
+    }
+
+    fn reject(&mut self, token: Token, message: Message, out: &mut RouterOutput) {
+        out.send(token, message);
+        self.disconnect(token, DisconnectReason::Rejected, out);
+    }
+
+    /// 私聊已路由给目标，但服务器写入目标连接失败（目标随即被断开）：通知仍在线的发送方
+    pub fn delivery_failed(&self, message: &Message) -> RouterOutput {
+        let mut out = RouterOutput::default();
+        if let (true, Some(target_id)) = (message.msg_type.is_user_content(), message.direct_target()) {
+            if let Some(sender_token) = self.token_of(&message.sender_id) {
+                out.send(sender_token, target_offline(message, target_id));
+            }
+        }
+        out
+    }
+
+    fn handle_join_message(&mut self, message: &Message, token: Token, out: &mut RouterOutput) {
+        // 同一连接上再次 Join：user_id 不变（或为空）时视为刷新会话，否则按先离开再加入处理
+        if let Some(current) = self.peers.get(&token).map(|peer| peer.user_id.clone()) {
+            if message.sender_id.is_empty() || message.sender_id == current {
+                self.refresh_session(message, token, out);
+                return;
+            }
+            info!("token={:?} re-joined as user_id={} (was {}), treating as leave + join", token, message.sender_id, current);
+            let addr = self.addrs.get(&token).copied();
+            self.remove_connection(token, DisconnectReason::Left, out);
+            if let Some(addr) = addr {
+                self.addrs.insert(token, addr);
+            }
+        }
+
+        // sender_id 为空的是访客，由服务器分配 user_id
+        let guest_id = if message.sender_id.is_empty() {
+            if !self.config.allow_guests {
+                info!("empty user_id from token={:?} and guests are not allowed, rejecting", token);
+                self.reject(token, server_error(ErrorCode::InvalidUserId, "user_id must not be empty".to_string()), out);
+                return;
+            }
+            Some(self.generate_guest_id(token))
+        } else {
+            None
+        };
+        let user_id = guest_id.as_ref().unwrap_or(&message.sender_id);
+        debug!("join request user_id={} token={:?} claimed_addr={}:{}",
+               user_id, token, message.sender_peer_address, message.sender_listen_port);
+
+        if self.is_banned(user_id) {
+            info!("user_id={} is banned, rejecting join from token={:?}", user_id, token);
+            self.audit_auth_failure(token, user_id, "banned", out);
+            self.reject(token, server_message(MessageType::JoinRejected, format!("user_id {} is banned", user_id)), out);
+            return;
+        }
+
+        // Join 的 content 携带可选的身份公钥：首次出现时固定下来，之后必须一致
+        let identity_key = message.content.as_deref().filter(|key| !key.is_empty());
+        if let Some(pinned) = self.registry.get(user_id).and_then(|record| record.pinned_key.as_deref()) {
+            if identity_key != Some(pinned) {
+                info!("user_id={} presented a key that does not match the pinned one, rejecting token={:?}", user_id, token);
+                self.audit_auth_failure(token, user_id, "identity key mismatch", out);
+                self.reject(token, server_message(MessageType::JoinRejected, format!("identity key mismatch for {}", user_id)), out);
+                return;
+            }
+        }
+
+        // 同一 user_id 已被另一个连接占用
+        if let Some(existing) = self.token_of(user_id) {
+            if existing != token {
+                match self.config.duplicate_join_policy {
+                    DuplicateJoinPolicy::Reject => {
+                        info!("user_id={} already connected, rejecting token={:?}", user_id, token);
+                        self.reject(token, server_error(ErrorCode::UserIdInUse, format!("user_id {} is already in use", user_id)), out);
+                        return;
+                    }
+                    DuplicateJoinPolicy::Displace => {
+                        info!("user_id={} reconnected from token={:?}, displacing token={:?}", user_id, token, existing);
+                        out.send(existing, server_message(MessageType::Kick, "logged in from another connection".to_string()));
+                        self.disconnect(existing, DisconnectReason::Kicked, out);
+                    }
+                }
+            }
+        }
+
+        let address = self.peer_address(token, &message.sender_peer_address);
+        let peer_info = PeerInfo::new(user_id.clone(), address.clone(), message.sender_listen_port);
+
+        self.peers.insert(token, peer_info);
+        // 加入时回复的列表和欢迎消息也算发出过消息
+        self.last_sent.insert(token, Instant::now());
+        self.awaiting_join.remove(&token);
+        self.user_to_token.insert(user_id.clone(), token);
+        self.mark_peer_list_changed(user_id);
+        self.registry.record_join(user_id, &address, message.sender_listen_port);
+        if let Some(key) = identity_key {
+            if self.registry.get(user_id).is_some_and(|record| record.pinned_key.is_none()) {
+                self.registry.pin_key(user_id, key);
+            }
+        }
+
+        info!("user joined user_id={} token={:?} addr={}:{}", user_id, token, address, message.sender_listen_port);
+        out.audit.push(AuditEvent::Join {
+            user_id: user_id.clone(),
+            remote_addr: self.addrs.get(&token).copied(),
+            listen_port: message.sender_listen_port,
+        });
+
+        // Notify other users
+        let mut join_notification = server_message(MessageType::UserJoined, user_id.clone());
+        join_notification.sender_id = user_id.clone();
+        join_notification.sender_peer_address = address;
+        join_notification.sender_listen_port = message.sender_listen_port;
+        out.broadcast(self.peer_tokens(Some(token)), join_notification);
+
+        self.send_peer_list(token, None, out);
+        // 暂存的私聊先于欢迎消息投递：它们和欢迎消息一样按 Newline 编码，也一定排在之后的实时消息前面
+        self.deliver_offline_queue(user_id, token, out);
+        // 欢迎消息仍按 Newline 发送，之后该连接改用协商出的格式
+        let framing = Framing::negotiate(message.framings.as_deref(), &self.config.framings);
+        self.send_welcome(token, guest_id, framing, out);
+        if framing != Framing::Newline {
+            out.events.push(RouterEvent::SetFraming(token, framing));
+        }
+    }
+
+    /// 刷新已加入连接的会话：更新地址和端口、重置心跳时间并重发对等节点列表，不再广播 UserJoined
+    fn refresh_session(&mut self, message: &Message, token: Token, out: &mut RouterOutput) {
+        let address = self.peer_address(token, &message.sender_peer_address);
+        let port = message.sender_listen_port;
+        let Some(peer_info) = self.peers.get_mut(&token) else {
+            return;
+        };
+        peer_info.last_heartbeat = Instant::now();
+        let changed = peer_info.address != address || peer_info.port != port;
+        peer_info.address = address.clone();
+        peer_info.port = port;
+        let user_id = peer_info.user_id.clone();
+
+        debug!("session refresh user_id={} token={:?} addr={}:{} changed={}", user_id, token, address, port, changed);
+        if changed {
+            self.mark_peer_list_changed(&user_id);
+        }
+        self.registry.record_join(&user_id, &address, port);
+        self.send_peer_list(token, None, out);
+    }
+
+    /// 生成访客 user_id，保证不与在线用户和注册表中的用户重复
+    fn generate_guest_id(&self, token: Token) -> String {
+        let state = RandomState::new();
+        (0u64..)
+            .map(|attempt| format!("guest-{:06x}", state.hash_one((token.0, attempt, SystemTime::now())) & 0xff_ffff))
+            .find(|id| !self.user_to_token.contains_key(id) && self.registry.get(id).is_none())
+            .unwrap_or_default()
+    }
+
+    /// 欢迎消息紧跟在对等节点列表之后，保证新用户先于任何聊天收到公告
+    fn send_welcome(&self, token: Token, assigned_user_id: Option<String>, framing: Framing, out: &mut RouterOutput) {
+        let info = ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            connected_users: self.peers.len(),
+            motd: self.config.motd.clone(),
+            assigned_user_id,
+            framing,
+        };
+        out.send(token, server_message(MessageType::Welcome, serde_json::to_string(&info).unwrap_or_default()));
+    }
+
+    /// 确定对等节点的可达地址：使用 accept 时观察到的 IP，而不是客户端自称的地址。
+    /// 仅当观察到的是回环地址且配置允许时，才采用客户端声明的地址。
+    fn peer_address(&self, token: Token, claimed: &str) -> String {
+        match self.addrs.get(&token) {
+            Some(addr) if addr.ip().is_loopback()
+                && self.config.trust_claimed_address_on_loopback
+                && !claimed.is_empty() => claimed.to_string(),
+            Some(addr) => addr.ip().to_string(),
+            None => claimed.to_string(),
+        }
+    }
+
+    /// 带 msg_id 的用户消息转发成功后回复 RelayAck；同一发送方重复发送（客户端重试）的消息
+    /// 只回复确认、不再转发，也不计入配额。
+    /// 发送方以连接上加入的 user_id 为准：自称的 sender_id 不符时先改正，签名校验、去重和转发都使用改正后的消息；
+    /// 尚未加入的连接回复 NotJoined
+    fn handle_chat_message(&mut self, message: &Message, token: Token, out: &mut RouterOutput) {
+        let Some(user_id) = self.peers.get(&token).map(|peer| peer.user_id.clone()) else {
+            debug!("{:?} from token={:?} before join", message.msg_type, token);
+            out.send(token, server_error(ErrorCode::NotJoined, "join before sending messages".to_string()));
+            return;
+        };
+        let corrected;
+        let message = if user_id != message.sender_id {
+            warn!("token={:?} joined as user_id={} sent {:?} claiming sender_id={}", token, user_id, message.msg_type, message.sender_id);
+            corrected = Message { sender_id: user_id.clone(), ..message.clone() };
+            &corrected
+        } else {
+            message
+        };
+        let dedup_key = message.msg_id.clone().map(|msg_id| (user_id.clone(), msg_id));
+        if let Some(key) = dedup_key.as_ref().filter(|key| self.recent_relays.contains(key)) {
+            debug!("dropping duplicate {:?} msg_id={} from user_id={}", message.msg_type, key.1, key.0);
+            out.send(token, relay_ack(&key.1));
+            out.events.push(RouterEvent::DuplicateDropped);
+            return;
+        }
+        if let Some(error) = self.check_content_length(message) {
+            out.send(token, error);
+            return;
+        }
+        if let Some(error) = self.check_signature(message) {
+            out.send(token, error);
+            return;
+        }
+        if let Some(error) = self.consume_quota(&user_id, message) {
+            out.send(token, error);
+            return;
+        }
+        if self.relay_chat_message(message, token, out) {
+            if message.msg_type == MessageType::Chat && self.config.chat_log_enabled() {
+                out.chats.push(self.relay_copy(message, token));
+            }
+            if let Some((user_id, msg_id)) = dedup_key {
+                out.send(token, relay_ack(&msg_id));
+                self.recent_relays.insert((user_id, msg_id), Instant::now(), self.config.relay_dedup_capacity);
+            }
+        }
+    }
+
+    /// 内容超过 max_content_chars 时返回给发送方的 MessageTooLarge 错误（reply_to 指向被拒绝的消息）。
+    /// 客户端发送前也会检查，这里防止修改过的客户端绕过限制
+    fn check_content_length(&self, message: &Message) -> Option<Message> {
+        let max = self.config.max_content_chars?;
+        let chars = message.content.as_deref()?.chars().count();
+        if chars <= max {
+            return None;
+        }
+        debug!("rejecting {:?} from user_id={} with {} characters (max {})", message.msg_type, message.sender_id, chars, max);
+        let mut error = server_error(ErrorCode::MessageTooLarge, format!("content has {} characters, max is {}", chars, max));
+        error.reply_to = message.msg_id.clone();
+        Some(error)
+    }
+
+    /// 校验签名；策略为 Reject 且签名无效时返回给发送方的 Rejected 错误（reply_to 指向被拒绝的消息）。
+    /// 在 relay_copy 之前校验，改写后的字段不参与签名，签名本身原样转发
+    fn check_signature(&self, message: &Message) -> Option<Message> {
+        let key = self.config.signing_key.as_deref()?;
+        if verify_message(message, key.as_bytes()) {
+            return None;
+        }
+        match self.config.signature_policy {
+            SignaturePolicy::Mark => {
+                warn!("relaying {:?} msg_id={:?} from user_id={} with invalid signature", message.msg_type, message.msg_id, message.sender_id);
+                None
+            }
+            SignaturePolicy::Reject => {
+                debug!("rejecting {:?} from user_id={} with invalid signature", message.msg_type, message.sender_id);
+                let mut error = server_error(ErrorCode::Rejected, "invalid signature".to_string());
+                error.reply_to = message.msg_id.clone();
+                Some(error)
+            }
+        }
+    }
+
+    /// 计入配额；配额用完时返回给发送方的 QuotaExceeded 错误（reply_to 指向被拒绝的消息）
+    fn consume_quota(&mut self, user_id: &str, message: &Message) -> Option<Message> {
+        let limit = self.config.message_quota?;
+        let now = SystemTime::now();
+        let resets_at = self.registry.consume_quota(user_id, limit, self.config.quota_window, now).err()?;
+        let resets_in = resets_at.duration_since(now).unwrap_or_default().as_secs();
+        let resets_at = resets_at.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+        debug!("user_id={} exceeded message quota of {}, resets in {}s", user_id, limit, resets_in);
+        let mut error = server_error(ErrorCode::QuotaExceeded,
+            format!("message quota of {} exceeded, resets in {}s (unix time {})", limit, resets_in, resets_at));
+        error.reply_to = message.msg_id.clone();
+        Some(error)
+    }
+
+    /// 公共消息必须显式以 `*` 为目标；缺少目标、空目标或未知用户都回复 Error，而不是当作广播。
+    /// 返回消息是否被转发
+    fn relay_chat_message(&mut self, message: &Message, token: Token, out: &mut RouterOutput) -> bool {
+        let relay = self.relay_copy(message, token);
+        if let Some(name) = message.room.as_deref() {
+            return self.handle_room_chat(name, relay, token, out);
+        }
+        match message.target_id.as_deref() {
+            Some(BROADCAST_TARGET) => {
+                let tokens = self.peer_tokens(None);
+                debug!("relaying public chat from user_id={} to {} peers", message.sender_id, tokens.len());
+                out.broadcast(tokens, relay);
+                true
+            }
+            None | Some("") => {
+                debug!("chat from user_id={} token={:?} has no target", message.sender_id, token);
+                out.send(token, server_error(ErrorCode::MissingTarget,
+                    format!("chat message needs a target_id (use \"{}\" to broadcast)", BROADCAST_TARGET)));
+                false
+            }
+            Some(target_id) => match self.token_of(target_id) {
+                Some(target_token) => {
+                    debug!("relaying private chat from user_id={} to user_id={} token={:?}", message.sender_id, target_id, target_token);
+                    out.send(target_token, relay);
+                    true
+                }
+                None if self.can_queue_offline(target_id) => {
+                    debug!("user_id={} is reconnecting, queueing chat from user_id={}", target_id, message.sender_id);
+                    self.offline_queue.entry(target_id.to_string()).or_default().push_back((Instant::now(), relay));
+                    true
+                }
+                None => {
+                    debug!("chat from user_id={} targets offline user_id={}", message.sender_id, target_id);
+                    out.send(token, target_offline(message, target_id));
+                    false
+                }
+            },
+        }
+    }
+
+    /// 目标在 offline_queue_window 内还在线过（注册表的 last_seen 在离开时更新），且暂存未满
+    fn can_queue_offline(&self, user_id: &str) -> bool {
+        let Some(window) = self.config.offline_queue_window else {
+            return false;
+        };
+        let recently_seen = self.registry.get(user_id)
+            .is_some_and(|record| record.last_seen.elapsed().unwrap_or_default() <= window);
+        let queued = self.offline_queue.get(user_id).map_or(0, VecDeque::len);
+        recently_seen && queued < self.config.offline_queue_max_messages
+    }
+
+    fn deliver_offline_queue(&mut self, user_id: &str, token: Token, out: &mut RouterOutput) {
+        let Some(queue) = self.offline_queue.remove(user_id) else {
+            return;
+        };
+        info!("delivering {} queued messages to user_id={} token={:?}", queue.len(), user_id, token);
+        for (_, message) in queue {
+            out.send(token, message);
+        }
+    }
+
+    /// 丢弃暂存超过 offline_queue_window 的消息，并告知仍在线的发送方目标不在线
+    fn expire_offline_queue(&mut self, now: Instant, out: &mut RouterOutput) {
+        let Some(window) = self.config.offline_queue_window else {
+            return;
+        };
+        let mut expired = Vec::new();
+        self.offline_queue.retain(|user_id, queue| {
+            while queue.front().is_some_and(|(queued_at, _)| now.saturating_duration_since(*queued_at) > window) {
+                if let Some((_, message)) = queue.pop_front() {
+                    expired.push((user_id.clone(), message));
+                }
+            }
+            !queue.is_empty()
+        });
+        for (user_id, message) in expired {
+            debug!("queued chat from user_id={} to user_id={} expired", message.sender_id, user_id);
+            if let Some(sender_token) = self.token_of(&message.sender_id) {
+                out.send(sender_token, target_offline(&message, &user_id));
+            }
+        }
+    }
+
+    /// 房间聊天只发给房间成员（包括发送方），发送方必须是成员
+    fn handle_room_chat(&mut self, name: &str, relay: Message, token: Token, out: &mut RouterOutput) -> bool {
+        let Some(user_id) = self.peers.get(&token).map(|peer| peer.user_id.clone()) else {
+            return false;
+        };
+        match self.rooms.get(name).filter(|room| room.is_member(&user_id)) {
+            Some(room) => {
+                let tokens = self.member_tokens(room);
+                debug!("relaying room chat from user_id={} to room={} ({} members online)", user_id, name, tokens.len());
+                out.broadcast(tokens, relay);
+                true
+            }
+            None => {
+                out.send(token, server_error(ErrorCode::NotRoomMember, format!("you are not a member of room {}", name)));
+                false
+            }
+        }
+    }
+
+    /// 房间操作。操作者以连接上已加入的 user_id 为准，而不是消息自称的 sender_id
+    fn handle_room_message(&mut self, message: &Message, token: Token, out: &mut RouterOutput) {
+        let Some(user_id) = self.peers.get(&token).map(|peer| peer.user_id.clone()) else {
+            debug!("room request {:?} from token={:?} before join", message.msg_type, token);
+            out.send(token, server_error(ErrorCode::NotJoined, "join before using rooms".to_string()));
+            return;
+        };
+        let Some(name) = message.room.clone().filter(|name| !name.is_empty()) else {
+            out.send(token, server_error(ErrorCode::MissingTarget, "room request needs a room name".to_string()));
+            return;
+        };
+
+        let result = match message.msg_type {
+            MessageType::JoinRoom => self.join_room(&user_id, &name, out),
+            MessageType::LeaveRoom => self.leave_room(&user_id, &name, out),
+            MessageType::RoomInvite => self.invite_to_room(&user_id, &name, message, out),
+            MessageType::KickFromRoom => self.kick_from_room(&user_id, &name, message, out),
+            MessageType::SetRoomConfig => self.set_room_config(&user_id, &name, message, out),
+            _ => Ok(()),
+        };
+        if let Err((code, reason)) = result {
+            debug!("room request {:?} from user_id={} for room={} failed: {}", message.msg_type, user_id, name, reason);
+            let mut error = server_error(code, reason);
+            error.room = Some(name);
+            out.send(token, error);
+        }
+    }
+
+    fn join_room(&mut self, user_id: &str, name: &str, out: &mut RouterOutput) -> Result<(), (ErrorCode, String)> {
+        match self.rooms.get_mut(name) {
+            Some(room) if room.is_member(user_id) => {}
+            Some(room) => {
+                if !room.admits(user_id) {
+                    return Err((ErrorCode::NotInvited, format!("room {} is invite-only", name)));
+                }
+                if room.is_full() {
+                    return Err((ErrorCode::RoomFull, format!("room {} is full", name)));
+                }
+                room.add_member(user_id);
+                info!("user_id={} joined room={}", user_id, name);
+            }
+            None => {
+                info!("user_id={} created room={}", user_id, name);
+                self.rooms.insert(name.to_string(), Room::new(name.to_string(), user_id.to_string()));
+            }
+        }
+        self.send_room_update(name, out);
+        Ok(())
+    }
+
+    fn leave_room(&mut self, user_id: &str, name: &str, out: &mut RouterOutput) -> Result<(), (ErrorCode, String)> {
+        if !self.remove_room_member(user_id, name, out) {
+            return Err((ErrorCode::NotRoomMember, format!("you are not a member of room {}", name)));
+        }
+        info!("user_id={} left room={}", user_id, name);
+        Ok(())
+    }
+
+    fn invite_to_room(&mut self, user_id: &str, name: &str, message: &Message, out: &mut RouterOutput) -> Result<(), (ErrorCode, String)> {
+        let Some(invitee) = message.target_id.as_deref().filter(|target| !target.is_empty()) else {
+            return Err((ErrorCode::MissingTarget, "invite needs a target_id".to_string()));
+        };
+        let room = self.rooms.get_mut(name)
+            .filter(|room| room.is_member(user_id))
+            .ok_or_else(|| (ErrorCode::NotRoomMember, format!("you are not a member of room {}", name)))?;
+        room.invite(invitee);
+        debug!("user_id={} invited user_id={} to room={}", user_id, invitee, name);
+
+        if let Some(invitee_token) = self.token_of(invitee) {
+            let mut invite = server_message(MessageType::RoomInvite, format!("{} invited you to room {}", user_id, name));
+            invite.sender_id = user_id.to_string();
+            invite.target_id = Some(invitee.to_string());
+            invite.room = Some(name.to_string());
+            out.send(invitee_token, invite);
+        }
+        Ok(())
+    }
+
+    fn kick_from_room(&mut self, user_id: &str, name: &str, message: &Message, out: &mut RouterOutput) -> Result<(), (ErrorCode, String)> {
+        let Some(target) = message.target_id.clone().filter(|target| !target.is_empty()) else {
+            return Err((ErrorCode::MissingTarget, "kick needs a target_id".to_string()));
+        };
+        self.require_owner(user_id, name)?;
+        if !self.remove_room_member(&target, name, out) {
+            return Err((ErrorCode::NotRoomMember, format!("{} is not a member of room {}", target, name)));
+        }
+        info!("user_id={} kicked user_id={} from room={}", user_id, target, name);
+
+        if let Some(target_token) = self.token_of(&target) {
+            let mut kick = server_message(MessageType::KickFromRoom, format!("removed from room {} by {}", name, user_id));
+            kick.sender_id = user_id.to_string();
+            kick.target_id = Some(target);
+            kick.room = Some(name.to_string());
+            out.send(target_token, kick);
+        }
+        Ok(())
+    }
+
+    fn set_room_config(&mut self, user_id: &str, name: &str, message: &Message, out: &mut RouterOutput) -> Result<(), (ErrorCode, String)> {
+        self.require_owner(user_id, name)?;
+        let config: RoomConfig = message.content.as_deref()
+            .and_then(|content| serde_json::from_str(content).ok())
+            .ok_or_else(|| (ErrorCode::InvalidRoomConfig, "content must be a JSON room config".to_string()))?;
+        if let Some(room) = self.rooms.get_mut(name) {
+            room.set_config(config).map_err(|reason| (ErrorCode::InvalidRoomConfig, reason))?;
+        }
+        info!("user_id={} updated config of room={}", user_id, name);
+        self.send_room_update(name, out);
+        Ok(())
+    }
+
+    fn require_owner(&self, user_id: &str, name: &str) -> Result<(), (ErrorCode, String)> {
+        match self.rooms.get(name) {
+            Some(room) if room.is_owner(user_id) => Ok(()),
+            Some(_) => Err((ErrorCode::NotRoomOwner, format!("only the owner of room {} can do that", name))),
+            None => Err((ErrorCode::NotRoomMember, format!("you are not a member of room {}", name))),
+        }
+    }
+
+    /// 把用户移出房间并通知剩余成员；房间空了就删除。返回该用户原本是否是成员
+    fn remove_room_member(&mut self, user_id: &str, name: &str, out: &mut RouterOutput) -> bool {
+        let Some(room) = self.rooms.get_mut(name) else {
+            return false;
+        };
+        if !room.remove_member(user_id) {
+            return false;
+        }
+        if room.members().is_empty() {
+            info!("room={} is empty, removing it", name);
+            self.rooms.remove(name);
+        } else {
+            self.send_room_update(name, out);
+        }
+        true
+    }
+
+    fn send_room_update(&self, name: &str, out: &mut RouterOutput) {
+        let Some(room) = self.rooms.get(name) else {
+            return;
+        };
+        let mut update = server_message(MessageType::RoomUpdate, serde_json::to_string(&room.info()).unwrap_or_default());
+        update.room = Some(name.to_string());
+        out.broadcast(self.member_tokens(room), update);
+    }
+
+    fn member_tokens(&self, room: &Room) -> Vec<Token> {
+        room.members().iter().filter_map(|member| self.token_of(member)).collect()
+    }
+
+    /// 生成转发给接收方的副本：来源固定为服务器，时间戳改为服务器收到的时间（原值保留在 client_timestamp），
+    /// 发送方、地址和端口填写服务器为该连接记录的值；连接尚未加入时清空地址和端口
+    fn relay_copy(&self, message: &Message, token: Token) -> Message {
+        let mut relay = message.clone();
+        relay.source = MessageSource::Server;
+        relay.client_timestamp = Some(message.timestamp);
+        relay.timestamp = SystemTime::now();
+        relay.framings = None;
+        match self.peers.get(&token) {
+            Some(peer) => {
+                relay.sender_id = peer.user_id.clone();
+                relay.sender_peer_address = peer.address.clone();
+                relay.sender_listen_port = peer.port;
+            }
+            None => {
+                relay.sender_peer_address = String::new();
+                relay.sender_listen_port = 0;
+            }
+        }
+        relay
+    }
+
+    fn handle_heartbeat_message(&mut self, message: &Message, token: Token, out: &mut RouterOutput) {
+        if let Some(peer_info) = self.peers.get_mut(&token) {
+            peer_info.last_heartbeat = Instant::now();
+        }
+
+        // 回显客户端的 nonce，便于客户端计算 RTT；同时附带对等节点列表版本号
+        let mut ack = server_message(MessageType::HeartbeatAck, message.content.clone().unwrap_or_default());
+        ack.peer_list_version = Some(self.peer_list_version);
+        out.send(token, ack);
+    }
+
+    fn handle_connect_request(&mut self, message: &Message, token: Token, out: &mut RouterOutput) {
+        let Some(requester) = self.peers.get(&token).map(|peer| peer.user_id.clone()) else {
+            debug!("connect request from token={:?} before join", token);
+            out.send(token, server_error(ErrorCode::NotJoined, "join before requesting connections".to_string()));
+            return;
+        };
+        let Some(target_id) = message.target_id.as_deref().filter(|target_id| !target_id.is_empty()) else {
+            out.send(token, server_error(ErrorCode::MissingTarget, "connect request needs a target_id".to_string()));
+            return;
+        };
+        let Some(peer_info) = self.token_of(target_id).and_then(|target_token| self.peers.get(&target_token)) else {
+            out.send(token, server_error(ErrorCode::TargetOffline, format!("{} is offline", target_id)));
+            return;
+        };
+
+        let content = format!("{},{}", peer_info.address, peer_info.port);
+        let mut connect_response = server_message(MessageType::ConnectResponse, content);
+        connect_response.sender_id = peer_info.user_id.clone();
+        connect_response.target_id = Some(requester);
+        connect_response.sender_peer_address = peer_info.address.clone();
+        connect_response.sender_listen_port = peer_info.port;
+        out.send(token, connect_response);
+    }
+
+    /// 列表包含所有人的地址，只发给已加入的连接（被拒绝加入的连接也收不到）
+    fn handle_peer_list_request(&self, message: &Message, token: Token, out: &mut RouterOutput) {
+        if !self.peers.contains_key(&token) {
+            debug!("peer list request from token={:?} before join", token);
+            out.send(token, server_error(ErrorCode::NotJoined, "join before requesting the peer list".to_string()));
+            return;
+        }
+        self.send_peer_list(token, message.page, out);
+    }
+
+    /// 按 user_id 排序后分页发送完整列表（至少一页，空列表也会发送）。page 为 None 时发送全部页，
+    /// 否则只发送该页；超出范围的页码回复一个空页，客户端可以从 total_pages 得知实际页数。
+    /// 每页都带有生成时的 peer_list_version，逐页请求期间列表变化时客户端据此发现并重新获取
+    fn send_peer_list(&self, token: Token, page: Option<u32>, out: &mut RouterOutput) {
+        // PeerInfo 序列化后与 PeerEntry 格式相同（跳过时间字段），直接序列化引用，不必为每个成员复制一份 PeerEntry
+        let mut peer_list: Vec<&PeerInfo> = self.peers.values().collect();
+        peer_list.sort_unstable_by(|a, b| a.user_id.cmp(&b.user_id));
+        let pages: Vec<&[&PeerInfo]> = peer_list.chunks(self.config.peer_list_page_size.max(1)).collect();
+        let total_pages = pages.len().max(1) as u32;
+
+        debug!("sending peer list v{} to token={:?} ({} peers, {} pages, requested page {:?})",
+               self.peer_list_version, token, peer_list.len(), total_pages, page);
+
+        // 页码来自客户端，用闭区间避免 u32::MAX + 1 溢出
+        let requested = match page {
+            Some(page) => page..=page,
+            None => 0..=total_pages - 1,
+        };
+        for page in requested {
+            let entries = pages.get(page as usize).copied().unwrap_or_default();
+            out.send(token, self.peer_list_page(entries, page, total_pages));
+        }
+    }
+
+    fn peer_list_page(&self, entries: &[&PeerInfo], page: u32, total_pages: u32) -> Message {
+        let mut list = Message::peer_list(entries);
+        list.page = Some(page);
+        list.total_pages = Some(total_pages);
+        list.peer_list_version = Some(self.peer_list_version);
+        list
+    }
+
+    /// 主动断开连接：清理状态、通知剩余用户，并要求服务器关闭该连接
+    fn disconnect(&mut self, token: Token, reason: DisconnectReason, out: &mut RouterOutput) {
+        self.remove_connection(token, reason, out);
+        out.close(token, reason);
+    }
+
+    /// 清理该 token 的成员状态；如果该连接已加入，则向剩余用户广播 UserLeft（附带原因）
+    fn remove_connection(&mut self, token: Token, reason: DisconnectReason, out: &mut RouterOutput) {
+        self.addrs.remove(&token);
+        self.awaiting_join.remove(&token);
+        self.last_sent.remove(&token);
+        let Some(info) = self.peers.remove(&token) else {
+            return;
+        };
+        if self.user_to_token.get(&info.user_id) == Some(&token) {
+            self.user_to_token.remove(&info.user_id);
+        }
+        self.mark_peer_list_changed(&info.user_id);
+        info!("user left user_id={} token={:?} reason={}", info.user_id, token, reason);
+        out.audit.push(AuditEvent::Leave { user_id: info.user_id.clone(), reason });
+        self.registry.touch(&info.user_id);
+        let rooms: Vec<String> = self.rooms.values()
+            .filter(|room| room.is_member(&info.user_id))
+            .map(|room| room.name().to_string())
+            .collect();
+        for name in rooms {
+            self.remove_room_member(&info.user_id, &name, out);
+        }
+
+        let mut leave_notification = server_message(MessageType::UserLeft, reason.as_str().to_string());
+        leave_notification.sender_id = info.user_id;
+        out.broadcast(self.peer_tokens(None), leave_notification);
+    }
+
+    /// 记录该用户在对等节点列表中的最新状态（在线时为其列表项，否则为离开）
+    fn mark_peer_list_changed(&mut self, user_id: &str) {
+        self.peer_list_version += 1;
+        let entry = self.token_of(user_id)
+            .and_then(|token| self.peers.get(&token))
+            .map(PeerEntry::from);
+        self.pending_delta.insert(user_id.to_string(), entry);
+        if self.peer_list_changed_at.is_none() {
+            self.peer_list_changed_at = Some(Instant::now());
+        }
+    }
+
+    /// 成员变化后（合并窗口结束时）向所有在线用户广播增量；完整列表只在加入和客户端请求时发送
+    fn push_peer_list_updates(&mut self, now: Instant, out: &mut RouterOutput) {
+        match self.peer_list_changed_at {
+            Some(changed_at) if now.saturating_duration_since(changed_at) >= self.config.peer_list_push_interval => {
+                self.peer_list_changed_at = None;
+                let mut delta = PeerListDelta { base_epoch: self.pushed_epoch, epoch: self.peer_list_version, added: Vec::new(), removed: Vec::new() };
+                for (user_id, entry) in std::mem::take(&mut self.pending_delta) {
+                    match entry {
+                        Some(entry) => delta.added.push(entry),
+                        None => delta.removed.push(user_id),
+                    }
+                }
+                self.pushed_epoch = self.peer_list_version;
+
+                debug!("pushing peer list delta v{}..v{} (+{} -{})", delta.base_epoch, delta.epoch, delta.added.len(), delta.removed.len());
+                let mut message = server_message(MessageType::PeerListDelta, serde_json::to_string(&delta).unwrap_or_default());
+                message.peer_list_version = Some(delta.epoch);
+                out.broadcast(self.peer_tokens(None), message);
+            }
+            _ => {}
+        }
+    }
+
+    /// 只向超过一个心跳间隔没有发出任何消息的连接发送保活心跳，繁忙的连接上其他消息已经起到同样的作用。
+    /// 同一轮中到期的连接共用一条广播
+    fn check_heartbeat(&mut self, now: Instant, out: &mut RouterOutput) {
+        let interval = self.config.heartbeat_interval;
+        let idle_tokens: Vec<Token> = self.peers.keys()
+            .filter(|token| self.last_sent.get(token).is_none_or(|sent| now.saturating_duration_since(*sent) > interval))
+            .copied()
+            .collect();
+        if idle_tokens.is_empty() {
+            return;
+        }
+        for token in &idle_tokens {
+            self.last_sent.insert(*token, now);
+        }
+        // 列表版本只放在 peer_list_version 字段中，content 留空
+        let mut heartbeat_message = Message::heartbeat("SERVER".to_string());
+        heartbeat_message.peer_list_version = Some(self.peer_list_version);
+        out.broadcast(idle_tokens, heartbeat_message);
+    }
+
+    fn check_peer_timeouts(&mut self, now: Instant, out: &mut RouterOutput) {
+        let timeout_duration = self.config.peer_timeout;
+
+        let timeout_tokens: Vec<_> = self.peers.iter()
+            .filter(|(_, info)| now.saturating_duration_since(info.last_heartbeat) > timeout_duration)
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in timeout_tokens {
+            self.disconnect(token, DisconnectReason::Timeout, out);
+        }
+    }
+
+    /// 心跳正常但长时间没有实际活动的连接（配置了 idle_timeout 时）
+    fn check_idle_peers(&mut self, now: Instant, out: &mut RouterOutput) {
+        let Some(idle_timeout) = self.config.idle_timeout else {
+            return;
+        };
+
+        let idle_tokens: Vec<_> = self.peers.iter()
+            .filter(|(_, info)| now.saturating_duration_since(info.last_activity) > idle_timeout)
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in idle_tokens {
+            debug!("token={:?} idle for more than {:?}, disconnecting", token, idle_timeout);
+            out.send(token, server_message(MessageType::Kick, format!("no activity for {:?}", idle_timeout)));
+            self.disconnect(token, DisconnectReason::Idle, out);
+        }
+    }
+
+    /// 断开建立连接后迟迟不发送 Join 的连接（它们不在 peers 中，心跳超时检查覆盖不到）
+    fn check_join_timeouts(&mut self, now: Instant, out: &mut RouterOutput) {
+        let join_timeout = self.config.join_timeout;
+        let silent_tokens: Vec<_> = self.awaiting_join.iter()
+            .filter(|(_, opened_at)| now.saturating_duration_since(**opened_at) > join_timeout)
+            .map(|(token, _)| *token)
+            .collect();
+
+        for token in silent_tokens {
+            debug!("token={:?} did not join within {:?}, disconnecting", token, join_timeout);
+            self.disconnect(token, DisconnectReason::Timeout, out);
+        }
+    }
+}
+
+/// 构造一条来自服务器的简单通知消息
+pub(crate) fn server_message(msg_type: MessageType, content: String) -> Message {
+    Message::new(msg_type, "SERVER".to_string()).with_content(content)
+}
+
+/// 构造一条带错误码的 Error 消息
+pub(crate) fn server_error(code: ErrorCode, content: String) -> Message {
+    let mut error = server_message(MessageType::Error, content);
+    error.error_code = Some(code);
+    error
+}
+
+/// 用户消息的转发确认，reply_to 指向原消息
+fn relay_ack(msg_id: &str) -> Message {
+    let mut ack = server_message(MessageType::RelayAck, String::new());
+    ack.content = None;
+    ack.reply_to = Some(msg_id.to_string());
+    ack
+}
+
+/// 私聊目标不在线的回执：target_id 为原目标，reply_to 指向原消息
+fn target_offline(message: &Message, target_id: &str) -> Message {
+    let mut error = server_error(ErrorCode::TargetOffline, format!("{} is offline", target_id));
+    error.target_id = Some(target_id.to_string());
+    error.reply_to = message.msg_id.clone();
+    error
+}
@@ -1,405 +1,1544 @@
-use crate::common::*;
 use mio::{Events, Interest, Poll, Token};
 use mio::net::{TcpListener, TcpStream};
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::time::{Duration, Instant, SystemTime};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
 use std::io::{Read, Write};
-use crate::common::{Message, MessageType, PeerInfo, P2PError, serialize_message, deserialize_message, MessageSource};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use mio::Waker;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use log::{debug, error, info, warn};
+use crate::stats::{ConnStats, DropReason, ServerStats};
+use crate::health::{Health, LagMonitor};
+use crate::audit::{AuditEvent, AuditFileInfo, AuditLog};
+#[cfg(feature = "chat-history")]
+use crate::history::ChatHistory;
+use crate::hooks::{ClosureHook, HookChain, HookRegistration, MessageHook};
+use crate::socket::{resources_exhausted, SocketOptions};
+use crate::workers::{WorkItem, WorkerPool};
+use crate::registry::{Registry, UserRecord};
+use crate::router::{Delivery, Router, RouterEvent, RouterOutput, server_error, server_message};
+use crate::common::{DisconnectReason, ErrorCode, ErrorContext, Framing, Message, MessageType, P2PError, PeerInfo, Result, SignaturePolicy, TokenAllocator, serialize_message, deserialize_message_lenient, duration_ms, option_duration_ms, DISPLAY_CONTENT_CHARS};
 
-const SERVER: Token = Token(0);
-const FIRST_PEER: Token = Token(2);
+const WAKER: Token = Token(0); // 用于唤醒事件循环（关闭信号）
+// 监听器依次使用 Token(1)..=Token(n)，连接 token 从 n + 1 开始分配，两者不会重叠
+const FIRST_LISTENER: usize = 1;
+
+// 负载均衡器的健康检查探测：尚未 Join 的连接发送 `PING\n` 时回复 `PONG\n` 并关闭，不注册为用户
+const PROBE_REQUEST: &[u8] = b"PING";
+const PROBE_RESPONSE: &[u8] = b"PONG\n";
+// 连接数已满时最多同时保留多少条等待探测帧的连接，超出的直接拒绝
+const MAX_OVERFLOW_PROBES: usize = 16;
+// accept 因文件描述符等资源耗尽失败后暂停 accept 的时间
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// 关闭时等待写出积压数据（包括关闭通知）的最长时间
+const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 后台运行的服务器线程句柄
+pub type ServerThread = JoinHandle<Result<()>>;
+
+/// 服务器关闭句柄，可克隆并在其他线程中触发关闭
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl ShutdownHandle {
+    /// 请求服务器关闭：`start` 会在通知所有客户端并关闭连接后返回
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+        if let Err(e) = self.waker.wake() {
+            error!("failed to wake server event loop: {}", e);
+        }
+    }
+    
+    pub fn is_shutdown(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+/// 服务器管理指令
+#[derive(Debug, Clone)]
+pub enum ServerCommand {
+    Kick(String),       // 踢出指定用户
+    Ban(String, Option<Duration>),  // 封禁用户（可选时长，None 为永久），在线时同时踢出
+    Unban(String),      // 解除封禁
+    BanIp(IpAddr),      // 封禁 IP，同时断开来自该地址的连接
+    UnbanIp(IpAddr),    // 解除 IP 封禁
+    Broadcast(String),  // 系统公告
+    ListPeers,          // 打印当前在线用户
+    Stats(mpsc::Sender<ServerStats>),  // 通过回传通道获取统计快照
+    QueryUser(String, mpsc::Sender<Option<UserRecord>>),  // 查询用户的注册表记录（包括配额使用情况）
+    ResetQuota(String), // 清零用户的消息配额
+    AuditInfo(mpsc::Sender<Option<AuditFileInfo>>),  // 查询当前审计文件的路径和大小（未启用时为 None）
+    SetMotd(Option<String>),  // 修改公告，只影响之后加入的用户
+    Shutdown,           // 关闭服务器
+}
+
+/// 管理指令发送器：发送指令后唤醒事件循环，使指令立即得到处理
+#[derive(Clone)]
+pub struct ServerControlSender {
+    sender: mpsc::Sender<ServerCommand>,
+    waker: Arc<Waker>,
+}
+
+impl ServerControlSender {
+    pub fn send(&self, command: ServerCommand) -> Result<(), mpsc::SendError<ServerCommand>> {
+        self.sender.send(command)?;
+        if let Err(e) = self.waker.wake() {
+            error!("failed to wake server event loop: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// 一次广播的结果：单个接收者发送失败只会被记录并跳过，不会中断对其他人的投递
+#[derive(Debug, Default)]
+pub struct BroadcastReport {
+    /// 成功写入（或已进入写缓冲）的接收者数量
+    pub delivered: usize,
+    /// 发送失败的接收者及对应错误
+    pub failed: Vec<(Token, P2PError)>,
+}
+
+impl BroadcastReport {
+    pub fn is_complete(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+/// 在线用户的对外视图（可序列化，用于管理面板和 ListPeers）
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectedUser {
+    pub user_id: String,
+    pub address: String,
+    pub port: u16,
+    /// 距离最近一次收到心跳的秒数
+    pub secs_since_heartbeat: u64,
+}
+
+/// 重复 user_id 加入时的处理策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateJoinPolicy {
+    /// 拒绝新连接：回复 Error 并断开
+    Reject,
+    /// 踢掉旧会话（发送 Kick 并清理），由新连接接管
+    Displace,
+}
+
+/// 写队列超过上限（慢速消费者）时的处理策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// 丢弃最旧的聊天消息，保留控制消息；只剩控制消息仍超限时断开
+    DropOldest,
+    /// 直接断开该连接
+    Disconnect,
+}
+
+/// 服务器配置
+///
+/// 可通过 [`ServerConfig::from_file`] 从 TOML 文件加载，时间字段以毫秒表示（如 `heartbeat_interval_ms = 30000`），
+/// 未出现的字段使用默认值。
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// 监听地址（`P2PServer::from_config` 使用）
+    pub bind_addr: String,
+    /// 额外的监听地址（如本机工具用 127.0.0.1、局域网用 0.0.0.0），所有监听器共享同一组连接和成员状态
+    pub extra_bind_addrs: Vec<String>,
+    pub duplicate_join_policy: DuplicateJoinPolicy,
+    pub max_connections: usize,
+    /// 连接数已满时，新连接在该时间内发来健康检查探测（`PING\n`）仍会得到回复，
+    /// 超时或发来其他数据才以 ServerFull 拒绝。为 0 时立即拒绝（探测在满员时也会失败）
+    #[serde(rename = "probe_wait_ms", with = "duration_ms")]
+    pub probe_wait: Duration,
+    /// 事件循环每次 poll 的超时时间
+    #[serde(rename = "poll_timeout_ms", with = "duration_ms")]
+    pub poll_timeout: Duration,
+    /// 单次 poll 最多返回的事件数
+    pub event_capacity: usize,
+    /// 保活心跳间隔：连接超过该时间没有收到服务器的任何消息时发送一次心跳
+    #[serde(rename = "heartbeat_interval_ms", with = "duration_ms")]
+    pub heartbeat_interval: Duration,
+    /// 超过该时间未收到任何消息（包括心跳）的客户端将被断开，必须大于心跳间隔。
+    /// 对方主机消失或网络中断造成的半开连接可以由 TCP 保活（`socket.keepalive`）更早发现
+    #[serde(rename = "peer_timeout_ms", with = "duration_ms")]
+    pub peer_timeout: Duration,
+    /// 已加入的连接超过该时间没有实际活动（聊天、请求；心跳不算）则断开，None 为不限制
+    #[serde(rename = "idle_timeout_ms", with = "option_duration_ms")]
+    pub idle_timeout: Option<Duration>,
+    /// 连接建立后必须在该时间内发送 Join，否则关闭连接（防止只连接不加入的客户端长期占用连接数）
+    #[serde(rename = "join_timeout_ms", with = "duration_ms")]
+    pub join_timeout: Duration,
+    /// 单条消息（一帧）的最大字节数，超过时断开连接
+    pub max_message_size: usize,
+    /// 单个连接写队列的上限（字节数和消息数），超过后按 slow_consumer_policy 处理
+    pub write_queue_max_bytes: usize,
+    pub write_queue_max_messages: usize,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    /// 每次从 socket 读取的缓冲区大小（所有连接共用一块缓冲区）
+    pub read_buffer_size: usize,
+    /// 一次可读事件中单个连接最多读取的次数，达到后让出给其他连接，剩余数据在下一轮继续读取
+    pub max_reads_per_event: usize,
+    /// 每个连接每秒最多处理的消息数（None 为不限制），超出的消息被丢弃并回复 Error
+    pub max_messages_per_second: Option<u32>,
+    /// 每个用户在一个配额周期内最多发送的用户消息数（聊天、回应、编辑；控制消息不计），None 为不限制。
+    /// 使用情况保存在注册表中，服务器重启后仍然有效
+    pub message_quota: Option<u32>,
+    /// 聊天、回应和编辑内容的最大字符数，超过的消息不转发并回复 MessageTooLarge 错误；None 为不限制
+    /// （仍受 `MAX_CONTENT_BYTES` 约束）。与 max_message_size 不同，这是产品上的消息长度限制
+    pub max_content_chars: Option<usize>,
+    /// 与客户端共享的签名密钥。配置后校验聊天、回应和编辑的 HMAC 签名（缺少签名也视为无效），
+    /// 处理方式由 signature_policy 决定
+    pub signing_key: Option<String>,
+    /// 签名无效时 Mark 记录警告后照常转发（接收方自行校验），Reject 不转发并回复 Rejected 错误
+    pub signature_policy: SignaturePolicy,
+    /// 配额周期长度，从周期内第一条消息开始计算
+    #[serde(rename = "quota_window_ms", with = "duration_ms")]
+    pub quota_window: Duration,
+    /// 成员变化后推送对等节点列表前的合并窗口
+    #[serde(rename = "peer_list_push_interval_ms", with = "duration_ms")]
+    pub peer_list_push_interval: Duration,
+    /// 完整对等节点列表每页最多包含的节点数，超出时分多条 PeerList 发送，避免单帧超过 max_message_size
+    pub peer_list_page_size: usize,
+    /// 记住最近转发过的用户消息 msg_id 的条数上限，用于丢弃客户端重试造成的重复
+    pub relay_dedup_capacity: usize,
+    /// 私聊目标不在线、但在该时间内还在线过（例如断线重连中）时，服务器暂存消息并在其下次加入时按顺序投递；
+    /// 超时仍未重新加入则丢弃并向发送方回复 TargetOffline。None 为不暂存，直接回复 TargetOffline
+    #[serde(rename = "offline_queue_window_ms", with = "option_duration_ms")]
+    pub offline_queue_window: Option<Duration>,
+    /// 每个离线用户最多暂存的消息数，超出后新消息直接回复 TargetOffline
+    pub offline_queue_max_messages: usize,
+    /// 转发过的 msg_id 的保留时间，超过后在周期维护中清理
+    #[serde(rename = "relay_dedup_window_ms", with = "duration_ms")]
+    pub relay_dedup_window: Duration,
+    /// 用户注册表文件（JSON），None 时只保存在内存中、重启后丢失
+    pub registry_path: Option<PathBuf>,
+    /// 注册表修改后延迟写盘的时间，合并短时间内的多次修改
+    #[serde(rename = "registry_flush_interval_ms", with = "duration_ms")]
+    pub registry_flush_interval: Duration,
+    /// 审计日志文件（每行一条 JSON 事件），None 为不记录
+    pub audit_log_path: Option<PathBuf>,
+    /// 审计文件超过该大小后轮转
+    pub audit_log_max_bytes: u64,
+    /// 轮转后保留的旧审计文件个数（`<path>.1` 最新），0 为不保留
+    pub audit_log_max_files: usize,
+    /// 聊天记录文件（每条转发的 Chat 一行 JSON），None 为不记录。需要启用 `chat-history` feature
+    #[cfg(feature = "chat-history")]
+    pub chat_log_path: Option<PathBuf>,
+    /// 聊天记录文件超过该大小后轮转
+    #[cfg(feature = "chat-history")]
+    pub chat_log_max_bytes: u64,
+    /// 轮转后保留的旧聊天记录文件个数（`<path>.1` 最新），0 为不保留
+    #[cfg(feature = "chat-history")]
+    pub chat_log_max_files: usize,
+    /// 聊天记录写入缓冲后最迟多久 flush 到磁盘，进程崩溃时最多丢失这段时间内的记录
+    #[cfg(feature = "chat-history")]
+    #[serde(rename = "chat_log_flush_interval_ms", with = "duration_ms")]
+    pub chat_log_flush_interval: Duration,
+    /// 周期性输出统计摘要的间隔（None 为不输出）
+    #[serde(rename = "stats_log_interval_ms", with = "option_duration_ms")]
+    pub stats_log_interval: Option<Duration>,
+    /// 单轮事件循环处理耗时超过该值时输出告警；平均耗时超过它即为 Degraded，超过 4 倍为 Unhealthy
+    #[serde(rename = "lag_warn_threshold_ms", with = "duration_ms")]
+    pub lag_warn_threshold: Duration,
+    /// 在 trace 级别的路由日志中包含消息内容（默认只记录内容的长度和哈希）
+    pub log_content: bool,
+    /// log_content 开启时日志中每条消息内容最多显示的字符数
+    pub log_content_chars: usize,
+    /// 连接来自回环地址时，是否采用客户端自称的 sender_peer_address（用于本机转发/代理场景）
+    pub trust_claimed_address_on_loopback: bool,
+    /// 随欢迎消息发给新加入用户的公告（规则、当日消息等），运行时可通过 `set_motd` 修改
+    pub motd: Option<String>,
+    /// 允许 sender_id 为空的 Join，由服务器分配 `guest-xxxxxx` 形式的 user_id
+    pub allow_guests: bool,
+    /// 运行消息钩子的工作线程数。为 0 时钩子在事件循环线程中内联执行；大于 0 时已加入连接的消息交给
+    /// 工作线程执行钩子（同一连接固定由同一线程处理，保持顺序），路由和 socket 读写仍在事件循环线程。
+    /// 没有注册钩子时不启动工作线程
+    pub worker_threads: usize,
+    /// 支持的帧格式。客户端在 Join 中按偏好声明，服务器选第一个双方都支持的；未声明的旧客户端使用 Newline
+    pub framings: Vec<Framing>,
+    /// 监听器和已接入连接的套接字选项，配置文件中写作 `[socket]` 表
+    pub socket: SocketOptions,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_addr: "127.0.0.1:8080".to_string(),
+            extra_bind_addrs: Vec::new(),
+            duplicate_join_policy: DuplicateJoinPolicy::Reject,
+            max_connections: 1024,
+            probe_wait: Duration::from_millis(200),
+            poll_timeout: Duration::from_millis(100),
+            event_capacity: 128,
+            heartbeat_interval: Duration::from_secs(30),
+            peer_timeout: Duration::from_secs(60),
+            idle_timeout: None,
+            join_timeout: Duration::from_secs(10),
+            max_message_size: 64 * 1024,
+            read_buffer_size: 16 * 1024,
+            max_reads_per_event: 16,
+            write_queue_max_bytes: 1024 * 1024,
+            write_queue_max_messages: 1024,
+            slow_consumer_policy: SlowConsumerPolicy::Disconnect,
+            max_messages_per_second: None,
+            message_quota: None,
+            max_content_chars: None,
+            signing_key: None,
+            signature_policy: SignaturePolicy::Mark,
+            quota_window: Duration::from_secs(24 * 60 * 60),
+            peer_list_push_interval: Duration::from_millis(500),
+            peer_list_page_size: 500,
+            relay_dedup_capacity: 10_000,
+            offline_queue_window: Some(Duration::from_secs(300)),
+            offline_queue_max_messages: 100,
+            relay_dedup_window: Duration::from_secs(300),
+            trust_claimed_address_on_loopback: false,
+            motd: None,
+            allow_guests: false,
+            framings: vec![Framing::Newline, Framing::LengthPrefixed],
+            socket: SocketOptions::default(),
+            worker_threads: 0,
+            log_content: false,
+            log_content_chars: DISPLAY_CONTENT_CHARS,
+            stats_log_interval: None,
+            lag_warn_threshold: Duration::from_millis(250),
+            registry_path: None,
+            registry_flush_interval: Duration::from_secs(1),
+            audit_log_path: None,
+            audit_log_max_bytes: 10 * 1024 * 1024,
+            audit_log_max_files: 5,
+            #[cfg(feature = "chat-history")]
+            chat_log_path: None,
+            #[cfg(feature = "chat-history")]
+            chat_log_max_bytes: 10 * 1024 * 1024,
+            #[cfg(feature = "chat-history")]
+            chat_log_max_files: 5,
+            #[cfg(feature = "chat-history")]
+            chat_log_flush_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// 从 TOML 文件加载配置并校验
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| P2PError::ConfigError(format!("failed to read {}: {}", path.display(), e)))?;
+        let config: ServerConfig = toml::from_str(&text)
+            .map_err(|e| P2PError::ConfigError(format!("failed to parse {}: {}", path.display(), e)))?;
+        config.validate()?;
+        Ok(config)
+    }
+    
+    /// 检查配置项之间的约束，不合法时尽早失败
+    pub fn validate(&self) -> Result<()> {
+        if self.peer_timeout <= self.heartbeat_interval {
+            return Err(P2PError::ConfigError(format!(
+                "peer_timeout ({:?}) must be greater than heartbeat_interval ({:?})",
+                self.peer_timeout, self.heartbeat_interval
+            )));
+        }
+        let nonzero = [
+            ("max_connections", self.max_connections),
+            ("event_capacity", self.event_capacity),
+            ("max_message_size", self.max_message_size),
+            ("read_buffer_size", self.read_buffer_size),
+            ("max_reads_per_event", self.max_reads_per_event),
+            ("write_queue_max_bytes", self.write_queue_max_bytes),
+            ("write_queue_max_messages", self.write_queue_max_messages),
+            ("peer_list_page_size", self.peer_list_page_size),
+            ("relay_dedup_capacity", self.relay_dedup_capacity),
+            ("offline_queue_max_messages", self.offline_queue_max_messages),
+        ];
+        for (name, value) in nonzero {
+            if value == 0 {
+                return Err(P2PError::ConfigError(format!("{} must be nonzero", name)));
+            }
+        }
+        let buffers = [
+            ("socket.send_buffer_size", self.socket.send_buffer_size),
+            ("socket.recv_buffer_size", self.socket.recv_buffer_size),
+        ];
+        for (name, value) in buffers {
+            if value == Some(0) {
+                return Err(P2PError::ConfigError(format!("{} must be nonzero", name)));
+            }
+        }
+        let keepalive = &self.socket.keepalive;
+        if keepalive.enabled && (keepalive.idle.is_zero() || keepalive.interval.is_zero() || keepalive.retries == 0) {
+            return Err(P2PError::ConfigError("socket.keepalive idle_ms, interval_ms and retries must be nonzero".to_string()));
+        }
+        if self.poll_timeout.is_zero() {
+            return Err(P2PError::ConfigError("poll_timeout must be nonzero".to_string()));
+        }
+        if self.max_content_chars == Some(0) {
+            return Err(P2PError::ConfigError("max_content_chars must be nonzero".to_string()));
+        }
+        if self.audit_log_max_bytes == 0 {
+            return Err(P2PError::ConfigError("audit_log_max_bytes must be nonzero".to_string()));
+        }
+        #[cfg(feature = "chat-history")]
+        if self.chat_log_max_bytes == 0 || self.chat_log_flush_interval.is_zero() {
+            return Err(P2PError::ConfigError("chat_log_max_bytes and chat_log_flush_interval must be nonzero".to_string()));
+        }
+        if self.lag_warn_threshold.is_zero() {
+            return Err(P2PError::ConfigError("lag_warn_threshold must be nonzero".to_string()));
+        }
+        if self.join_timeout.is_zero() {
+            return Err(P2PError::ConfigError("join_timeout must be nonzero".to_string()));
+        }
+        if self.idle_timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(P2PError::ConfigError("idle_timeout must be nonzero".to_string()));
+        }
+        if self.offline_queue_window.is_some_and(|window| window.is_zero()) {
+            return Err(P2PError::ConfigError("offline_queue_window must be nonzero".to_string()));
+        }
+        if self.max_messages_per_second == Some(0) {
+            return Err(P2PError::ConfigError("max_messages_per_second must be nonzero".to_string()));
+        }
+        if self.message_quota == Some(0) || self.quota_window.is_zero() {
+            return Err(P2PError::ConfigError("message_quota and quota_window must be nonzero".to_string()));
+        }
+        Ok(())
+    }
+
+    /// 是否需要路由器收集转发的聊天消息（未启用 `chat-history` feature 时总是 false）
+    pub(crate) fn chat_log_enabled(&self) -> bool {
+        #[cfg(feature = "chat-history")]
+        return self.chat_log_path.is_some();
+        #[cfg(not(feature = "chat-history"))]
+        return false;
+    }
+}
+
+/// 写队列中的一帧。广播时所有接收者共享同一份序列化结果
+struct OutFrame {
+    data: Arc<Vec<u8>>,
+    written: usize,  // 已写出的字节数
+    droppable: bool,  // 聊天消息在队列积压时可以丢弃，控制消息不行
+}
+
+/// 单个连接的写队列，记录尚未写出的字节数以便限制慢速消费者
+#[derive(Default)]
+struct WriteQueue {
+    frames: VecDeque<OutFrame>,
+    bytes: usize,
+    warned: bool,  // 已记录过 80% 容量警告（队列清空后重置）
+}
+
+impl WriteQueue {
+    fn push(&mut self, frame: OutFrame) {
+        self.bytes += frame.data.len();
+        self.frames.push_back(frame);
+    }
+    
+    /// 丢弃最旧的一条可丢弃且尚未开始写出的帧，没有可丢弃的帧时返回 false
+    fn drop_oldest(&mut self) -> bool {
+        let Some(index) = self.frames.iter().position(|frame| frame.droppable && frame.written == 0) else {
+            return false;
+        };
+        if let Some(frame) = self.frames.remove(index) {
+            self.bytes -= frame.data.len();
+        }
+        true
+    }
+    
+    /// 尽量把队列中的数据写入 socket，返回写出的字节数和队列是否已清空（遇到 WouldBlock 时未清空）
+    fn write_to(&mut self, stream: &mut TcpStream) -> std::io::Result<(usize, bool)> {
+        let mut written = 0;
+        while let Some(frame) = self.frames.front_mut() {
+            match stream.write(&frame.data[frame.written..]) {
+                Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero)),
+                Ok(n) => {
+                    frame.written += n;
+                    self.bytes -= n;
+                    written += n;
+                    if frame.written == frame.data.len() {
+                        self.frames.pop_front();
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok((written, false)),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok((written, true))
+    }
+}
 
 pub struct P2PServer {
-    listener: TcpListener,
+    listeners: Vec<TcpListener>,  // 下标 i 的监听器使用 Token(FIRST_LISTENER + i)
     poll: Poll,
     events: Events,
     streams: HashMap<Token, TcpStream>,
-    buffers: HashMap<Token, Vec<u8>>,
-    peers: HashMap<Token, PeerInfo>,
-    user_to_token: HashMap<String, Token>,
-    next_token: Token,
-    last_heartbeat: Instant,
+    buffers: HashMap<Token, Vec<u8>>,  // 读缓冲：尚未组成完整帧的数据
+    read_buffer: Vec<u8>,  // 从 socket 读取时复用的缓冲区
+    // 因达到 max_reads_per_event 而未读完的连接。mio 是边沿触发，这些连接不会再收到可读事件
+    read_backlog: HashSet<Token>,
+    framings: HashMap<Token, Framing>,  // Join 时协商出的帧格式，没有记录的连接使用 Newline
+    // 连接数已满时暂时接受、只等待健康检查探测的连接（接受时间），不计入连接数，也不交给路由器
+    overflow: HashMap<Token, Instant>,
+    // 资源耗尽时暂停 accept 到该时刻；期间到达的连接留在内核的 backlog 中，之后一并接受
+    accept_paused_until: Option<Instant>,
+    write_queues: HashMap<Token, WriteQueue>,
+    // 成员关系和消息路由，服务器只负责把路由结果写到连接上
+    router: Router,
+    tokens: TokenAllocator,
+    shutdown: ShutdownHandle,
+    config: ServerConfig,
+    // 限流窗口：token -> (窗口开始时间, 窗口内已处理消息数)
+    rate_windows: HashMap<Token, (Instant, u32)>,
+    // 路由前依次调用的消息钩子：注册信息，以及事件循环线程内联执行时使用的钩子链
+    hook_registrations: Vec<HookRegistration>,
+    hooks: HookChain,
+    // 执行钩子的工作线程，配置了 worker_threads 且有钩子时在第一次轮询时启动
+    workers: Option<WorkerPool>,
+    stats: ServerStats,
+    last_stats_log: Instant,
+    lag_monitor: LagMonitor,
+    audit: Option<AuditLog>,
+    #[cfg(feature = "chat-history")]
+    chat_log: Option<ChatHistory>,
+    // 管理指令通道
+    control_sender: mpsc::Sender<ServerCommand>,
+    control_receiver: mpsc::Receiver<ServerCommand>,
 }
 
 impl P2PServer {
-    pub fn new(addr: &str) -> Result<Self, P2PError> {
-        let addr: SocketAddr = addr.parse().map_err(|e: std::net::AddrParseError| P2PError::ConnectionError(e.to_string()))?;
-        let mut listener = TcpListener::bind(addr)?;
+    pub fn new(addr: &str) -> Result<Self> {
+        Self::new_with_config(addr, ServerConfig::default())
+    }
+    
+    /// 使用配置中的 bind_addr 和 extra_bind_addrs 创建服务器
+    pub fn from_config(config: ServerConfig) -> Result<Self> {
+        let addrs = std::iter::once(&config.bind_addr)
+            .chain(&config.extra_bind_addrs)
+            .map(|addr| addr.parse())
+            .collect::<Result<Vec<SocketAddr>, _>>()?;
+        Self::new_multi(&addrs, config)
+    }
+    
+    pub fn new_with_config(addr: &str, config: ServerConfig) -> Result<Self> {
+        Self::new_multi(&[addr.parse()?], config)
+    }
+    
+    /// 同时监听多个地址，从任一监听器接入的连接进入同一组连接和成员状态
+    pub fn new_multi(addrs: &[SocketAddr], config: ServerConfig) -> Result<Self> {
+        config.validate()?;
+        if addrs.is_empty() {
+            return Err(P2PError::ConfigError("at least one listen address is required".to_string()));
+        }
+        let registry = match &config.registry_path {
+            Some(path) => Registry::load(path)?,
+            None => Registry::in_memory(),
+        };
+        if config.socket.keepalive.enabled && config.socket.keepalive.detection_time() >= config.peer_timeout {
+            warn!("TCP keepalive needs up to {:?} to detect a dead peer, not less than peer_timeout ({:?}); it will never fire first",
+                config.socket.keepalive.detection_time(), config.peer_timeout);
+        }
+        let audit = match &config.audit_log_path {
+            Some(path) => Some(AuditLog::open(path, config.audit_log_max_bytes, config.audit_log_max_files)?),
+            None => None,
+        };
+        #[cfg(feature = "chat-history")]
+        let chat_log = match &config.chat_log_path {
+            Some(path) => Some(ChatHistory::open(path, config.chat_log_max_bytes, config.chat_log_max_files,
+                config.chat_log_flush_interval)?),
+            None => None,
+        };
         let poll = Poll::new()?;
-        
-        poll.registry()
-            .register(&mut listener, SERVER, Interest::READABLE)?;
+        let mut listeners = Vec::with_capacity(addrs.len());
+        for (index, addr) in addrs.iter().enumerate() {
+            let mut listener = config.socket.bind(*addr).with_context(|| format!("binding listener on {}", addr))?;
+            poll.registry()
+                .register(&mut listener, Token(FIRST_LISTENER + index), Interest::READABLE)
+                .with_context(|| format!("registering listener on {}", addr))?;
+            listeners.push(listener);
+        }
+        let first_peer = Token(FIRST_LISTENER + listeners.len());
+        let waker = Waker::new(poll.registry(), WAKER)?;
+        let (control_sender, control_receiver) = mpsc::channel();
             
         Ok(Self {
-            listener,
+            listeners,
             poll,
-            events: Events::with_capacity(128),
+            events: Events::with_capacity(config.event_capacity),
             streams: HashMap::new(),
             buffers: HashMap::new(),
-            peers: HashMap::new(),
-            user_to_token: HashMap::new(),
-            next_token: FIRST_PEER,
-            last_heartbeat: Instant::now(),
+            read_buffer: vec![0; config.read_buffer_size],
+            read_backlog: HashSet::new(),
+            framings: HashMap::new(),
+            overflow: HashMap::new(),
+            accept_paused_until: None,
+            write_queues: HashMap::new(),
+            router: Router::new(config.clone(), registry),
+            tokens: TokenAllocator::new(first_peer),
+            shutdown: ShutdownHandle {
+                flag: Arc::new(AtomicBool::new(false)),
+                waker: Arc::new(waker),
+            },
+            config,
+            rate_windows: HashMap::new(),
+            hook_registrations: Vec::new(),
+            hooks: HookChain::default(),
+            workers: None,
+            stats: ServerStats::default(),
+            last_stats_log: Instant::now(),
+            lag_monitor: LagMonitor::new(),
+            audit,
+            #[cfg(feature = "chat-history")]
+            chat_log,
+            control_sender,
+            control_receiver,
         })
     }
     
-    pub fn start(&mut self) -> Result<(), P2PError> {
-        println!("P2P server started on {}", self.listener.local_addr()?);
+    /// 在 127.0.0.1 的随机端口上启动服务器并在后台线程运行事件循环，
+    /// 返回线程句柄、实际监听地址和关闭句柄（主要用于测试）
+    pub fn spawn_ephemeral() -> Result<(ServerThread, SocketAddr, ShutdownHandle)> {
+        Self::spawn_ephemeral_with_config(ServerConfig::default())
+    }
+    
+    pub fn spawn_ephemeral_with_config(config: ServerConfig) -> Result<(ServerThread, SocketAddr, ShutdownHandle)> {
+        let mut server = Self::new_with_config("127.0.0.1:0", config)?;
+        let addr = server.local_addr()?;
+        let shutdown = server.shutdown_handle();
+        let handle = thread::spawn(move || server.start());
+        Ok((handle, addr, shutdown))
+    }
+    
+    /// 实际绑定的（第一个）监听地址（绑定端口0时可用于获取系统分配的端口）
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listeners[0].local_addr()?)
+    }
+    
+    /// 所有监听器实际绑定的地址，顺序与创建时一致
+    pub fn local_addrs(&self) -> Result<Vec<SocketAddr>> {
+        Ok(self.listeners.iter().map(TcpListener::local_addr).collect::<Result<_, _>>()?)
+    }
+    
+    fn listener_index(&self, token: Token) -> Option<usize> {
+        token.0.checked_sub(FIRST_LISTENER).filter(|&index| index < self.listeners.len())
+    }
+    
+    /// 获取关闭句柄，用于从其他线程（如信号处理器）停止服务器
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+    
+    /// 当前的运行统计
+    pub fn stats(&self) -> &ServerStats {
+        &self.stats
+    }
+    
+    /// 各连接读写计数的快照，用于找出刷屏或卡住的连接
+    pub fn connection_stats(&self) -> HashMap<Token, ConnStats> {
+        self.stats.connections.clone()
+    }
+    
+    /// 根据事件循环延迟判断的健康状态
+    pub fn health(&self) -> Health {
+        self.stats.loop_lag.health(self.config.lag_warn_threshold)
+    }
+    
+    /// 当前在线（已 Join）的用户，按 user_id 排序
+    pub fn connected_users(&self) -> Vec<ConnectedUser> {
+        let mut users: Vec<ConnectedUser> = self.router.peers()
+            .map(|(_, info)| ConnectedUser {
+                user_id: info.user_id.clone(),
+                address: info.address.clone(),
+                port: info.port,
+                secs_since_heartbeat: info.last_heartbeat.elapsed().as_secs(),
+            })
+            .collect();
+        users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        users
+    }
+    
+    /// 注册消息钩子（应在 `start` 之前调用），按注册顺序执行
+    pub fn add_hook(&mut self, hook: Box<dyn MessageHook>) {
+        self.register_hook(HookRegistration::Single(Arc::new(Mutex::new(hook))));
+    }
+    
+    /// 注册由工厂创建的消息钩子（应在 `start` 之前调用）。启用工作线程时每个线程各创建一个实例，
+    /// 不必在线程之间加锁；`add_hook` 注册的单个实例则由所有工作线程共享、依次加锁调用
+    pub fn add_hook_factory<F>(&mut self, factory: F)
+    where
+        F: Fn() -> Box<dyn MessageHook> + Send + Sync + 'static,
+    {
+        self.register_hook(HookRegistration::Factory(Arc::new(factory)));
+    }
+    
+    /// 注册消息过滤闭包（应在 `start` 之前调用）：在路由前以发送方信息和可修改的消息调用，
+    /// 返回 false 时丢弃消息并回复 Error。与 `add_hook` 注册的钩子共用同一条链，按注册顺序执行
+    pub fn on_message<F>(&mut self, f: F)
+    where
+        F: FnMut(&PeerInfo, &mut Message) -> bool + Send + 'static,
+    {
+        self.add_hook(Box::new(ClosureHook(f)));
+    }
+    
+    fn register_hook(&mut self, registration: HookRegistration) {
+        self.hooks.push(&registration);
+        self.hook_registrations.push(registration);
+    }
+    
+    /// 获取管理指令发送器，用于在运行中踢人、广播或关闭服务器
+    pub fn get_control_sender(&self) -> ServerControlSender {
+        ServerControlSender {
+            sender: self.control_sender.clone(),
+            waker: self.shutdown.waker.clone(),
+        }
+    }
+    
+    pub fn start(&mut self) -> Result<()> {
+        let addrs: Vec<String> = self.local_addrs()?.iter().map(SocketAddr::to_string).collect();
+        info!("P2P server started on {}", addrs.join(", "));
         
-        loop {
-            self.poll.poll(&mut self.events, Some(Duration::from_millis(100)))?;
-            
-            // Collect event information first to avoid borrow conflicts
-            let mut server_events = Vec::new();
-            let mut readable_tokens = Vec::new();
-            let mut writable_tokens = Vec::new();
-            
-            for event in &self.events {
-                match event.token() {
-                    SERVER => {
+        // 单个连接的错误在 run_once 内部处理；返回到这里的错误是致命的，但同样要通知客户端并保存注册表
+        let result = loop {
+            if self.shutdown.is_shutdown() {
+                break Ok(());
+            }
+            if let Err(e) = self.run_once(self.config.poll_timeout) {
+                error!("event loop failed, shutting down: {}", e);
+                break Err(e);
+            }
+        };
+        
+        self.close_all_connections();
+        if let Err(e) = self.router.registry_mut().flush() {
+            error!("failed to save user registry: {}", e);
+        }
+        info!("P2P server stopped");
+        result
+    }
+    
+    /// 执行一次事件轮询和分发
+    pub fn run_once(&mut self, timeout: Duration) -> Result<()> {
+        if self.workers.is_none() && self.config.worker_threads > 0 && !self.hooks.is_empty() {
+            let pool = WorkerPool::spawn(self.config.worker_threads, &self.hook_registrations, self.shutdown.waker.clone())?;
+            self.workers = Some(pool);
+        }
+        // 还有未读完的连接时不等待，立即继续读取
+        let timeout = if self.read_backlog.is_empty() { timeout } else { Duration::ZERO };
+        let timeout = match self.accept_paused_until {
+            Some(until) => timeout.min(until.saturating_duration_since(Instant::now())),
+            None => timeout,
+        };
+        self.poll.poll(&mut self.events, Some(timeout))?;
+        let started = Instant::now();
+        
+        // Collect event information first to avoid borrow conflicts
+        let mut listener_events = Vec::new();
+        let mut readable_tokens: Vec<Token> = self.read_backlog.drain().collect();
+        let mut writable_tokens = Vec::new();
+        
+        for event in &self.events {
+            match event.token() {
+                WAKER => {}
+                token => match self.listener_index(token) {
+                    Some(index) => {
                         if event.is_readable() {
-                            server_events.push(event.token());
+                            listener_events.push(index);
                         }
                     }
-                    token => {
-                        if event.is_readable() {
+                    None => {
+                        if event.is_readable() && !readable_tokens.contains(&token) {
                             readable_tokens.push(token);
                         }
                         if event.is_writable() {
                             writable_tokens.push(token);
                         }
                     }
-                }
-            }
-            
-            // Process server events
-            for _token in server_events {
-                self.accept_new_connection()?;
+                },
             }
-            
-            // Process readable events
-            for token in readable_tokens {
-                self.handle_readable(token)?;
+        }
+        
+        // Process listener events
+        match self.accept_paused_until {
+            Some(until) if Instant::now() < until => {}
+            // 暂停期间的可读事件已经错过（边沿触发），恢复时主动 accept 所有监听器
+            Some(_) => {
+                self.accept_paused_until = None;
+                for index in 0..self.listeners.len() {
+                    self.accept_new_connections(index);
+                }
             }
-            
-            // Process writable events
-            for token in writable_tokens {
-                self.handle_writable(token)?;
+            None => {
+                for index in listener_events {
+                    self.accept_new_connections(index);
+                }
             }
-            
-            self.check_heartbeat()?;
-            self.check_peer_timeouts()?;
-        }
-    }
-    
-    fn accept_new_connection(&mut self) -> Result<(), P2PError> {
-        match self.listener.accept() {
-            Ok((mut stream, addr)) => {
-                let token = self.next_token;
-                self.next_token = Token(self.next_token.0 + 1);
-                
-                self.poll.registry()
-                    .register(&mut stream, token, Interest::READABLE)?;
-                
-                self.streams.insert(token, stream);
-                self.buffers.insert(token, Vec::new());
-                
-                println!("New client connected: {}", addr);
-            },
-            Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => return Err(P2PError::IoError(e)),
-            _ => {}
         }
+        
+        // Process readable events
+        for token in readable_tokens {
+            self.handle_readable(token)?;
+        }
+        
+        // Process writable events
+        for token in writable_tokens {
+            self.handle_writable(token);
+        }
+        
+        self.process_work_results();
+        self.process_commands()?;
+        self.expire_overflow();
+        let output = self.router.tick(Instant::now());
+        self.dispatch(output);
+        self.log_stats_periodically();
+        if let Err(e) = self.router.registry_mut().flush_if_due(self.config.registry_flush_interval) {
+            error!("failed to save user registry: {}", e);
+        }
+        self.record_loop_lag(started);
         Ok(())
     }
     
-    fn handle_readable(&mut self, token: Token) -> Result<(), P2PError> {
-        if let Some(stream) = self.streams.get_mut(&token) {
-            let mut buffer = [0; 1024];
-            match stream.read(&mut buffer) {
-                Ok(0) => self.remove_peer(token),
-                Ok(n) => {
-                    if let Some(peer_buffer) = self.buffers.get_mut(&token) {
-                        peer_buffer.extend_from_slice(&buffer[..n]);
+    /// 记录本轮（poll 返回之后）的处理耗时，过慢时告警
+    fn record_loop_lag(&mut self, started: Instant) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(started);
+        if self.lag_monitor.record(elapsed, self.config.lag_warn_threshold, now) {
+            warn!("event loop iteration took {:?} (threshold {:?})", elapsed, self.config.lag_warn_threshold);
+        }
+        self.stats.loop_lag = self.lag_monitor.lag();
+    }
+    
+    fn log_stats_periodically(&mut self) {
+        if let Some(interval) = self.config.stats_log_interval {
+            if self.last_stats_log.elapsed() >= interval {
+                info!("stats: {}", self.stats.summary());
+                self.last_stats_log = Instant::now();
+            }
+        }
+    }
+    
+    /// 处理所有待处理的管理指令
+    fn process_commands(&mut self) -> Result<()> {
+        while let Ok(command) = self.control_receiver.try_recv() {
+            match command {
+                ServerCommand::Kick(user_id) => self.kick_user(&user_id),
+                ServerCommand::Ban(user_id, duration) => self.ban_user(&user_id, duration),
+                ServerCommand::Unban(user_id) => self.unban_user(&user_id),
+                ServerCommand::BanIp(ip) => self.ban_ip(ip),
+                ServerCommand::UnbanIp(ip) => self.unban_ip(ip),
+                ServerCommand::Broadcast(content) => {
+                    let report = self.broadcast_system(content);
+                    if !report.is_complete() {
+                        warn!("system broadcast reached {} users, {} failed", report.delivered, report.failed.len());
                     }
-                    self.try_parse_messages(token)?;
                 }
-                Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => {
-                    self.remove_peer(token);
-                    return Err(P2PError::IoError(e));
+                ServerCommand::ListPeers => {
+                    let users = self.connected_users();
+                    info!("connected users ({}):", users.len());
+                    for user in users {
+                        info!("  - user_id={} addr={}:{} last_heartbeat={}s ago",
+                              user.user_id, user.address, user.port, user.secs_since_heartbeat);
+                    }
+                }
+                ServerCommand::Stats(reply) => {
+                    let _ = reply.send(self.stats.clone());
+                }
+                ServerCommand::QueryUser(user_id, reply) => {
+                    let _ = reply.send(self.router.user_record(&user_id).cloned());
                 }
-                _ => {}
+                ServerCommand::SetMotd(motd) => self.set_motd(motd),
+                ServerCommand::ResetQuota(user_id) => self.reset_quota(&user_id),
+                ServerCommand::AuditInfo(reply) => {
+                    let _ = reply.send(self.audit_info());
+                }
+                ServerCommand::Shutdown => self.shutdown.flag.store(true, Ordering::SeqCst),
             }
         }
         Ok(())
     }
     
-    fn try_parse_messages(&mut self, token: Token) -> Result<(), P2PError> {
-        let mut messages = Vec::new();
-        
-        if let Some(buffer) = self.buffers.get_mut(&token) {
-            while let Some(delimiter_pos) = buffer.iter().position(|&b| b == b'\n') {
-                let message_data = buffer.drain(..=delimiter_pos).collect::<Vec<_>>();
-                let message_data = &message_data[..message_data.len() - 1];
-                
-                if let Ok(message) = deserialize_message(message_data) {
-                    messages.push(message);
+    /// 封禁用户，使其在到期前无法重新加入；若在线则立即踢出
+    pub fn ban_user(&mut self, user_id: &str, duration: Option<Duration>) {
+        self.audit(AuditEvent::Ban { user_id: user_id.to_string(), duration_ms: duration.map(|d| d.as_millis() as u64) });
+        let output = self.router.ban(user_id, duration);
+        self.dispatch(output);
+    }
+    
+    pub fn unban_user(&mut self, user_id: &str) {
+        self.audit(AuditEvent::Unban { user_id: user_id.to_string() });
+        self.router.unban(user_id);
+    }
+    
+    /// 封禁 IP 并断开当前来自该地址的所有连接
+    pub fn ban_ip(&mut self, ip: IpAddr) {
+        self.audit(AuditEvent::BanIp { ip });
+        let output = self.router.ban_ip(ip);
+        self.dispatch(output);
+    }
+    
+    pub fn unban_ip(&mut self, ip: IpAddr) {
+        self.audit(AuditEvent::UnbanIp { ip });
+        self.router.unban_ip(ip);
+    }
+    
+    pub fn is_ip_banned(&self, ip: IpAddr) -> bool {
+        self.router.is_ip_banned(ip)
+    }
+    
+    /// 修改公告（None 为清除），已在线的用户不会重新收到
+    pub fn set_motd(&mut self, motd: Option<String>) {
+        info!("motd set to {:?}", motd);
+        self.config.motd = motd.clone();
+        self.router.set_motd(motd);
+    }
+    
+    pub fn reset_quota(&mut self, user_id: &str) {
+        self.router.reset_quota(user_id);
+    }
+    
+    /// 查询用户的注册表记录
+    pub fn user_record(&self, user_id: &str) -> Option<&UserRecord> {
+        self.router.user_record(user_id)
+    }
+    
+    /// 踢出用户：通知对方、关闭连接，并告知其他用户
+    pub fn kick_user(&mut self, user_id: &str) {
+        self.audit(AuditEvent::Kick { user_id: user_id.to_string() });
+        let output = self.router.kick(user_id);
+        self.dispatch(output);
+    }
+    
+    /// 向所有已加入的客户端推送系统公告（如维护通知）
+    pub fn broadcast_system(&mut self, content: String) -> BroadcastReport {
+        let output = self.router.system_broadcast(content);
+        self.dispatch(output)
+    }
+    
+    /// 当前审计文件的路径和大小，未配置审计日志时为 None
+    pub fn audit_info(&self) -> Option<AuditFileInfo> {
+        self.audit.as_ref().map(AuditLog::info)
+    }
+    
+    fn audit(&self, event: AuditEvent) {
+        if let Some(audit) = &self.audit {
+            audit.record(event);
+        }
+    }
+    
+    /// 执行路由结果：按顺序投递消息，然后关闭路由器要求关闭的连接。
+    /// 返回其中广播投递的汇总结果
+    fn dispatch(&mut self, output: RouterOutput) -> BroadcastReport {
+        let mut report = BroadcastReport::default();
+        for delivery in output.deliveries {
+            match delivery {
+                Delivery::To(token, message) => {
+                    let was_open = self.streams.contains_key(&token);
+                    let failed = match self.send_message(token, &message) {
+                        Ok(()) => was_open && !self.streams.contains_key(&token),
+                        Err(e) => {
+                            warn!("delivery failed: {}", e);
+                            true
+                        }
+                    };
+                    if failed {
+                        let output = self.router.delivery_failed(&message);
+                        self.dispatch(output);
+                    }
+                }
+                Delivery::Broadcast(tokens, message) => {
+                    let result = self.broadcast_to(tokens, &message);
+                    report.delivered += result.delivered;
+                    report.failed.extend(result.failed);
                 }
             }
         }
-        
-        for message in messages {
-            self.handle_message(&message, token)?;
+        for event in output.audit {
+            self.audit(event);
         }
-        
-        Ok(())
+        #[cfg(feature = "chat-history")]
+        if let Some(chat_log) = &self.chat_log {
+            for message in &output.chats {
+                chat_log.record(message);
+            }
+        }
+        for event in output.events {
+            match event {
+                RouterEvent::Close(token, reason) => self.disconnect_peer(token, reason),
+                RouterEvent::SetFraming(token, framing) => {
+                    debug!("token={:?} switches to {:?} framing", token, framing);
+                    self.framings.insert(token, framing);
+                }
+                RouterEvent::DuplicateDropped => self.stats.record_drop(DropReason::Duplicate),
+            }
+        }
+        report
     }
     
-    fn handle_message(&mut self, message: &Message, token: Token) -> Result<(), P2PError> {
-        match message.msg_type {
-            MessageType::Join => self.handle_join_message(message, token)?,
-            MessageType::Leave => self.handle_leave_message(message, token)?,
-            MessageType::Chat => self.handle_chat_message(message)?,
-            MessageType::Heartbeat => self.handle_heartbeat_message(token)?,
-            MessageType::PeerListRequest => self.handle_peer_list_request(token)?,
-            MessageType::ConnectRequest => self.handle_connect_request(message, token)?,
-            _ => println!("Unknown message type: {:?}", message.msg_type),
+    /// 向一组连接发送同一条消息：每种帧格式只序列化一次，各连接的写队列共享同一份数据；
+    /// 失败的接收者记录日志后跳过
+    fn broadcast_to(&mut self, tokens: Vec<Token>, message: &Message) -> BroadcastReport {
+        let mut report = BroadcastReport::default();
+        let mut encoded: HashMap<Framing, Arc<Vec<u8>>> = HashMap::new();
+        let droppable = message.msg_type.is_user_content();
+        for token in tokens {
+            let framing = self.framing_of(token);
+            let data = match encoded.get(&framing) {
+                Some(data) => data.clone(),
+                None => match framing.encode(message) {
+                    Ok(data) => encoded.entry(framing).or_insert(Arc::new(data)).clone(),
+                    Err(e) => {
+                        warn!("failed to serialize {:?} for broadcast: {}", message.msg_type, e);
+                        report.failed.push((token, P2PError::Serialization(serde::ser::Error::custom(&e))));
+                        continue;
+                    }
+                },
+            };
+            match self.enqueue_frame(token, data, droppable) {
+                Ok(()) if self.streams.contains_key(&token) => report.delivered += 1,
+                // 写入时连接因写错误或写队列已满被断开
+                Ok(()) => report.failed.push((token, P2PError::Connection(format!("token={:?} was disconnected while sending", token)))),
+                Err(e) => {
+                    warn!("failed to deliver {:?} to token={:?}: {}", message.msg_type, token, e);
+                    report.failed.push((token, e));
+                }
+            }
         }
-        Ok(())
+        self.stats.broadcasts += 1;
+        self.stats.broadcast_recipients += report.delivered as u64;
+        report
     }
     
-    fn handle_join_message(&mut self, message: &Message, token: Token) -> Result<(), P2PError> {
-        let user_id = &message.sender_id;
-        println!("🔥 收到用户 {} 的join消息，监听地址: {}:{}", 
-                 user_id, message.sender_peer_address, message.sender_listen_port);
+    /// 通知所有客户端服务器即将关闭，然后关闭所有连接。与 `disconnect_peer` 共用连接清理，
+    /// 但不逐个广播 UserLeft（所有人都在被断开）
+    fn close_all_connections(&mut self) {
+        let shutdown_message = server_message(MessageType::ServerShutdown, "Server is shutting down".to_string());
         
-        let peer_info = PeerInfo::new(
-            user_id.clone(),
-            message.sender_peer_address.clone(),
-            message.sender_listen_port
-        );
-        
-        self.peers.insert(token, peer_info.clone());
-        self.user_to_token.insert(user_id.clone(), token);
-        
-        println!("User {} joined with listen port {}", user_id, message.sender_listen_port);
+        let tokens: Vec<Token> = self.streams.keys().cloned().collect();
+        for &token in &tokens {
+            if let Err(e) = self.send_message(token, &shutdown_message) {
+                warn!("failed to notify token={:?} of shutdown: {}", token, e);
+            }
+        }
         
-        // Notify other users
-        let join_notification = Message {
-            msg_type: MessageType::UserJoined,
-            sender_id: user_id.clone(),
-            target_id: None,
-            content: Some(user_id.clone()),
-            sender_peer_address: message.sender_peer_address.clone(),
-            sender_listen_port: message.sender_listen_port,
-            timestamp: SystemTime::now(),
-            source: MessageSource::Server,
-        };
+        // 在限定时间内写出各连接积压的数据，关闭通知排在最后；写出失败的连接不再等待
+        let deadline = Instant::now() + SHUTDOWN_FLUSH_TIMEOUT;
+        let mut pending = tokens.clone();
+        loop {
+            pending.retain(|token| match (self.streams.get_mut(token), self.write_queues.get_mut(token)) {
+                (Some(stream), Some(queue)) => matches!(queue.write_to(stream), Ok((_, false))),
+                _ => false,
+            });
+            if pending.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            if let Err(e) = self.poll.poll(&mut self.events, Some(Duration::from_millis(10))) {
+                warn!("poll failed while flushing on shutdown: {}", e);
+                break;
+            }
+        }
+        if !pending.is_empty() {
+            warn!("{} connections still had queued data after {:?}, closing anyway", pending.len(), SHUTDOWN_FLUSH_TIMEOUT);
+        }
         
-        let peer_tokens: Vec<Token> = self.peers.keys().filter(|&t| *t != token).cloned().collect();
-        for peer_token in peer_tokens {
-            self.send_message(peer_token, &join_notification)?;
+        // 与拒绝连接时相同：先丢弃未读的数据再关闭写端，避免内核发送 RST 使客户端收不到关闭通知
+        for token in tokens {
+            if let Some(mut stream) = self.release_connection(token) {
+                discard_pending_input(&mut stream);
+                let _ = stream.shutdown(std::net::Shutdown::Write);
+            }
         }
         
-        self.send_peer_list(token)?;
-        Ok(())
+        // 关闭时在线的用户也要在审计日志中留下离开记录
+        let users: Vec<String> = self.router.peers().map(|(_, info)| info.user_id.clone()).collect();
+        for user_id in users {
+            self.audit(AuditEvent::Leave { user_id, reason: DisconnectReason::Left });
+        }
+        self.router.clear();
+        self.stats.current_connections = 0;
+    }
+    
+    /// 接受监听器上所有等待中的连接。失败只影响单个连接或暂停 accept，不会让服务器退出
+    fn accept_new_connections(&mut self, listener: usize) {
+        loop {
+            match self.listeners[listener].accept() {
+                Ok((stream, addr)) => {
+                    if let Err(e) = self.register_connection(stream, addr) {
+                        warn!("dropping connection from addr={}: {}", addr, e);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                // 对方在 accept 之前就已放弃的连接
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::ConnectionReset) => continue,
+                Err(e) => {
+                    let local = self.listeners[listener].local_addr().map(|addr| addr.to_string()).unwrap_or_default();
+                    if resources_exhausted(&e) {
+                        warn!("accepting connections on {} failed: {}, pausing accept for {:?}", local, e, ACCEPT_BACKOFF);
+                        self.accept_paused_until = Some(Instant::now() + ACCEPT_BACKOFF);
+                    } else {
+                        warn!("accepting connections on {} failed: {}", local, e);
+                    }
+                    break;
+                }
+            }
+        }
     }
     
-    fn handle_leave_message(&mut self, message: &Message, token: Token) -> Result<(), P2PError> {
-        let user_id = &message.sender_id;
-        self.remove_peer(token);
+    fn register_connection(&mut self, mut stream: TcpStream, addr: SocketAddr) -> Result<()> {
+        // 被封禁的 IP：不回复任何消息，直接关闭
+        if self.router.is_ip_banned(addr.ip()) {
+            info!("rejecting addr={}: ip is banned", addr);
+            self.stats.record_drop(DropReason::BannedIp);
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+            return Ok(());
+        }
         
-        println!("User {} left", user_id);
+        // 达到连接上限：先等待 probe_wait，让健康检查探测仍能得到回复；
+        // 其余连接回复一条 Error 后关闭，而不是让客户端一直挂起
+        let connected = self.streams.len() - self.overflow.len();
+        let over_limit = connected >= self.config.max_connections;
+        if over_limit && (self.config.probe_wait.is_zero() || self.overflow.len() >= MAX_OVERFLOW_PROBES) {
+            warn!("rejecting addr={}: server full ({} connected)", addr, connected);
+            self.stats.record_drop(DropReason::ServerFull);
+            return reject_server_full(&mut stream, connected);
+        }
         
-        let leave_notification = Message {
-            msg_type: MessageType::UserLeft,
-            sender_id: user_id.clone(),
-            target_id: None,
-            content: Some(user_id.clone()),
-            sender_peer_address: String::new(),
-            sender_listen_port: 0,
-            timestamp: SystemTime::now(),
-            source: MessageSource::Server,
-        };
+        if let Err(e) = self.config.socket.apply(&stream) {
+            warn!("failed to set socket options for addr={}: {}", addr, e);
+        }
+        let token = self.tokens.allocate();
         
-        let peer_tokens: Vec<Token> = self.peers.keys().cloned().collect();
-        for peer_token in peer_tokens {
-            self.send_message(peer_token, &leave_notification)?;
+        if let Err(e) = self.poll.registry().register(&mut stream, token, Interest::READABLE) {
+            self.tokens.release(token);
+            return Err(P2PError::Io(e).context(format!("registering connection from {}", addr)));
         }
         
-        Ok(())
-    }
-    
-    fn handle_chat_message(&mut self, message: &Message) -> Result<(), P2PError> {
-        if let Some(target_id) = &message.target_id {
-            if let Some(token) = self.user_to_token.get(target_id) {
-                self.send_message(*token, message)?;
-            }
-        } else {
-            let peer_tokens: Vec<Token> = self.peers.keys().cloned().collect();
-            for token in peer_tokens {
-                self.send_message(token, message)?;
-            }
+        self.streams.insert(token, stream);
+        self.buffers.insert(token, Vec::new());
+        self.write_queues.insert(token, WriteQueue::default());
+        if over_limit {
+            self.overflow.insert(token, Instant::now());
+            debug!("holding addr={} over the connection limit for a health probe", addr);
+            return Ok(());
         }
+        self.router.connection_opened(token, addr);
+        self.stats.total_accepted += 1;
+        self.stats.current_connections += 1;
+        
+        // 连接在 Join 之前可能只是健康检查探测，用户级别的日志在 Join 时由路由器输出
+        debug!("client connected token={:?} addr={}", token, addr);
         Ok(())
     }
     
-    fn handle_heartbeat_message(&mut self, token: Token) -> Result<(), P2PError> {
-        if let Some(peer_info) = self.peers.get_mut(&token) {
-            peer_info.last_heartbeat = Instant::now();
+    /// 回复健康检查探测并关闭连接。探测不计入连接数统计，已按普通连接计入的要扣除
+    fn answer_probe(&mut self, token: Token) {
+        let counted = !self.overflow.contains_key(&token);
+        if let Some(mut stream) = self.release_connection(token) {
+            // 非阻塞写：几个字节总能放进新连接的发送缓冲区
+            let _ = stream.write_all(PROBE_RESPONSE);
+            discard_pending_input(&mut stream);
+            let _ = stream.shutdown(std::net::Shutdown::Write);
         }
-        Ok(())
+        if counted {
+            let output = self.router.connection_closed(token, DisconnectReason::Left);
+            self.dispatch(output);
+        }
+        self.stats.record_probe(counted);
+        debug!("answered health probe on token={:?}", token);
     }
     
-    fn handle_peer_list_request(&mut self, token: Token) -> Result<(), P2PError> {
-        self.send_peer_list(token)?;
-        Ok(())
+    /// 满员时暂时接受的连接没有发来探测：以 ServerFull 拒绝
+    fn reject_overflow(&mut self, token: Token) {
+        let connected = self.streams.len() - self.overflow.len();
+        if let Some(mut stream) = self.release_connection(token) {
+            warn!("rejecting token={:?}: server full ({} connected)", token, connected);
+            self.stats.record_drop(DropReason::ServerFull);
+            if let Err(e) = reject_server_full(&mut stream, connected) {
+                warn!("failed to reject token={:?}: {}", token, e);
+            }
+        }
     }
     
-    fn handle_connect_request(&mut self, message: &Message, token: Token) -> Result<(), P2PError> {
-        if let Some(target_id) = &message.target_id {
-            if let Some(target_token) = self.user_to_token.get(target_id) {
-                if let Some(peer_info) = self.peers.get(target_token) {
-                    let content = format!("{},{}", peer_info.address, peer_info.port);
-                    let connect_response = Message {
-                        msg_type: MessageType::ConnectResponse,
-                        sender_id: peer_info.user_id.clone(),
-                        target_id: Some(message.sender_id.clone()),
-                        content: Some(content),
-                        sender_peer_address: peer_info.address.clone(),
-                        sender_listen_port: peer_info.port,
-                        timestamp: SystemTime::now(),
-                        source: MessageSource::Server,
-                    };
-                    
-                    self.send_message(token, &connect_response)?;
-                }
-            }
+    fn expire_overflow(&mut self) {
+        let wait = self.config.probe_wait;
+        let expired: Vec<Token> = self.overflow.iter()
+            .filter(|(_, accepted_at)| accepted_at.elapsed() >= wait)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            self.reject_overflow(token);
         }
-        Ok(())
     }
     
-    fn handle_writable(&mut self, token: Token) -> Result<(), P2PError> {
-        if let Some(stream) = self.streams.get_mut(&token) {
-            if let Some(buffer) = self.buffers.get_mut(&token) {
-                if !buffer.is_empty() {
-                    match stream.write_all(buffer) {
-                        Ok(()) => {
-                            buffer.clear();
-                            // Switch back to read-only mode
-                            self.poll.registry()
-                                .reregister(stream, token, Interest::READABLE)?;
-                        }
-                        Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => {
-                            self.remove_peer(token);
-                            return Err(e.into());
-                        }
-                        _ => {}
+    /// 读取直到 WouldBlock（最多 max_reads_per_event 次），读完后统一解析
+    fn handle_readable(&mut self, token: Token) -> Result<()> {
+        let Some(stream) = self.streams.get_mut(&token) else {
+            return Ok(());
+        };
+        let mut closed = None;
+        let mut drained = false;
+        for _ in 0..self.config.max_reads_per_event {
+            match stream.read(&mut self.read_buffer) {
+                Ok(0) => {
+                    closed = Some(DisconnectReason::Disconnected);
+                    break;
+                }
+                Ok(n) => {
+                    self.stats.bytes_in += n as u64;
+                    self.stats.connections.entry(token).or_default().bytes_read += n as u64;
+                    if let Some(peer_buffer) = self.buffers.get_mut(&token) {
+                        peer_buffer.extend_from_slice(&self.read_buffer[..n]);
                     }
                 }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    drained = true;
+                    break;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!("read error on token={:?}: {}", token, e);
+                    closed = Some(DisconnectReason::Error);
+                    break;
+                }
+            }
+        }
+        
+        // 先处理连接关闭前已经收到的完整消息
+        self.try_parse_messages(token)?;
+        if !self.streams.contains_key(&token) {
+            return Ok(());
+        }
+        match closed {
+            Some(reason) => self.disconnect_peer(token, reason),
+            None if !drained => {
+                self.read_backlog.insert(token);
             }
+            None => {}
         }
         Ok(())
     }
     
-    fn send_message(&mut self, token: Token, message: &Message) -> Result<(), P2PError> {
-        if let Some(stream) = self.streams.get_mut(&token) {
-            let data = serialize_message(message)?;
-            
-            // Try to write immediately
-            match stream.write_all(&data) {
-                Ok(()) => {
-                    // Message sent successfully
+    fn try_parse_messages(&mut self, token: Token) -> Result<()> {
+        let max_message_size = self.config.max_message_size;
+        let mut oversized = false;
+        
+        // 逐帧解析并处理：Join 协商出新的帧格式后，后面的数据要按新格式解析
+        while self.streams.contains_key(&token) {
+            let framing = self.framing_of(token);
+            let Some(buffer) = self.buffers.get_mut(&token) else {
+                break;
+            };
+            let Some(message_data) = framing.take_frame(buffer) else {
+                // 尚未收完但已超过上限的半帧
+                oversized = buffer.len() > max_message_size;
+                break;
+            };
+            if message_data.len() > max_message_size {
+                oversized = true;
+                break;
+            }
+            if message_data == PROBE_REQUEST && framing == Framing::Newline && self.router.peer_info(token).is_none() {
+                self.answer_probe(token);
+                break;
+            }
+            if self.overflow.contains_key(&token) {
+                self.reject_overflow(token);
+                break;
+            }
+            let parsed = deserialize_message_lenient(&message_data).and_then(|message| message.validate().map(|()| message));
+            let conn = self.stats.connections.entry(token).or_default();
+            match &parsed {
+                Ok(_) => conn.messages_parsed += 1,
+                Err(_) => conn.parse_failures += 1,
+            }
+            match parsed {
+                Ok(message) => self.handle_inbound(message, message_data.len(), token)?,
+                // 不是本协议的客户端（多半连错了端口），回复错误也没有意义，直接关闭
+                Err(P2PError::NotP2PProtocol(preview)) => {
+                    let addr = self.streams.get(&token).and_then(|stream| stream.peer_addr().ok());
+                    warn!("token={:?} addr={:?} is not speaking the P2P protocol (frame starts with \"{}\"), closing; check the client's port",
+                        token, addr, preview);
+                    self.stats.record_drop(DropReason::Malformed);
+                    self.disconnect_peer(token, DisconnectReason::Error);
                 }
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    // Buffer the message for later
-                    if let Some(buffer) = self.buffers.get_mut(&token) {
-                        buffer.extend_from_slice(&data);
-                        self.poll.registry()
-                            .reregister(stream, token, Interest::READABLE | Interest::WRITABLE)?;
+                Err(P2PError::InvalidMessage { msg_type, code, reason }) => {
+                    debug!("dropping invalid {} from token={:?}: {}", msg_type, token, reason);
+                    self.stats.record_drop(DropReason::Malformed);
+                    if let Err(e) = self.send_message(token, &server_error(code, format!("invalid {}: {}", msg_type, reason))) {
+                        warn!("failed to report invalid {} to token={:?}: {}", msg_type, token, e);
                     }
                 }
                 Err(e) => {
-                    self.remove_peer(token);
-                    return Err(P2PError::IoError(e));
+                    debug!("dropping malformed frame from token={:?}: {}", token, e);
+                    self.stats.record_drop(DropReason::Malformed);
+                    // 回复失败只影响这一个连接，不能让整个服务器退出
+                    if let Err(e) = self.send_message(token, &server_error(ErrorCode::MalformedMessage, format!("malformed message: {}", e))) {
+                        warn!("failed to report malformed frame to token={:?}: {}", token, e);
+                    }
                 }
             }
         }
+        
+        if oversized && self.streams.contains_key(&token) {
+            warn!("message from token={:?} exceeds {} bytes, disconnecting", token, max_message_size);
+            self.stats.record_drop(DropReason::Oversized);
+            let error = server_error(ErrorCode::MessageTooLarge, format!("message exceeds {} bytes", max_message_size));
+            let _ = self.send_message(token, &error);
+            if let Some(stream) = self.streams.get_mut(&token) {
+                discard_pending_input(stream);
+            }
+            self.disconnect_peer(token, DisconnectReason::Error);
+        }
+        
         Ok(())
     }
     
-    fn remove_peer(&mut self, token: Token) {
-        if let Some(peer_info) = self.peers.remove(&token) {
-            self.user_to_token.remove(&peer_info.user_id);
+    /// 一条入站消息依次经过限流、统计和钩子，然后交给路由。
+    /// 握手（Join）不计入限流，客户端不会在加入之前就被限流
+    fn handle_inbound(&mut self, message: Message, size: usize, token: Token) -> Result<()> {
+        if message.msg_type != MessageType::Join && !self.check_rate_limit(token) {
+            self.stats.record_drop(DropReason::RateLimited);
+            warn!("rate limit exceeded for token={:?}, dropping {:?}", token, message.msg_type);
+            let error = server_error(ErrorCode::RateLimited, "rate limit exceeded".to_string());
+            // 被限流的客户端往往同时也不读取，回复失败时只记录日志
+            if let Err(e) = self.send_message(token, &error) {
+                warn!("failed to report rate limit to token={:?}: {}", token, e);
+            }
+            return Ok(());
         }
-        self.streams.remove(&token);
-        self.buffers.remove(&token);
-        println!("Removed peer: {:?}", token);
+        self.stats.record_message(&message.msg_type, &message.sender_id, size);
+        if let Some(message) = self.run_hooks(message, token) {
+            let output = self.router.route(&message, token);
+            self.dispatch(output);
+        }
+        Ok(())
     }
     
-    fn send_peer_list(&mut self, token: Token) -> Result<(), P2PError> {
-        let peer_list: Vec<_> = self.peers.values()
-            .map(|info| (info.user_id.clone(), info.address.clone(), info.port))
-            .collect();
-        
-        println!("🗺️ 发送对等节点列表给 token {:?}, 包含 {} 个节点:", token, peer_list.len());
-        for (user_id, address, port) in &peer_list {
-            println!("  - {}: {}:{}", user_id, address, port);
+    /// 路由工作线程执行完钩子的消息
+    fn process_work_results(&mut self) {
+        while let Some(result) = self.workers.as_ref().and_then(WorkerPool::try_result) {
+            // 结果返回前连接可能已断开，token 也可能已分配给新的连接
+            if self.router.peer_info(result.token).map(|info| info.user_id.as_str()) != Some(result.user_id.as_str()) {
+                debug!("dropping hook result for token={:?}: connection is gone", result.token);
+                continue;
+            }
+            match result.outcome {
+                Ok(message) => {
+                    let output = self.router.route(&message, result.token);
+                    self.dispatch(output);
+                }
+                Err(reason) => self.reject_message(result.token, &result.user_id, reason),
+            }
         }
-        
-        let peer_list_data = serde_json::to_vec(&peer_list)?;
-        
-        let peer_list_message = Message {
-            msg_type: MessageType::PeerList,
-            sender_id: "SERVER".to_string(),
-            target_id: None,
-            content: Some(String::from_utf8_lossy(&peer_list_data).to_string()),
-            sender_peer_address: String::new(),
-            sender_listen_port: 0,
-            timestamp: SystemTime::now(),
-            source: MessageSource::Server,
+    }
+    
+    fn framing_of(&self, token: Token) -> Framing {
+        self.framings.get(&token).copied().unwrap_or_default()
+    }
+    
+    /// 依次调用消息钩子，返回最终要路由的消息；被拒绝时回复 Error 并返回 None。
+    /// 尚未加入（没有 PeerInfo）的连接发来的消息不经过钩子。启用工作线程时消息交给工作线程并返回 None，
+    /// 结果由 `process_work_results` 路由
+    fn run_hooks(&mut self, message: Message, token: Token) -> Option<Message> {
+        let Some(peer_info) = self.router.peer_info(token).cloned() else {
+            return Some(message);
         };
+        if let Some(workers) = &self.workers {
+            workers.submit(WorkItem { token, message, from: peer_info });
+            return None;
+        }
         
-        self.send_message(token, &peer_list_message)?;
-        Ok(())
+        match self.hooks.run(message, &peer_info) {
+            Ok(message) => Some(message),
+            Err(reason) => {
+                self.reject_message(token, &peer_info.user_id, reason);
+                None
+            }
+        }
     }
     
-    fn check_heartbeat(&mut self) -> Result<(), P2PError> {
+    fn reject_message(&mut self, token: Token, user_id: &str, reason: String) {
+        debug!("hook rejected message from user_id={}: {}", user_id, reason);
+        self.stats.record_drop(DropReason::Filtered);
+        if let Err(e) = self.send_message(token, &server_error(ErrorCode::Rejected, reason)) {
+            warn!("failed to report rejected message to user_id={}: {}", user_id, e);
+        }
+    }
+    
+    /// 按固定一秒窗口计数，返回该消息是否允许处理
+    fn check_rate_limit(&mut self, token: Token) -> bool {
+        let Some(limit) = self.config.max_messages_per_second else {
+            return true;
+        };
         let now = Instant::now();
-        if now.duration_since(self.last_heartbeat) > Duration::from_secs(30) {
-            let heartbeat_message = Message {
-                msg_type: MessageType::Heartbeat,
-                sender_id: "SERVER".to_string(),
-                target_id: None,
-                content: None,
-                sender_peer_address: String::new(),
-                sender_listen_port: 0,
-                timestamp: SystemTime::now(),
-                source: MessageSource::Server,
-            };
-            
-            let peer_tokens: Vec<Token> = self.peers.keys().cloned().collect();
-            for token in peer_tokens {
-                self.send_message(token, &heartbeat_message)?;
+        let window = self.rate_windows.entry(token).or_insert((now, 0));
+        if now.duration_since(window.0) >= Duration::from_secs(1) {
+            *window = (now, 0);
+        }
+        window.1 += 1;
+        window.1 <= limit
+    }
+    
+    fn handle_writable(&mut self, token: Token) {
+        // 写队列清空后切回只读关注；写错误已在 flush_write_queue 中断开连接
+        if self.flush_write_queue(token) {
+            if let Some(stream) = self.streams.get_mut(&token) {
+                if let Err(e) = self.poll.registry().reregister(stream, token, Interest::READABLE) {
+                    warn!("failed to reregister token={:?}: {}", token, e);
+                    self.disconnect_peer(token, DisconnectReason::Error);
+                }
             }
-            self.last_heartbeat = now;
         }
-        Ok(())
     }
     
-    fn check_peer_timeouts(&mut self) -> Result<(), P2PError> {
-        let now = Instant::now();
-        let timeout_duration = Duration::from_secs(60);
-        
-        let timeout_tokens: Vec<_> = self.peers.iter()
-            .filter(|(_, info)| now.duration_since(info.last_heartbeat) > timeout_duration)
-            .map(|(token, _)| *token)
-            .collect();
+    fn send_message(&mut self, token: Token, message: &Message) -> Result<()> {
+        if !self.streams.contains_key(&token) {
+            return Ok(());
+        }
+        // 写队列满时连接会被断开，因此先取出接收方名称
+        let recipient = self.router.peer_info(token).map_or_else(|| format!("token={:?}", token), |info| info.user_id.clone());
+        self.framing_of(token).encode(message)
+            .and_then(|data| self.enqueue_frame(token, Arc::new(data), message.msg_type.is_user_content()))
+            .with_context(|| format!("sending {:?} to {}", message.msg_type, recipient))
+    }
+    
+    /// 把一帧加入连接的写队列；队列原本为空时立即尝试写出
+    fn enqueue_frame(&mut self, token: Token, data: Arc<Vec<u8>>, droppable: bool) -> Result<()> {
+        let Some(queue) = self.write_queues.get_mut(&token) else {
+            return Ok(());
+        };
+        self.stats.bytes_out += data.len() as u64;
+        queue.push(OutFrame { data, written: 0, droppable });
+        self.router.record_sent(token, Instant::now());
+        if queue.frames.len() == 1 {
+            self.flush_write_queue(token);
+        }
+        self.enforce_queue_limits(token)
+    }
+    
+    /// 写队列超过上限时按配置丢弃最旧的聊天消息或断开连接；达到上限的 80% 时记录一次警告。
+    /// 断开慢消费者不算错误，调用方通过连接是否仍然存在来判断投递是否成功
+    fn enforce_queue_limits(&mut self, token: Token) -> Result<()> {
+        let max_bytes = self.config.write_queue_max_bytes;
+        let max_messages = self.config.write_queue_max_messages;
+        let Some(queue) = self.write_queues.get_mut(&token) else {
+            return Ok(());
+        };
+        let over_limit = |queue: &WriteQueue| queue.bytes > max_bytes || queue.frames.len() > max_messages;
         
-        for token in timeout_tokens {
-            self.remove_peer(token);
+        if over_limit(queue) && self.config.slow_consumer_policy == SlowConsumerPolicy::DropOldest {
+            let mut dropped = 0;
+            while over_limit(queue) && queue.drop_oldest() {
+                self.stats.record_drop(DropReason::SlowConsumer);
+                dropped += 1;
+            }
+            debug!("dropped {} queued chat messages for slow token={:?}", dropped, token);
+        }
+        if over_limit(queue) {
+            warn!("write queue for token={:?} exceeds limit ({} messages, {} bytes), disconnecting slow consumer",
+                  token, queue.frames.len(), queue.bytes);
+            // 慢消费者到此已处理完毕，不是服务器的错误
+            self.disconnect_peer(token, DisconnectReason::SlowConsumer);
+            return Ok(());
         }
         
+        if !queue.warned && (queue.bytes * 5 >= max_bytes * 4 || queue.frames.len() * 5 >= max_messages * 4) {
+            queue.warned = true;
+            warn!("write queue for token={:?} at 80% capacity ({} messages, {} bytes)", token, queue.frames.len(), queue.bytes);
+        }
+        self.stats.record_queue_depth(token, queue.frames.len(), queue.bytes);
         Ok(())
     }
+    
+    /// 尽量写出写队列中的数据，返回队列是否已清空。
+    /// 遇到 WouldBlock 时关注 WRITABLE 事件，等待下次可写再继续；写错误或无法关注 WRITABLE 时断开该连接并返回 false
+    fn flush_write_queue(&mut self, token: Token) -> bool {
+        let (Some(stream), Some(queue)) = (self.streams.get_mut(&token), self.write_queues.get_mut(&token)) else {
+            return false;
+        };
+        
+        match queue.write_to(stream) {
+            Ok((written, drained)) => {
+                self.stats.connections.entry(token).or_default().bytes_written += written as u64;
+                if !drained {
+                    self.stats.record_queue_depth(token, queue.frames.len(), queue.bytes);
+                    if let Err(e) = self.poll.registry().reregister(stream, token, Interest::READABLE | Interest::WRITABLE) {
+                        warn!("failed to reregister token={:?} for writing: {}", token, e);
+                        self.disconnect_peer(token, DisconnectReason::Error);
+                    }
+                    return false;
+                }
+                queue.warned = false;
+                self.stats.record_queue_depth(token, 0, 0);
+                true
+            }
+            Err(e) => {
+                warn!("write error on token={:?}: {}", token, e);
+                self.disconnect_peer(token, DisconnectReason::Error);
+                false
+            }
+        }
+    }
+    
+    /// 移除连接的唯一入口：Leave、读到 EOF、读写错误、心跳/空闲/加入超时、踢出、慢消费者都经过这里。
+    /// 关闭连接并清理该 token 的全部状态、记录统计；路由器负责成员和房间清理、审计，
+    /// 并向剩余用户广播 UserLeft（附带原因）
+    fn disconnect_peer(&mut self, token: Token, reason: DisconnectReason) {
+        // 等待探测的连接从未计入统计，也不在路由器中
+        if self.overflow.contains_key(&token) {
+            self.release_connection(token);
+            return;
+        }
+        if self.release_connection(token).is_some() {
+            self.stats.record_disconnect(reason);
+            debug!("removed connection token={:?} reason={}", token, reason);
+        }
+        
+        let output = self.router.connection_closed(token, reason);
+        self.dispatch(output);
+    }
+    
+    /// 清理连接在服务器一侧的全部状态（不通知路由器），返回已从事件循环注销的 socket
+    fn release_connection(&mut self, token: Token) -> Option<TcpStream> {
+        let stream = self.streams.remove(&token).map(|mut stream| {
+            let _ = self.poll.registry().deregister(&mut stream);
+            self.tokens.release(token);
+            stream
+        });
+        self.buffers.remove(&token);
+        self.write_queues.remove(&token);
+        self.stats.queue_depths.remove(&token);
+        self.stats.connections.remove(&token);
+        self.rate_windows.remove(&token);
+        self.read_backlog.remove(&token);
+        self.framings.remove(&token);
+        self.overflow.remove(&token);
+        stream
+    }
+}
+
+/// 回复 ServerFull 错误后关闭写端
+fn reject_server_full(stream: &mut TcpStream, connected: usize) -> Result<()> {
+    let error = server_error(ErrorCode::ServerFull, format!("server full, {} connected", connected));
+    let _ = stream.write_all(&serialize_message(&error)?);
+    discard_pending_input(stream);
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+    Ok(())
+}
+
+// 丢弃已到达的数据再关闭，避免内核因未读数据发送 RST 导致错误帧丢失
+fn discard_pending_input(stream: &mut TcpStream) {
+    let mut discard = [0; 1024];
+    while let Ok(n) = stream.read(&mut discard) {
+        if n == 0 {
+            break;
+        }
+    }
 }
@@ -0,0 +1,206 @@
+// 客户端和服务器共用的 TCP 套接字设置。所有套接字都先用 socket2 创建并设置好选项，
+// 再交给 mio 注册，避免注册后才修改选项、第一批数据仍按旧设置发送
+use mio::net::{TcpListener, TcpStream};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use crate::common::duration_ms;
+
+/// 监听队列长度，与 mio 的 `TcpListener::bind` 相同
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// TCP 套接字选项
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SocketOptions {
+    /// 关闭 Nagle 算法（TCP_NODELAY）。聊天消息都很小，开启 Nagle 时会被攒批延迟发送
+    pub nodelay: bool,
+    /// 监听套接字设置 SO_REUSEADDR，重启后可以立即重新绑定仍有 TIME_WAIT 连接的端口。
+    /// Windows 上该选项允许抢占正在使用的端口，因此只在 unix 上生效
+    pub reuse_address: bool,
+    /// 监听套接字设置 SO_REUSEPORT，允许多个进程同时监听同一端口（由内核分配新连接）。
+    /// 只在支持该选项的 unix 平台上生效，默认关闭
+    pub reuse_port: bool,
+    /// 发送缓冲区大小（SO_SNDBUF），None 使用系统默认值
+    pub send_buffer_size: Option<usize>,
+    /// 接收缓冲区大小（SO_RCVBUF），None 使用系统默认值
+    pub recv_buffer_size: Option<usize>,
+    /// TCP 层保活（SO_KEEPALIVE），配置文件中写作 `[socket.keepalive]` 表
+    pub keepalive: KeepaliveOptions,
+}
+
+/// TCP 层保活参数。连接空闲 idle 后内核每隔 interval 发送一次探测，连续 retries 次没有回应时
+/// 连接被判定为失效，之后的读写返回错误（ETIMEDOUT），事件循环按普通连接错误处理。
+///
+/// 与应用层心跳的关系：心跳（`heartbeat_interval` / `peer_timeout`）要靠双方事件循环正常运转，
+/// 对方卡住或网络路径中断时最多要等 peer_timeout 才断开；保活探测由内核应答，能更早发现对方
+/// 主机已消失或路径中断（半开连接），但发现不了进程卡住的情况。任何数据（包括心跳）都会重新开始
+/// idle 计时，因此心跳间隔小于 idle 时只有心跳停止后才会发出探测。最坏检测时间约为
+/// idle + interval × retries，应小于 peer_timeout，否则总是应用层超时先生效
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeepaliveOptions {
+    pub enabled: bool,
+    /// 连接空闲多久后开始探测（TCP_KEEPIDLE）
+    #[serde(rename = "idle_ms", with = "duration_ms")]
+    pub idle: Duration,
+    /// 探测间隔（TCP_KEEPINTVL），不支持单独设置的平台上使用系统值
+    #[serde(rename = "interval_ms", with = "duration_ms")]
+    pub interval: Duration,
+    /// 判定失效前的探测次数（TCP_KEEPCNT），不支持单独设置的平台上使用系统值
+    pub retries: u32,
+}
+
+impl Default for KeepaliveOptions {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            idle: Duration::from_secs(15),
+            interval: Duration::from_secs(5),
+            retries: 3,
+        }
+    }
+}
+
+impl KeepaliveOptions {
+    /// 最坏情况下发现失效连接所需的时间（从最后一次收到数据算起）
+    pub fn detection_time(&self) -> Duration {
+        self.idle + self.interval * self.retries
+    }
+
+    fn apply(&self, socket: &Socket) -> io::Result<()> {
+        if !self.enabled {
+            return socket.set_keepalive(false);
+        }
+        let params = TcpKeepalive::new().with_time(self.idle);
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios",
+            target_os = "freebsd", target_os = "netbsd", windows))]
+        let params = params.with_interval(self.interval);
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "macos", target_os = "ios",
+            target_os = "freebsd", target_os = "netbsd"))]
+        let params = params.with_retries(self.retries);
+        socket.set_tcp_keepalive(&params)
+    }
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            reuse_address: true,
+            reuse_port: false,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            keepalive: KeepaliveOptions::default(),
+        }
+    }
+}
+
+impl SocketOptions {
+    /// 把连接相关的选项应用到已建立的连接上（监听器 accept 得到的连接）
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let raw = borrow_socket(stream);
+        let socket = SockRef::from(&raw);
+        socket.set_nodelay(self.nodelay)?;
+        self.keepalive.apply(&socket)?;
+        self.apply_buffers(&socket)
+    }
+
+    fn apply_buffers(&self, socket: &Socket) -> io::Result<()> {
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// 创建非阻塞监听器。缓冲区大小会被 accept 得到的连接继承
+    pub(crate) fn bind(&self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        if cfg!(unix) && self.reuse_address {
+            socket.set_reuse_address(true)?;
+        }
+        if self.reuse_port {
+            set_reuse_port(&socket)?;
+        }
+        self.apply_buffers(&socket)?;
+        socket.bind(&addr.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+        Ok(TcpListener::from_std(socket.into()))
+    }
+
+    /// 以非阻塞方式连接 `peer`，连接结果通过可写事件得知。`local` 不为 None 时先绑定该本地地址
+    /// （多网卡、VPN 分流时把 P2P 流量固定在某个网络上）
+    pub(crate) fn connect(&self, peer: SocketAddr, local: Option<SocketAddr>) -> io::Result<TcpStream> {
+        if let Some(local) = local {
+            if local.is_ipv4() != peer.is_ipv4() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("local address {} and peer address {} are of different families", local, peer)));
+            }
+        }
+        let socket = Socket::new(Domain::for_address(peer), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_nonblocking(true)?;
+        socket.set_nodelay(self.nodelay)?;
+        self.keepalive.apply(&socket)?;
+        self.apply_buffers(&socket)?;
+        if let Some(local) = local {
+            socket.bind(&local.into())?;
+        }
+        match socket.connect(&peer.into()) {
+            Ok(()) => {}
+            Err(e) if connect_in_progress(&e) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(TcpStream::from_std(socket.into()))
+    }
+}
+
+#[cfg(unix)]
+fn connect_in_progress(error: &io::Error) -> bool {
+    error.raw_os_error() == Some(libc::EINPROGRESS)
+}
+
+#[cfg(not(unix))]
+fn connect_in_progress(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::WouldBlock
+}
+
+/// accept 因进程或系统的资源耗尽（文件描述符、内存）而失败，应暂停 accept 而不是立即重试
+#[cfg(unix)]
+pub(crate) fn resources_exhausted(error: &io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn resources_exhausted(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::OutOfMemory
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn borrow_socket(stream: &TcpStream) -> std::os::fd::BorrowedFd<'_> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: 描述符由 stream 持有，借用的生命周期不超过 stream
+    unsafe { std::os::fd::BorrowedFd::borrow_raw(stream.as_raw_fd()) }
+}
+
+#[cfg(windows)]
+fn borrow_socket(stream: &TcpStream) -> std::os::windows::io::BorrowedSocket<'_> {
+    use std::os::windows::io::AsRawSocket;
+    // SAFETY: 套接字由 stream 持有，借用的生命周期不超过 stream
+    unsafe { std::os::windows::io::BorrowedSocket::borrow_raw(stream.as_raw_socket()) }
+}
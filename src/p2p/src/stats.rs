@@ -0,0 +1,126 @@
+// 服务器运行统计（事件循环是单线程的，计数器直接使用普通整数，无需加锁）
+use std::collections::HashMap;
+use mio::Token;
+use crate::common::{DisconnectReason, MessageType};
+use crate::health::LoopLag;
+
+/// 消息被丢弃的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DropReason {
+    RateLimited,    // 超出每秒消息数限制
+    Oversized,      // 超过最大消息长度
+    Malformed,      // 无法解析的帧
+    ServerFull,     // 达到连接上限被拒绝的连接
+    Filtered,       // 被消息钩子拒绝
+    SlowConsumer,   // 写队列已满时丢弃的聊天消息
+    BannedIp,       // 来自被封禁 IP、在 accept 时直接关闭的连接
+    Duplicate,      // 客户端重试造成的重复用户消息（已确认但不再转发）
+}
+
+/// 单个连接写队列的当前深度
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueDepth {
+    pub messages: usize,
+    pub bytes: usize,
+    /// 该连接写队列出现过的最大字节数
+    pub peak_bytes: usize,
+}
+
+/// 单个连接的读写计数，用于找出刷屏或卡住的连接（服务器和客户端都按 token 记录）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// 成功解析的消息数
+    pub messages_parsed: u64,
+    /// 无法解析、被丢弃的帧数
+    pub parse_failures: u64,
+}
+
+/// 单个用户的消息计数
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserStats {
+    pub messages_sent: u64,
+    pub bytes_sent: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServerStats {
+    /// 当前打开的连接数（包括尚未 Join 的连接）
+    pub current_connections: u64,
+    /// 累计接受的连接数
+    pub total_accepted: u64,
+    /// 已回复的健康检查探测数（探测连接不计入上面两项）
+    pub health_probes: u64,
+    /// 按类型统计收到并处理的消息
+    pub messages_by_type: HashMap<MessageType, u64>,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// 广播次数，以及这些广播实际投递的接收者总数
+    pub broadcasts: u64,
+    pub broadcast_recipients: u64,
+    pub drops: HashMap<DropReason, u64>,
+    pub disconnects: HashMap<DisconnectReason, u64>,
+    /// 按 user_id 统计发送的消息
+    pub per_user: HashMap<String, UserStats>,
+    /// 各连接写队列的深度（连接关闭后移除）
+    pub queue_depths: HashMap<Token, QueueDepth>,
+    /// 各连接的读写计数（连接关闭后移除）
+    pub connections: HashMap<Token, ConnStats>,
+    /// 事件循环每轮处理耗时的统计
+    pub loop_lag: LoopLag,
+}
+
+impl ServerStats {
+    pub fn messages_of(&self, msg_type: &MessageType) -> u64 {
+        self.messages_by_type.get(msg_type).copied().unwrap_or(0)
+    }
+
+    pub fn drops_of(&self, reason: DropReason) -> u64 {
+        self.drops.get(&reason).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn record_message(&mut self, msg_type: &MessageType, user_id: &str, bytes: usize) {
+        *self.messages_by_type.entry(msg_type.clone()).or_insert(0) += 1;
+        let user = self.per_user.entry(user_id.to_string()).or_default();
+        user.messages_sent += 1;
+        user.bytes_sent += bytes as u64;
+    }
+
+    pub(crate) fn record_drop(&mut self, reason: DropReason) {
+        *self.drops.entry(reason).or_insert(0) += 1;
+    }
+
+    pub(crate) fn record_queue_depth(&mut self, token: Token, messages: usize, bytes: usize) {
+        let depth = self.queue_depths.entry(token).or_default();
+        depth.messages = messages;
+        depth.bytes = bytes;
+        depth.peak_bytes = depth.peak_bytes.max(bytes);
+    }
+
+    /// counted 为 true 表示探测连接已按普通连接计入，需要扣除
+    pub(crate) fn record_probe(&mut self, counted: bool) {
+        self.health_probes += 1;
+        if counted {
+            self.total_accepted = self.total_accepted.saturating_sub(1);
+            self.current_connections = self.current_connections.saturating_sub(1);
+        }
+    }
+
+    pub(crate) fn record_disconnect(&mut self, reason: DisconnectReason) {
+        self.current_connections = self.current_connections.saturating_sub(1);
+        *self.disconnects.entry(reason).or_insert(0) += 1;
+    }
+
+    /// 单行摘要，用于周期性日志和管理命令输出
+    pub fn summary(&self) -> String {
+        let relayed: u64 = self.messages_by_type.values().sum();
+        let dropped: u64 = self.drops.values().sum();
+        format!(
+            "connections={} accepted={} messages={} bytes_in={} bytes_out={} broadcasts={} fanout={} dropped={} users={} lag_ms={} max_lag_ms={}",
+            self.current_connections, self.total_accepted, relayed, self.bytes_in, self.bytes_out,
+            self.broadcasts, self.broadcast_recipients, dropped, self.per_user.len(),
+            self.loop_lag.ewma.as_millis(), self.loop_lag.max_last_minute.as_millis()
+        )
+    }
+}
@@ -0,0 +1,90 @@
+// 服务器工作线程池：把耗时的消息钩子从事件循环线程移到固定数量的工作线程上执行。
+// 事件循环仍然负责 socket 读写和路由，工作线程只运行钩子链并把结果送回事件循环
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use crossbeam_channel::{Receiver, Sender};
+use log::warn;
+use mio::{Token, Waker};
+use crate::common::{Message, PeerInfo};
+use crate::hooks::{HookChain, HookRegistration};
+
+/// 每个工作线程输入队列的容量，队列满时事件循环阻塞等待（反压），不会无限堆积
+const QUEUE_CAPACITY: usize = 1024;
+
+/// 交给工作线程的一条入站消息
+pub(crate) struct WorkItem {
+    pub token: Token,
+    pub message: Message,
+    pub from: PeerInfo,
+}
+
+/// 工作线程的处理结果：经过钩子的消息，或拒绝原因
+pub(crate) struct WorkResult {
+    pub token: Token,
+    /// 提交时该连接的 user_id，用于识别结果返回前连接已断开、token 被复用的情况
+    pub user_id: String,
+    pub outcome: Result<Message, String>,
+}
+
+pub(crate) struct WorkerPool {
+    queues: Vec<Sender<WorkItem>>,
+    results: Receiver<WorkResult>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// 启动 size 个工作线程，每个线程持有一条独立的钩子链；结果返回后通过 waker 唤醒事件循环
+    pub fn spawn(size: usize, registrations: &[HookRegistration], waker: Arc<Waker>) -> std::io::Result<Self> {
+        let (result_sender, results) = crossbeam_channel::unbounded();
+        let mut queues = Vec::with_capacity(size);
+        let mut threads = Vec::with_capacity(size);
+        for index in 0..size {
+            let (sender, receiver) = crossbeam_channel::bounded::<WorkItem>(QUEUE_CAPACITY);
+            let mut chain = HookChain::build(registrations);
+            let result_sender = result_sender.clone();
+            let waker = waker.clone();
+            let thread = thread::Builder::new()
+                .name(format!("p2p-worker-{}", index))
+                .spawn(move || {
+                    for item in receiver {
+                        let user_id = item.from.user_id.clone();
+                        let outcome = chain.run(item.message, &item.from);
+                        if result_sender.send(WorkResult { token: item.token, user_id, outcome }).is_err() {
+                            break;
+                        }
+                        if let Err(e) = waker.wake() {
+                            warn!("worker failed to wake event loop: {}", e);
+                        }
+                    }
+                })?;
+            queues.push(sender);
+            threads.push(thread);
+        }
+        Ok(Self { queues, results, threads })
+    }
+
+    /// 按 token 固定分配工作线程，保证同一连接的消息按收到的顺序处理
+    pub fn submit(&self, item: WorkItem) {
+        let queue = &self.queues[item.token.0 % self.queues.len()];
+        if queue.send(item).is_err() {
+            warn!("worker thread exited, dropping message");
+        }
+    }
+
+    /// 取出一条已完成的结果，没有时返回 None
+    pub fn try_result(&self) -> Option<WorkResult> {
+        self.results.try_recv().ok()
+    }
+}
+
+impl Drop for WorkerPool {
+    /// 关闭输入队列，等待工作线程处理完剩余消息后退出
+    fn drop(&mut self) {
+        self.queues.clear();
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                warn!("worker thread panicked");
+            }
+        }
+    }
+}
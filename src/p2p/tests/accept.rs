@@ -0,0 +1,49 @@
+// 修改进程的文件描述符上限，单独放在一个测试程序里，避免影响其他并行运行的测试
+#![cfg(unix)]
+
+mod support;
+
+use p2p::common::MessageType;
+use p2p::server::{P2PServer, ServerCommand, ServerConfig};
+use std::fs::File;
+use std::time::Duration;
+use support::{join_message, TestClient};
+
+fn set_fd_limit(limit: libc::rlim_t) -> libc::rlim_t {
+    let mut current = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: 只读写本函数内的 rlimit 结构
+    unsafe {
+        assert_eq!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut current), 0);
+        let lowered = libc::rlimit { rlim_cur: limit.min(current.rlim_max), rlim_max: current.rlim_max };
+        assert_eq!(libc::setrlimit(libc::RLIMIT_NOFILE, &lowered), 0);
+    }
+    current.rlim_cur
+}
+
+#[test]
+fn server_keeps_running_when_accept_runs_out_of_file_descriptors() {
+    let mut server = P2PServer::new_with_config("127.0.0.1:0", ServerConfig::default()).unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+
+    // 占满描述符，只留下客户端连接自己需要的两个（连接和 try_clone），服务器 accept 时 EMFILE
+    let original = set_fd_limit(256);
+    let mut hog = Vec::new();
+    while let Ok(file) = File::open("/dev/null") {
+        hog.push(file);
+    }
+    hog.truncate(hog.len() - 2);
+    let mut alice = TestClient::connect(addr);
+    alice.send(&join_message("alice", 9000));
+    std::thread::sleep(Duration::from_millis(300));
+
+    // 释放描述符后，暂停结束的服务器接受积压在 backlog 中的连接
+    drop(hog);
+    set_fd_limit(original);
+    alice.expect(MessageType::PeerList);
+    alice.expect(MessageType::Welcome);
+
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+}
@@ -0,0 +1,43 @@
+use p2p::audit::{AuditEvent, AuditLog, AuditRecord};
+use std::path::PathBuf;
+
+fn rotated(path: &std::path::Path, index: usize) -> PathBuf {
+    PathBuf::from(format!("{}.{}", path.display(), index))
+}
+
+#[test]
+fn audit_file_rotates_and_keeps_limited_history() {
+    let path = std::env::temp_dir().join(format!("p2p-audit-rotate-{}.jsonl", std::process::id()));
+    let files = [path.clone(), rotated(&path, 1), rotated(&path, 2), rotated(&path, 3)];
+    for file in &files {
+        let _ = std::fs::remove_file(file);
+    }
+
+    let audit = AuditLog::open(&path, 300, 2).unwrap();
+    for i in 0..30 {
+        audit.record(AuditEvent::Kick { user_id: format!("user-{}", i) });
+    }
+    drop(audit);
+
+    assert!(!files[3].exists());
+    let mut kept = Vec::new();
+    for file in files[..3].iter().rev() {
+        let text = std::fs::read_to_string(file).unwrap();
+        assert!(text.len() <= 300, "{} is {} bytes", file.display(), text.len());
+        for line in text.lines() {
+            let record: AuditRecord = serde_json::from_str(line).unwrap();
+            kept.push(record.event);
+        }
+    }
+    // 最旧的记录随轮转被删除，保留下来的是按顺序的最后若干条
+    let last = kept.len();
+    let expected: Vec<AuditEvent> = (30 - last..30)
+        .map(|i| AuditEvent::Kick { user_id: format!("user-{}", i) })
+        .collect();
+    assert_eq!(kept, expected);
+    assert!(last < 30);
+
+    for file in &files[..3] {
+        std::fs::remove_file(file).unwrap();
+    }
+}
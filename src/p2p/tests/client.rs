@@ -0,0 +1,1334 @@
+mod support;
+
+use p2p::client::{ClientCommand, ClientConfig, ClientEvent, ContentLimitPolicy, DeliveryPath, MessageTarget, P2PClient, PeerConnection, PeerLinkStatus, PendingMessage};
+use mio::Token;
+use p2p::health::Health;
+use p2p::common::{ErrorCode, Framing, GossipEntry, Message, MessageSource, MessageType, P2PError, PeerEntry, PeerGossip, PeerReachability, SignaturePolicy, sign_message, verify_message};
+use p2p::registry::Registry;
+use p2p::router::Router;
+use p2p::server::{P2PServer, ServerConfig};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime};
+use support::{chat_message, join_message, TestClient};
+
+#[test]
+fn new_returns_error_when_local_port_in_use() {
+    let occupied = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = occupied.local_addr().unwrap().port();
+
+    let result = P2PClient::new("127.0.0.1:8080", port, "alice".to_string());
+    match result {
+        Err(P2PError::Connection(msg)) => assert!(msg.contains(&port.to_string())),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("binding an occupied port should fail"),
+    }
+}
+
+#[test]
+fn new_returns_error_on_invalid_server_addr() {
+    let result = P2PClient::new("not an address", 0, "alice".to_string());
+    assert!(matches!(result, Err(P2PError::AddrParse(_))));
+}
+
+#[test]
+fn join_is_sent_once_connection_completes() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+    let mut observer = TestClient::join(addr, "observer");
+
+    let mut client = P2PClient::new(&addr.to_string(), 0, "alice".to_string()).unwrap();
+    client.connect().unwrap();
+    client.request_peer_list().unwrap();
+    for _ in 0..10 {
+        client.poll_once().unwrap();
+    }
+
+    assert_eq!(observer.expect(MessageType::UserJoined).sender_id, "alice");
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn blocking_peer_list_request_returns_the_list() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+    let _bob = TestClient::join(addr, "bob");
+
+    let mut alice = P2PClient::new(&addr.to_string(), 0, "alice".to_string()).unwrap();
+    assert!(matches!(alice.request_peer_list_blocking(Duration::from_secs(1)), Err(P2PError::Connection(_))));
+    alice.connect().unwrap();
+    let peers = alice.request_peer_list_blocking(Duration::from_secs(5)).unwrap();
+    assert_eq!(peers.iter().map(|peer| peer.user_id.as_str()).collect::<Vec<_>>(), ["bob"]);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn blocking_peer_list_request_times_out_without_a_response() {
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut alice = P2PClient::new(&silent.local_addr().unwrap().to_string(), 0, "alice".to_string()).unwrap();
+    alice.connect().unwrap();
+
+    let started = Instant::now();
+    let result = alice.request_peer_list_blocking(Duration::from_millis(300));
+    assert!(matches!(result, Err(P2PError::Timeout(_))));
+    assert!(started.elapsed() >= Duration::from_millis(300));
+}
+
+#[test]
+fn health_reflects_server_connection() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+
+    let mut alice = P2PClient::new(&addr.to_string(), 0, "alice".to_string()).unwrap();
+    assert_eq!(alice.health(), Health::Unhealthy);
+    alice.connect().unwrap();
+    for _ in 0..5 {
+        alice.poll_once().unwrap();
+    }
+    let status = alice.status();
+    assert_eq!(status.health, Health::Healthy);
+    assert_eq!(status.loop_lag.slow_iterations, 0);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn heartbeats_are_skipped_while_traffic_flows_both_ways() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = ClientConfig { heartbeat_interval: Duration::from_millis(400), ..ClientConfig::default() };
+    let mut alice = P2PClient::new_with_config(&server.local_addr().unwrap().to_string(), 0, "alice".to_string(), config).unwrap();
+    alice.connect().unwrap();
+    let mut link = TestClient::accept(&server);
+    link.set_read_timeout(Duration::from_millis(10));
+    // 扮演服务器：确认收到的心跳，返回心跳数
+    let count_heartbeats = |link: &mut TestClient| {
+        let heartbeats: Vec<Message> = std::iter::from_fn(|| link.recv())
+            .filter(|message| message.msg_type == MessageType::Heartbeat)
+            .collect();
+        for heartbeat in &heartbeats {
+            link.send(&Message::new(MessageType::HeartbeatAck, "SERVER".to_string()).with_content(heartbeat.content.clone().unwrap_or_default()));
+        }
+        heartbeats.len()
+    };
+    let mut heartbeats = 0;
+
+    // 持续三个心跳间隔双向都有消息，期间不应发送心跳
+    let busy_until = Instant::now() + Duration::from_millis(1200);
+    while Instant::now() < busy_until {
+        alice.send_smart_message(None, "busy".to_string()).unwrap();
+        link.send(&Message::new(MessageType::System, "SERVER".to_string()).with_content("tick".to_string()));
+        alice.poll_once().unwrap();
+        heartbeats += count_heartbeats(&mut link);
+    }
+    assert_eq!(heartbeats, 0);
+
+    // 安静下来后恢复按间隔发送
+    let quiet_until = Instant::now() + Duration::from_millis(1000);
+    while Instant::now() < quiet_until {
+        alice.poll_once().unwrap();
+        heartbeats += count_heartbeats(&mut link);
+    }
+    assert!(heartbeats >= 1);
+}
+
+#[test]
+fn missed_heartbeat_acks_mark_server_disconnected() {
+    // 只接受连接、从不回复的“服务器”
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = silent.local_addr().unwrap();
+    let config = ClientConfig {
+        heartbeat_interval: Duration::from_millis(50),
+        max_missed_heartbeat_acks: 2,
+        ..ClientConfig::default()
+    };
+
+    let mut client = P2PClient::new_with_config(&addr.to_string(), 0, "alice".to_string(), config).unwrap();
+    client.connect().unwrap();
+    let (_conn, _) = silent.accept().unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while client.is_connected() && Instant::now() < deadline {
+        client.poll_once().unwrap();
+    }
+    assert!(!client.is_connected());
+}
+
+#[test]
+fn same_text_sent_publicly_and_privately_is_delivered_twice() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+
+    let mut alice = P2PClient::new(&addr.to_string(), 0, "alice".to_string()).unwrap();
+    let events = alice.take_event_receiver().unwrap();
+    alice.connect().unwrap();
+    let mut bob = P2PClient::new(&addr.to_string(), 0, "bob".to_string()).unwrap();
+    bob.connect().unwrap();
+    for _ in 0..5 {
+        alice.poll_once().unwrap();
+        bob.poll_once().unwrap();
+    }
+
+    bob.send_smart_message(None, "same words".to_string()).unwrap();
+    bob.send_smart_message(Some("alice".to_string()), "same words".to_string()).unwrap();
+
+    let mut chats = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while chats.len() < 2 && Instant::now() < deadline {
+        bob.poll_once().unwrap();
+        alice.poll_once().unwrap();
+        while let Ok(event) = events.try_recv() {
+            if let ClientEvent::ChatReceived { sender_id, target_id, content, .. } = event {
+                chats.push((sender_id, target_id, content));
+            }
+        }
+    }
+
+    assert_eq!(chats, vec![
+        ("bob".to_string(), None, "same words".to_string()),
+        ("bob".to_string(), Some("alice".to_string()), "same words".to_string()),
+    ]);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn oversized_message_is_rejected_before_queueing() {
+    let config = ClientConfig { max_message_bytes: 512, ..ClientConfig::default() };
+    let client = P2PClient::new_with_config("127.0.0.1:8080", 0, "alice".to_string(), config).unwrap();
+
+    let result = client.send_smart_message(None, "x".repeat(1024));
+    match result {
+        Err(P2PError::MessageTooLarge { size, max }) => {
+            assert_eq!(max, 512);
+            assert!(size > 1024);
+        }
+        other => panic!("expected MessageTooLarge, got {:?}", other),
+    }
+    assert!(client.send_smart_message(None, "short".to_string()).is_ok());
+}
+
+#[test]
+fn long_content_is_rejected_or_truncated_per_policy() {
+    let config = ClientConfig { max_content_chars: Some(4), ..ClientConfig::default() };
+    let client = P2PClient::new_with_config("127.0.0.1:8080", 0, "alice".to_string(), config).unwrap();
+    assert_eq!(client.send_smart_message(None, "你好世界！".to_string()), Err(P2PError::ContentTooLong { chars: 5, max: 4 }));
+    assert!(client.send_smart_message(None, "你好世界".to_string()).is_ok());
+
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+    let mut bob = TestClient::join(addr, "bob");
+    let config = ClientConfig {
+        max_content_chars: Some(4),
+        content_limit_policy: ContentLimitPolicy::Truncate,
+        ..ClientConfig::default()
+    };
+    let mut alice = P2PClient::new_with_config(&addr.to_string(), 0, "alice".to_string(), config).unwrap();
+    alice.connect().unwrap();
+    alice.send_smart_message(None, "你好世界！再见".to_string()).unwrap();
+    for _ in 0..5 {
+        alice.poll_once().unwrap();
+    }
+    assert_eq!(bob.expect(MessageType::Chat).content.as_deref(), Some("你好世界"));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn signed_messages_are_verified_and_forgeries_dropped_under_reject() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+    let mut bob = TestClient::join(addr, "bob");
+    let config = ClientConfig {
+        signing_key: Some("k".to_string()),
+        signature_policy: SignaturePolicy::Reject,
+        ..ClientConfig::default()
+    };
+    let mut alice = P2PClient::new_with_config(&addr.to_string(), 0, "alice".to_string(), config).unwrap();
+    let events = alice.take_event_receiver().unwrap();
+    alice.connect().unwrap();
+    for _ in 0..5 {
+        alice.poll_once().unwrap();
+    }
+
+    // alice 发出的消息带有可验证的签名
+    alice.send_smart_message(None, "hello".to_string()).unwrap();
+    for _ in 0..5 {
+        alice.poll_once().unwrap();
+    }
+    assert!(verify_message(&bob.expect(MessageType::Chat), b"k"));
+
+    let mut forged = chat_message("bob", None, "forged");
+    forged.signature = Some(sign_message(&forged, b"wrong"));
+    bob.send(&forged);
+    let mut genuine = chat_message("bob", None, "genuine");
+    genuine.signature = Some(sign_message(&genuine, b"k"));
+    bob.send(&genuine);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut received = Vec::new();
+    while !received.iter().any(|(content, _)| content == "genuine") && Instant::now() < deadline {
+        alice.poll_once().unwrap();
+        while let Ok(event) = events.try_recv() {
+            if let ClientEvent::ChatReceived { sender_id, content, verified, .. } = event {
+                if sender_id == "bob" {
+                    received.push((content, verified));
+                }
+            }
+        }
+    }
+    assert_eq!(received, vec![("genuine".to_string(), Some(true))]);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+/// 轮询直到条件满足，超时则失败
+fn poll_client_until(client: &mut P2PClient, what: &str, done: impl Fn(&P2PClient) -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done(client) {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        client.poll_once().unwrap();
+    }
+}
+
+fn peer_state(client: &P2PClient, peer_id: &str) -> Option<(PeerReachability, Option<SystemTime>)> {
+    client.peers().into_iter().find(|info| info.user_id == peer_id).map(|info| (info.reachability, info.last_seen))
+}
+
+#[test]
+fn peer_reachability_and_last_seen_follow_the_link_lifecycle() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+    let bob_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut bob = TestClient::connect(addr);
+    bob.send(&join_message("bob", bob_listener.local_addr().unwrap().port()));
+    bob.expect(MessageType::PeerList);
+
+    let mut alice = P2PClient::new(&addr.to_string(), 0, "alice".to_string()).unwrap();
+    let events = alice.take_event_receiver().unwrap();
+    alice.connect().unwrap();
+    poll_client_until(&mut alice, "bob in the peer list", |alice| peer_state(alice, "bob").is_some());
+    assert_eq!(peer_state(&alice, "bob"), Some((PeerReachability::Known, None)));
+
+    alice.connect_to_peer("bob").unwrap();
+    assert_eq!(peer_state(&alice, "bob").unwrap().0, PeerReachability::Connecting);
+    let mut link = TestClient::accept(&bob_listener);
+    assert_eq!(link.expect(MessageType::Hello).sender_id, "alice");
+    link.send(&Message::new(MessageType::Hello, "bob".to_string()).with_peer_info("127.0.0.1".to_string(), 0));
+    poll_client_until(&mut alice, "handshake", |alice| peer_state(alice, "bob").unwrap().0 == PeerReachability::Connected);
+    let first_seen = peer_state(&alice, "bob").unwrap().1.expect("Hello counts as seen");
+
+    // 经服务器转发的消息同样更新 last_seen，但不改变直连状态
+    std::thread::sleep(Duration::from_millis(20));
+    bob.send(&chat_message("bob", None, "still here"));
+    poll_client_until(&mut alice, "relayed chat", |alice| peer_state(alice, "bob").unwrap().1 > Some(first_seen));
+    assert_eq!(peer_state(&alice, "bob").unwrap().0, PeerReachability::Connected);
+
+    drop(link);
+    poll_client_until(&mut alice, "link loss", |alice| peer_state(alice, "bob").unwrap().0 == PeerReachability::Unreachable);
+    drop(bob);
+    poll_client_until(&mut alice, "UserLeft", |alice| peer_state(alice, "bob").is_none());
+
+    let transitions: Vec<_> = events.try_iter()
+        .filter_map(|event| match event {
+            ClientEvent::PeerStatusChanged { peer_id, status, last_seen } if peer_id == "bob" => Some((status, last_seen.is_some())),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(transitions, vec![
+        (PeerReachability::Connecting, false),
+        (PeerReachability::Connected, true),
+        (PeerReachability::Unreachable, true),
+    ]);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn user_left_marks_a_connected_peer_unreachable() {
+    let mut alice = P2PClient::new_testing("alice".to_string()).unwrap();
+    let events = alice.take_event_receiver().unwrap();
+    alice.inject_received(Message::peer_list(&[PeerEntry::new("bob".to_string(), "127.0.0.1".to_string(), 9001)])).unwrap();
+    alice.connect_to_peer("bob").unwrap();
+    assert_eq!(peer_state(&alice, "bob").unwrap().0, PeerReachability::Connected);
+
+    alice.inject_received(Message::new(MessageType::UserLeft, "bob".to_string()).with_content("Left".to_string())).unwrap();
+    assert_eq!(peer_state(&alice, "bob"), None);
+    let statuses: Vec<_> = events.try_iter()
+        .filter_map(|event| match event {
+            ClientEvent::PeerStatusChanged { status, .. } => Some(status),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(statuses, vec![PeerReachability::Connected, PeerReachability::Unreachable]);
+}
+
+#[test]
+fn send_with_fallback_routes_through_server_when_peer_unreachable() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+    // bob 声明的监听端口上没有任何程序，直连必然失败
+    let dead_port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut bob = TestClient::connect(addr);
+    bob.send(&join_message("bob", dead_port));
+    bob.expect(MessageType::PeerList);
+
+    let mut alice = P2PClient::new(&addr.to_string(), 0, "alice".to_string()).unwrap();
+    let events = alice.take_event_receiver().unwrap();
+    alice.connect().unwrap();
+    for _ in 0..5 {
+        alice.poll_once().unwrap();
+    }
+
+    let path = alice.send_with_fallback("bob", "are you there?".to_string()).unwrap();
+    assert_eq!(path, DeliveryPath::Server);
+    alice.poll_once().unwrap();
+    assert_eq!(bob.expect(MessageType::Chat).content.as_deref(), Some("are you there?"));
+    assert!(events.try_iter().any(|event| matches!(
+        event,
+        ClientEvent::MessageSent { ref target_id, path: DeliveryPath::Server } if target_id == "bob"
+    )));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn connect_to_peer_reports_token_and_address() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+    let bob_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let bob_addr = bob_listener.local_addr().unwrap();
+    let mut bob = TestClient::connect(addr);
+    bob.send(&join_message("bob", bob_addr.port()));
+    bob.expect(MessageType::PeerList);
+
+    let mut alice = P2PClient::new(&addr.to_string(), 0, "alice".to_string()).unwrap();
+    alice.connect().unwrap();
+    for _ in 0..5 {
+        alice.poll_once().unwrap();
+    }
+
+    let PeerConnection::Connected { token, addr: Some(peer_addr) } = alice.connect_to_peer("bob").unwrap() else {
+        panic!("expected a new connection to bob");
+    };
+    assert_eq!(peer_addr, bob_addr);
+    assert_eq!(alice.connect_to_peer("bob").unwrap(), PeerConnection::AlreadyConnected(token));
+    assert_eq!(alice.connect_to_peer("nobody"), Err(P2PError::PeerNotFound("nobody".to_string())));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn peer_dials_use_configured_outbound_address() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+    let bob_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut bob = TestClient::connect(addr);
+    bob.send(&join_message("bob", bob_listener.local_addr().unwrap().port()));
+    bob.expect(MessageType::PeerList);
+
+    let source = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let config = ClientConfig::default().with_outbound_bind_addr(source);
+    let mut alice = P2PClient::new_with_config(&addr.to_string(), 0, "alice".to_string(), config).unwrap();
+    alice.connect().unwrap();
+    for _ in 0..5 {
+        alice.poll_once().unwrap();
+    }
+
+    alice.connect_to_peer("bob").unwrap();
+    let (_, dialed_from) = bob_listener.accept().unwrap();
+    assert_eq!(dialed_from, source);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn dial_failures_carry_the_peer_and_operation_in_their_context() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+    let bob_port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut bob = TestClient::connect(addr);
+    bob.send(&join_message("bob", bob_port));
+    bob.expect(MessageType::PeerList);
+
+    // IPv6 的本地地址无法用来连接 IPv4 的节点，拨号同步失败
+    let config = ClientConfig::default().with_outbound_bind_addr("[::1]:0".parse().unwrap());
+    let mut alice = P2PClient::new_with_config(&addr.to_string(), 0, "alice".to_string(), config).unwrap();
+    alice.connect().unwrap();
+    for _ in 0..5 {
+        alice.poll_once().unwrap();
+    }
+
+    let error = alice.connect_to_peer("bob").unwrap_err();
+    let formatted = error.to_string();
+    assert!(formatted.starts_with(&format!("连接对等节点 bob: 拨号 127.0.0.1:{}: IO error: ", bob_port)), "{}", formatted);
+    assert!(matches!(error.root_cause(), P2PError::Io(e) if e.kind() == std::io::ErrorKind::InvalidInput), "{:?}", error);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn private_message_to_offline_user_emits_failed_send_result() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+
+    let mut alice = P2PClient::new(&addr.to_string(), 0, "alice".to_string()).unwrap();
+    let events = alice.take_event_receiver().unwrap();
+    alice.connect().unwrap();
+    for _ in 0..5 {
+        alice.poll_once().unwrap();
+    }
+    alice.send_smart_message(Some("ghost".to_string()), "anyone?".to_string()).unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut result = None;
+    while result.is_none() && Instant::now() < deadline {
+        alice.poll_once().unwrap();
+        result = events.try_iter().find_map(|event| match event {
+            ClientEvent::SendResult { msg_id, target_id, result } => Some((msg_id, target_id, result)),
+            _ => None,
+        });
+    }
+    let (msg_id, target_id, result) = result.expect("no SendResult event");
+    assert!(msg_id.is_some());
+    assert_eq!(target_id, "ghost");
+    assert_eq!(result, Err(ErrorCode::TargetOffline));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+/// 轮询两个客户端，直到 events 中出现满足条件的事件
+fn poll_until(alice: &mut P2PClient, bob: &mut P2PClient, events: &mpsc::Receiver<ClientEvent>, wanted: &dyn Fn(&ClientEvent) -> bool) -> ClientEvent {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        alice.poll_once().unwrap();
+        bob.poll_once().unwrap();
+        if let Some(event) = events.try_iter().find(|event| wanted(event)) {
+            return event;
+        }
+    }
+    panic!("expected event was not received");
+}
+
+#[test]
+fn reactions_and_edits_reference_earlier_messages() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+
+    let mut alice = P2PClient::new(&addr.to_string(), 0, "alice".to_string()).unwrap();
+    let alice_events = alice.take_event_receiver().unwrap();
+    alice.connect().unwrap();
+    let mut bob = P2PClient::new(&addr.to_string(), 0, "bob".to_string()).unwrap();
+    let bob_events = bob.take_event_receiver().unwrap();
+    bob.connect().unwrap();
+    for _ in 0..5 {
+        alice.poll_once().unwrap();
+        bob.poll_once().unwrap();
+    }
+
+
+    alice.send_smart_message(None, "lunch?".to_string()).unwrap();
+    let chat = poll_until(&mut alice, &mut bob, &bob_events, &|event| matches!(event, ClientEvent::ChatReceived { .. }));
+    let ClientEvent::ChatReceived { msg_id: Some(msg_id), .. } = chat else {
+        panic!("chat without msg_id: {:?}", chat);
+    };
+
+    bob.react(None, msg_id.clone(), "👍".to_string()).unwrap();
+    let reaction = poll_until(&mut alice, &mut bob, &alice_events, &|event| matches!(event, ClientEvent::ReactionReceived { .. }));
+    assert!(matches!(reaction, ClientEvent::ReactionReceived { ref target_msg_id, ref emoji, known: true, .. }
+        if *target_msg_id == msg_id && emoji == "👍"));
+
+    // bob 冒名编辑 alice 的消息会被丢弃，alice 自己的编辑才会送达
+    bob.edit_message(None, msg_id.clone(), "forged".to_string()).unwrap();
+    alice.edit_message(None, msg_id.clone(), "lunch at 12?".to_string()).unwrap();
+    let edit = poll_until(&mut alice, &mut bob, &bob_events, &|event| matches!(event, ClientEvent::MessageEdited { .. }));
+    assert!(matches!(edit, ClientEvent::MessageEdited { ref sender_id, ref new_content, known: true, .. }
+        if sender_id == "alice" && new_content == "lunch at 12?"));
+
+    // 引用本地未知的消息时仍然发出事件，由界面决定是否忽略
+    alice.react(None, "unknown-id".to_string(), "🎉".to_string()).unwrap();
+    let unknown = poll_until(&mut alice, &mut bob, &bob_events, &|event| matches!(event, ClientEvent::ReactionReceived { .. }));
+    assert!(matches!(unknown, ClientEvent::ReactionReceived { known: false, .. }));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn testing_client_records_sends_with_their_targets() {
+    let mut client = P2PClient::new_testing("alice".to_string()).unwrap();
+    client.connect_to_peer("bob").unwrap();
+
+    client.send_smart_message(Some("bob".to_string()), "direct".to_string()).unwrap();
+    client.send_smart_message(Some("carol".to_string()), "relayed".to_string()).unwrap();
+
+    let sent = client.sent_pending().unwrap();
+    assert_eq!(sent.len(), 2);
+    assert!(matches!(sent[0].target, MessageTarget::Peer(_)));
+    assert_eq!(sent[0].message.msg_type, MessageType::Chat);
+    assert_eq!(sent[0].message.sender_id, "alice");
+    assert_eq!(sent[0].message.target_id.as_deref(), Some("bob"));
+    assert!(sent[0].message.msg_id.is_some());
+    assert!(matches!(sent[1].target, MessageTarget::Server));
+    assert_eq!(sent[1].message.content.as_deref(), Some("relayed"));
+
+    let contents: Vec<_> = client.sent_messages().unwrap().into_iter().filter_map(|message| message.content).collect();
+    assert_eq!(contents, vec!["direct", "relayed"]);
+}
+
+#[test]
+fn invalid_messages_are_rejected_at_the_source() {
+    let mut client = P2PClient::new_testing("alice".to_string()).unwrap();
+
+    let result = client.send_smart_message(None, String::new());
+    assert!(matches!(result, Err(P2PError::InvalidMessage { msg_type: MessageType::Chat, code: ErrorCode::MalformedMessage, .. })), "{:?}", result);
+    let result = client.edit_message(None, String::new(), "fixed".to_string());
+    assert!(matches!(result, Err(P2PError::InvalidMessage { msg_type: MessageType::Edit, .. })), "{:?}", result);
+
+    // 绕过发送方法直接投递到通道的消息在发送时被丢弃
+    let sender = client.get_message_sender();
+    let connect = Message::new(MessageType::ConnectRequest, "alice".to_string());
+    sender.send(PendingMessage { target: MessageTarget::Server, message: connect }).unwrap();
+    sender.send(PendingMessage { target: MessageTarget::Server, message: chat_message("alice", None, "ok") }).unwrap();
+
+    let sent = client.sent_messages().unwrap();
+    assert_eq!(sent.iter().map(|message| message.msg_type.clone()).collect::<Vec<_>>(), vec![MessageType::Chat]);
+}
+
+#[test]
+fn guest_client_adopts_server_assigned_id() {
+    let config = p2p::server::ServerConfig { allow_guests: true, ..Default::default() };
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral_with_config(config).unwrap();
+
+    let mut guest = P2PClient::new(&addr.to_string(), 0, String::new()).unwrap();
+    guest.connect().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while guest.user_id().is_empty() && Instant::now() < deadline {
+        guest.poll_once().unwrap();
+    }
+    assert!(guest.user_id().starts_with("guest-"));
+
+    let outgoing = guest.create_smart_chat_message(None, "hello".to_string());
+    assert_eq!(outgoing.message.sender_id, guest.user_id());
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn length_prefixed_client_chats_with_newline_client() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+
+    let mut bob = P2PClient::new(&addr.to_string(), 0, "bob".to_string()).unwrap();
+    let bob_events = bob.take_event_receiver().unwrap();
+    bob.connect().unwrap();
+    let config = ClientConfig { framings: vec![Framing::LengthPrefixed, Framing::Newline], ..ClientConfig::default() };
+    let mut alice = P2PClient::new_with_config(&addr.to_string(), 0, "alice".to_string(), config).unwrap();
+    let alice_events = alice.take_event_receiver().unwrap();
+    poll_until(&mut alice, &mut bob, &bob_events, &|event| matches!(event, ClientEvent::ServerInfo(_)));
+
+    // 在欢迎消息到达之前就排队的消息也要按协商出的格式发出
+    alice.connect().unwrap();
+    alice.send_smart_message(None, "early".to_string()).unwrap();
+    let info = poll_until(&mut alice, &mut bob, &alice_events, &|event| matches!(event, ClientEvent::ServerInfo(_)));
+    assert!(matches!(info, ClientEvent::ServerInfo(ref info) if info.framing == Framing::LengthPrefixed));
+    let chat = poll_until(&mut alice, &mut bob, &bob_events, &|event| matches!(event, ClientEvent::ChatReceived { .. }));
+    assert!(matches!(chat, ClientEvent::ChatReceived { ref content, .. } if content == "early"));
+
+    bob.send_smart_message(None, "reply".to_string()).unwrap();
+    // 公共消息也会回显给发送方，这里只等 bob 的回复
+    poll_until(&mut alice, &mut bob, &alice_events, &|event| matches!(event, ClientEvent::ChatReceived { content, .. } if content == "reply"));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn raw_messages_are_emitted_before_derived_events() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+
+    let mut alice = P2PClient::new(&addr.to_string(), 0, "alice".to_string()).unwrap();
+    let events = alice.take_event_receiver().unwrap();
+    alice.connect().unwrap();
+    let mut seen = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !seen.iter().any(|event| matches!(event, ClientEvent::ServerInfo(_))) && Instant::now() < deadline {
+        alice.poll_once().unwrap();
+        seen.extend(events.try_iter());
+    }
+
+    let welcome = seen.iter().position(|event| matches!(event, ClientEvent::MessageReceived(message) if message.msg_type == MessageType::Welcome));
+    let info = seen.iter().position(|event| matches!(event, ClientEvent::ServerInfo(_)));
+    assert!(welcome.unwrap() < info.unwrap());
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn server_errors_are_emitted_as_events() {
+    let mut alice = P2PClient::new_testing("alice".to_string()).unwrap();
+    let events = alice.take_event_receiver().unwrap();
+
+    let mut error = Message::new(MessageType::Error, "server".to_string()).with_content("join before using rooms".to_string());
+    error.error_code = Some(ErrorCode::NotJoined);
+    error.reply_to = Some("m1".to_string());
+    alice.inject_received(error).unwrap();
+
+    let errors: Vec<_> = events.try_iter()
+        .filter_map(|event| match event {
+            ClientEvent::ServerError { code, detail, reply_to } => Some((code, detail, reply_to)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(errors, vec![(Some(ErrorCode::NotJoined), "join before using rooms".to_string(), Some("m1".to_string()))]);
+}
+
+#[test]
+fn unhandled_message_types_are_passed_to_the_application() {
+    let mut alice = P2PClient::new_testing("alice".to_string()).unwrap();
+    let events = alice.take_event_receiver().unwrap();
+
+    let mut custom = Message::new(MessageType::ConnectRequest, "bob".to_string())
+        .with_target("alice".to_string())
+        .with_content("{\"game\":\"chess\"}".to_string());
+    custom.source = MessageSource::Peer;
+    alice.inject_received(custom.clone()).unwrap();
+    alice.inject_received(Message::new(MessageType::System, "SERVER".to_string()).with_content("hi".to_string())).unwrap();
+
+    let unhandled: Vec<_> = events.try_iter()
+        .filter_map(|event| match event {
+            ClientEvent::Unhandled(message) => Some(message),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(unhandled.len(), 1);
+    assert_eq!(unhandled[0].msg_type, MessageType::ConnectRequest);
+    assert_eq!(unhandled[0].content, custom.content);
+}
+
+#[test]
+fn server_and_peer_deliveries_merge_into_one_conversation() {
+    let mut alice = P2PClient::new_testing("alice".to_string()).unwrap();
+    let events = alice.take_event_receiver().unwrap();
+    alice.connect_to_peer("bob").unwrap();
+
+    let first = chat_message("bob", Some("alice"), "first").with_generated_msg_id();
+    let second = chat_message("bob", Some("alice"), "second").with_generated_msg_id();
+    let mut via_peer = second.clone();
+    via_peer.source = MessageSource::Peer;
+
+    // 直连先送达第二条，随后经服务器到达第一条和重复的第二条
+    alice.inject_received(via_peer).unwrap();
+    alice.inject_received(first).unwrap();
+    alice.inject_received(second).unwrap();
+
+    let chats: Vec<(String, MessageSource, bool)> = events.try_iter()
+        .filter_map(|event| match event {
+            ClientEvent::ChatReceived { content, source, late, .. } => Some((content, source, late)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(chats, vec![
+        ("second".to_string(), MessageSource::Peer, false),
+        ("first".to_string(), MessageSource::Server, true),
+    ]);
+}
+
+#[test]
+fn reorder_window_releases_chats_in_send_order() {
+    let config = ClientConfig { reorder_window: Some(Duration::from_millis(150)), ..ClientConfig::default() };
+    let mut alice = P2PClient::new_testing_with_config("alice".to_string(), config).unwrap();
+    let events = alice.take_event_receiver().unwrap();
+    let chats = |events: &mpsc::Receiver<ClientEvent>| -> Vec<(String, String, bool)> {
+        events.try_iter()
+            .filter_map(|event| match event {
+                ClientEvent::ChatReceived { sender_id, content, late, .. } => Some((sender_id, content, late)),
+                _ => None,
+            })
+            .collect()
+    };
+
+    let question = chat_message("bob", Some("alice"), "question").with_generated_msg_id();
+    let lost = chat_message("bob", Some("alice"), "lost").with_generated_msg_id();
+    let reply = chat_message("bob", Some("alice"), "reply").with_generated_msg_id();
+    let mut via_peer = reply.clone();
+    via_peer.source = MessageSource::Peer;
+    // 没有 msg_id 的消息按发送时间排序，早于上面三条
+    let mut legacy = chat_message("carol", None, "legacy");
+    legacy.timestamp -= Duration::from_secs(1);
+    let mut unsequenced = chat_message("carol", None, "unsequenced");
+    unsequenced.timestamp -= Duration::from_secs(2);
+
+    // 直连先送达回复，问题经服务器随后到达；"lost" 始终没有到达
+    let started = Instant::now();
+    alice.inject_received(via_peer).unwrap();
+    alice.inject_received(legacy).unwrap();
+    alice.inject_received(question).unwrap();
+    alice.inject_received(unsequenced).unwrap();
+    alice.inject_received(reply).unwrap();
+    assert!(chats(&events).is_empty(), "chats are held for the reorder window");
+
+    let mut received = Vec::new();
+    while received.len() < 4 {
+        assert!(started.elapsed() < Duration::from_secs(5), "held chats were never released: {:?}", received);
+        alice.poll_once().unwrap();
+        received.extend(chats(&events));
+    }
+    assert!(started.elapsed() >= Duration::from_millis(150));
+    // 不同发送方之间没有顺序要求，同一发送方按发送顺序放行
+    let from = |sender: &str| -> Vec<(&str, bool)> {
+        received.iter()
+            .filter(|(sender_id, _, _)| sender_id == sender)
+            .map(|(_, content, late)| (content.as_str(), *late))
+            .collect()
+    };
+    assert_eq!(from("bob"), vec![("question", false), ("reply", false)]);
+    assert_eq!(from("carol"), vec![("unsequenced", false), ("legacy", false)]);
+
+    // 窗口过后才到达的更早消息不再等待，直接投递并标记为迟到
+    alice.inject_received(lost).unwrap();
+    assert_eq!(chats(&events), vec![("bob".to_string(), "lost".to_string(), true)]);
+}
+
+#[test]
+fn server_bound_messages_survive_backpressure() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut client = P2PClient::new(&addr.to_string(), 0, "alice".to_string()).unwrap();
+    client.connect().unwrap();
+    let (server_side, _) = listener.accept().unwrap();
+
+    // 服务器暂不读取，几 MB 数据会超出内核缓冲区，剩余部分留在客户端的写缓冲中
+    let content = "x".repeat(60_000);
+    for _ in 0..100 {
+        client.send_smart_message(None, content.clone()).unwrap();
+    }
+    for _ in 0..5 {
+        client.poll_once().unwrap();
+    }
+
+    server_side.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let reader = std::thread::spawn(move || {
+        use std::io::BufRead;
+        std::io::BufReader::new(server_side).lines().map_while(Result::ok).take(101).count()
+    });
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !reader.is_finished() && Instant::now() < deadline {
+        client.poll_once().unwrap();
+    }
+    // Join + 100 条聊天
+    assert_eq!(reader.join().unwrap(), 101);
+}
+
+#[test]
+fn peer_list_deltas_resync_after_a_gap_and_beat_full_lists() {
+    let config = ServerConfig {
+        heartbeat_interval: Duration::from_secs(3600),
+        peer_timeout: Duration::from_secs(7200),
+        ..ServerConfig::default()
+    };
+    let mut router = Router::new(config, Registry::in_memory());
+    let observer_token = Token(1);
+    router.connection_opened(observer_token, "127.0.0.1:5000".parse().unwrap());
+    let mut observer = P2PClient::new_testing("observer".to_string()).unwrap();
+    let events = observer.take_event_receiver().unwrap();
+    for message in router.route(&join_message("observer", 9000), observer_token).messages_to(observer_token) {
+        observer.inject_received(message.clone()).unwrap();
+    }
+
+    // 120 个用户依次加入，随后前 80 个依次离开；每次变化后合并窗口都已结束
+    let mut now = Instant::now();
+    let (mut delta_bytes, mut full_bytes, mut requests_handled) = (0, 0, 0);
+    for step in 0..200usize {
+        let (user, token) = if step < 120 {
+            (format!("user{}", step), Token(10 + step))
+        } else {
+            (format!("user{}", step - 120), Token(10 + step - 120))
+        };
+        if step < 120 {
+            router.connection_opened(token, format!("127.0.0.1:{}", 6000 + step).parse().unwrap());
+            router.route(&join_message(&user, 7000 + step as u16), token);
+        } else {
+            router.route(&Message::new(MessageType::Leave, user), token);
+        }
+        now += Duration::from_secs(1);
+        let output = router.tick(now);
+
+        let full_list = router.route(&Message::new(MessageType::PeerListRequest, "observer".to_string()), observer_token);
+        let full_list_bytes = full_list.messages_to(observer_token)[0].size_bytes();
+        for (recipient, message) in output.pairs() {
+            assert_eq!(message.msg_type, MessageType::PeerListDelta);
+            delta_bytes += message.size_bytes();
+            full_bytes += full_list_bytes;
+            // 模拟观察者漏掉一次增量
+            if recipient == observer_token && step != 50 {
+                observer.inject_received(message.clone()).unwrap();
+            }
+        }
+
+        // 观察者发现缺口后请求完整列表
+        let sent = observer.sent_messages().unwrap();
+        for request in &sent[requests_handled..] {
+            assert_eq!(request.msg_type, MessageType::PeerListRequest);
+            for message in router.route(request, observer_token).messages_to(observer_token) {
+                observer.inject_received(message.clone()).unwrap();
+            }
+        }
+        requests_handled = sent.len();
+    }
+
+    assert_eq!(requests_handled, 1);
+    let Some(ClientEvent::PeerListUpdated { version, peers }) = events.try_iter()
+        .filter(|event| matches!(event, ClientEvent::PeerListUpdated { .. }))
+        .last() else {
+        panic!("observer never updated its peer list");
+    };
+    assert_eq!(version, router.peer_list_version());
+    let mut known: Vec<String> = peers.into_iter().map(|peer| peer.user_id).collect();
+    let mut expected: Vec<String> = router.peers().map(|(_, info)| info.user_id.clone()).filter(|id| id != "observer").collect();
+    known.sort();
+    expected.sort();
+    assert_eq!(known.len(), 40);
+    assert_eq!(known, expected);
+    assert!(delta_bytes * 4 < full_bytes, "deltas {} bytes vs full lists {} bytes", delta_bytes, full_bytes);
+}
+
+#[test]
+fn large_peer_lists_are_paginated_and_reassembled() {
+    let config = ServerConfig {
+        heartbeat_interval: Duration::from_secs(3600),
+        peer_timeout: Duration::from_secs(7200),
+        ..ServerConfig::default()
+    };
+    let max_message_size = config.max_message_size;
+    let mut router = Router::new(config, Registry::in_memory());
+    for i in 0..5000usize {
+        let token = Token(10 + i);
+        router.connection_opened(token, format!("10.0.{}.{}:7000", i / 256, i % 256).parse().unwrap());
+        router.route(&join_message(&format!("synthetic-peer-{:04}", i), 7000), token);
+    }
+
+    let observer_token = Token(1);
+    router.connection_opened(observer_token, "127.0.0.1:5000".parse().unwrap());
+    router.route(&join_message("observer", 9000), observer_token);
+    let mut observer = P2PClient::new_testing("observer".to_string()).unwrap();
+    let events = observer.take_event_receiver().unwrap();
+
+    let output = router.route(&Message::new(MessageType::PeerListRequest, "observer".to_string()), observer_token);
+    let pages = output.messages_to(observer_token);
+    assert_eq!(pages.len(), 11);
+    for (index, page) in pages.iter().enumerate() {
+        assert_eq!(page.msg_type, MessageType::PeerList);
+        assert_eq!(page.page, Some(index as u32));
+        assert_eq!(page.total_pages, Some(11));
+        assert_eq!(page.peer_list_version, Some(router.peer_list_version()));
+        assert!(page.size_bytes() <= max_message_size, "page {} is {} bytes", index, page.size_bytes());
+    }
+    // 页乱序到达也能拼出完整列表，且只在收齐后更新一次
+    for page in pages.iter().rev() {
+        observer.inject_received((*page).clone()).unwrap();
+    }
+    let updates: Vec<ClientEvent> = events.try_iter()
+        .filter(|event| matches!(event, ClientEvent::PeerListUpdated { .. }))
+        .collect();
+    assert_eq!(updates.len(), 1);
+    let ClientEvent::PeerListUpdated { version, peers } = &updates[0] else { unreachable!() };
+    assert_eq!(*version, router.peer_list_version());
+    assert_eq!(peers.len(), 5000);
+    assert!(peers.iter().any(|peer| peer.user_id == "synthetic-peer-4999" && peer.address == "10.0.19.135"));
+
+    // 单独请求某一页
+    let mut request = Message::new(MessageType::PeerListRequest, "observer".to_string());
+    request.page = Some(3);
+    let output = router.route(&request, observer_token);
+    let single = output.messages_to(observer_token);
+    assert_eq!(single.len(), 1);
+    assert_eq!(single[0].page, Some(3));
+}
+
+#[test]
+fn peer_list_change_between_pages_refetches_the_whole_list() {
+    let page = |version: u64, page: u32, peers: &[&str]| {
+        let entries: Vec<PeerEntry> = peers.iter().map(|id| PeerEntry::new(id.to_string(), "127.0.0.1".to_string(), 7000)).collect();
+        let mut message = Message::new(MessageType::PeerList, "server".to_string()).with_content(serde_json::to_string(&entries).unwrap());
+        message.page = Some(page);
+        message.total_pages = Some(2);
+        message.peer_list_version = Some(version);
+        message
+    };
+    let mut observer = P2PClient::new_testing("observer".to_string()).unwrap();
+    let events = observer.take_event_receiver().unwrap();
+
+    // 逐页拉取：第 0 页是 v5，拉第 1 页时列表已经变成 v6
+    observer.inject_received(page(5, 0, &["alice", "bob"])).unwrap();
+    observer.inject_received(page(6, 1, &["dave"])).unwrap();
+    let requests: Vec<Message> = observer.sent_messages().unwrap().into_iter()
+        .filter(|message| message.msg_type == MessageType::PeerListRequest)
+        .collect();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].page, None);
+
+    // 迟到的旧版本页被忽略，新版本收齐后只应用一次，不会混入 v5 的节点
+    observer.inject_received(page(5, 1, &["carol"])).unwrap();
+    observer.inject_received(page(6, 0, &["alice", "carol"])).unwrap();
+    observer.inject_received(page(6, 1, &["dave"])).unwrap();
+    let updates: Vec<(u64, Vec<String>)> = events.try_iter()
+        .filter_map(|event| match event {
+            ClientEvent::PeerListUpdated { version, peers } => {
+                let mut ids: Vec<String> = peers.into_iter().map(|peer| peer.user_id).collect();
+                ids.sort();
+                Some((version, ids))
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(updates, vec![(6, vec!["alice".to_string(), "carol".to_string(), "dave".to_string()])]);
+}
+
+fn peer_list_requests(client: &mut P2PClient) -> Vec<Message> {
+    client.sent_messages().unwrap().into_iter()
+        .filter(|message| message.msg_type == MessageType::PeerListRequest)
+        .collect()
+}
+
+#[test]
+fn server_heartbeats_refresh_a_stale_peer_list() {
+    let config = ServerConfig {
+        heartbeat_interval: Duration::from_secs(1),
+        peer_timeout: Duration::from_secs(3600),
+        ..ServerConfig::default()
+    };
+    let mut router = Router::new(config, Registry::in_memory());
+    let observer_token = Token(1);
+    router.connection_opened(observer_token, "127.0.0.1:5000".parse().unwrap());
+    let mut observer = P2PClient::new_testing("observer".to_string()).unwrap();
+    let events = observer.take_event_receiver().unwrap();
+    for message in router.route(&join_message("observer", 9000), observer_token).messages_to(observer_token) {
+        observer.inject_received(message.clone()).unwrap();
+    }
+    let requested = peer_list_requests(&mut observer).len();
+
+    // bob 加入后观察者没有收到增量推送，只收到了心跳
+    router.connection_opened(Token(2), "127.0.0.1:5001".parse().unwrap());
+    router.route(&join_message("bob", 9001), Token(2));
+    let output = router.tick(Instant::now() + Duration::from_secs(5));
+    let heartbeat = output.messages_to(observer_token).into_iter()
+        .find(|message| message.msg_type == MessageType::Heartbeat)
+        .expect("idle observer should get a heartbeat")
+        .clone();
+    assert_eq!(heartbeat.peer_list_version, Some(router.peer_list_version()));
+    assert_eq!(heartbeat.content, None);
+
+    observer.inject_received(heartbeat.clone()).unwrap();
+    let requests = peer_list_requests(&mut observer);
+    assert_eq!(requests.len(), requested + 1);
+    for message in router.route(&requests[requested], observer_token).messages_to(observer_token) {
+        observer.inject_received(message.clone()).unwrap();
+    }
+    let Some(ClientEvent::PeerListUpdated { version, peers }) = events.try_iter()
+        .filter(|event| matches!(event, ClientEvent::PeerListUpdated { .. }))
+        .last() else {
+        panic!("observer never updated its peer list");
+    };
+    assert_eq!(version, router.peer_list_version());
+    assert!(peers.iter().any(|peer| peer.user_id == "bob"));
+
+    // 版本已经是最新的，同样的心跳不再触发刷新
+    observer.inject_received(heartbeat).unwrap();
+    assert_eq!(peer_list_requests(&mut observer).len(), requested + 1);
+}
+
+#[test]
+fn connection_stats_count_server_traffic() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+    let mut alice = P2PClient::new(&addr.to_string(), 0, "alice".to_string()).unwrap();
+    alice.connect().unwrap();
+    alice.request_peer_list().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while alice.connection_stats().get(&Token(0)).is_none_or(|conn| conn.messages_parsed < 2) {
+        assert!(Instant::now() < deadline, "no replies from the server");
+        alice.poll_once().unwrap();
+    }
+
+    let stats = alice.connection_stats();
+    let server = stats[&Token(0)];
+    assert!(server.bytes_read > 0 && server.bytes_written > 0);
+    assert_eq!(server.parse_failures, 0);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn status_reports_connection_counts() {
+    let mut client = P2PClient::new_testing("alice".to_string()).unwrap();
+    client.connect_to_peer("bob").unwrap();
+
+    let status = client.status();
+    assert_eq!(status.user_id, "alice");
+    assert!(!status.connected);
+    assert_eq!(status.known_peer_count, 0);
+    assert_eq!(status.active_p2p_count, 1);
+    assert!(status.uptime < Duration::from_secs(60));
+}
+
+#[test]
+fn mesh_clients_discover_each_other_without_a_server() {
+    let mut alice = P2PClient::new_mesh(0, "alice".to_string(), ClientConfig::default()).unwrap();
+    alice.connect().unwrap();
+    let seed = SocketAddr::from(([127, 0, 0, 1], alice.status().listen_port));
+    let seeded = || ClientConfig { seed_peers: vec![seed], ..ClientConfig::default() };
+    let mut bob = P2PClient::new_mesh(0, "bob".to_string(), seeded()).unwrap();
+    let bob_events = bob.take_event_receiver().unwrap();
+    bob.connect().unwrap();
+    let mut carol = P2PClient::new_mesh(0, "carol".to_string(), seeded()).unwrap();
+    carol.connect().unwrap();
+
+    // bob 和 carol 只知道 alice，通过 alice 转告得知对方后由 bob 主动连接
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while carol.status().active_p2p_count < 2 && Instant::now() < deadline {
+        for client in [&mut alice, &mut bob, &mut carol] {
+            client.poll_once().unwrap();
+        }
+    }
+    assert_eq!(alice.status().active_p2p_count, 2);
+    assert_eq!(carol.status().active_p2p_count, 2);
+    assert_eq!(carol.health(), Health::Healthy);
+    assert!(!carol.is_connected());
+
+    carol.send_smart_message(None, "hello mesh".to_string()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut received = None;
+    while received.is_none() && Instant::now() < deadline {
+        for client in [&mut alice, &mut bob, &mut carol] {
+            client.poll_once().unwrap();
+        }
+        received = bob_events.try_iter().find_map(|event| match event {
+            ClientEvent::ChatReceived { sender_id, content, source, .. } => Some((sender_id, content, source)),
+            _ => None,
+        });
+    }
+    assert_eq!(received, Some(("carol".to_string(), "hello mesh".to_string(), MessageSource::Peer)));
+}
+
+/// 与 mesh 客户端直连的测试节点：发送 Hello 表明身份后按行收发
+fn mesh_link(client: &mut P2PClient, user_id: &str, port: u16) -> TestClient {
+    let addr = SocketAddr::from(([127, 0, 0, 1], client.status().listen_port));
+    let mut link = TestClient::connect(addr);
+    link.set_read_timeout(Duration::from_millis(20));
+    link.send(&Message::new(MessageType::Hello, user_id.to_string()).with_peer_info("127.0.0.1".to_string(), port));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        client.poll_once().unwrap();
+        if let Some(message) = link.recv() {
+            assert_eq!(message.msg_type, MessageType::Hello);
+            return link;
+        }
+    }
+    panic!("no Hello reply for {}", user_id);
+}
+
+#[test]
+fn oversized_frames_from_peers_are_dropped_before_parsing() {
+    let config = ClientConfig { max_message_bytes: 700, ..ClientConfig::default() };
+    let mut alice = P2PClient::new_mesh(0, "alice".to_string(), config).unwrap();
+    alice.connect().unwrap();
+    let mut link = mesh_link(&mut alice, "mallory", 9000);
+
+    // 一次读到的完整但超长的帧只丢弃，连接保留
+    link.send_raw(format!("{}\n", "x".repeat(900)).as_bytes());
+    for _ in 0..5 {
+        alice.poll_once().unwrap();
+    }
+    assert_eq!(alice.status().active_p2p_count, 1);
+
+    // 没有结束符、已超过上限的半帧不再继续缓存，断开连接
+    link.send_raw("x".repeat(4096).as_bytes());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while alice.status().active_p2p_count > 0 && Instant::now() < deadline {
+        alice.poll_once().unwrap();
+    }
+    assert_eq!(alice.status().active_p2p_count, 0);
+}
+
+#[test]
+fn oversized_frame_from_server_drops_the_connection_and_reconnects() {
+    let server = TcpListener::bind("127.0.0.1:0").unwrap();
+    let config = ClientConfig { max_message_bytes: 700, ..ClientConfig::default() };
+    let mut alice = P2PClient::new_with_config(&server.local_addr().unwrap().to_string(), 0, "alice".to_string(), config).unwrap();
+    alice.connect().unwrap();
+    let mut link = TestClient::accept(&server);
+
+    // 没有结束符的数据超过上限后不再继续缓存，断开服务器连接
+    link.send_raw("x".repeat(4096).as_bytes());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while alice.is_connected() && Instant::now() < deadline {
+        alice.poll_once().unwrap();
+    }
+    assert!(!alice.is_connected());
+
+    // 之后照常重连
+    alice.try_reconnect().unwrap();
+    let _relinked = TestClient::accept(&server);
+    assert!(alice.is_connected());
+}
+
+fn gossip_message(sender: &str, ttl: u8, peers: &[(&str, u16, u64)]) -> Message {
+    let peers = peers.iter()
+        .map(|(user_id, port, heard_ms_ago)| GossipEntry {
+            user_id: user_id.to_string(),
+            address: "127.0.0.1".to_string(),
+            port: *port,
+            heard_ms_ago: *heard_ms_ago,
+        })
+        .collect();
+    let content = serde_json::to_string(&PeerGossip { ttl, peers }).unwrap();
+    Message::new(MessageType::PeerGossip, sender.to_string()).with_content(content)
+}
+
+/// 轮询客户端，收集 link 上收到的转告，直到超时
+fn gossip_received(client: &mut P2PClient, link: &mut TestClient, wait: Duration) -> Vec<PeerGossip> {
+    let deadline = Instant::now() + wait;
+    let mut received = Vec::new();
+    while Instant::now() < deadline {
+        client.poll_once().unwrap();
+        while let Some(message) = link.recv() {
+            if message.msg_type == MessageType::PeerGossip {
+                received.push(serde_json::from_str(message.content.as_deref().unwrap()).unwrap());
+            }
+        }
+    }
+    received
+}
+
+#[test]
+fn gossip_is_forwarded_until_ttl_runs_out_and_prefers_recent_addresses() {
+    // 测试节点的 user_id 都小于 "alice"，alice 不会主动去连接它们
+    let mut alice = P2PClient::new_mesh(0, "alice".to_string(), ClientConfig::default()).unwrap();
+    let events = alice.take_event_receiver().unwrap();
+    let mut abe = mesh_link(&mut alice, "abe", 7001);
+    let mut abby = mesh_link(&mut alice, "abby", 7002);
+    gossip_received(&mut alice, &mut abe, Duration::from_millis(200));
+
+    abby.send(&gossip_message("abby", 1, &[("aaron", 7100, 0), ("abby", 7002, 0)]));
+    let forwarded = gossip_received(&mut alice, &mut abe, Duration::from_millis(300));
+    assert_eq!(forwarded.len(), 1);
+    assert_eq!(forwarded[0].ttl, 0);
+    assert_eq!(forwarded[0].peers.iter().map(|entry| entry.user_id.as_str()).collect::<Vec<_>>(), vec!["aaron"]);
+
+    // ttl 为 0 的转告只合并不转发；重复的转告没有变化，也不转发
+    abby.send(&gossip_message("abby", 0, &[("adam", 7200, 0)]));
+    abby.send(&gossip_message("abby", 3, &[("aaron", 7100, 0)]));
+    assert!(gossip_received(&mut alice, &mut abe, Duration::from_millis(300)).is_empty());
+
+    // 同一节点的地址冲突：比已知信息更早的被忽略，更近的覆盖
+    abby.send(&gossip_message("abby", 0, &[("aaron", 7101, 60_000)]));
+    abby.send(&gossip_message("abby", 0, &[("aaron", 7102, 0)]));
+    gossip_received(&mut alice, &mut abe, Duration::from_millis(300));
+    let ports: Vec<u16> = events.try_iter()
+        .filter_map(|event| match event {
+            ClientEvent::PeerListUpdated { peers, .. } => peers.into_iter().find(|info| info.user_id == "aaron").map(|info| info.port),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(ports.last(), Some(&7102));
+    assert!(!ports.contains(&7101));
+}
+
+#[test]
+fn queued_commands_are_drained_in_one_loop_iteration() {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+    let mut alice = P2PClient::new(&addr.to_string(), 0, "alice".to_string()).unwrap();
+    alice.connect().unwrap();
+    let control = alice.get_control_sender();
+
+    // 空闲时每轮等待 50ms，逐轮处理 40 条指令至少需要 2 秒
+    for _ in 0..40 {
+        control.send(ClientCommand::ShowStatus).unwrap();
+    }
+    control.send(ClientCommand::Stop).unwrap();
+    let started = Instant::now();
+    let client = std::thread::spawn(move || alice.run());
+    client.join().unwrap().unwrap();
+    assert!(started.elapsed() < Duration::from_secs(1), "draining took {:?}", started.elapsed());
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn reconnect_checks_peer_links_and_redials_dropped_ones() {
+    for redial in [false, true] {
+        let (handle, addr, shutdown) = P2PServer::spawn_ephemeral().unwrap();
+        let bob_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let carol_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut bob = TestClient::connect(addr);
+        bob.send(&join_message("bob", bob_listener.local_addr().unwrap().port()));
+        let mut carol = TestClient::connect(addr);
+        carol.send(&join_message("carol", carol_listener.local_addr().unwrap().port()));
+        carol.expect(MessageType::PeerList);
+
+        let config = ClientConfig { redial_peers_on_reconnect: redial, ..ClientConfig::default() };
+        let mut alice = P2PClient::new_with_config(&addr.to_string(), 0, "alice".to_string(), config).unwrap();
+        let events = alice.take_event_receiver().unwrap();
+        alice.connect().unwrap();
+        for _ in 0..5 {
+            alice.poll_once().unwrap();
+        }
+        alice.connect_to_peer("bob").unwrap();
+        let carol_token = alice.connect_to_peer("carol").unwrap().token();
+        let (bob_link, _) = bob_listener.accept().unwrap();
+        let (_carol_link, _) = carol_listener.accept().unwrap();
+
+        // 服务器宕机，同时与 bob 的直连断开，与 carol 的直连不受影响
+        shutdown.shutdown();
+        handle.join().unwrap().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while alice.is_connected() && Instant::now() < deadline {
+            alice.poll_once().unwrap();
+        }
+        assert!(!alice.is_connected());
+        drop(bob_link);
+        std::thread::sleep(Duration::from_millis(50));
+
+        let _ = alice.try_reconnect();
+        let mut checked: Vec<(String, PeerLinkStatus)> = events.try_iter()
+            .filter_map(|event| match event {
+                ClientEvent::PeerLinkChecked { peer_id, status } => Some((peer_id, status)),
+                _ => None,
+            })
+            .collect();
+        checked.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(checked.len(), 2, "{:?}", checked);
+        assert_eq!(checked[1], ("carol".to_string(), PeerLinkStatus::Alive(carol_token)));
+        let bob_status = &checked[0].1;
+        if redial {
+            let PeerLinkStatus::Redialed(token) = bob_status else {
+                panic!("bob should be redialed, got {:?}", bob_status);
+            };
+            bob_listener.accept().unwrap();
+            assert_eq!(alice.connect_to_peer("bob").unwrap(), PeerConnection::AlreadyConnected(*token));
+        } else {
+            assert_eq!(*bob_status, PeerLinkStatus::Dropped);
+            assert!(matches!(alice.connect_to_peer("bob").unwrap(), PeerConnection::Connected { .. }));
+        }
+        assert_eq!(alice.connect_to_peer("carol").unwrap(), PeerConnection::AlreadyConnected(carol_token));
+    }
+}
+
+#[test]
+fn legacy_tuple_peer_list_is_applied() {
+    let mut observer = P2PClient::new_testing("observer".to_string()).unwrap();
+    let events = observer.take_event_receiver().unwrap();
+    let mut list = Message::new(MessageType::PeerList, "server".to_string())
+        .with_content(r#"[["alice","127.0.0.1",7001],["observer","127.0.0.1",7002]]"#.to_string());
+    list.peer_list_version = Some(1);
+    observer.inject_received(list).unwrap();
+
+    let peers = events.try_iter().find_map(|event| match event {
+        ClientEvent::PeerListUpdated { peers, .. } => Some(peers),
+        _ => None,
+    }).unwrap();
+    assert_eq!(peers.iter().map(PeerEntry::from).collect::<Vec<_>>(), vec![PeerEntry::new("alice".to_string(), "127.0.0.1".to_string(), 7001)]);
+}
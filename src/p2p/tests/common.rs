@@ -0,0 +1,511 @@
+use mio::Token;
+use p2p::common::{
+    canonical_signing_bytes, deserialize_message, deserialize_message_lenient, serialize_message, sign_message, take_frame, verify_message, ErrorCode, ErrorContext, Framing, Message, MessageSource, MessageType, P2PError, PeerEntry, PeerInfo,
+    PeerListDelta, PeerStatus, TokenAllocator,
+    MAX_CONTENT_BYTES, MESSAGE_SCHEMA_VERSION,
+};
+use std::time::{Duration, UNIX_EPOCH};
+
+#[test]
+fn take_frame_strips_lf_and_crlf() {
+    let mut buffer = b"{\"a\":1}\n{\"b\":2}\r\n{\"c\":3}\r\r\npartial".to_vec();
+
+    assert_eq!(take_frame(&mut buffer).unwrap(), b"{\"a\":1}");
+    assert_eq!(take_frame(&mut buffer).unwrap(), b"{\"b\":2}");
+    assert_eq!(take_frame(&mut buffer).unwrap(), b"{\"c\":3}");
+    assert_eq!(take_frame(&mut buffer), None);
+    assert_eq!(buffer, b"partial");
+}
+
+#[test]
+fn crlf_terminated_message_deserializes() {
+    let message = Message::new(MessageType::Chat, "alice".to_string()).with_content("hi".to_string()).with_target("*".to_string());
+    let mut buffer = serialize_message(&message).unwrap();
+    buffer.pop();
+    buffer.extend_from_slice(b"\r\n");
+
+    let frame = take_frame(&mut buffer).unwrap();
+    assert_eq!(frame.last(), Some(&b'}'));
+    let decoded = deserialize_message(&frame).unwrap();
+    assert_eq!(decoded.content.as_deref(), Some("hi"));
+}
+
+#[test]
+fn lenient_parse_reports_frames_from_other_protocols() {
+    let not_p2p = |data: &[u8]| match deserialize_message_lenient(data) {
+        Err(P2PError::NotP2PProtocol(preview)) => preview,
+        other => panic!("expected NotP2PProtocol, got {:?}", other),
+    };
+    assert_eq!(not_p2p(b"GET / HTTP/1.1\r"), "GET / HTTP/1.1\\r");
+    assert_eq!(not_p2p(b"\xff\xfe\x00binary"), "\\xff\\xfe\\x00binary");
+    assert_eq!(not_p2p(b"hello from the echo server"), "hello from the e");
+
+    // 看起来像 JSON 对象的帧仍然按普通解析报告具体错误
+    assert!(matches!(deserialize_message_lenient(b" {not json}"), Err(P2PError::Serialization(_))));
+    let message = Message::new(MessageType::Heartbeat, "alice".to_string());
+    let mut frame = serialize_message(&message).unwrap();
+    frame.pop();
+    assert_eq!(deserialize_message_lenient(&frame).unwrap().sender_id, "alice");
+}
+
+#[test]
+fn message_without_content_type_defaults_to_text_plain() {
+    let message = Message::new(MessageType::Chat, "alice".to_string()).with_content("hi".to_string()).with_target("*".to_string());
+    let mut value = serde_json::to_value(&message).unwrap();
+    value.as_object_mut().unwrap().remove("content_type");
+
+    let decoded: Message = serde_json::from_value(value).unwrap();
+    assert_eq!(decoded.content_type.as_deref(), Some("text/plain"));
+}
+
+#[test]
+fn schema_version_is_serialized_first() {
+    let message = Message::new(MessageType::Heartbeat, "alice".to_string());
+    let data = serialize_message(&message).unwrap();
+    assert!(data.starts_with(format!("{{\"v\":{},", MESSAGE_SCHEMA_VERSION).as_bytes()));
+}
+
+#[test]
+fn v1_message_without_newer_fields_is_migrated() {
+    // 版本号出现之前的客户端发出的消息：没有 v、source、content_type、msg_id 等字段
+    let v1 = br#"{"msg_type":"Chat","sender_id":"alice","target_id":"*","content":"hi","sender_peer_address":"127.0.0.1","sender_listen_port":9000,"timestamp":{"secs_since_epoch":1700000000,"nanos_since_epoch":0}}"#;
+
+    let decoded = deserialize_message(v1).unwrap();
+    assert_eq!(decoded.v, MESSAGE_SCHEMA_VERSION);
+    assert_eq!(decoded.content.as_deref(), Some("hi"));
+    assert_eq!(decoded.source, MessageSource::Server);
+    assert_eq!(decoded.content_type.as_deref(), Some("text/plain"));
+    assert_eq!(decoded.msg_id, None);
+    assert_eq!(decoded.framings, None);
+}
+
+#[test]
+fn newer_schema_versions_decode_known_fields() {
+    let message = Message::new(MessageType::Chat, "alice".to_string()).with_content("hi".to_string()).with_target("*".to_string());
+    let mut value = serde_json::to_value(&message).unwrap();
+    let object = value.as_object_mut().unwrap();
+    object.insert("v".to_string(), serde_json::json!(MESSAGE_SCHEMA_VERSION + 1));
+    object.insert("field_from_the_future".to_string(), serde_json::json!(true));
+
+    let decoded = deserialize_message(&serde_json::to_vec(&value).unwrap()).unwrap();
+    assert_eq!(decoded.content.as_deref(), Some("hi"));
+    assert_eq!(decoded.v, MESSAGE_SCHEMA_VERSION);
+}
+
+#[test]
+fn public_and_private_copies_get_distinct_ids_and_dedup_keys() {
+    let public = Message::new(MessageType::Chat, "alice".to_string())
+        .with_content("hi".to_string())
+        .with_generated_msg_id();
+    let mut private = public.clone().with_target("bob".to_string());
+    private.msg_id = None;
+    let private = private.with_generated_msg_id();
+
+    assert_ne!(public.msg_id, private.msg_id);
+    assert_ne!(public.dedup_key(), private.dedup_key());
+
+    // 即使 msg_id 被（错误地）复用，投递范围不同也不会互相去重
+    let mut reused = private.clone();
+    reused.msg_id = public.msg_id.clone();
+    assert_ne!(public.dedup_key(), reused.dedup_key());
+}
+
+#[test]
+fn display_summarizes_messages_on_one_line() {
+    assert_eq!(MessageType::PeerListDelta.to_string(), "PeerListDelta");
+
+    let at = |mut message: Message| {
+        message.timestamp = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        message
+    };
+    let chat = at(Message::new(MessageType::Chat, "alice".to_string())
+        .with_target("bob".to_string())
+        .with_content("hi".to_string()));
+    assert_eq!(chat.to_string(), r#"[Chat] alice -> bob @1700000000.123: "hi" (2 bytes)"#);
+
+    let heartbeat = at(Message::new(MessageType::Heartbeat, "alice".to_string()));
+    assert_eq!(heartbeat.to_string(), "[Heartbeat] alice @1700000000.123");
+
+    let mut room_chat = at(Message::new(MessageType::Chat, "alice".to_string()).with_room("lobby".to_string()).with_content("yo".to_string()));
+    room_chat.msg_id = Some("alice:*:1:0".to_string());
+    assert_eq!(room_chat.to_string(), r#"[Chat] alice #lobby id=alice:*:1:0 @1700000000.123: "yo" (2 bytes)"#);
+
+    let long = Message::new(MessageType::Chat, "alice".to_string()).with_content("é\n".repeat(100));
+    let shown = long.to_string();
+    assert!(!shown.contains('\n'));
+    assert!(shown.ends_with(r#"…" (300 bytes)"#), "{}", shown);
+    assert!(format!("{:.4}", long).ends_with(r#": "é\né\n…" (300 bytes)"#), "{:.4}", long);
+    assert!(format!("{:.0}", long).ends_with(r#": "…" (300 bytes)"#));
+}
+
+#[test]
+fn redacted_form_never_contains_content() {
+    let secret = "the launch code is 0000";
+    let message = Message::chat("alice".to_string(), Some("bob".to_string()), secret.to_string());
+    let redacted = message.redacted().to_string();
+    assert!(!redacted.contains(secret), "{}", redacted);
+    assert!(!redacted.contains("launch"), "{}", redacted);
+    assert!(redacted.starts_with("[Chat] alice -> bob id="), "{}", redacted);
+    assert!(redacted.contains(&format!("<redacted> ({} bytes, hash ", secret.len())), "{}", redacted);
+    // 只有内容不同的两条消息摘要相同，哈希不同；相同内容的哈希相同
+    let hash = |message: &Message| message.redacted().to_string().rsplit_once("hash ").unwrap().1.to_string();
+    let same = Message::chat("carol".to_string(), None, secret.to_string());
+    let other = Message::chat("alice".to_string(), Some("bob".to_string()), "the launch code is 0001".to_string());
+    assert_eq!(hash(&message), hash(&same));
+    assert_ne!(hash(&message), hash(&other));
+
+    let heartbeat = Message::heartbeat("alice".to_string());
+    assert_eq!(heartbeat.redacted().to_string(), heartbeat.to_string());
+}
+
+#[test]
+fn p2p_error_equality_compares_kinds() {
+    use p2p::common::P2PError;
+    use std::io::{Error, ErrorKind};
+
+    assert_eq!(P2PError::PeerNotFound("bob".to_string()), P2PError::PeerNotFound("bob".to_string()));
+    assert_ne!(P2PError::PeerNotFound("bob".to_string()), P2PError::PeerNotFound("carol".to_string()));
+    assert_eq!(
+        P2PError::from(Error::new(ErrorKind::ConnectionReset, "a")),
+        P2PError::from(Error::new(ErrorKind::ConnectionReset, "b"))
+    );
+    assert_ne!(
+        P2PError::from(Error::from(ErrorKind::ConnectionReset)),
+        P2PError::from(Error::from(ErrorKind::TimedOut))
+    );
+    assert_eq!(P2PError::Connection("x".to_string()), P2PError::Connection("x".to_string()));
+    assert_ne!(P2PError::Connection("x".to_string()), P2PError::ConfigError("x".to_string()));
+
+    let eof = deserialize_message(b"{").unwrap_err();
+    let also_eof = deserialize_message(b"{\"msg_type\":").unwrap_err();
+    assert_eq!(eof, also_eof);
+    assert_ne!(eof, deserialize_message(b"{}").unwrap_err());
+    assert_ne!(eof, P2PError::PeerNotFound("bob".to_string()));
+}
+
+#[test]
+fn decode_failures_have_distinct_variants() {
+    use p2p::common::P2PError;
+
+    assert!(matches!(deserialize_message(b"not json"), Err(P2PError::Serialization(_))));
+    assert!(matches!(deserialize_message(b"{\"msg_type\":\"Nope\"}"), Err(P2PError::Serialization(_))));
+    assert!(matches!(deserialize_message(b"{\"content\":\"\xff\"}"), Err(P2PError::InvalidUtf8(_))));
+    assert!(matches!("nowhere:80".parse::<std::net::SocketAddr>().map_err(P2PError::from), Err(P2PError::AddrParse(_))));
+}
+
+#[test]
+fn size_bytes_matches_serialized_frame_without_newline() {
+    let message = Message::new(MessageType::Chat, "alice".to_string()).with_content("hello".to_string()).with_target("*".to_string());
+    assert_eq!(message.size_bytes() + 1, serialize_message(&message).unwrap().len());
+}
+
+#[test]
+fn token_allocator_reuses_released_tokens_in_release_order() {
+    let mut tokens = TokenAllocator::new(Token(10));
+    let (a, b, c) = (tokens.allocate(), tokens.allocate(), tokens.allocate());
+    assert_eq!((a, b, c), (Token(10), Token(11), Token(12)));
+
+    tokens.release(c);
+    tokens.release(a);
+    assert_eq!(tokens.allocate(), c);
+    assert_eq!(tokens.allocate(), a);
+    assert_eq!(tokens.allocate(), Token(13));
+}
+
+#[test]
+fn framing_negotiation_and_length_prefixed_round_trip() {
+    let supported = [Framing::Newline, Framing::LengthPrefixed];
+    assert_eq!(Framing::negotiate(None, &supported), Framing::Newline);
+    assert_eq!(Framing::negotiate(Some(&[Framing::LengthPrefixed, Framing::Newline]), &supported), Framing::LengthPrefixed);
+    assert_eq!(Framing::negotiate(Some(&[Framing::LengthPrefixed]), &[Framing::Newline]), Framing::Newline);
+
+    let message = Message::new(MessageType::Chat, "alice".to_string()).with_content("line\nbreak".to_string()).with_target("*".to_string());
+    let mut buffer = Framing::LengthPrefixed.encode(&message).unwrap();
+    buffer.extend(Framing::LengthPrefixed.encode(&message).unwrap());
+    let partial = buffer.len() - 3;
+    let mut tail = buffer.split_off(partial);
+
+    let frame = Framing::LengthPrefixed.take_frame(&mut buffer).unwrap();
+    assert_eq!(deserialize_message(&frame).unwrap().content.as_deref(), Some("line\nbreak"));
+    assert_eq!(Framing::LengthPrefixed.take_frame(&mut buffer), None);
+    buffer.append(&mut tail);
+    assert!(Framing::LengthPrefixed.take_frame(&mut buffer).is_some());
+    assert!(buffer.is_empty());
+}
+
+/// 每种消息类型的一个合法示例，以及去掉后应被拒绝的必需字段
+fn validation_cases() -> Vec<(Message, Vec<(&'static str, ErrorCode)>)> {
+    use MessageType::*;
+    let base = |msg_type: MessageType| Message::new(msg_type, "alice".to_string());
+    let reply = |mut message: Message| {
+        message.reply_to = Some("alice:*:1:0".to_string());
+        message
+    };
+    let text = |message: Message| message.with_content("x".to_string());
+    let mut peer_list = text(base(PeerList));
+    peer_list.page = Some(0);
+    peer_list.total_pages = Some(1);
+    let mut peer_list_request = base(PeerListRequest);
+    peer_list_request.page = Some(1);
+    let mut error = text(base(Error));
+    error.error_code = Some(ErrorCode::TargetOffline);
+    let mut join = base(Join).with_peer_info("127.0.0.1".to_string(), 9000);
+    join.framings = Some(vec![Framing::Newline]);
+
+    use ErrorCode::{MalformedMessage as Malformed, MissingTarget as Target};
+    vec![
+        (join, vec![("port", Malformed)]),
+        (text(base(Chat)).with_target("*".to_string()), vec![("content", Malformed), ("target", Target)]),
+        (base(Leave), vec![]),
+        (peer_list, vec![("content", Malformed)]),
+        (peer_list_request, vec![]),
+        (base(ConnectRequest).with_target("bob".to_string()), vec![("target", Target)]),
+        (text(base(ConnectResponse)).with_target("bob".to_string()), vec![("target", Target), ("content", Malformed)]),
+        (base(Heartbeat), vec![]),
+        (base(HeartbeatAck), vec![]),
+        (base(UserJoined), vec![("sender", Malformed)]),
+        (base(UserLeft), vec![("sender", Malformed)]),
+        (base(ServerShutdown), vec![]),
+        (error, vec![("content", Malformed)]),
+        (base(Kick), vec![]),
+        (base(JoinRejected), vec![]),
+        (base(System), vec![]),
+        (reply(text(base(React)).with_room("lobby".to_string())), vec![("content", Malformed), ("room", Target), ("reply_to", Malformed)]),
+        (reply(text(base(Edit)).with_target("bob".to_string())), vec![("content", Malformed), ("target", Target), ("reply_to", Malformed)]),
+        (text(base(Welcome)), vec![("content", Malformed)]),
+        (base(JoinRoom).with_room("lobby".to_string()), vec![("room", Target)]),
+        (base(LeaveRoom).with_room("lobby".to_string()), vec![("room", Target)]),
+        (base(RoomInvite).with_room("lobby".to_string()).with_target("bob".to_string()), vec![("room", Target), ("target", Target)]),
+        (base(KickFromRoom).with_room("lobby".to_string()).with_target("bob".to_string()), vec![("room", Target), ("target", Target)]),
+        (text(base(SetRoomConfig)).with_room("lobby".to_string()), vec![("room", Target), ("content", Malformed)]),
+        (text(base(RoomUpdate)).with_room("lobby".to_string()), vec![("room", Target), ("content", Malformed)]),
+        (text(base(PeerListDelta)), vec![("content", Malformed)]),
+        (reply(base(RelayAck)), vec![("reply_to", Malformed)]),
+        (base(Hello).with_peer_info("127.0.0.1".to_string(), 9000), vec![("sender", Malformed)]),
+        (text(base(PeerGossip)), vec![("content", Malformed)]),
+    ]
+}
+
+fn without(message: &Message, field: &str) -> Message {
+    let mut message = message.clone();
+    match field {
+        "port" => message.sender_listen_port = 0,
+        "content" => message.content = Some(String::new()),
+        "target" => message.target_id = None,
+        "room" => message.room = None,
+        "reply_to" => message.reply_to = None,
+        "sender" => message.sender_id.clear(),
+        other => panic!("unknown field {}", other),
+    }
+    message
+}
+
+fn assert_invalid(message: &Message, expected: ErrorCode, case: &str) {
+    match message.validate() {
+        Err(P2PError::InvalidMessage { msg_type, code, .. }) => {
+            assert_eq!(msg_type, message.msg_type, "{}", case);
+            assert_eq!(code, expected, "{}", case);
+        }
+        other => panic!("{}: expected InvalidMessage, got {:?}", case, other),
+    }
+    assert!(serialize_message(message).is_err(), "{}", case);
+}
+
+#[test]
+fn validate_checks_required_and_forbidden_fields_of_every_message_type() {
+    let cases = validation_cases();
+    assert_eq!(cases.len(), 29, "every MessageType needs a validation case");
+
+    for (message, required) in &cases {
+        let msg_type = &message.msg_type;
+        assert_eq!(message.validate(), Ok(()), "{} example should be valid", msg_type);
+        let decoded = deserialize_message(&serialize_message(message).unwrap()).unwrap();
+        assert_eq!(decoded.validate(), Ok(()), "{}", msg_type);
+
+        for (field, code) in required {
+            assert_invalid(&without(message, field), *code, &format!("{} without {}", msg_type, field));
+        }
+
+        let mut forbidden: Vec<(&str, Message)> = Vec::new();
+        if *msg_type != MessageType::Error {
+            let mut m = message.clone();
+            m.error_code = Some(ErrorCode::Rejected);
+            forbidden.push(("error_code", m));
+        }
+        if *msg_type != MessageType::Join {
+            let mut m = message.clone();
+            m.framings = Some(vec![Framing::LengthPrefixed]);
+            forbidden.push(("framings", m));
+        }
+        if !matches!(msg_type, MessageType::PeerList | MessageType::PeerListRequest) {
+            let mut m = message.clone();
+            m.page = Some(0);
+            forbidden.push(("page", m));
+        }
+        if *msg_type != MessageType::PeerList {
+            let mut m = message.clone();
+            m.total_pages = Some(1);
+            forbidden.push(("total_pages", m));
+        }
+        for (field, m) in &forbidden {
+            assert_invalid(m, ErrorCode::MalformedMessage, &format!("{} with {}", msg_type, field));
+        }
+    }
+}
+
+#[test]
+fn validate_enforces_content_limit_and_guest_join() {
+    let at_limit = Message::new(MessageType::Chat, "alice".to_string())
+        .with_target("*".to_string())
+        .with_content("a".repeat(MAX_CONTENT_BYTES));
+    assert_eq!(at_limit.validate(), Ok(()));
+    let over = at_limit.clone().with_content("a".repeat(MAX_CONTENT_BYTES + 1));
+    assert_invalid(&over, ErrorCode::MessageTooLarge, "oversized chat");
+
+    // 访客 Join 没有 user_id，也不要求监听端口
+    assert_eq!(Message::new(MessageType::Join, String::new()).validate(), Ok(()));
+
+    let frame = br#"{"v":2,"msg_type":"Join","sender_id":"alice","sender_peer_address":"","sender_listen_port":0,"timestamp":{"secs_since_epoch":0,"nanos_since_epoch":0}}"#;
+    let decoded = deserialize_message(frame).unwrap();
+    assert!(matches!(decoded.validate(), Err(P2PError::InvalidMessage { code: ErrorCode::MalformedMessage, .. })));
+}
+
+#[test]
+fn peer_entries_round_trip_by_field_name() {
+    let mut entry = PeerEntry::new("alice".to_string(), "10.0.0.1".to_string(), 9000);
+    entry.status = PeerStatus::Away;
+    entry.capabilities = vec!["markdown".to_string()];
+    let json = serde_json::to_value(&entry).unwrap();
+    assert_eq!(json["user_id"], "alice");
+    assert_eq!(json["port"], 9000);
+    assert_eq!(serde_json::from_value::<PeerEntry>(json).unwrap(), entry);
+
+    // 字段顺序不影响解析，缺少的新字段取默认值
+    let reordered = r#"{"port":9001,"address":"10.0.0.2","user_id":"bob"}"#;
+    assert_eq!(serde_json::from_str::<PeerEntry>(reordered).unwrap(), PeerEntry::new("bob".to_string(), "10.0.0.2".to_string(), 9001));
+    let plain = serde_json::to_string(&PeerEntry::new("bob".to_string(), "10.0.0.2".to_string(), 9001)).unwrap();
+    assert!(!plain.contains("capabilities"), "{}", plain);
+
+    let info = PeerInfo::from(entry.clone());
+    assert_eq!(PeerEntry::from(&info), entry);
+    let decoded: PeerInfo = serde_json::from_str(&serde_json::to_string(&info).unwrap()).unwrap();
+    assert_eq!(PeerEntry::from(&decoded), entry);
+}
+
+#[test]
+fn legacy_tuple_peer_entries_are_still_accepted() {
+    let legacy = r#"[["alice","10.0.0.1",9000],{"user_id":"bob","address":"10.0.0.2","port":9001,"status":"Online"}]"#;
+    let entries: Vec<PeerEntry> = serde_json::from_str(legacy).unwrap();
+    assert_eq!(entries, vec![
+        PeerEntry::new("alice".to_string(), "10.0.0.1".to_string(), 9000),
+        PeerEntry::new("bob".to_string(), "10.0.0.2".to_string(), 9001),
+    ]);
+
+    let delta = r#"{"base_epoch":1,"epoch":2,"added":[["carol","10.0.0.3",9002]],"removed":["dave"]}"#;
+    let delta: PeerListDelta = serde_json::from_str(delta).unwrap();
+    assert_eq!(delta.added, vec![PeerEntry::new("carol".to_string(), "10.0.0.3".to_string(), 9002)]);
+
+    assert!(serde_json::from_str::<PeerEntry>(r#"["alice",9000,"10.0.0.1"]"#).is_err());
+}
+
+#[test]
+fn constructors_build_valid_messages_of_the_expected_shape() {
+    let public = Message::chat("alice".to_string(), None, "hi".to_string());
+    let direct = Message::chat("alice".to_string(), Some("bob".to_string()), "hi".to_string());
+    let join = Message::join("alice".to_string(), "127.0.0.1".to_string(), 9000);
+    let guest = Message::join(String::new(), "127.0.0.1".to_string(), 0);
+    let heartbeat = Message::heartbeat("alice".to_string());
+    let list = Message::peer_list(&[PeerEntry::new("bob".to_string(), "10.0.0.2".to_string(), 9001)]);
+    for message in [&public, &direct, &join, &guest, &heartbeat, &list] {
+        assert_eq!(message.validate(), Ok(()), "{}", message);
+        assert_eq!(message.v, MESSAGE_SCHEMA_VERSION);
+        assert_eq!(message.source, MessageSource::Server);
+    }
+
+    let json = serde_json::to_value(&public).unwrap();
+    assert_eq!(json["msg_type"], "Chat");
+    assert_eq!(json["target_id"], "*");
+    assert_eq!(json["content"], "hi");
+    assert!(json["msg_id"].as_str().unwrap().starts_with("alice:*:"));
+    assert_eq!(direct.direct_target(), Some("bob"));
+    assert_ne!(public.msg_id, direct.msg_id);
+
+    let json = serde_json::to_value(&join).unwrap();
+    assert_eq!(json["msg_type"], "Join");
+    assert_eq!(json["sender_peer_address"], "127.0.0.1");
+    assert_eq!(json["sender_listen_port"], 9000);
+    assert_eq!(json["content"], serde_json::Value::Null);
+
+    assert_eq!(heartbeat.msg_type, MessageType::Heartbeat);
+    assert_eq!(heartbeat.content, None);
+
+    assert_eq!(list.msg_type, MessageType::PeerList);
+    assert_eq!(list.sender_id, "SERVER");
+    let entries: Vec<PeerEntry> = serde_json::from_str(list.content.as_deref().unwrap()).unwrap();
+    assert_eq!(entries[0].user_id, "bob");
+}
+
+fn signing_vector_message() -> Message {
+    let mut message = Message::chat("alice".to_string(), Some("bob".to_string()), "hi\nthere".to_string());
+    message.timestamp = UNIX_EPOCH + Duration::new(1_700_000_000, 1);
+    message.msg_id = Some("alice:b:1".to_string());
+    message
+}
+
+#[test]
+fn signing_matches_the_published_test_vector() {
+    // 其他语言的实现用这组数据核对规范格式和 HMAC 结果
+    let message = signing_vector_message();
+    assert_eq!(
+        canonical_signing_bytes(&message),
+        b"p2p-sig-v1\n4:Chat\n5:alice\n3:bob\n8:hi\nthere\n20:1700000000.000000001\n9:alice:b:1\n".to_vec()
+    );
+    let signature = sign_message(&message, b"secret-key");
+    assert_eq!(signature, "4315d029b55129644e2fe547659599735538370942f751706694243028021aff");
+
+    let mut signed = message;
+    signed.signature = Some(signature);
+    assert!(verify_message(&signed, b"secret-key"));
+    assert!(!verify_message(&signed, b"other-key"));
+
+    // 经过 JSON 往返和服务器转发改写（timestamp 移到 client_timestamp）后签名仍然有效
+    let mut relayed = deserialize_message(&serialize_message(&signed).unwrap()).unwrap();
+    relayed.client_timestamp = Some(relayed.timestamp);
+    relayed.timestamp = UNIX_EPOCH + Duration::from_secs(1_800_000_000);
+    relayed.source = MessageSource::Server;
+    relayed.sender_peer_address = "10.0.0.1".to_string();
+    assert!(verify_message(&relayed, b"secret-key"));
+}
+
+#[test]
+fn tampered_or_unsigned_messages_fail_verification() {
+    let mut message = signing_vector_message();
+    message.signature = Some(sign_message(&message, b"secret-key"));
+
+    let mut tampered = message.clone();
+    tampered.content = Some("hi\nthere!".to_string());
+    assert!(!verify_message(&tampered, b"secret-key"));
+    let mut retargeted = message.clone();
+    retargeted.target_id = Some("carol".to_string());
+    assert!(!verify_message(&retargeted, b"secret-key"));
+    let mut malformed = message.clone();
+    malformed.signature = Some("not hex".to_string());
+    assert!(!verify_message(&malformed, b"secret-key"));
+    let mut unsigned = message;
+    unsigned.signature = None;
+    assert!(!verify_message(&unsigned, b"secret-key"));
+}
+
+#[test]
+fn error_context_chains_into_the_formatted_error() {
+    let io = std::io::Error::new(std::io::ErrorKind::BrokenPipe, "broken pipe");
+    let result: Result<(), std::io::Error> = Err(io);
+    let error = result.context("writing frame").with_context(|| format!("sending peer list to {}", "alice")).unwrap_err();
+
+    assert_eq!(error.to_string(), "sending peer list to alice: writing frame: IO error: broken pipe");
+    assert!(matches!(error.root_cause(), P2PError::Io(e) if e.kind() == std::io::ErrorKind::BrokenPipe));
+    let broken_pipe = || P2PError::Io(std::io::ErrorKind::BrokenPipe.into());
+    assert_eq!(error, broken_pipe().context("writing frame").context("sending peer list to alice"));
+    assert_ne!(error, broken_pipe().context("sending peer list to alice"));
+    assert_eq!(Ok::<_, P2PError>(5).context("unused"), Ok(5));
+}
@@ -0,0 +1,247 @@
+// 消息编解码的性质测试和模糊测试：随机生成的消息编码后能原样解码；任意字节、截断或重复的帧
+// 经过取帧和解码都不会 panic。发现过的问题各自保留一个回归测试
+use p2p::common::{
+    deserialize_message, ErrorCode, Framing, Message, MessageSource, MessageType, P2PError,
+    MAX_JSON_DEPTH, MESSAGE_SCHEMA_VERSION,
+};
+use proptest::prelude::*;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MESSAGE_TYPES: [MessageType; 29] = [
+    MessageType::Join, MessageType::Chat, MessageType::Leave, MessageType::PeerList, MessageType::PeerListRequest,
+    MessageType::ConnectRequest, MessageType::ConnectResponse, MessageType::Heartbeat, MessageType::HeartbeatAck,
+    MessageType::UserJoined, MessageType::UserLeft, MessageType::ServerShutdown, MessageType::Error, MessageType::Kick,
+    MessageType::JoinRejected, MessageType::System, MessageType::React, MessageType::Edit, MessageType::Welcome,
+    MessageType::JoinRoom, MessageType::LeaveRoom, MessageType::RoomInvite, MessageType::KickFromRoom,
+    MessageType::SetRoomConfig, MessageType::RoomUpdate, MessageType::PeerListDelta, MessageType::RelayAck,
+    MessageType::Hello, MessageType::PeerGossip,
+];
+
+const ERROR_CODES: [ErrorCode; 16] = [
+    ErrorCode::TargetOffline, ErrorCode::MissingTarget, ErrorCode::UserIdInUse, ErrorCode::RateLimited,
+    ErrorCode::MessageTooLarge, ErrorCode::Rejected, ErrorCode::ServerFull, ErrorCode::InvalidUserId,
+    ErrorCode::NotRoomMember, ErrorCode::NotRoomOwner, ErrorCode::NotInvited, ErrorCode::RoomFull,
+    ErrorCode::InvalidRoomConfig, ErrorCode::QuotaExceeded, ErrorCode::MalformedMessage, ErrorCode::NotJoined,
+];
+
+/// 从 UNIX_EPOCH 到平台能表示的最大时间之间的任意时刻（serde 不能序列化早于 UNIX_EPOCH 的时间）
+fn timestamp() -> impl Strategy<Value = SystemTime> {
+    let secs = prop_oneof![Just(0), Just(i64::MAX as u64), 0..u32::MAX as u64, 0..=i64::MAX as u64];
+    (secs, 0..1_000_000_000u32)
+        .prop_map(|(secs, nanos)| UNIX_EPOCH.checked_add(Duration::new(secs, nanos)).unwrap_or(UNIX_EPOCH))
+}
+
+fn text() -> impl Strategy<Value = String> {
+    prop_oneof![
+        ".*",
+        "[\\\\\"\n\r\t\u{0}-\u{1f}]*",
+        "\\PC{0,64}",
+    ]
+}
+
+fn optional_text() -> impl Strategy<Value = Option<String>> {
+    prop::option::of(text())
+}
+
+fn framings() -> impl Strategy<Value = Option<Vec<Framing>>> {
+    prop::option::of(prop::collection::vec(prop_oneof![Just(Framing::Newline), Just(Framing::LengthPrefixed)], 0..4))
+}
+
+/// 任意字段组合的消息，不保证满足 `Message::validate`
+fn any_message() -> impl Strategy<Value = Message> {
+    (
+        (0..MESSAGE_TYPES.len(), text(), optional_text(), optional_text(), text(), any::<u16>(), timestamp()),
+        (any::<bool>(), prop::option::of(any::<u64>()), optional_text(), optional_text(), optional_text()),
+        (prop::option::of(0..ERROR_CODES.len()), framings(), prop::option::of(timestamp()), optional_text()),
+        (prop::option::of(any::<u32>()), prop::option::of(any::<u32>()), optional_text()),
+    )
+        .prop_map(|(head, meta, extra, pages)| {
+            let (msg_type, sender_id, target_id, content, address, port, timestamp) = head;
+            let (from_peer, peer_list_version, content_type, msg_id, reply_to) = meta;
+            let (error_code, framings, client_timestamp, room) = extra;
+            let (page, total_pages, signature) = pages;
+            let mut message = Message::new(MESSAGE_TYPES[msg_type].clone(), sender_id);
+            message.target_id = target_id;
+            message.content = content;
+            message.sender_peer_address = address;
+            message.sender_listen_port = port;
+            message.timestamp = timestamp;
+            message.source = if from_peer { MessageSource::Peer } else { MessageSource::Server };
+            message.peer_list_version = peer_list_version;
+            message.content_type = content_type;
+            message.msg_id = msg_id;
+            message.reply_to = reply_to;
+            message.error_code = error_code.map(|index| ERROR_CODES[index]);
+            message.framings = framings;
+            message.client_timestamp = client_timestamp;
+            message.room = room;
+            message.page = page;
+            message.total_pages = total_pages;
+            message.signature = signature;
+            message
+        })
+}
+
+/// 由构造函数生成、满足 `Message::validate` 的消息
+fn valid_message() -> impl Strategy<Value = Message> {
+    let user_id = "[a-z0-9_]{1,16}";
+    prop_oneof![
+        (user_id, prop::option::of(user_id), "\\PC{1,256}")
+            .prop_map(|(sender, target, content)| Message::chat(sender, target, content).with_generated_msg_id()),
+        (user_id, "[0-9.]{0,15}", 1..=u16::MAX)
+            .prop_map(|(sender, address, port)| Message::join(sender, address, port)),
+        (user_id, prop::option::of(any::<u64>())).prop_map(|(sender, version)| {
+            let mut heartbeat = Message::heartbeat(sender);
+            heartbeat.peer_list_version = version;
+            heartbeat
+        }),
+    ]
+}
+
+/// 比较时使用 JSON 形式（Message 没有实现 PartialEq）
+fn as_json(message: &Message) -> serde_json::Value {
+    serde_json::to_value(message).unwrap()
+}
+
+/// 把数据分成若干块依次放入缓冲区，每放一块就取出所有完整的帧
+fn split_frames(framing: Framing, data: &[u8], chunk_sizes: &[usize]) -> (Vec<Vec<u8>>, Vec<u8>) {
+    let mut buffer = Vec::new();
+    let mut frames = Vec::new();
+    let mut rest = data;
+    let mut sizes = chunk_sizes.iter().cycle();
+    while !rest.is_empty() {
+        let size = (*sizes.next().unwrap_or(&rest.len())).clamp(1, rest.len());
+        buffer.extend_from_slice(&rest[..size]);
+        rest = &rest[size..];
+        while let Some(frame) = framing.take_frame(&mut buffer) {
+            frames.push(frame);
+        }
+    }
+    (frames, buffer)
+}
+
+proptest! {
+    #[test]
+    fn any_message_roundtrips_through_json(message in any_message()) {
+        let json = serde_json::to_vec(&message).unwrap();
+        let decoded = deserialize_message(&json).unwrap();
+        prop_assert_eq!(decoded.v, MESSAGE_SCHEMA_VERSION);
+        prop_assert_eq!(as_json(&decoded), as_json(&message));
+    }
+
+    #[test]
+    fn valid_messages_roundtrip_through_both_framings(
+        messages in prop::collection::vec(valid_message(), 1..8),
+        chunk_sizes in prop::collection::vec(1..64usize, 1..8),
+    ) {
+        for framing in [Framing::Newline, Framing::LengthPrefixed] {
+            let data: Vec<u8> = messages.iter().flat_map(|message| framing.encode(message).unwrap()).collect();
+            let (frames, rest) = split_frames(framing, &data, &chunk_sizes);
+            prop_assert!(rest.is_empty());
+            prop_assert_eq!(frames.len(), messages.len());
+            for (frame, message) in frames.iter().zip(&messages) {
+                let decoded = deserialize_message(frame).unwrap();
+                decoded.validate().unwrap();
+                prop_assert_eq!(as_json(&decoded), as_json(message));
+            }
+        }
+    }
+
+    #[test]
+    fn random_bytes_never_panic(data in prop::collection::vec(any::<u8>(), 0..512), chunk_sizes in prop::collection::vec(1..64usize, 1..8)) {
+        let _ = deserialize_message(&data);
+        for framing in [Framing::Newline, Framing::LengthPrefixed] {
+            let (frames, _) = split_frames(framing, &data, &chunk_sizes);
+            for frame in frames {
+                let _ = deserialize_message(&frame).map(|message| message.validate());
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_duplicated_and_corrupted_frames_never_panic(
+        message in valid_message(),
+        cut in any::<prop::sample::Index>(),
+        copies in 1..4usize,
+        flips in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 0..4),
+    ) {
+        for framing in [Framing::Newline, Framing::LengthPrefixed] {
+            let frame = framing.encode(&message).unwrap();
+            let mut data = frame[..cut.index(frame.len())].to_vec();
+            for _ in 0..copies {
+                data.extend_from_slice(&frame);
+            }
+            for (index, byte) in &flips {
+                let position = index.index(data.len());
+                data[position] ^= byte;
+            }
+            let (frames, _) = split_frames(framing, &data, &[7]);
+            for frame in frames {
+                let _ = deserialize_message(&frame).map(|message| message.validate());
+            }
+        }
+    }
+
+    #[test]
+    fn random_json_values_never_panic(value in json_value()) {
+        let _ = deserialize_message(value.to_string().as_bytes());
+    }
+}
+
+/// 任意 JSON 值，其中对象包含消息的字段名，使解码能走到字段级别的错误处理
+fn json_value() -> impl Strategy<Value = serde_json::Value> {
+    let fields = prop_oneof![
+        Just("v"), Just("msg_type"), Just("sender_id"), Just("timestamp"), Just("secs_since_epoch"),
+        Just("nanos_since_epoch"), Just("framings"), Just("error_code"), Just("source"), Just("page"),
+    ];
+    let leaf = prop_oneof![
+        Just(serde_json::Value::Null),
+        any::<bool>().prop_map(serde_json::Value::from),
+        any::<u64>().prop_map(serde_json::Value::from),
+        any::<i64>().prop_map(serde_json::Value::from),
+        any::<f64>().prop_map(serde_json::Value::from),
+        ".{0,8}".prop_map(serde_json::Value::from),
+    ];
+    leaf.prop_recursive(4, 64, 8, move |inner| prop_oneof![
+        prop::collection::vec(inner.clone(), 0..8).prop_map(serde_json::Value::from),
+        prop::collection::vec((fields.clone(), inner), 0..8)
+            .prop_map(|entries| serde_json::Value::Object(entries.into_iter().map(|(k, v)| (k.to_string(), v)).collect())),
+    ])
+}
+
+#[test]
+fn deeply_nested_json_is_rejected_before_parsing() {
+    let nested = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+    assert!(matches!(deserialize_message(nested.as_bytes()), Err(P2PError::Protocol(_))));
+
+    // 字符串中的括号不计入嵌套
+    let message = Message::chat("alice".to_string(), None, "[".repeat(MAX_JSON_DEPTH * 2));
+    let decoded = deserialize_message(&serde_json::to_vec(&message).unwrap()).unwrap();
+    assert_eq!(decoded.content, message.content);
+
+    let deep = format!("{{\"v\":2,\"x\":{}1{}}}", "[".repeat(MAX_JSON_DEPTH), "]".repeat(MAX_JSON_DEPTH));
+    assert!(matches!(deserialize_message(deep.as_bytes()), Err(P2PError::Protocol(_))));
+}
+
+#[test]
+fn out_of_range_timestamps_are_errors_not_panics() {
+    let frame = |secs: &str, nanos: &str| format!(
+        "{{\"msg_type\":\"Chat\",\"sender_id\":\"a\",\"target_id\":\"*\",\"content\":\"hi\",\"sender_peer_address\":\"\",\
+         \"sender_listen_port\":0,\"timestamp\":{{\"secs_since_epoch\":{},\"nanos_since_epoch\":{}}}}}",
+        secs, nanos
+    );
+    assert!(deserialize_message(frame("0", "0").as_bytes()).is_ok());
+    // 超过 10^9 的纳秒数会进位到秒
+    assert!(deserialize_message(frame("0", "4294967295").as_bytes()).is_ok());
+    for (secs, nanos) in [("18446744073709551615", "999999999"), ("18446744073709551615", "4294967295"), ("-1", "0")] {
+        assert!(deserialize_message(frame(secs, nanos).as_bytes()).is_err(), "secs={} nanos={}", secs, nanos);
+    }
+}
+
+#[test]
+fn length_prefix_near_u32_max_waits_for_more_data() {
+    let mut buffer = u32::MAX.to_be_bytes().to_vec();
+    buffer.extend_from_slice(b"{}");
+    assert_eq!(Framing::LengthPrefixed.take_frame(&mut buffer), None);
+    assert_eq!(buffer.len(), 6);
+}
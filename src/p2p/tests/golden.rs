@@ -0,0 +1,284 @@
+// 线上格式的兼容性测试：tests/golden/ 下为每种消息类型保存一份规范的 JSON 帧，
+// 当前代码必须能解码这些帧，并且构造出的消息编码后与之一致。
+// 有意修改格式时用 `UPDATE_GOLDEN=1 cargo test --test golden` 重新生成（见 tests/golden/README.md），
+// legacy/ 下的旧格式帧是手写的，不会被重新生成
+use p2p::common::{
+    deserialize_message, serialize_message, ErrorCode, Framing, GossipEntry, Message, MessageSource, MessageType,
+    PeerEntry, PeerGossip, PeerListDelta, ServerInfo, MESSAGE_SCHEMA_VERSION,
+};
+use p2p::room::{RoomConfig, RoomInfo};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 设置后把当前编码结果写回 golden 文件，而不是与之比较
+const UPDATE_ENV: &str = "UPDATE_GOLDEN";
+
+fn golden_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+/// 固定的时间戳，使编码结果可重复
+fn fixed_time() -> SystemTime {
+    UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789)
+}
+
+/// 去掉随时间变化的字段：时间戳固定，生成的 msg_id 替换为固定值
+fn normalized(mut message: Message) -> Message {
+    message.timestamp = fixed_time();
+    if message.msg_id.is_some() {
+        message.msg_id = Some("golden-msg-id".to_string());
+    }
+    message
+}
+
+fn server(msg_type: MessageType, content: &str) -> Message {
+    Message::new(msg_type, "SERVER".to_string()).with_content(content.to_string())
+}
+
+fn peer_entry(user_id: &str, port: u16) -> PeerEntry {
+    PeerEntry::new(user_id.to_string(), "127.0.0.1".to_string(), port)
+}
+
+fn room_info() -> RoomInfo {
+    RoomInfo {
+        name: "lobby".to_string(),
+        config: RoomConfig { owner: "alice".to_string(), invite_only: true, max_members: Some(8) },
+        members: vec!["alice".to_string(), "bob".to_string()],
+    }
+}
+
+/// 每种消息类型一条典型消息，与客户端和服务器实际发出的字段组合一致
+fn golden_messages() -> Vec<Message> {
+    let mut join = Message::join("alice".to_string(), "127.0.0.1".to_string(), 9000);
+    join.framings = Some(vec![Framing::LengthPrefixed, Framing::Newline]);
+
+    let mut peer_list = Message::peer_list(&[peer_entry("alice", 9000), peer_entry("bob", 9001)]);
+    peer_list.peer_list_version = Some(3);
+    peer_list.page = Some(0);
+    peer_list.total_pages = Some(1);
+
+    let mut peer_list_request = Message::new(MessageType::PeerListRequest, "alice".to_string());
+    peer_list_request.page = Some(2);
+
+    let mut heartbeat = Message::heartbeat("alice".to_string()).with_content("42".to_string());
+    heartbeat.peer_list_version = Some(3);
+    let mut heartbeat_ack = server(MessageType::HeartbeatAck, "42");
+    heartbeat_ack.peer_list_version = Some(3);
+
+    let welcome = ServerInfo {
+        version: "0.1.0".to_string(),
+        connected_users: 2,
+        motd: Some("be nice".to_string()),
+        assigned_user_id: None,
+        framing: Framing::LengthPrefixed,
+    };
+    let mut error = server(MessageType::Error, "target bob is offline");
+    error.error_code = Some(ErrorCode::TargetOffline);
+    error.reply_to = Some("golden-reply-to".to_string());
+
+    let mut chat = Message::chat("alice".to_string(), Some("bob".to_string()), "你好 👋".to_string());
+    chat.client_timestamp = Some(UNIX_EPOCH + Duration::from_secs(1_699_999_999));
+    // normalized 会替换 msg_id，签名只用来固定字段格式，不要求能通过校验
+    chat.signature = Some("4315d029b55129644e2fe547659599735538370942f751706694243028021aff".to_string());
+    let mut react = Message::new(MessageType::React, "bob".to_string())
+        .with_target("alice".to_string())
+        .with_content("👍".to_string())
+        .with_generated_msg_id();
+    react.reply_to = Some("golden-reply-to".to_string());
+    let mut edit = Message::new(MessageType::Edit, "alice".to_string())
+        .with_target("*".to_string())
+        .with_content("edited".to_string())
+        .with_generated_msg_id();
+    edit.reply_to = Some("golden-reply-to".to_string());
+    let mut relay_ack = server(MessageType::RelayAck, "");
+    relay_ack.content = None;
+    relay_ack.reply_to = Some("golden-reply-to".to_string());
+
+    let delta = PeerListDelta { base_epoch: 2, epoch: 3, added: vec![peer_entry("carol", 9002)], removed: vec!["dave".to_string()] };
+    let mut peer_list_delta = server(MessageType::PeerListDelta, &serde_json::to_string(&delta).unwrap());
+    peer_list_delta.peer_list_version = Some(3);
+    let gossip = PeerGossip {
+        ttl: 2,
+        peers: vec![GossipEntry { user_id: "carol".to_string(), address: "127.0.0.1".to_string(), port: 9002, heard_ms_ago: 150 }],
+    };
+
+    let room = |msg_type: MessageType, sender: &str| Message::new(msg_type, sender.to_string()).with_room("lobby".to_string());
+    let room_config = serde_json::to_string(&room_info().config).unwrap();
+
+    vec![
+        join,
+        Message::chat("alice".to_string(), None, "hello everyone".to_string()).with_content_type("text/markdown".to_string()),
+        chat,
+        Message::new(MessageType::Leave, "alice".to_string()),
+        peer_list,
+        peer_list_request,
+        Message::new(MessageType::ConnectRequest, "alice".to_string()).with_target("bob".to_string()),
+        Message::new(MessageType::ConnectResponse, "bob".to_string())
+            .with_target("alice".to_string())
+            .with_content("127.0.0.1,9001".to_string())
+            .with_peer_info("127.0.0.1".to_string(), 9001),
+        heartbeat,
+        heartbeat_ack,
+        Message::new(MessageType::UserJoined, "bob".to_string())
+            .with_content("bob".to_string())
+            .with_peer_info("127.0.0.1".to_string(), 9001),
+        Message::new(MessageType::UserLeft, "bob".to_string()).with_content("Timeout".to_string()),
+        server(MessageType::ServerShutdown, "server is shutting down"),
+        error,
+        server(MessageType::Kick, "kicked by admin"),
+        server(MessageType::JoinRejected, "user_id alice is already in use"),
+        server(MessageType::System, "maintenance at midnight"),
+        react,
+        edit,
+        server(MessageType::Welcome, &serde_json::to_string(&welcome).unwrap()),
+        room(MessageType::JoinRoom, "bob"),
+        room(MessageType::LeaveRoom, "bob"),
+        room(MessageType::RoomInvite, "alice").with_target("carol".to_string()),
+        room(MessageType::KickFromRoom, "alice").with_target("bob".to_string()),
+        room(MessageType::SetRoomConfig, "alice").with_content(room_config),
+        room(MessageType::RoomUpdate, "SERVER").with_content(serde_json::to_string(&room_info()).unwrap()),
+        peer_list_delta,
+        relay_ack,
+        Message::new(MessageType::Hello, "alice".to_string())
+            .with_peer_info("127.0.0.1".to_string(), 9000)
+            .with_source(MessageSource::Peer),
+        Message::new(MessageType::PeerGossip, "alice".to_string())
+            .with_content(serde_json::to_string(&gossip).unwrap())
+            .with_source(MessageSource::Peer),
+    ]
+    .into_iter()
+    .map(normalized)
+    .collect()
+}
+
+/// 同一类型有多条时，第二条起加上序号
+fn golden_names(messages: &[Message]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for message in messages {
+        let base = message.msg_type.to_string();
+        let count = names.iter().filter(|name| name.split('.').next() == Some(base.as_str())).count();
+        names.push(if count == 0 { base } else { format!("{}.{}", base, count + 1) });
+    }
+    names
+}
+
+fn pretty(value: &Value) -> String {
+    serde_json::to_string_pretty(value).unwrap()
+}
+
+/// 按行比较两段文本（最长公共子序列），输出带 -/+ 标记的差异
+fn line_diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+    let (mut i, mut j, mut out) = (0, 0, String::new());
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            out += &format!("  {}\n", a[i]);
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out += &format!("- {}\n", a[i]);
+            i += 1;
+        } else {
+            out += &format!("+ {}\n", b[j]);
+            j += 1;
+        }
+    }
+    out
+}
+
+fn assert_same_json(name: &str, what: &str, expected: &Value, actual: &Value) {
+    if expected != actual {
+        panic!(
+            "{} mismatch for {} (- golden, + current):\n{}\nIf this change is intentional, rerun with {}=1 and review the diff.",
+            what, name, line_diff(&pretty(expected), &pretty(actual)), UPDATE_ENV
+        );
+    }
+}
+
+fn as_json(message: &Message) -> Value {
+    serde_json::to_value(message).unwrap()
+}
+
+fn read_golden(path: &std::path::Path) -> Vec<u8> {
+    std::fs::read(path).unwrap_or_else(|e| panic!("cannot read {}: {} (run with {}=1 to create it)", path.display(), e, UPDATE_ENV))
+}
+
+#[test]
+fn golden_frames_cover_every_message_type() {
+    let messages = golden_messages();
+    let distinct: std::collections::HashSet<String> = messages.iter().map(|message| message.msg_type.to_string()).collect();
+    assert_eq!(distinct.len(), 29, "add a golden message for the new MessageType");
+    for message in &messages {
+        message.validate().unwrap();
+    }
+}
+
+#[test]
+fn current_encoding_matches_golden_frames() {
+    let messages = golden_messages();
+    let update = std::env::var_os(UPDATE_ENV).is_some();
+    for (name, message) in golden_names(&messages).iter().zip(&messages) {
+        let path = golden_dir().join(format!("{}.json", name));
+        let encoded: Value = serde_json::from_slice(&serialize_message(message).unwrap()).unwrap();
+        if update {
+            std::fs::write(&path, pretty(&encoded) + "\n").unwrap();
+            continue;
+        }
+        let golden: Value = serde_json::from_slice(&read_golden(&path)).unwrap();
+        assert_same_json(name, "encoding", &golden, &encoded);
+    }
+}
+
+#[test]
+fn golden_frames_decode_to_expected_messages() {
+    if std::env::var_os(UPDATE_ENV).is_some() {
+        return;
+    }
+    let messages = golden_messages();
+    for (name, message) in golden_names(&messages).iter().zip(&messages) {
+        let decoded = deserialize_message(&read_golden(&golden_dir().join(format!("{}.json", name))))
+            .unwrap_or_else(|e| panic!("cannot decode golden {}: {}", name, e));
+        decoded.validate().unwrap();
+        assert_same_json(name, "decoding", &as_json(message), &as_json(&decoded));
+    }
+}
+
+/// 手写的旧格式帧解码后应得到的消息
+fn decode_legacy(file: &str) -> Message {
+    let path = golden_dir().join("legacy").join(file);
+    deserialize_message(&read_golden(&path)).unwrap_or_else(|e| panic!("cannot decode legacy {}: {}", file, e))
+}
+
+#[test]
+fn frame_without_source_field_defaults_to_server() {
+    // MessageSource 加入之前的格式：没有 v、source、content_type 以及之后新增的所有可选字段
+    let decoded = decode_legacy("v1_chat_without_source.json");
+    let mut expected = Message::new(MessageType::Chat, "alice".to_string())
+        .with_target("*".to_string())
+        .with_content("hello from an old client".to_string())
+        .with_peer_info("127.0.0.1".to_string(), 9000);
+    expected.timestamp = fixed_time();
+    assert_eq!(decoded.v, MESSAGE_SCHEMA_VERSION);
+    assert_eq!(decoded.source, MessageSource::Server);
+    assert_same_json("v1_chat_without_source", "decoding", &as_json(&expected), &as_json(&decoded));
+}
+
+#[test]
+fn frame_from_a_newer_version_ignores_unknown_fields() {
+    let decoded = decode_legacy("v3_chat_with_future_fields.json");
+    let mut expected = Message::new(MessageType::Chat, "alice".to_string())
+        .with_target("bob".to_string())
+        .with_content("hello from the future".to_string())
+        .with_source(MessageSource::Peer);
+    expected.timestamp = fixed_time();
+    expected.msg_id = Some("golden-msg-id".to_string());
+    assert_same_json("v3_chat_with_future_fields", "decoding", &as_json(&expected), &as_json(&decoded));
+}
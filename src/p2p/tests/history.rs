@@ -0,0 +1,106 @@
+mod support;
+
+use p2p::common::{Message, MessageType};
+use p2p::history::{ChatHistory, ChatRecord};
+use p2p::server::ServerConfig;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use support::{chat_message, spawn_server, TestClient};
+
+fn rotated(path: &Path, index: usize) -> PathBuf {
+    PathBuf::from(format!("{}.{}", path.display(), index))
+}
+
+fn read_records(path: &Path) -> Vec<ChatRecord> {
+    std::fs::read_to_string(path).unwrap_or_default()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn relayed_chats_are_flushed_to_the_history_file() {
+    let path = std::env::temp_dir().join(format!("p2p-history-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = ServerConfig {
+        chat_log_path: Some(path.clone()),
+        chat_log_flush_interval: Duration::from_millis(50),
+        offline_queue_window: None,
+        ..ServerConfig::default()
+    };
+    let (addr, shutdown, handle) = spawn_server(config);
+
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+    for client in [&mut alice, &mut bob] {
+        client.send(&Message::new(MessageType::JoinRoom, String::new()).with_room("lobby".to_string()));
+        client.expect(MessageType::RoomUpdate);
+    }
+    alice.expect(MessageType::RoomUpdate);
+
+    let mut hello = chat_message("alice", None, "hello all");
+    hello.msg_id = Some("alice:1".to_string());
+    alice.send(&hello);
+    bob.expect(MessageType::Chat);
+    alice.send(&chat_message("alice", Some("bob"), "psst"));
+    bob.expect(MessageType::Chat);
+    bob.send(&chat_message("bob", None, "lobby news").with_room("lobby".to_string()));
+    alice.expect(MessageType::Chat);
+    // 未转发的消息（目标不在线）不记录
+    alice.send(&chat_message("alice", Some("nobody"), "lost"));
+    alice.expect(MessageType::Error);
+
+    // 服务器运行期间按 flush 间隔写盘
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while read_records(&path).len() < 3 && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+
+    let records = read_records(&path);
+    let summary: Vec<_> = records.iter()
+        .map(|record| (record.sender_id.as_str(), record.target_id.as_deref(), record.room.as_deref(), record.content.as_deref()))
+        .collect();
+    assert_eq!(summary, vec![
+        ("alice", Some("*"), None, Some("hello all")),
+        ("alice", Some("bob"), None, Some("psst")),
+        ("bob", None, Some("lobby"), Some("lobby news")),
+    ]);
+    assert_eq!(records[0].msg_id.as_deref(), Some("alice:1"));
+    assert!(records.iter().all(|record| record.sent_at.is_some_and(|sent_at| sent_at <= record.timestamp)));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn history_file_rotates_by_size() {
+    let path = std::env::temp_dir().join(format!("p2p-history-rotate-{}.jsonl", std::process::id()));
+    let files = [path.clone(), rotated(&path, 1), rotated(&path, 2)];
+    for file in &files {
+        let _ = std::fs::remove_file(file);
+    }
+
+    let history = ChatHistory::open(&path, 400, 1, Duration::from_secs(60)).unwrap();
+    assert_eq!(history.path(), path);
+    for i in 0..20 {
+        history.record(&chat_message("alice", None, &format!("message {}", i)));
+    }
+    // Drop 时写完剩余记录并 flush，不必等到 flush 间隔
+    drop(history);
+
+    assert!(!files[2].exists());
+    let mut kept = Vec::new();
+    for file in files[..2].iter().rev() {
+        assert!(std::fs::metadata(file).unwrap().len() <= 400);
+        kept.extend(read_records(file).into_iter().map(|record| record.content.unwrap()));
+    }
+    let expected: Vec<String> = (20 - kept.len()..20).map(|i| format!("message {}", i)).collect();
+    assert_eq!(kept, expected);
+    assert!(kept.len() < 20);
+
+    for file in &files[..2] {
+        std::fs::remove_file(file).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+mod support;
+
+use p2p::common::{Message, MessageType, PeerInfo};
+use p2p::hooks::{HookDecision, MaxLengthFilter, MessageHook, ProfanityFilter};
+use p2p::server::{P2PServer, ServerConfig, ServerThread, ShutdownHandle};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use support::{chat_message, TestClient};
+
+/// 在内容末尾追加标记的钩子
+struct Tag(&'static str);
+
+impl MessageHook for Tag {
+    fn on_inbound(&mut self, msg: &Message, _from: &PeerInfo) -> HookDecision {
+        let mut tagged = msg.clone();
+        tagged.content = msg.content.as_ref().map(|content| format!("{}{}", content, self.0));
+        HookDecision::Modify(tagged)
+    }
+}
+
+fn spawn_with_hooks(hooks: Vec<Box<dyn MessageHook>>) -> (SocketAddr, ShutdownHandle, ServerThread) {
+    let mut server = P2PServer::new("127.0.0.1:0").unwrap();
+    for hook in hooks {
+        server.add_hook(hook);
+    }
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let handle = std::thread::spawn(move || server.start());
+    (addr, shutdown, handle)
+}
+
+#[test]
+fn rejected_message_returns_error_and_is_not_relayed() {
+    let (addr, shutdown, handle) = spawn_with_hooks(vec![Box::new(ProfanityFilter::new(["darn"]))]);
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+
+    alice.send(&chat_message("alice", Some("bob"), "well DARN it"));
+    let error = alice.expect(MessageType::Error);
+    assert_eq!(error.content.as_deref(), Some("message contains a blocked word"));
+
+    alice.send(&chat_message("alice", Some("bob"), "hello"));
+    assert_eq!(bob.expect(MessageType::Chat).content.as_deref(), Some("hello"));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn hooks_modify_messages_in_registration_order() {
+    let hooks: Vec<Box<dyn MessageHook>> = vec![Box::new(Tag("-a")), Box::new(Tag("-b")), Box::new(MaxLengthFilter::new(12))];
+    let (addr, shutdown, handle) = spawn_with_hooks(hooks);
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+
+    alice.send(&chat_message("alice", Some("bob"), "hi"));
+    assert_eq!(bob.expect(MessageType::Chat).content.as_deref(), Some("hi-a-b"));
+
+    // 原文 10 个字符未超限，但前两个钩子追加标记后达到 14 个字符
+    alice.send(&chat_message("alice", Some("bob"), "0123456789"));
+    assert!(alice.expect(MessageType::Error).content.unwrap().contains("limit is 12"));
+    bob.set_read_timeout(Duration::from_millis(300));
+    assert!(bob.recv().is_none());
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn filters_only_inspect_chat_content() {
+    let from = PeerInfo::new("alice".to_string(), "127.0.0.1".to_string(), 9000);
+    let mut profanity = ProfanityFilter::new(["darn"]);
+    let mut max_length = MaxLengthFilter::new(3);
+
+    let join = Message::new(MessageType::Join, "alice".to_string()).with_content("darn long key".to_string());
+    assert!(matches!(profanity.on_inbound(&join, &from), HookDecision::Allow));
+    assert!(matches!(max_length.on_inbound(&join, &from), HookDecision::Allow));
+
+    let chat = chat_message("alice", None, "darn");
+    assert!(matches!(profanity.on_inbound(&chat, &from), HookDecision::Reject(_)));
+    assert!(matches!(max_length.on_inbound(&chat, &from), HookDecision::Reject(_)));
+}
+
+#[test]
+fn message_closures_rewrite_in_order_and_short_circuit_on_rejection() {
+    let mut server = P2PServer::new("127.0.0.1:0").unwrap();
+    server.on_message(|_from, message| {
+        if let Some(content) = message.content.as_mut() {
+            *content = content.replace("cat", "dog");
+        }
+        true
+    });
+    server.on_message(|from, message| {
+        // 第一个闭包改写后的内容才会到这里
+        let blocked = message.content.as_deref().is_some_and(|content| content.contains("dog"));
+        !(blocked && from.user_id == "alice")
+    });
+    server.on_message(|_from, message| {
+        message.content = message.content.take().map(|content| format!("[{}]", content));
+        true
+    });
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let handle = std::thread::spawn(move || server.start());
+
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+
+    alice.send(&chat_message("alice", Some("bob"), "my cat"));
+    assert_eq!(alice.expect(MessageType::Error).content.as_deref(), Some("message rejected by server"));
+    bob.send(&chat_message("bob", Some("alice"), "my cat"));
+    assert_eq!(alice.expect(MessageType::Chat).content.as_deref(), Some("[my dog]"));
+    alice.send(&chat_message("alice", Some("bob"), "hi"));
+    assert_eq!(bob.expect(MessageType::Chat).content.as_deref(), Some("[hi]"));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+/// 按内容长短休眠不同时间的钩子，用于打乱工作线程之间的完成顺序
+struct Slow;
+
+impl MessageHook for Slow {
+    fn on_inbound(&mut self, msg: &Message, _from: &PeerInfo) -> HookDecision {
+        let delay = msg.content.as_ref().map_or(0, |content| content.len() as u64 % 4);
+        std::thread::sleep(Duration::from_millis(delay));
+        HookDecision::Allow
+    }
+}
+
+#[test]
+fn worker_pool_keeps_per_connection_order_and_reports_rejections() {
+    let config = ServerConfig { worker_threads: 3, ..ServerConfig::default() };
+    let mut server = P2PServer::new_with_config("127.0.0.1:0", config).unwrap();
+    let instances = Arc::new(AtomicUsize::new(0));
+    let counter = instances.clone();
+    server.add_hook_factory(move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+        Box::new(Slow)
+    });
+    server.add_hook(Box::new(ProfanityFilter::new(["darn"])));
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_handle();
+    let handle = std::thread::spawn(move || server.start());
+
+    let mut alice = TestClient::join(addr, "alice");
+    let mut carol = TestClient::join(addr, "carol");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+    carol.expect(MessageType::UserJoined);
+
+    for i in 0..20 {
+        alice.send(&chat_message("alice", Some("bob"), &format!("a{}{}", i, "!".repeat(i % 3))));
+        carol.send(&chat_message("carol", Some("bob"), &format!("c{}", i)));
+    }
+    alice.send(&chat_message("alice", Some("bob"), "darn"));
+    assert_eq!(alice.expect(MessageType::Error).content.as_deref(), Some("message contains a blocked word"));
+
+    let mut from_alice = Vec::new();
+    let mut from_carol = Vec::new();
+    while from_alice.len() + from_carol.len() < 40 {
+        let chat = bob.expect(MessageType::Chat);
+        let content = chat.content.unwrap();
+        match chat.sender_id.as_str() {
+            "alice" => from_alice.push(content),
+            _ => from_carol.push(content),
+        }
+    }
+    let expected: Vec<String> = (0..20).map(|i| format!("a{}{}", i, "!".repeat(i % 3))).collect();
+    assert_eq!(from_alice, expected);
+    assert_eq!(from_carol, (0..20).map(|i| format!("c{}", i)).collect::<Vec<_>>());
+    // 内联链一个实例，每个工作线程各一个
+    assert_eq!(instances.load(Ordering::SeqCst), 4);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
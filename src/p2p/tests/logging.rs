@@ -0,0 +1,92 @@
+mod support;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use p2p::common::MessageType;
+use p2p::server::ServerConfig;
+use std::sync::{Mutex, Once};
+use support::{chat_message, spawn_server, TestClient};
+
+/// 把所有日志记录到内存中的测试 logger
+struct CapturingLogger {
+    records: Mutex<Vec<(Level, String)>>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.records.lock().unwrap().push((record.level(), record.args().to_string()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger { records: Mutex::new(Vec::new()) };
+
+fn captured() -> Vec<(Level, String)> {
+    LOGGER.records.lock().unwrap().clone()
+}
+
+/// 同一测试进程中的各个测试共用一个 logger，只能安装一次
+fn install_logger() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+    });
+}
+
+#[test]
+fn join_relay_and_leave_are_logged() {
+    install_logger();
+
+    let config = ServerConfig { log_content: true, ..ServerConfig::default() };
+    let (addr, shutdown, handle) = spawn_server(config);
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+
+    bob.send(&chat_message("bob", Some("alice"), "secret plans"));
+    alice.expect(MessageType::Chat);
+    drop(bob);
+    alice.expect(MessageType::UserLeft);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+
+    let records = captured();
+    let has = |level: Level, needle: &str| records.iter().any(|(l, m)| *l == level && m.contains(needle));
+    assert!(has(Level::Info, "user joined user_id=alice"));
+    assert!(has(Level::Info, "user joined user_id=bob"));
+    assert!(has(Level::Debug, "relaying private chat from user_id=bob to user_id=alice"));
+    assert!(has(Level::Trace, "routing Chat from user_id=bob"));
+    assert!(has(Level::Trace, "secret plans"));
+    assert!(has(Level::Info, "user left user_id=bob"));
+}
+
+#[test]
+fn routed_content_is_redacted_unless_enabled() {
+    install_logger();
+
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut carol = TestClient::join(addr, "carol");
+    let mut dave = TestClient::join(addr, "dave");
+    carol.expect(MessageType::UserJoined);
+
+    dave.send(&chat_message("dave", Some("carol"), "hidden launch codes"));
+    carol.expect(MessageType::Chat);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+
+    let records = captured();
+    assert!(records.iter().all(|(_, m)| !m.contains("hidden launch codes")));
+    let routed: Vec<_> = records.iter()
+        .filter(|(l, m)| *l == Level::Trace && m.starts_with("routing Chat from user_id=dave"))
+        .collect();
+    assert_eq!(routed.len(), 1, "{:?}", routed);
+    assert!(routed[0].1.contains("[Chat] dave -> carol"), "{}", routed[0].1);
+    assert!(routed[0].1.contains("<redacted> (19 bytes, hash "), "{}", routed[0].1);
+}
@@ -0,0 +1,23 @@
+use p2p::registry::Registry;
+use std::time::Duration;
+
+#[test]
+fn failed_flush_is_retried_by_flush_if_due() {
+    let dir = std::env::temp_dir().join(format!("p2p-registry-retry-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("registry.json");
+
+    // 目录还不存在，写临时文件失败
+    let mut registry = Registry::load(&path).unwrap();
+    registry.set_ban("mallory", None);
+    assert!(registry.flush().is_err());
+    assert!(!path.exists());
+
+    // 没有新的修改，修复磁盘问题后的下一次 flush_if_due 仍会写出之前的封禁
+    std::fs::create_dir_all(&dir).unwrap();
+    registry.flush_if_due(Duration::ZERO).unwrap();
+    let reloaded = Registry::load(&path).unwrap();
+    assert!(reloaded.get("mallory").unwrap().ban.is_some());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,575 @@
+mod support;
+
+use mio::Token;
+use p2p::common::{DisconnectReason, ErrorCode, Framing, Message, MessageSource, MessageType, PeerEntry, ServerInfo};
+use p2p::registry::Registry;
+use p2p::room::{RoomConfig, RoomInfo};
+use p2p::router::{Router, RouterEvent};
+use p2p::server::{DuplicateJoinPolicy, ServerConfig};
+use std::time::{Duration, Instant, SystemTime};
+use support::{chat_message, join_message};
+
+const ALICE: Token = Token(2);
+const BOB: Token = Token(3);
+const CAROL: Token = Token(4);
+
+fn router_with(config: ServerConfig) -> Router {
+    let mut router = Router::new(config, Registry::in_memory());
+    for (i, token) in [ALICE, BOB, CAROL].into_iter().enumerate() {
+        router.connection_opened(token, format!("127.0.0.1:{}", 5000 + i).parse().unwrap());
+    }
+    router
+}
+
+/// alice 和 bob 已加入的路由器
+fn router_with_two_peers() -> Router {
+    let mut router = router_with(ServerConfig::default());
+    router.route(&join_message("alice", 9001), ALICE);
+    router.route(&join_message("bob", 9002), BOB);
+    router
+}
+
+fn room_request(msg_type: MessageType, sender: &str, room: &str, target: Option<&str>) -> Message {
+    let message = Message::new(msg_type, sender.to_string()).with_room(room.to_string());
+    match target {
+        Some(target) => message.with_target(target.to_string()),
+        None => message,
+    }
+}
+
+fn error_code(messages: &[&Message]) -> Option<ErrorCode> {
+    messages.iter().find(|message| message.msg_type == MessageType::Error).and_then(|message| message.error_code)
+}
+
+fn types(messages: &[&Message]) -> Vec<MessageType> {
+    messages.iter().map(|message| message.msg_type.clone()).collect()
+}
+
+#[test]
+fn join_sends_peer_list_and_notifies_others() {
+    let mut router = router_with(ServerConfig::default());
+    router.route(&join_message("alice", 9001), ALICE);
+
+    let output = router.route(&join_message("bob", 9002), BOB);
+    assert_eq!(types(&output.messages_to(ALICE)), vec![MessageType::UserJoined]);
+    assert_eq!(types(&output.messages_to(BOB)), vec![MessageType::PeerList, MessageType::Welcome]);
+    assert!(output.events.is_empty());
+    assert_eq!(router.token_of("bob"), Some(BOB));
+    assert_eq!(router.peer_list_version(), 2);
+}
+
+#[test]
+fn second_join_on_same_connection_refreshes_session() {
+    let mut router = router_with_two_peers();
+    let version = router.peer_list_version();
+
+    let output = router.route(&join_message("bob", 9102), BOB);
+    assert!(output.messages_to(ALICE).is_empty());
+    assert_eq!(types(&output.messages_to(BOB)), vec![MessageType::PeerList]);
+    assert!(output.events.is_empty());
+    assert_eq!(router.token_of("bob"), Some(BOB));
+    assert_eq!(router.peers().count(), 2);
+    assert_eq!(router.registry().get("bob").unwrap().last_port, 9102);
+    assert_eq!(router.peer_list_version(), version + 1);
+
+    // user_id 改变时按离开再加入处理
+    let output = router.route(&join_message("bobby", 9102), BOB);
+    assert_eq!(types(&output.messages_to(ALICE)), vec![MessageType::UserLeft, MessageType::UserJoined]);
+    assert_eq!(types(&output.messages_to(BOB)), vec![MessageType::PeerList, MessageType::Welcome]);
+    assert_eq!(router.token_of("bob"), None);
+    assert_eq!(router.token_of("bobby"), Some(BOB));
+}
+
+#[test]
+fn leave_closes_connection_and_notifies_others() {
+    let mut router = router_with_two_peers();
+
+    let output = router.route(&Message::new(MessageType::Leave, "bob".to_string()), BOB);
+    let left = output.messages_to(ALICE);
+    assert_eq!(types(&left), vec![MessageType::UserLeft]);
+    assert_eq!(left[0].content.as_deref(), Some(DisconnectReason::Left.as_str()));
+    assert_eq!(output.events, vec![RouterEvent::Close(BOB, DisconnectReason::Left)]);
+    assert_eq!(router.token_of("bob"), None);
+}
+
+#[test]
+fn chat_is_broadcast_or_routed_to_target() {
+    let mut router = router_with_two_peers();
+    router.route(&join_message("carol", 9003), CAROL);
+
+    let public = router.route(&chat_message("alice", None, "hi all"), ALICE);
+    let mut recipients: Vec<Token> = public.pairs().into_iter().map(|(token, _)| token).collect();
+    recipients.sort();
+    assert_eq!(recipients, vec![ALICE, BOB, CAROL]);
+
+    let private = router.route(&chat_message("alice", Some("carol"), "psst"), ALICE);
+    let pairs = private.pairs();
+    assert_eq!(pairs.len(), 1);
+    assert_eq!(pairs[0].0, CAROL);
+    assert_eq!(pairs[0].1.content.as_deref(), Some("psst"));
+
+}
+
+#[test]
+fn chat_without_valid_target_is_an_error_not_a_broadcast() {
+    let mut router = router_with_two_peers();
+
+    let untargeted = Message::new(MessageType::Chat, "alice".to_string()).with_content("hi".to_string());
+    for message in [untargeted.clone(), untargeted.with_target(String::new()), chat_message("alice", Some("nobody"), "hello?")] {
+        let output = router.route(&message, ALICE);
+        let pairs = output.pairs();
+        assert_eq!(pairs.len(), 1, "{:?} should only produce an error", message.target_id);
+        assert_eq!(pairs[0].0, ALICE);
+        assert_eq!(pairs[0].1.msg_type, MessageType::Error);
+    }
+}
+
+#[test]
+fn relayed_chat_is_restamped_by_server() {
+    let mut router = router_with_two_peers();
+    let sent_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let mut forged = chat_message("alice", Some("bob"), "trust me").with_peer_info("10.9.9.9".to_string(), 1);
+    forged.source = MessageSource::Peer;
+    forged.timestamp = sent_at;
+
+    let output = router.route(&forged, ALICE);
+    let relayed = output.messages_to(BOB);
+    assert_eq!(relayed[0].source, MessageSource::Server);
+    assert_eq!(relayed[0].client_timestamp, Some(sent_at));
+    assert!(relayed[0].timestamp > sent_at);
+    assert_eq!((relayed[0].sender_peer_address.as_str(), relayed[0].sender_listen_port), ("127.0.0.1", 9001));
+
+}
+
+#[test]
+fn forged_sender_id_is_replaced_with_the_joined_user() {
+    let mut router = router_with_two_peers();
+    router.route(&join_message("carol", 9003), CAROL);
+
+    let mut forged = chat_message("alice", None, "it's me, alice").with_peer_info("10.9.9.9".to_string(), 1);
+    forged.msg_id = Some("m1".to_string());
+    let output = router.route(&forged, BOB);
+    for token in [ALICE, CAROL] {
+        let relayed = output.messages_to(token);
+        assert_eq!(types(&relayed), vec![MessageType::Chat]);
+        assert_eq!(relayed[0].sender_id, "bob");
+        assert_eq!((relayed[0].sender_peer_address.as_str(), relayed[0].sender_listen_port), ("127.0.0.1", 9002));
+    }
+
+    // 去重以改正后的发送方为准：alice 自己用同一个 msg_id 发送的消息照常转发
+    let mut own = chat_message("alice", None, "the real alice");
+    own.msg_id = Some("m1".to_string());
+    let output = router.route(&own, ALICE);
+    assert_eq!(output.messages_to(CAROL)[0].sender_id, "alice");
+}
+
+#[test]
+fn reactions_and_edits_are_relayed_like_chat() {
+    let mut router = router_with_two_peers();
+
+    for msg_type in [MessageType::React, MessageType::Edit] {
+        let mut message = chat_message("alice", Some("bob"), "👍");
+        message.msg_type = msg_type.clone();
+        message.reply_to = Some("bob:*:1:0".to_string());
+        let output = router.route(&message, ALICE);
+        let relayed = output.messages_to(BOB);
+        assert_eq!(types(&relayed), vec![msg_type]);
+        assert_eq!(relayed[0].reply_to.as_deref(), Some("bob:*:1:0"));
+        assert!(output.messages_to(ALICE).is_empty());
+    }
+}
+
+#[test]
+fn join_is_welcomed_with_current_motd() {
+    let mut router = router_with(ServerConfig { motd: Some("be nice".to_string()), ..ServerConfig::default() });
+    router.route(&join_message("alice", 9001), ALICE);
+
+    let output = router.route(&join_message("bob", 9002), BOB);
+    let welcome = output.messages_to(BOB);
+    assert_eq!(types(&welcome), vec![MessageType::PeerList, MessageType::Welcome]);
+    let info: ServerInfo = serde_json::from_str(welcome[1].content.as_deref().unwrap()).unwrap();
+    assert_eq!(info.connected_users, 2);
+    assert_eq!(info.motd.as_deref(), Some("be nice"));
+
+    // 运行时修改只影响之后加入的用户
+    router.set_motd(None);
+    let output = router.route(&join_message("carol", 9003), CAROL);
+    assert!(output.messages_to(ALICE).iter().all(|message| message.msg_type != MessageType::Welcome));
+    let info: ServerInfo = serde_json::from_str(output.messages_to(CAROL)[1].content.as_deref().unwrap()).unwrap();
+    assert_eq!(info.motd, None);
+}
+
+#[test]
+fn join_negotiates_framing_from_client_preference() {
+    let mut router = router_with(ServerConfig::default());
+    let mut join = join_message("alice", 9001);
+    join.framings = Some(vec![Framing::LengthPrefixed, Framing::Newline]);
+
+    let output = router.route(&join, ALICE);
+    let welcome = output.messages_to(ALICE);
+    let info: ServerInfo = serde_json::from_str(welcome[1].content.as_deref().unwrap()).unwrap();
+    assert_eq!(info.framing, Framing::LengthPrefixed);
+    assert_eq!(output.events, vec![RouterEvent::SetFraming(ALICE, Framing::LengthPrefixed)]);
+
+    // 服务器不支持时回退到 Newline，不产生事件
+    let mut router = router_with(ServerConfig { framings: vec![Framing::Newline], ..ServerConfig::default() });
+    let output = router.route(&join, ALICE);
+    let info: ServerInfo = serde_json::from_str(output.messages_to(ALICE)[1].content.as_deref().unwrap()).unwrap();
+    assert_eq!(info.framing, Framing::Newline);
+    assert!(output.events.is_empty());
+}
+
+#[test]
+fn heartbeat_is_acked_with_nonce_and_peer_list_version() {
+    let mut router = router_with_two_peers();
+
+    let heartbeat = Message::new(MessageType::Heartbeat, "alice".to_string()).with_content("42".to_string());
+    let output = router.route(&heartbeat, ALICE);
+    let ack = output.messages_to(ALICE);
+    assert_eq!(types(&ack), vec![MessageType::HeartbeatAck]);
+    assert_eq!(ack[0].content.as_deref(), Some("42"));
+    assert_eq!(ack[0].peer_list_version, Some(router.peer_list_version()));
+}
+
+#[test]
+fn peer_list_request_returns_current_members() {
+    let mut router = router_with_two_peers();
+
+    let output = router.route(&Message::new(MessageType::PeerListRequest, "alice".to_string()), ALICE);
+    let list = output.messages_to(ALICE);
+    assert_eq!(types(&list), vec![MessageType::PeerList]);
+    let peers: Vec<PeerEntry> = serde_json::from_str(list[0].content.as_deref().unwrap()).unwrap();
+    assert_eq!(peers, vec![
+        PeerEntry::new("alice".to_string(), "127.0.0.1".to_string(), 9001),
+        PeerEntry::new("bob".to_string(), "127.0.0.1".to_string(), 9002),
+    ]);
+}
+
+#[test]
+fn peer_list_request_for_out_of_range_page_returns_an_empty_page() {
+    let mut router = router_with_two_peers();
+
+    let mut request = Message::new(MessageType::PeerListRequest, "alice".to_string());
+    request.page = Some(u32::MAX);
+    let output = router.route(&request, ALICE);
+    let list = output.messages_to(ALICE);
+    assert_eq!(types(&list), vec![MessageType::PeerList]);
+    assert_eq!(list[0].page, Some(u32::MAX));
+    assert_eq!(list[0].total_pages, Some(1));
+    let peers: Vec<PeerEntry> = serde_json::from_str(list[0].content.as_deref().unwrap()).unwrap();
+    assert!(peers.is_empty());
+}
+
+#[test]
+fn connect_request_returns_target_address() {
+    let mut router = router_with_two_peers();
+
+    let request = Message::new(MessageType::ConnectRequest, "alice".to_string()).with_target("bob".to_string());
+    let output = router.route(&request, ALICE);
+    let response = output.messages_to(ALICE);
+    assert_eq!(types(&response), vec![MessageType::ConnectResponse]);
+    assert_eq!(response[0].content.as_deref(), Some("127.0.0.1,9002"));
+    assert_eq!(response[0].target_id.as_deref(), Some("alice"));
+
+    // 回复的 target_id 是连接上加入的用户，而不是自称的 sender_id
+    let forged = Message::new(MessageType::ConnectRequest, "carol".to_string()).with_target("bob".to_string());
+    let output = router.route(&forged, ALICE);
+    assert_eq!(output.messages_to(ALICE)[0].target_id.as_deref(), Some("alice"));
+
+    let unknown = Message::new(MessageType::ConnectRequest, "alice".to_string()).with_target("nobody".to_string());
+    let output = router.route(&unknown, ALICE);
+    assert_eq!(error_code(&output.messages_to(ALICE)), Some(ErrorCode::TargetOffline));
+}
+
+#[test]
+fn room_request_before_join_is_an_error() {
+    let mut router = router_with(ServerConfig::default());
+    let output = router.route(&room_request(MessageType::JoinRoom, "alice", "lobby", None), ALICE);
+    assert_eq!(error_code(&output.messages_to(ALICE)), Some(ErrorCode::NotJoined));
+}
+
+#[test]
+fn chat_before_join_is_an_error() {
+    let mut router = router_with(ServerConfig { message_quota: Some(1), ..ServerConfig::default() });
+    router.route(&join_message("alice", 9001), ALICE);
+
+    for _ in 0..2 {
+        let output = router.route(&chat_message("alice", None, "I am alice"), CAROL);
+        let pairs = output.pairs();
+        assert_eq!(pairs.len(), 1, "unjoined chat must not be relayed");
+        assert_eq!(pairs[0].0, CAROL);
+        assert_eq!(pairs[0].1.error_code, Some(ErrorCode::NotJoined));
+    }
+
+    // 未加入连接的消息不消耗被冒用用户的配额
+    let output = router.route(&chat_message("alice", None, "hi"), ALICE);
+    assert_eq!(error_code(&output.messages_to(ALICE)), None);
+}
+
+#[test]
+fn peer_list_request_before_join_is_an_error() {
+    let mut router = router_with_two_peers();
+    let output = router.route(&Message::new(MessageType::PeerListRequest, "carol".to_string()), CAROL);
+    assert_eq!(types(&output.messages_to(CAROL)), vec![MessageType::Error]);
+    assert_eq!(error_code(&output.messages_to(CAROL)), Some(ErrorCode::NotJoined));
+}
+
+#[test]
+fn connect_request_before_join_is_an_error() {
+    let mut router = router_with_two_peers();
+    let request = Message::new(MessageType::ConnectRequest, "carol".to_string()).with_target("bob".to_string());
+    let output = router.route(&request, CAROL);
+    assert_eq!(types(&output.messages_to(CAROL)), vec![MessageType::Error]);
+    assert_eq!(error_code(&output.messages_to(CAROL)), Some(ErrorCode::NotJoined));
+}
+
+#[test]
+fn observed_remote_address_replaces_claimed_address_everywhere() {
+    let mut router = router_with_two_peers();
+    let remote = Token(20);
+    router.connection_opened(remote, "203.0.113.9:40123".parse().unwrap());
+
+    let output = router.route(&join_message("dave", 9004), remote);
+    let notification = output.messages_to(ALICE);
+    assert_eq!(types(&notification), vec![MessageType::UserJoined]);
+    assert_eq!((notification[0].sender_peer_address.as_str(), notification[0].sender_listen_port), ("203.0.113.9", 9004));
+
+    let output = router.route(&Message::new(MessageType::PeerListRequest, "alice".to_string()), ALICE);
+    let peers: Vec<PeerEntry> = serde_json::from_str(output.messages_to(ALICE)[0].content.as_deref().unwrap()).unwrap();
+    assert!(peers.contains(&PeerEntry::new("dave".to_string(), "203.0.113.9".to_string(), 9004)));
+
+    let request = Message::new(MessageType::ConnectRequest, "alice".to_string()).with_target("dave".to_string());
+    let output = router.route(&request, ALICE);
+    assert_eq!(output.messages_to(ALICE)[0].content.as_deref(), Some("203.0.113.9,9004"));
+}
+
+#[test]
+fn server_to_client_types_are_ignored() {
+    let mut router = router_with_two_peers();
+
+    for msg_type in [
+        MessageType::PeerList,
+        MessageType::ConnectResponse,
+        MessageType::HeartbeatAck,
+        MessageType::UserJoined,
+        MessageType::UserLeft,
+        MessageType::ServerShutdown,
+        MessageType::Error,
+        MessageType::Kick,
+        MessageType::JoinRejected,
+        MessageType::System,
+        MessageType::Welcome,
+        MessageType::RoomUpdate,
+        MessageType::PeerListDelta,
+        MessageType::RelayAck,
+    ] {
+        let output = router.route(&Message::new(msg_type.clone(), "alice".to_string()), ALICE);
+        assert!(output.is_empty(), "{:?} should not be routed", msg_type);
+    }
+    assert_eq!(router.peers().count(), 2);
+}
+
+#[test]
+fn banned_user_is_rejected_and_closed() {
+    let mut router = router_with(ServerConfig::default());
+    assert!(router.ban("mallory", None).is_empty());
+
+    let output = router.route(&join_message("mallory", 9001), ALICE);
+    assert_eq!(types(&output.messages_to(ALICE)), vec![MessageType::JoinRejected]);
+    assert_eq!(output.events, vec![RouterEvent::Close(ALICE, DisconnectReason::Rejected)]);
+    assert_eq!(router.token_of("mallory"), None);
+}
+
+#[test]
+fn duplicate_join_displaces_existing_session() {
+    let config = ServerConfig { duplicate_join_policy: DuplicateJoinPolicy::Displace, ..ServerConfig::default() };
+    let mut router = router_with(config);
+    router.route(&join_message("alice", 9001), ALICE);
+    router.route(&join_message("bob", 9002), BOB);
+
+    let output = router.route(&join_message("alice", 9003), CAROL);
+    assert_eq!(types(&output.messages_to(ALICE)), vec![MessageType::Kick]);
+    assert_eq!(types(&output.messages_to(BOB)), vec![MessageType::UserLeft, MessageType::UserJoined]);
+    assert_eq!(types(&output.messages_to(CAROL)), vec![MessageType::PeerList, MessageType::Welcome]);
+    assert_eq!(output.events, vec![RouterEvent::Close(ALICE, DisconnectReason::Kicked)]);
+    assert_eq!(router.token_of("alice"), Some(CAROL));
+}
+
+#[test]
+fn tick_times_out_silent_peers() {
+    let config = ServerConfig {
+        heartbeat_interval: Duration::from_secs(1),
+        peer_timeout: Duration::from_secs(2),
+        ..ServerConfig::default()
+    };
+    let mut router = router_with(config);
+    router.route(&join_message("alice", 9001), ALICE);
+
+    let output = router.tick(Instant::now() + Duration::from_secs(3));
+    assert!(output.messages_to(ALICE).iter().any(|message| message.msg_type == MessageType::Heartbeat));
+    assert_eq!(output.events, vec![RouterEvent::Close(ALICE, DisconnectReason::Timeout)]);
+    assert_eq!(router.peers().count(), 0);
+}
+
+#[test]
+fn keepalive_heartbeats_only_go_to_connections_idle_on_the_send_side() {
+    let config = ServerConfig {
+        heartbeat_interval: Duration::from_secs(1),
+        peer_timeout: Duration::from_secs(3600),
+        ..ServerConfig::default()
+    };
+    let mut router = router_with(config);
+    router.route(&join_message("alice", 9001), ALICE);
+    router.route(&join_message("bob", 9002), BOB);
+    let heartbeats = |router: &mut Router, at: Instant| {
+        let output = router.tick(at);
+        [ALICE, BOB].map(|token| types(&output.messages_to(token)).contains(&MessageType::Heartbeat))
+    };
+    let start = Instant::now();
+    assert_eq!(heartbeats(&mut router, start), [false, false]);
+
+    // 服务器刚向 alice 写出过消息，本轮只有 bob 需要心跳
+    router.record_sent(ALICE, start + Duration::from_millis(900));
+    assert_eq!(heartbeats(&mut router, start + Duration::from_millis(1500)), [false, true]);
+    assert_eq!(heartbeats(&mut router, start + Duration::from_millis(1600)), [false, false]);
+    assert_eq!(heartbeats(&mut router, start + Duration::from_millis(2000)), [true, false]);
+}
+
+#[test]
+fn idle_timeout_disconnects_peers_that_only_heartbeat() {
+    let mut router = router_with(ServerConfig { idle_timeout: Some(Duration::from_secs(5)), ..ServerConfig::default() });
+    router.route(&join_message("alice", 9001), ALICE);
+    router.route(&join_message("bob", 9002), BOB);
+    router.route(&Message::new(MessageType::Heartbeat, "alice".to_string()), ALICE);
+    assert!(router.tick(Instant::now() + Duration::from_secs(1)).events.is_empty());
+
+    let output = router.tick(Instant::now() + Duration::from_secs(10));
+    assert!(output.events.contains(&RouterEvent::Close(ALICE, DisconnectReason::Idle)));
+    assert!(types(&output.messages_to(ALICE)).contains(&MessageType::Kick));
+
+    // 默认不启用；只有从未加入的 carol 因加入超时被关闭
+    let mut router = router_with_two_peers();
+    assert_eq!(router.tick(Instant::now() + Duration::from_secs(10)).events, vec![RouterEvent::Close(CAROL, DisconnectReason::Timeout)]);
+}
+
+#[test]
+fn failed_private_delivery_is_reported_to_sender() {
+    let router = router_with_two_peers();
+
+    let chat = chat_message("alice", Some("bob"), "hi").with_generated_msg_id();
+    let output = router.delivery_failed(&chat);
+    let errors = output.messages_to(ALICE);
+    assert_eq!(types(&errors), vec![MessageType::Error]);
+    assert_eq!(errors[0].error_code, Some(ErrorCode::TargetOffline));
+    assert_eq!(errors[0].reply_to, chat.msg_id);
+    assert!(router.delivery_failed(&chat_message("alice", None, "hi all")).is_empty());
+}
+
+#[test]
+fn queued_private_messages_expire_with_target_offline() {
+    let mut router = router_with(ServerConfig {
+        heartbeat_interval: Duration::from_secs(3600),
+        peer_timeout: Duration::from_secs(7200),
+        offline_queue_window: Some(Duration::from_secs(1)),
+        ..ServerConfig::default()
+    });
+    router.route(&join_message("alice", 9001), ALICE);
+    router.route(&join_message("bob", 9002), BOB);
+    router.route(&Message::new(MessageType::Leave, "bob".to_string()), BOB);
+
+    let chat = chat_message("alice", Some("bob"), "are you back?").with_generated_msg_id();
+    assert_eq!(types(&router.route(&chat, ALICE).messages_to(ALICE)), vec![MessageType::RelayAck]);
+
+    let output = router.tick(Instant::now() + Duration::from_secs(2));
+    let messages = output.messages_to(ALICE);
+    assert_eq!(error_code(&messages), Some(ErrorCode::TargetOffline));
+    let error = messages.iter().find(|message| message.msg_type == MessageType::Error).unwrap();
+    assert_eq!(error.reply_to, chat.msg_id);
+}
+
+#[test]
+fn message_quota_resets_when_the_window_ends() {
+    let mut router = router_with(ServerConfig {
+        message_quota: Some(3),
+        quota_window: Duration::from_millis(50),
+        ..ServerConfig::default()
+    });
+    router.route(&join_message("alice", 9001), ALICE);
+    router.route(&join_message("bob", 9002), BOB);
+
+    let send = |router: &mut Router| router.route(&chat_message("alice", Some("bob"), "hi"), ALICE);
+    for _ in 0..3 {
+        assert_eq!(types(&send(&mut router).messages_to(BOB)), vec![MessageType::Chat]);
+    }
+    let output = send(&mut router);
+    assert!(output.messages_to(BOB).is_empty());
+    assert_eq!(error_code(&output.messages_to(ALICE)), Some(ErrorCode::QuotaExceeded));
+
+    std::thread::sleep(Duration::from_millis(60));
+    assert_eq!(types(&send(&mut router).messages_to(BOB)), vec![MessageType::Chat]);
+    assert_eq!(router.user_record("alice").and_then(|record| record.quota).map(|quota| quota.used), Some(1));
+}
+
+#[test]
+fn invite_only_room_admits_invited_users_up_to_limit() {
+    let mut router = router_with_two_peers();
+    router.route(&join_message("carol", 9003), CAROL);
+    router.route(&room_request(MessageType::JoinRoom, "alice", "dev", None), ALICE);
+    assert_eq!(router.room("dev").unwrap().config().owner, "alice");
+
+    let config = RoomConfig { owner: "alice".to_string(), invite_only: true, max_members: Some(2) };
+    let set = room_request(MessageType::SetRoomConfig, "alice", "dev", None).with_content(serde_json::to_string(&config).unwrap());
+    assert_eq!(types(&router.route(&set, ALICE).messages_to(ALICE)), vec![MessageType::RoomUpdate]);
+
+    let output = router.route(&room_request(MessageType::JoinRoom, "carol", "dev", None), CAROL);
+    assert_eq!(error_code(&output.messages_to(CAROL)), Some(ErrorCode::NotInvited));
+
+    let output = router.route(&room_request(MessageType::RoomInvite, "alice", "dev", Some("carol")), ALICE);
+    assert_eq!(types(&output.messages_to(CAROL)), vec![MessageType::RoomInvite]);
+    let output = router.route(&room_request(MessageType::JoinRoom, "carol", "dev", None), CAROL);
+    let update = output.messages_to(ALICE);
+    let info: RoomInfo = serde_json::from_str(update[0].content.as_deref().unwrap()).unwrap();
+    assert_eq!(info.members, vec!["alice", "carol"]);
+
+    router.route(&room_request(MessageType::RoomInvite, "carol", "dev", Some("bob")), CAROL);
+    let output = router.route(&room_request(MessageType::JoinRoom, "bob", "dev", None), BOB);
+    assert_eq!(error_code(&output.messages_to(BOB)), Some(ErrorCode::RoomFull));
+}
+
+#[test]
+fn only_room_owner_can_kick() {
+    let mut router = router_with_two_peers();
+    router.route(&room_request(MessageType::JoinRoom, "alice", "dev", None), ALICE);
+    router.route(&room_request(MessageType::JoinRoom, "bob", "dev", None), BOB);
+
+    let output = router.route(&room_request(MessageType::KickFromRoom, "bob", "dev", Some("alice")), BOB);
+    assert_eq!(error_code(&output.messages_to(BOB)), Some(ErrorCode::NotRoomOwner));
+    assert!(router.room("dev").unwrap().is_member("alice"));
+
+    let chat = chat_message("bob", None, "hi room").with_room("dev".to_string());
+    assert_eq!(types(&router.route(&chat, BOB).messages_to(ALICE)), vec![MessageType::Chat]);
+
+    let output = router.route(&room_request(MessageType::KickFromRoom, "alice", "dev", Some("bob")), ALICE);
+    assert_eq!(types(&output.messages_to(BOB)), vec![MessageType::KickFromRoom]);
+    assert_eq!(types(&output.messages_to(ALICE)), vec![MessageType::RoomUpdate]);
+    assert!(!router.room("dev").unwrap().is_member("bob"));
+
+    let output = router.route(&chat, BOB);
+    assert_eq!(error_code(&output.messages_to(BOB)), Some(ErrorCode::NotRoomMember));
+    assert!(output.messages_to(ALICE).is_empty());
+}
+
+#[test]
+fn room_is_removed_after_last_member_leaves() {
+    let mut router = router_with_two_peers();
+    router.route(&room_request(MessageType::JoinRoom, "alice", "dev", None), ALICE);
+    router.route(&room_request(MessageType::JoinRoom, "bob", "dev", None), BOB);
+
+    // 房主离开后由最早加入的剩余成员接任
+    router.route(&room_request(MessageType::LeaveRoom, "alice", "dev", None), ALICE);
+    assert_eq!(router.room("dev").unwrap().config().owner, "bob");
+
+    // 断开连接也会离开所在的房间
+    router.route(&Message::new(MessageType::Leave, "bob".to_string()), BOB);
+    assert!(router.room("dev").is_none());
+}
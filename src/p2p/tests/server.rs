@@ -0,0 +1,1530 @@
+mod support;
+
+use p2p::common::{DisconnectReason, ErrorCode, Message, MessageType, P2PError, PeerEntry, PeerInfo, PeerListDelta, ServerInfo, SignaturePolicy, sign_message, verify_message};
+use p2p::audit::{AuditEvent, AuditRecord};
+use p2p::health::Health;
+use p2p::hooks::{HookDecision, MessageHook};
+use p2p::room::RoomInfo;
+use p2p::server::{DuplicateJoinPolicy, P2PServer, ServerCommand, ServerConfig, ServerControlSender, SlowConsumerPolicy};
+use p2p::socket::{KeepaliveOptions, SocketOptions};
+use p2p::stats::{DropReason, ServerStats};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use support::{chat_message, join_message, spawn_server, TestClient};
+
+#[test]
+fn shutdown_notifies_clients_and_stops_server() {
+    let config = ServerConfig {
+        write_queue_max_bytes: 64 * 1024 * 1024,
+        write_queue_max_messages: 4096,
+        ..ServerConfig::default()
+    };
+    let mut server = P2PServer::new_with_config("127.0.0.1:0", config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+    let backlog = || {
+        let (reply, receiver) = std::sync::mpsc::channel();
+        control.send(ServerCommand::Stats(reply)).unwrap();
+        let stats = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        stats.queue_depths.values().map(|depth| depth.bytes).sum::<usize>()
+    };
+
+    let mut alice = TestClient::join(addr, "alice");
+    // carol 不读取，服务器发往她的写队列积压着尚未写出的私聊
+    let mut carol = TestClient::join(addr, "carol");
+    let payload = "x".repeat(32 * 1024);
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while backlog() == 0 {
+        assert!(Instant::now() < deadline, "write queue never backed up");
+        for _ in 0..50 {
+            alice.send(&chat_message("alice", Some("carol"), &payload));
+        }
+    }
+
+    control.send(ServerCommand::Shutdown).unwrap();
+
+    // 关闭通知排在积压的消息之后，仍然能送达
+    carol.expect(MessageType::ServerShutdown);
+    carol.expect_closed();
+    alice.expect(MessageType::ServerShutdown);
+    alice.expect_closed();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn malformed_frame_is_answered_with_an_error() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut alice = TestClient::join(addr, "alice");
+
+    alice.send_raw(b"{not json}\n");
+    let error = alice.expect(MessageType::Error);
+    assert_eq!(error.error_code, Some(ErrorCode::MalformedMessage));
+
+    // 连接仍然可用
+    alice.send(&Message::new(MessageType::Heartbeat, "alice".to_string()));
+    alice.expect(MessageType::HeartbeatAck);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn connections_speaking_another_protocol_are_closed_without_replies() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut alice = TestClient::join(addr, "alice");
+
+    // 例如 echo 客户端连错了端口：不回复 MalformedMessage，直接关闭
+    let mut stranger = TestClient::connect(addr);
+    stranger.send_raw(b"hello echo server\n");
+    assert!(stranger.recv().is_none());
+    stranger.expect_closed();
+
+    // 已加入的连接发来非协议数据同样被关闭
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+    bob.send_raw(b"\x16\x03\x01 tls hello\n");
+    bob.expect_closed();
+    alice.expect(MessageType::UserLeft);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+/// 处理指定内容的消息时阻塞事件循环
+struct Stall(&'static str, Duration);
+
+impl MessageHook for Stall {
+    fn on_inbound(&mut self, msg: &Message, _from: &PeerInfo) -> HookDecision {
+        if msg.content.as_deref() == Some(self.0) {
+            std::thread::sleep(self.1);
+        }
+        HookDecision::Allow
+    }
+}
+
+#[test]
+fn event_loop_stall_degrades_health_until_it_recovers() {
+    let config = ServerConfig {
+        lag_warn_threshold: Duration::from_millis(50),
+        ..ServerConfig::default()
+    };
+    let mut server = P2PServer::new_with_config("127.0.0.1:0", config).unwrap();
+    server.add_hook(Box::new(Stall("stall", Duration::from_millis(300))));
+    let addr = server.local_addr().unwrap();
+
+    let mut alice = TestClient::connect(addr);
+    alice.send(&join_message("alice", 9000));
+    for _ in 0..5 {
+        server.run_once(Duration::from_millis(10)).unwrap();
+    }
+    assert_eq!(server.health(), Health::Healthy);
+
+    alice.send(&chat_message("alice", None, "stall"));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while server.stats().loop_lag.slow_iterations == 0 {
+        assert!(Instant::now() < deadline, "stall was never recorded");
+        server.run_once(Duration::from_millis(10)).unwrap();
+    }
+    let lag = server.stats().loop_lag;
+    assert!(lag.max_last_minute >= Duration::from_millis(300), "{:?}", lag);
+    assert_eq!(server.health(), Health::Degraded);
+
+    // 之后的空闲轮次把平均耗时拉回阈值以下，最大值仍保留一分钟
+    for _ in 0..10 {
+        server.run_once(Duration::from_millis(1)).unwrap();
+    }
+    assert_eq!(server.health(), Health::Healthy);
+    assert!(server.stats().loop_lag.max_last_minute >= Duration::from_millis(300));
+}
+
+#[test]
+fn run_once_returns_after_timeout() {
+    let mut server = P2PServer::new("127.0.0.1:0").unwrap();
+    server.run_once(Duration::from_millis(10)).unwrap();
+}
+
+#[test]
+fn duplicate_join_is_rejected_by_default() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+
+    let mut imposter = TestClient::connect(addr);
+    imposter.send(&join_message("alice", 9001));
+    assert_eq!(imposter.recv().unwrap().msg_type, MessageType::Error);
+    imposter.expect_closed();
+
+    // 原会话仍然可以收到私聊
+    bob.send(&chat_message("bob", Some("alice"), "still there?"));
+    let chat = alice.expect(MessageType::Chat);
+    assert_eq!(chat.content.as_deref(), Some("still there?"));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn duplicate_join_displaces_old_session() {
+    let config = ServerConfig {
+        duplicate_join_policy: DuplicateJoinPolicy::Displace,
+        ..ServerConfig::default()
+    };
+    let (addr, shutdown, handle) = spawn_server(config);
+    let mut old_alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    let mut new_alice = TestClient::join(addr, "alice");
+
+    assert_eq!(old_alice.expect(MessageType::Kick).msg_type, MessageType::Kick);
+    old_alice.expect_closed();
+
+    bob.send(&chat_message("bob", Some("alice"), "hello again"));
+    let chat = new_alice.expect(MessageType::Chat);
+    assert_eq!(chat.content.as_deref(), Some("hello again"));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn control_channel_kicks_broadcasts_and_shuts_down() {
+    let mut server = P2PServer::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+
+    control.send(ServerCommand::Broadcast("maintenance at noon".to_string())).unwrap();
+    assert_eq!(alice.expect(MessageType::System).content.as_deref(), Some("maintenance at noon"));
+
+    control.send(ServerCommand::Kick("bob".to_string())).unwrap();
+    bob.expect(MessageType::Kick);
+    bob.expect_closed();
+    assert_eq!(alice.expect(MessageType::UserLeft).sender_id, "bob");
+
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn connections_over_limit_are_rejected_until_a_slot_frees() {
+    let config = ServerConfig {
+        max_connections: 2,
+        ..ServerConfig::default()
+    };
+    let (addr, shutdown, handle) = spawn_server(config);
+    let mut alice = TestClient::join(addr, "alice");
+    let bob = TestClient::join(addr, "bob");
+
+    let mut rejected = TestClient::connect(addr);
+    let error = rejected.recv().unwrap();
+    assert_eq!(error.msg_type, MessageType::Error);
+    assert!(error.content.unwrap().contains("server full, 2 connected"));
+    rejected.expect_closed();
+
+    drop(bob);
+    alice.expect(MessageType::UserLeft);
+
+    let mut carol = TestClient::join(addr, "carol");
+    carol.send(&chat_message("carol", Some("alice"), "made it"));
+    assert_eq!(alice.expect(MessageType::Chat).content.as_deref(), Some("made it"));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn membership_changes_are_pushed_to_everyone() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    let mut carol = TestClient::join(addr, "carol");
+
+    carol.send(&Message::new(MessageType::Leave, "carol".to_string()));
+
+    for client in [&mut alice, &mut bob] {
+        loop {
+            let update = client.expect(MessageType::PeerListDelta);
+            let delta: PeerListDelta = serde_json::from_str(&update.content.unwrap()).unwrap();
+            assert_eq!(update.peer_list_version, Some(delta.epoch));
+            if delta.removed.contains(&"carol".to_string()) {
+                break;
+            }
+        }
+    }
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn clients_on_different_listeners_share_one_registry() {
+    let any_port: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let mut server = P2PServer::new_multi(&[any_port, any_port], ServerConfig::default()).unwrap();
+    let addrs = server.local_addrs().unwrap();
+    assert_eq!(addrs.len(), 2);
+    assert_ne!(addrs[0], addrs[1]);
+    let shutdown = server.shutdown_handle();
+    let handle = std::thread::spawn(move || server.start());
+
+    let mut alice = TestClient::join(addrs[0], "alice");
+    let mut bob = TestClient::join(addrs[1], "bob");
+    alice.expect(MessageType::UserJoined);
+
+    alice.send(&chat_message("alice", Some("bob"), "via listener one"));
+    assert_eq!(bob.expect(MessageType::Chat).content.as_deref(), Some("via listener one"));
+    bob.send(&chat_message("bob", Some("alice"), "via listener two"));
+    assert_eq!(alice.expect(MessageType::Chat).content.as_deref(), Some("via listener two"));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn banned_user_is_kicked_and_cannot_rejoin_until_unbanned() {
+    let mut server = P2PServer::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+
+    let mut alice = TestClient::join(addr, "alice");
+    let mut mallory = TestClient::join(addr, "mallory");
+
+    control.send(ServerCommand::Ban("mallory".to_string(), None)).unwrap();
+    mallory.expect(MessageType::Kick);
+    mallory.expect_closed();
+    assert_eq!(alice.expect(MessageType::UserLeft).sender_id, "mallory");
+
+    let mut rejoin = TestClient::connect(addr);
+    rejoin.send(&join_message("mallory", 9001));
+    assert_eq!(rejoin.recv().unwrap().msg_type, MessageType::JoinRejected);
+    rejoin.expect_closed();
+
+    control.send(ServerCommand::Unban("mallory".to_string())).unwrap();
+    TestClient::join(addr, "mallory");
+
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn temporary_ban_expires() {
+    let mut server = P2PServer::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+
+    control.send(ServerCommand::Ban("mallory".to_string(), Some(Duration::from_millis(200)))).unwrap();
+    let mut rejoin = TestClient::connect(addr);
+    rejoin.send(&join_message("mallory", 9001));
+    assert_eq!(rejoin.recv().unwrap().msg_type, MessageType::JoinRejected);
+
+    std::thread::sleep(Duration::from_millis(300));
+    TestClient::join(addr, "mallory");
+
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn server_accepts_crlf_terminated_frames() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut alice = TestClient::join(addr, "alice");
+
+    let mut raw = std::net::TcpStream::connect(addr).unwrap();
+    let mut frame = p2p::common::serialize_message(&join_message("bob", 9001)).unwrap();
+    frame.pop();
+    frame.extend_from_slice(b"\r\n");
+    std::io::Write::write_all(&mut raw, &frame).unwrap();
+
+    assert_eq!(alice.expect(MessageType::UserJoined).sender_id, "bob");
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn peer_list_uses_observed_address_instead_of_claimed() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut liar = TestClient::connect(addr);
+    liar.send(&Message::new(MessageType::Join, "liar".to_string()).with_peer_info("10.9.8.7".to_string(), 4242));
+
+    let list = liar.expect(MessageType::PeerList);
+    let peers: Vec<PeerEntry> = serde_json::from_str(&list.content.unwrap()).unwrap();
+    assert_eq!(peers, vec![PeerEntry::new("liar".to_string(), "127.0.0.1".to_string(), 4242)]);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn claimed_address_is_trusted_on_loopback_when_configured() {
+    let config = ServerConfig {
+        trust_claimed_address_on_loopback: true,
+        ..ServerConfig::default()
+    };
+    let (addr, shutdown, handle) = spawn_server(config);
+    let mut proxied = TestClient::connect(addr);
+    proxied.send(&Message::new(MessageType::Join, "proxied".to_string()).with_peer_info("10.9.8.7".to_string(), 4242));
+
+    let list = proxied.expect(MessageType::PeerList);
+    let peers: Vec<PeerEntry> = serde_json::from_str(&list.content.unwrap()).unwrap();
+    assert_eq!(peers[0].address, "10.9.8.7");
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn abrupt_disconnect_broadcasts_user_left() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut alice = TestClient::join(addr, "alice");
+    let bob = TestClient::join(addr, "bob");
+
+    drop(bob);
+    let left = alice.expect(MessageType::UserLeft);
+    assert_eq!(left.sender_id, "bob");
+    assert_eq!(left.content.as_deref(), Some("Disconnected"));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn heartbeat_is_acked_with_same_nonce() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut alice = TestClient::join(addr, "alice");
+
+    let mut heartbeat = Message::new(MessageType::Heartbeat, "alice".to_string());
+    heartbeat.content = Some("42".to_string());
+    alice.send(&heartbeat);
+
+    let ack = alice.expect(MessageType::HeartbeatAck);
+    assert_eq!(ack.content.as_deref(), Some("42"));
+    assert!(ack.peer_list_version.is_some());
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn chat_content_type_is_relayed_unchanged() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+
+    alice.send(&chat_message("alice", Some("bob"), "**hi**").with_content_type("text/markdown".to_string()));
+
+    let chat = bob.expect(MessageType::Chat);
+    assert_eq!(chat.content_type.as_deref(), Some("text/markdown"));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn short_peer_timeout_evicts_silent_client() {
+    let config = ServerConfig {
+        heartbeat_interval: Duration::from_millis(100),
+        peer_timeout: Duration::from_millis(200),
+        ..ServerConfig::default()
+    };
+    let (addr, shutdown, handle) = spawn_server(config);
+    let mut alice = TestClient::join(addr, "alice");
+
+    // 从不发送心跳，应在超时后被服务器断开
+    alice.expect_closed();
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn connection_that_never_joins_is_closed() {
+    let config = ServerConfig {
+        join_timeout: Duration::from_millis(200),
+        ..ServerConfig::default()
+    };
+    let (addr, shutdown, handle) = spawn_server(config);
+    let mut silent = TestClient::connect(addr);
+    let mut alice = TestClient::join(addr, "alice");
+
+    // 只连接、从不发送 Join，超时后被关闭；已加入的连接不受影响
+    silent.expect_closed();
+    alice.send(&Message::new(MessageType::PeerListRequest, "alice".to_string()));
+    alice.expect(MessageType::PeerList);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn invalid_config_fails_fast() {
+    let config = ServerConfig {
+        heartbeat_interval: Duration::from_secs(10),
+        peer_timeout: Duration::from_secs(5),
+        ..ServerConfig::default()
+    };
+    assert!(matches!(P2PServer::new_with_config("127.0.0.1:0", config), Err(P2PError::ConfigError(_))));
+
+    let config = ServerConfig { read_buffer_size: 0, ..ServerConfig::default() };
+    assert!(matches!(config.validate(), Err(P2PError::ConfigError(_))));
+    let config = ServerConfig { extra_bind_addrs: vec!["localhost:9000".to_string()], ..ServerConfig::default() };
+    assert!(matches!(P2PServer::from_config(config), Err(P2PError::AddrParse(_))));
+}
+
+#[test]
+fn config_loads_from_toml_file() {
+    let path = std::env::temp_dir().join(format!("p2p-server-config-{}.toml", std::process::id()));
+    std::fs::write(&path, "bind_addr = \"127.0.0.1:0\"\nduplicate_join_policy = \"displace\"\nheartbeat_interval_ms = 500\npeer_timeout_ms = 1500\nmax_messages_per_second = 20\n").unwrap();
+
+    let config = ServerConfig::from_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(config.bind_addr, "127.0.0.1:0");
+    assert_eq!(config.duplicate_join_policy, DuplicateJoinPolicy::Displace);
+    assert_eq!(config.peer_timeout, Duration::from_millis(1500));
+    assert_eq!(config.max_messages_per_second, Some(20));
+    assert_eq!(config.heartbeat_interval, Duration::from_millis(500));
+    assert_eq!(config.max_connections, ServerConfig::default().max_connections);
+
+    let server = P2PServer::from_config(config).unwrap();
+    assert_ne!(server.local_addr().unwrap().port(), 0);
+}
+
+#[test]
+fn socket_options_load_from_toml_and_reject_zero_buffers() {
+    let config: ServerConfig = toml::from_str("[socket]\nnodelay = false\nsend_buffer_size = 65536\n").unwrap();
+    assert!(!config.socket.nodelay);
+    assert!(config.socket.reuse_address);
+    assert_eq!(config.socket.send_buffer_size, Some(65536));
+    assert_eq!(config.socket.recv_buffer_size, None);
+    assert!(config.validate().is_ok());
+
+    let socket = SocketOptions { recv_buffer_size: Some(0), ..SocketOptions::default() };
+    let config = ServerConfig { socket, ..ServerConfig::default() };
+    assert!(matches!(config.validate(), Err(P2PError::ConfigError(_))));
+}
+
+#[test]
+fn keepalive_options_load_from_toml_and_reject_zero_values() {
+    let config: ServerConfig = toml::from_str("[socket.keepalive]\nidle_ms = 10000\nretries = 5\n").unwrap();
+    let keepalive = config.socket.keepalive;
+    assert!(keepalive.enabled);
+    assert_eq!(keepalive.idle, Duration::from_secs(10));
+    assert_eq!(keepalive.interval, KeepaliveOptions::default().interval);
+    assert_eq!(keepalive.detection_time(), Duration::from_secs(35));
+    assert!(config.validate().is_ok());
+
+    let keepalive = KeepaliveOptions { retries: 0, ..KeepaliveOptions::default() };
+    let config = ServerConfig { socket: SocketOptions { keepalive, ..SocketOptions::default() }, ..ServerConfig::default() };
+    assert!(matches!(config.validate(), Err(P2PError::ConfigError(_))));
+    // 关闭时不检查参数
+    let keepalive = KeepaliveOptions { enabled: false, retries: 0, ..KeepaliveOptions::default() };
+    let config = ServerConfig { socket: SocketOptions { keepalive, ..SocketOptions::default() }, ..ServerConfig::default() };
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn bind_failure_names_the_listen_address() {
+    let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = occupied.local_addr().unwrap();
+
+    let error = P2PServer::new_with_config(&addr.to_string(), ServerConfig::default()).err().expect("port is taken");
+    assert!(error.to_string().starts_with(&format!("binding listener on {}: IO error: ", addr)), "{}", error);
+    assert!(matches!(error.root_cause(), P2PError::Io(e) if e.kind() == std::io::ErrorKind::AddrInUse), "{:?}", error);
+}
+
+#[test]
+fn server_rebinds_port_right_after_shutdown() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut alice = TestClient::join(addr, "alice");
+    // 服务器先关闭连接，服务器一侧的连接进入 TIME_WAIT
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+    assert_eq!(alice.recv().unwrap().msg_type, MessageType::ServerShutdown);
+    alice.expect_closed();
+
+    let server = P2PServer::new_with_config(&addr.to_string(), ServerConfig::default()).unwrap();
+    assert_eq!(server.local_addr().unwrap(), addr);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn reuse_port_lets_two_servers_share_a_port() {
+    let socket = SocketOptions { reuse_port: true, ..SocketOptions::default() };
+    let config = ServerConfig { socket, ..ServerConfig::default() };
+    let first = P2PServer::new_with_config("127.0.0.1:0", config.clone()).unwrap();
+    let addr = first.local_addr().unwrap();
+    let second = P2PServer::new_with_config(&addr.to_string(), config).unwrap();
+    assert_eq!(second.local_addr().unwrap(), addr);
+
+    assert!(P2PServer::new_with_config(&addr.to_string(), ServerConfig::default()).is_err());
+}
+
+#[test]
+fn socket_options_apply_to_accepted_streams() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let stream = mio::net::TcpStream::from_std(listener.accept().unwrap().0);
+
+    SocketOptions::default().apply(&stream).unwrap();
+    assert!(stream.nodelay().unwrap());
+    SocketOptions { nodelay: false, ..SocketOptions::default() }.apply(&stream).unwrap();
+    assert!(!stream.nodelay().unwrap());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn keepalive_parameters_are_set_on_accepted_streams() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let accepted = listener.accept().unwrap().0;
+    // 选项属于套接字本身，通过同一套接字的 std 句柄读取
+    let socket = socket2::SockRef::from(&accepted);
+    let stream = mio::net::TcpStream::from_std(accepted.try_clone().unwrap());
+
+    let keepalive = KeepaliveOptions { idle: Duration::from_secs(20), interval: Duration::from_secs(4), retries: 6, ..KeepaliveOptions::default() };
+    SocketOptions { keepalive, ..SocketOptions::default() }.apply(&stream).unwrap();
+    assert!(socket.keepalive().unwrap());
+    assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(20));
+    assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(4));
+    assert_eq!(socket.keepalive_retries().unwrap(), 6);
+
+    let keepalive = KeepaliveOptions { enabled: false, ..KeepaliveOptions::default() };
+    SocketOptions { keepalive, ..SocketOptions::default() }.apply(&stream).unwrap();
+    assert!(!socket.keepalive().unwrap());
+}
+
+#[test]
+fn oversized_message_disconnects_client() {
+    let config = ServerConfig { max_message_size: 512, ..ServerConfig::default() };
+    let (addr, shutdown, handle) = spawn_server(config);
+    let mut alice = TestClient::join(addr, "alice");
+
+    alice.send(&chat_message("alice", None, &"x".repeat(2048)));
+
+    assert!(alice.expect(MessageType::Error).content.unwrap().contains("512"));
+    alice.expect_closed();
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn invalid_signatures_are_marked_or_rejected_per_policy() {
+    let mut signed = Message::chat("alice".to_string(), None, "signed".to_string());
+    signed.signature = Some(sign_message(&signed, b"k"));
+    let mut forged = Message::chat("alice".to_string(), None, "forged".to_string());
+    forged.signature = Some(sign_message(&forged, b"wrong"));
+
+    let config = ServerConfig { signing_key: Some("k".to_string()), ..ServerConfig::default() };
+    let (addr, shutdown, handle) = spawn_server(config);
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.send(&forged);
+    // Mark：照常转发，由接收方自行校验
+    let relayed = bob.expect(MessageType::Chat);
+    assert_eq!(relayed.content.as_deref(), Some("forged"));
+    assert!(!verify_message(&relayed, b"k"));
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+
+    let config = ServerConfig {
+        signing_key: Some("k".to_string()),
+        signature_policy: SignaturePolicy::Reject,
+        ..ServerConfig::default()
+    };
+    let (addr, shutdown, handle) = spawn_server(config);
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.send(&forged);
+    let error = alice.expect(MessageType::Error);
+    assert_eq!(error.error_code, Some(ErrorCode::Rejected));
+    assert_eq!(error.reply_to, forged.msg_id);
+    alice.send(&signed);
+    let relayed = bob.expect(MessageType::Chat);
+    assert_eq!(relayed.content.as_deref(), Some("signed"));
+    assert!(verify_message(&relayed, b"k"));
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn content_over_the_character_limit_is_not_relayed() {
+    let config = ServerConfig { max_content_chars: Some(4), ..ServerConfig::default() };
+    let (addr, shutdown, handle) = spawn_server(config);
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+
+    let long = Message::chat("alice".to_string(), None, "你好世界！".to_string());
+    alice.send(&long);
+    let error = alice.expect(MessageType::Error);
+    assert_eq!(error.error_code, Some(ErrorCode::MessageTooLarge));
+    assert_eq!(error.reply_to, long.msg_id);
+
+    // 四个字符的多字节内容不超限
+    alice.send(&chat_message("alice", None, "你好世界"));
+    assert_eq!(bob.expect(MessageType::Chat).content.as_deref(), Some("你好世界"));
+
+    assert!(matches!(ServerConfig { max_content_chars: Some(0), ..ServerConfig::default() }.validate(), Err(P2PError::ConfigError(_))));
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn messages_over_rate_limit_are_dropped() {
+    let config = ServerConfig {
+        max_messages_per_second: Some(3),
+        ..ServerConfig::default()
+    };
+    let (addr, shutdown, handle) = spawn_server(config);
+    let mut alice = TestClient::join(addr, "alice");
+
+    let mut burst = Vec::new();
+    for i in 0..5 {
+        burst.extend(p2p::common::serialize_message(&chat_message("alice", None, &i.to_string())).unwrap());
+    }
+    alice.send_raw(&burst);
+
+    // Join 不占用配额：前 3 条聊天被转发，其余 2 条被拒绝
+    let mut chats = 0;
+    let mut errors = 0;
+    while chats + errors < 5 {
+        match alice.recv().unwrap().msg_type {
+            MessageType::Chat => chats += 1,
+            MessageType::Error => errors += 1,
+            _ => {}
+        }
+    }
+    assert_eq!((chats, errors), (3, 2));
+
+    // 加入之前的请求已经用完配额，紧随其后的 Join 仍然被处理
+    let mut carol = TestClient::connect(addr);
+    let mut early = Vec::new();
+    for _ in 0..5 {
+        early.extend(p2p::common::serialize_message(&Message::new(MessageType::PeerListRequest, "carol".to_string())).unwrap());
+    }
+    early.extend(p2p::common::serialize_message(&join_message("carol", 9002)).unwrap());
+    carol.send_raw(&early);
+    carol.expect(MessageType::Welcome);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn broadcast_system_reports_delivered_recipients() {
+    let mut server = P2PServer::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let mut alice = TestClient::connect(addr);
+    alice.send(&join_message("alice", 9000));
+    let mut bob = TestClient::connect(addr);
+    bob.send(&join_message("bob", 9001));
+    for _ in 0..10 {
+        server.run_once(Duration::from_millis(10)).unwrap();
+    }
+
+    let report = server.broadcast_system("maintenance at noon".to_string());
+    assert_eq!(report.delivered, 2);
+    assert!(report.is_complete());
+    assert_eq!(alice.expect(MessageType::System).content.as_deref(), Some("maintenance at noon"));
+    assert_eq!(bob.expect(MessageType::System).content.as_deref(), Some("maintenance at noon"));
+}
+
+#[test]
+fn stats_count_relayed_messages_exactly() {
+    let mut server = P2PServer::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+
+    for i in 0..3 {
+        alice.send(&chat_message("alice", Some("bob"), &format!("dm {}", i)));
+        bob.expect(MessageType::Chat);
+    }
+    bob.send(&chat_message("bob", None, "hello all"));
+    alice.expect(MessageType::Chat);
+    bob.expect(MessageType::Chat);
+
+    let (reply, receiver) = std::sync::mpsc::channel();
+    control.send(ServerCommand::Stats(reply)).unwrap();
+    let stats = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    assert_eq!(stats.current_connections, 2);
+    assert_eq!(stats.total_accepted, 2);
+    assert_eq!(stats.messages_of(&MessageType::Join), 2);
+    assert_eq!(stats.messages_of(&MessageType::Chat), 4);
+    assert_eq!(stats.per_user["alice"].messages_sent, 4);
+    assert_eq!(stats.per_user["bob"].messages_sent, 2);
+    assert!(stats.bytes_in > 0 && stats.bytes_out > stats.bytes_in);
+
+    drop(bob);
+    alice.expect(MessageType::UserLeft);
+    let (reply, receiver) = std::sync::mpsc::channel();
+    control.send(ServerCommand::Stats(reply)).unwrap();
+    let stats = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(stats.current_connections, 1);
+    assert_eq!(stats.disconnects[&p2p::common::DisconnectReason::Disconnected], 1);
+
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn retried_messages_are_relayed_once_and_acked_twice() {
+    let mut server = P2PServer::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+
+    let message = chat_message("alice", Some("bob"), "sent twice").with_generated_msg_id();
+    let msg_id = message.msg_id.clone();
+    alice.send(&message);
+    alice.send(&message);
+    assert_eq!(alice.expect(MessageType::RelayAck).reply_to, msg_id);
+    assert_eq!(alice.expect(MessageType::RelayAck).reply_to, msg_id);
+
+    alice.send(&chat_message("alice", Some("bob"), "next"));
+    assert_eq!(bob.expect(MessageType::Chat).msg_id, msg_id);
+    assert_eq!(bob.expect(MessageType::Chat).content.as_deref(), Some("next"));
+
+    let (reply, receiver) = std::sync::mpsc::channel();
+    control.send(ServerCommand::Stats(reply)).unwrap();
+    let stats = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(stats.drops_of(DropReason::Duplicate), 1);
+
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn ban_survives_server_restart_with_registry() {
+    let path = std::env::temp_dir().join(format!("p2p-registry-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = ServerConfig {
+        registry_path: Some(path.clone()),
+        ..ServerConfig::default()
+    };
+
+    let mut server = P2PServer::new_with_config("127.0.0.1:0", config.clone()).unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+    TestClient::join(addr, "alice");
+    control.send(ServerCommand::Ban("mallory".to_string(), None)).unwrap();
+    control.send(ServerCommand::BanIp("198.51.100.7".parse().unwrap())).unwrap();
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+
+    let mut server = P2PServer::new_with_config("127.0.0.1:0", config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    assert!(server.user_record("alice").is_some());
+    assert!(server.is_ip_banned("198.51.100.7".parse().unwrap()));
+    let handle = std::thread::spawn(move || server.start());
+
+    let mut rejoin = TestClient::connect(addr);
+    rejoin.send(&join_message("mallory", 9001));
+    assert_eq!(rejoin.recv().unwrap().msg_type, MessageType::JoinRejected);
+
+    let (reply, record) = std::sync::mpsc::channel();
+    control.send(ServerCommand::QueryUser("mallory".to_string(), reply)).unwrap();
+    let record = record.recv_timeout(Duration::from_secs(5)).unwrap().unwrap();
+    assert_eq!(record.ban.map(|ban| ban.until), Some(None));
+
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn audit_log_records_session_in_order() {
+    let path = std::env::temp_dir().join(format!("p2p-audit-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = ServerConfig {
+        audit_log_path: Some(path.clone()),
+        ..ServerConfig::default()
+    };
+    let mut server = P2PServer::new_with_config("127.0.0.1:0", config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+    let sync = || {
+        let (reply, receiver) = std::sync::mpsc::channel();
+        control.send(ServerCommand::AuditInfo(reply)).unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap()
+    };
+
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+    bob.send(&Message::new(MessageType::Leave, "bob".to_string()));
+    alice.expect(MessageType::UserLeft);
+    control.send(ServerCommand::Kick("alice".to_string())).unwrap();
+    alice.expect_closed();
+    control.send(ServerCommand::Ban("mallory".to_string(), None)).unwrap();
+    sync();
+    let mut mallory = TestClient::connect(addr);
+    mallory.send(&join_message("mallory", 9002));
+    mallory.expect(MessageType::JoinRejected);
+    let _carol = TestClient::join(addr, "carol");
+
+    let info = sync().expect("audit log is enabled");
+    assert_eq!(info.path, path);
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+
+    let records: Vec<AuditRecord> = std::fs::read_to_string(&path).unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let events: Vec<AuditEvent> = records.into_iter().map(|record| record.event).collect();
+    let leave = |user_id: &str, reason| AuditEvent::Leave { user_id: user_id.to_string(), reason };
+    let ip = Some("127.0.0.1".parse::<std::net::IpAddr>().unwrap());
+    assert!(events.iter().all(|event| match event {
+        AuditEvent::Join { remote_addr, .. } | AuditEvent::AuthFailure { remote_addr, .. } => remote_addr.map(|addr| addr.ip()) == ip,
+        _ => true,
+    }), "{:?}", events);
+    let kinds: Vec<AuditEvent> = events.into_iter()
+        .map(|event| match event {
+            AuditEvent::Join { user_id, listen_port, .. } => AuditEvent::Join { user_id, remote_addr: None, listen_port },
+            AuditEvent::AuthFailure { user_id, reason, .. } => AuditEvent::AuthFailure { user_id, remote_addr: None, reason },
+            event => event,
+        })
+        .collect();
+    let join = |user_id: &str, listen_port| AuditEvent::Join { user_id: user_id.to_string(), remote_addr: None, listen_port };
+    assert_eq!(kinds, vec![
+        join("alice", 9000),
+        join("bob", 9000),
+        leave("bob", DisconnectReason::Left),
+        AuditEvent::Kick { user_id: "alice".to_string() },
+        leave("alice", DisconnectReason::Kicked),
+        AuditEvent::Ban { user_id: "mallory".to_string(), duration_ms: None },
+        AuditEvent::AuthFailure { user_id: "mallory".to_string(), remote_addr: None, reason: "banned".to_string() },
+        join("carol", 9000),
+        leave("carol", DisconnectReason::Left),
+    ]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn message_quota_rejects_extra_chats_and_survives_restart() {
+    let path = std::env::temp_dir().join(format!("p2p-quota-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = ServerConfig {
+        registry_path: Some(path.clone()),
+        message_quota: Some(3),
+        ..ServerConfig::default()
+    };
+
+    let mut server = P2PServer::new_with_config("127.0.0.1:0", config.clone()).unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+
+    for i in 1..=3 {
+        alice.send(&chat_message("alice", Some("bob"), &format!("chat {}", i)));
+        assert_eq!(bob.expect(MessageType::Chat).content, Some(format!("chat {}", i)));
+    }
+    let rejected = chat_message("alice", Some("bob"), "chat 4").with_generated_msg_id();
+    alice.send(&rejected);
+    let error = alice.expect(MessageType::Error);
+    assert_eq!(error.error_code, Some(ErrorCode::QuotaExceeded));
+    assert_eq!(error.reply_to, rejected.msg_id);
+    assert!(error.content.unwrap().contains("resets in"));
+    // 心跳等控制消息不受配额限制
+    alice.send(&Message::new(MessageType::Heartbeat, "alice".to_string()));
+    alice.expect(MessageType::HeartbeatAck);
+
+    // 管理员清零后可以继续发送，之前被拒绝的消息没有被转发
+    control.send(ServerCommand::ResetQuota("alice".to_string())).unwrap();
+    let (reply, record) = std::sync::mpsc::channel();
+    control.send(ServerCommand::QueryUser("alice".to_string(), reply)).unwrap();
+    assert_eq!(record.recv_timeout(Duration::from_secs(5)).unwrap().unwrap().quota, None);
+    alice.send(&chat_message("alice", Some("bob"), "chat 5"));
+    assert_eq!(bob.expect(MessageType::Chat).content.as_deref(), Some("chat 5"));
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+
+    let server = P2PServer::new_with_config("127.0.0.1:0", config).unwrap();
+    assert_eq!(server.user_record("alice").and_then(|record| record.quota).map(|quota| quota.used), Some(1));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn join_with_different_identity_key_is_rejected() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+
+    let mut alice = TestClient::connect(addr);
+    alice.send(&join_message("alice", 9000).with_content("key-1".to_string()));
+    alice.expect(MessageType::PeerList);
+    alice.send(&Message::new(MessageType::Leave, "alice".to_string()));
+    alice.expect_closed();
+
+    let mut imposter = TestClient::connect(addr);
+    imposter.send(&join_message("alice", 9001).with_content("key-2".to_string()));
+    assert_eq!(imposter.expect(MessageType::JoinRejected).content.as_deref(), Some("identity key mismatch for alice"));
+
+    let mut alice = TestClient::connect(addr);
+    alice.send(&join_message("alice", 9000).with_content("key-1".to_string()));
+    alice.expect(MessageType::PeerList);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn join_broadcast_excludes_the_joining_connection() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    assert_eq!(alice.expect(MessageType::UserJoined).sender_id, "bob");
+
+    // bob 收到的下一条 UserJoined/Chat 应该是这条聊天，而不是自己的加入通知
+    alice.send(&chat_message("alice", None, "welcome"));
+    loop {
+        let message = bob.recv().unwrap();
+        assert_ne!(message.msg_type, MessageType::UserJoined, "joiner received its own UserJoined");
+        if message.msg_type == MessageType::Chat {
+            assert_eq!(message.content.as_deref(), Some("welcome"));
+            break;
+        }
+    }
+    // 广播聊天不排除发送者
+    assert_eq!(alice.expect(MessageType::Chat).content.as_deref(), Some("welcome"));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+/// alice 持续给从不读取的 bob 发大量私聊，直到 `done` 对统计快照成立；
+/// 然后确认 carol 和 dave 之间的消息没有被拖慢，返回最后的统计快照
+fn flood_slow_reader(policy: SlowConsumerPolicy, done: impl Fn(&ServerStats) -> bool) -> ServerStats {
+    let config = ServerConfig {
+        write_queue_max_bytes: 256 * 1024,
+        slow_consumer_policy: policy,
+        ..ServerConfig::default()
+    };
+    let mut server = P2PServer::new_with_config("127.0.0.1:0", config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+    let stats = || {
+        let (reply, receiver) = std::sync::mpsc::channel();
+        control.send(ServerCommand::Stats(reply)).unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap()
+    };
+
+    let mut alice = TestClient::join(addr, "alice");
+    let _bob = TestClient::join(addr, "bob");
+    let mut carol = TestClient::join(addr, "carol");
+    let mut dave = TestClient::join(addr, "dave");
+
+    // 总量远大于内核的 socket 缓冲区，服务器端发往 bob 的写队列必然积压
+    let payload = "x".repeat(32 * 1024);
+    let deadline = Instant::now() + Duration::from_secs(30);
+    let mut snapshot = stats();
+    while !done(&snapshot) {
+        assert!(Instant::now() < deadline, "slow consumer was never handled: {}", snapshot.summary());
+        for _ in 0..50 {
+            alice.send(&chat_message("alice", Some("bob"), &payload));
+        }
+        snapshot = stats();
+    }
+
+    let sent_at = Instant::now();
+    carol.send(&chat_message("carol", Some("dave"), "still there?"));
+    assert_eq!(dave.expect(MessageType::Chat).content.as_deref(), Some("still there?"));
+    assert!(sent_at.elapsed() < Duration::from_secs(1), "dave was delayed by {:?}", sent_at.elapsed());
+
+    let snapshot = stats();
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+    snapshot
+}
+
+#[test]
+fn slow_consumer_is_disconnected_when_queue_is_full() {
+    let stats = flood_slow_reader(SlowConsumerPolicy::Disconnect, |stats| stats.disconnects.contains_key(&DisconnectReason::SlowConsumer));
+    assert_eq!(stats.disconnects.get(&DisconnectReason::SlowConsumer), Some(&1));
+}
+
+#[test]
+fn slow_consumer_queue_stays_bounded_by_dropping_chat() {
+    let stats = flood_slow_reader(SlowConsumerPolicy::DropOldest, |stats| stats.drops_of(DropReason::SlowConsumer) > 0);
+    assert_eq!(stats.disconnects.get(&DisconnectReason::SlowConsumer), None);
+    assert!(stats.queue_depths.values().all(|depth| depth.peak_bytes <= 256 * 1024));
+}
+
+#[test]
+fn slow_consumer_flooding_its_own_requests_does_not_stop_the_server() {
+    let config = ServerConfig {
+        write_queue_max_messages: 64,
+        ..ServerConfig::default()
+    };
+    let mut server = P2PServer::new_with_config("127.0.0.1:0", config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+    let stats = || {
+        let (reply, receiver) = std::sync::mpsc::channel();
+        control.send(ServerCommand::Stats(reply)).unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap()
+    };
+
+    // 每行都会得到一条 MalformedMessage 回复，但客户端从不读取，回复积压在服务器的写队列里
+    let mut flooder = std::net::TcpStream::connect(addr).unwrap();
+    let batch = b"{\"bad\":1}\n".repeat(1000);
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while !stats().disconnects.contains_key(&DisconnectReason::SlowConsumer) {
+        assert!(Instant::now() < deadline, "slow consumer was never disconnected");
+        if std::io::Write::write_all(&mut flooder, &batch).is_err() {
+            break;
+        }
+    }
+
+    // 服务器仍在运行并接受新连接
+    let mut alice = TestClient::join(addr, "alice");
+    alice.send(&Message::new(MessageType::Heartbeat, "alice".to_string()));
+    alice.expect(MessageType::HeartbeatAck);
+
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn connected_users_lists_joined_peers_sorted() {
+    let mut server = P2PServer::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let mut bob = TestClient::connect(addr);
+    bob.send(&join_message("bob", 9001));
+    let mut alice = TestClient::connect(addr);
+    alice.send(&join_message("alice", 9000));
+    let _lurker = TestClient::connect(addr);
+    for _ in 0..50 {
+        server.run_once(Duration::from_millis(10)).unwrap();
+        if server.connected_users().len() == 2 {
+            break;
+        }
+    }
+
+    let users = server.connected_users();
+    let ids: Vec<&str> = users.iter().map(|user| user.user_id.as_str()).collect();
+    assert_eq!(ids, vec!["alice", "bob"]);
+    assert_eq!(users[1].port, 9001);
+    assert_eq!(users[0].secs_since_heartbeat, 0);
+    assert!(serde_json::to_string(&users).unwrap().contains("\"secs_since_heartbeat\":0"));
+}
+
+#[test]
+fn private_chat_to_offline_target_returns_target_offline_error() {
+    let config = ServerConfig { offline_queue_window: None, ..ServerConfig::default() };
+    let (addr, shutdown, handle) = spawn_server(config);
+    let mut alice = TestClient::join(addr, "alice");
+    let bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+    drop(bob);
+    alice.expect(MessageType::UserLeft);
+
+    for target in ["bob", "ghost"] {
+        let chat = chat_message("alice", Some(target), "hello?").with_generated_msg_id();
+        alice.send(&chat);
+        let error = alice.expect(MessageType::Error);
+        assert_eq!(error.error_code, Some(ErrorCode::TargetOffline));
+        assert_eq!(error.target_id.as_deref(), Some(target));
+        assert_eq!(error.reply_to, chat.msg_id);
+    }
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn private_messages_to_reconnecting_user_are_queued_in_order() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut alice = TestClient::join(addr, "alice");
+    let bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+    drop(bob);
+    alice.expect(MessageType::UserLeft);
+
+    for i in 1..=3 {
+        let chat = chat_message("alice", Some("bob"), &format!("queued {}", i)).with_generated_msg_id();
+        alice.send(&chat);
+        assert_eq!(alice.expect(MessageType::RelayAck).reply_to, chat.msg_id);
+    }
+
+    // 重新加入：暂存的消息在欢迎消息之前按顺序送达，且只送达一次
+    let mut bob = TestClient::connect(addr);
+    bob.send(&join_message("bob", 9000));
+    let mut received = Vec::new();
+    loop {
+        let message = bob.recv().expect("connection closed before welcome");
+        match message.msg_type {
+            MessageType::Chat => received.push(message.content.unwrap()),
+            MessageType::Welcome => break,
+            _ => {}
+        }
+    }
+    assert_eq!(received, vec!["queued 1", "queued 2", "queued 3"]);
+
+    alice.send(&chat_message("alice", Some("bob"), "live"));
+    assert_eq!(bob.expect(MessageType::Chat).content.as_deref(), Some("live"));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn pipelined_messages_are_drained_without_waiting_for_poll_cycles() {
+    const COUNT: u64 = 10_000;
+    let config = ServerConfig {
+        write_queue_max_messages: 2 * COUNT as usize,
+        write_queue_max_bytes: 64 * 1024 * 1024,
+        ..ServerConfig::default()
+    };
+    let mut server = P2PServer::new_with_config("127.0.0.1:0", config).unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let mut alice = TestClient::connect(addr);
+    alice.send(&join_message("alice", 9000));
+    let mut bob = TestClient::connect(addr);
+    bob.send(&join_message("bob", 9001));
+    for _ in 0..10 {
+        server.run_once(Duration::from_millis(10)).unwrap();
+    }
+
+    // 一次性写入约 1 MB 的消息，由单独线程发送以免阻塞事件循环
+    let mut pipelined = Vec::new();
+    for i in 0..COUNT {
+        pipelined.extend(p2p::common::serialize_message(&chat_message("alice", Some("bob"), &format!("msg {}", i))).unwrap());
+    }
+    // alice 还有未读的消息，线程结束时关闭连接会触发 RST，所以把连接交还给测试
+    let writer = std::thread::spawn(move || {
+        alice.send_raw(&pipelined);
+        alice
+    });
+
+    let started = Instant::now();
+    let mut cycles = 0;
+    while server.stats().messages_of(&MessageType::Chat) < COUNT {
+        assert!(started.elapsed() < Duration::from_secs(10), "only {} messages processed: {}", server.stats().messages_of(&MessageType::Chat), server.stats().summary());
+        server.run_once(Duration::from_millis(100)).unwrap();
+        cycles += 1;
+    }
+    let _alice = writer.join().unwrap();
+
+    // 每次可读事件只读一次时需要上千轮 poll，每轮最多等待 100 ms
+    assert!(cycles < 500, "took {} poll cycles", cycles);
+    assert!(started.elapsed() < Duration::from_secs(5), "took {:?}", started.elapsed());
+    assert_eq!(bob.expect(MessageType::Chat).content.as_deref(), Some("msg 0"));
+}
+
+#[test]
+fn motd_arrives_before_live_chat() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig { motd: Some("no spam".to_string()), ..ServerConfig::default() });
+    let mut alice = TestClient::join(addr, "alice");
+
+    let mut bob = TestClient::connect(addr);
+    bob.send(&join_message("bob", 9001));
+    alice.expect(MessageType::UserJoined);
+    alice.send(&chat_message("alice", None, "welcome bob"));
+
+    let received: Vec<MessageType> = std::iter::from_fn(|| bob.recv())
+        .map(|message| message.msg_type)
+        .take_while(|msg_type| *msg_type != MessageType::Chat)
+        .collect();
+    assert_eq!(received, vec![MessageType::PeerList, MessageType::Welcome]);
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn banned_ip_is_disconnected_and_rejected_until_unbanned() {
+    let mut server = P2PServer::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+    let loopback: std::net::IpAddr = "127.0.0.1".parse().unwrap();
+
+    let mut alice = TestClient::join(addr, "alice");
+    control.send(ServerCommand::BanIp(loopback)).unwrap();
+    alice.expect(MessageType::Kick);
+    alice.expect_closed();
+
+    // 换个名字重新连接也会在加入前被关闭
+    let mut renamed = TestClient::connect(addr);
+    renamed.send(&join_message("alice2", 9001));
+    renamed.expect_closed();
+
+    // 等统计回复到达，确保解封指令已经处理
+    control.send(ServerCommand::UnbanIp(loopback)).unwrap();
+    let (reply, stats) = std::sync::mpsc::channel();
+    control.send(ServerCommand::Stats(reply)).unwrap();
+    assert_eq!(stats.recv_timeout(Duration::from_secs(5)).unwrap().drops_of(DropReason::BannedIp), 1);
+    TestClient::join(addr, "alice");
+
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+/// 以空 user_id 加入，返回服务器分配的 id
+fn join_as_guest(addr: std::net::SocketAddr) -> (TestClient, String) {
+    let mut client = TestClient::connect(addr);
+    client.send(&join_message("", 9000));
+    let welcome = client.expect(MessageType::Welcome);
+    let info: ServerInfo = serde_json::from_str(welcome.content.as_deref().unwrap()).unwrap();
+    (client, info.assigned_user_id.expect("guest was not assigned an id"))
+}
+
+#[test]
+fn guests_get_distinct_ids_that_private_messages_can_target() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig { allow_guests: true, ..ServerConfig::default() });
+    let (mut first, first_id) = join_as_guest(addr);
+    let (mut second, second_id) = join_as_guest(addr);
+    assert!(first_id.starts_with("guest-"));
+    assert_ne!(first_id, second_id);
+    assert_eq!(first.expect(MessageType::UserJoined).sender_id, second_id);
+
+    first.send(&chat_message(&first_id, Some(&second_id), "hi guest"));
+    let chat = second.expect(MessageType::Chat);
+    assert_eq!(chat.sender_id, first_id);
+    assert_eq!(chat.content.as_deref(), Some("hi guest"));
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn empty_user_id_is_rejected_when_guests_are_disabled() {
+    let (addr, shutdown, handle) = spawn_server(ServerConfig::default());
+    let mut client = TestClient::connect(addr);
+    client.send(&join_message("", 9000));
+
+    let error = client.expect(MessageType::Error);
+    assert_eq!(error.error_code, Some(ErrorCode::InvalidUserId));
+    client.expect_closed();
+
+    shutdown.shutdown();
+    handle.join().unwrap().unwrap();
+}
+
+/// 发送健康检查探测，返回服务器的回复（读到连接关闭为止）
+fn probe(addr: SocketAddr) -> String {
+    use std::io::{Read, Write};
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"PING\n").unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    reply
+}
+
+#[test]
+fn health_probes_get_pong_without_joining_even_when_full() {
+    let config = ServerConfig { max_connections: 2, ..ServerConfig::default() };
+    let mut server = P2PServer::new_with_config("127.0.0.1:0", config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+    let stats = || {
+        let (reply, receiver) = std::sync::mpsc::channel();
+        control.send(ServerCommand::Stats(reply)).unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap()
+    };
+
+    assert_eq!(probe(addr), "PONG\n");
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+
+    // 满员时探测仍然得到回复，普通连接照常被拒绝
+    assert_eq!(probe(addr), "PONG\n");
+    let mut rejected = TestClient::connect(addr);
+    assert_eq!(rejected.recv().unwrap().error_code, Some(ErrorCode::ServerFull));
+    rejected.expect_closed();
+
+    alice.send(&chat_message("alice", Some("bob"), "still here"));
+    assert_eq!(bob.expect(MessageType::Chat).content.as_deref(), Some("still here"));
+    let snapshot = stats();
+    assert_eq!(snapshot.health_probes, 2);
+    assert_eq!(snapshot.total_accepted, 2);
+    assert_eq!(snapshot.current_connections, 2);
+
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+#[test]
+fn per_connection_stats_single_out_the_noisy_peer() {
+    let mut server = P2PServer::new("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+
+    let mut alice = TestClient::join(addr, "alice");
+    let _bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+    alice.send_raw(b"{not json}\n");
+    alice.expect(MessageType::Error);
+
+    let (reply, receiver) = std::sync::mpsc::channel();
+    control.send(ServerCommand::Stats(reply)).unwrap();
+    let stats = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(stats.connections.len(), 2);
+    let noisy: Vec<_> = stats.connections.values().filter(|conn| conn.parse_failures > 0).collect();
+    assert_eq!(noisy.len(), 1);
+    assert_eq!(noisy[0].parse_failures, 1);
+    assert_eq!(noisy[0].messages_parsed, 1);
+    for conn in stats.connections.values() {
+        assert!(conn.bytes_read > 0 && conn.bytes_written > 0, "{:?}", conn);
+    }
+
+    drop(alice);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let (reply, receiver) = std::sync::mpsc::channel();
+        control.send(ServerCommand::Stats(reply)).unwrap();
+        if receiver.recv_timeout(Duration::from_secs(5)).unwrap().connections.len() == 1 {
+            break;
+        }
+        assert!(Instant::now() < deadline, "closed connection was not removed");
+        std::thread::sleep(Duration::from_millis(20));
+    }
+
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+}
+
+/// 让 bob 以 trigger 的方式断开，检查每条断开路径留下的结果一致：剩余用户收到带原因的 UserLeft、
+/// 房间成员被移除、统计和审计日志都记录了这次断开，服务器不再保留该连接的任何状态。
+/// trigger 返回的连接保持打开直到检查结束（用于等待服务器端超时）
+fn assert_disconnect_cleanup(
+    name: &str,
+    config: ServerConfig,
+    reason: DisconnectReason,
+    trigger: impl FnOnce(&ServerControlSender, TestClient) -> Option<TestClient>,
+) {
+    let path = std::env::temp_dir().join(format!("p2p-disconnect-{}-{}.jsonl", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = ServerConfig { audit_log_path: Some(path.clone()), ..config };
+    let mut server = P2PServer::new_with_config("127.0.0.1:0", config).unwrap();
+    let addr = server.local_addr().unwrap();
+    let control = server.get_control_sender();
+    let handle = std::thread::spawn(move || server.start());
+    let stats = || {
+        let (reply, receiver) = std::sync::mpsc::channel();
+        control.send(ServerCommand::Stats(reply)).unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap()
+    };
+
+    let mut alice = TestClient::join(addr, "alice");
+    let mut bob = TestClient::join(addr, "bob");
+    alice.expect(MessageType::UserJoined);
+    alice.send(&Message::new(MessageType::JoinRoom, "alice".to_string()).with_room("lobby".to_string()));
+    alice.expect(MessageType::RoomUpdate);
+    bob.send(&Message::new(MessageType::JoinRoom, "bob".to_string()).with_room("lobby".to_string()));
+    bob.expect(MessageType::RoomUpdate);
+    alice.expect(MessageType::RoomUpdate);
+
+    let _silent = trigger(&control, bob);
+
+    // alice 一直发送心跳，自己不会因超时被断开
+    alice.set_read_timeout(Duration::from_millis(50));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut left = None;
+    let mut room = None;
+    while left.is_none() || room.is_none() {
+        assert!(Instant::now() < deadline, "{}: cleanup was never announced", name);
+        match alice.recv() {
+            Some(message) if message.msg_type == MessageType::UserLeft => left = Some(message),
+            Some(message) if message.msg_type == MessageType::RoomUpdate => room = Some(message),
+            Some(_) => {}
+            None => alice.send(&Message::new(MessageType::Heartbeat, "alice".to_string())),
+        }
+    }
+    let left = left.unwrap();
+    assert_eq!(left.sender_id, "bob", "{}", name);
+    assert_eq!(left.content.as_deref(), Some(reason.as_str()), "{}", name);
+    let room: RoomInfo = serde_json::from_str(room.unwrap().content.as_deref().unwrap()).unwrap();
+    assert_eq!(room.members, vec!["alice".to_string()], "{}", name);
+
+    let snapshot = stats();
+    assert_eq!(snapshot.disconnects.get(&reason), Some(&1), "{}: {}", name, snapshot.summary());
+    assert_eq!(snapshot.current_connections, 1, "{}", name);
+    assert_eq!(snapshot.connections.len(), 1, "{}", name);
+    assert_eq!(snapshot.queue_depths.len(), 1, "{}", name);
+
+    control.send(ServerCommand::Shutdown).unwrap();
+    handle.join().unwrap().unwrap();
+    let events: Vec<AuditEvent> = std::fs::read_to_string(&path).unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<AuditRecord>(line).unwrap().event)
+        .collect();
+    let bob_left = AuditEvent::Leave { user_id: "bob".to_string(), reason };
+    assert_eq!(events.iter().filter(|event| **event == bob_left).count(), 1, "{}: {:?}", name, events);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn leave_message_cleans_up_like_every_other_disconnect() {
+    assert_disconnect_cleanup("leave", ServerConfig::default(), DisconnectReason::Left, |_, mut bob| {
+        bob.send(&Message::new(MessageType::Leave, "bob".to_string()));
+        bob.expect_closed();
+        None
+    });
+}
+
+#[test]
+fn closed_socket_cleans_up_like_every_other_disconnect() {
+    assert_disconnect_cleanup("eof", ServerConfig::default(), DisconnectReason::Disconnected, |_, bob| {
+        drop(bob);
+        None
+    });
+}
+
+#[test]
+fn heartbeat_timeout_cleans_up_like_every_other_disconnect() {
+    let config = ServerConfig {
+        heartbeat_interval: Duration::from_millis(100),
+        peer_timeout: Duration::from_millis(300),
+        ..ServerConfig::default()
+    };
+    assert_disconnect_cleanup("timeout", config, DisconnectReason::Timeout, |_, bob| Some(bob));
+}
+
+#[test]
+fn kick_cleans_up_like_every_other_disconnect() {
+    assert_disconnect_cleanup("kick", ServerConfig::default(), DisconnectReason::Kicked, |control, mut bob| {
+        control.send(ServerCommand::Kick("bob".to_string())).unwrap();
+        bob.expect_closed();
+        None
+    });
+}
+
+#[test]
+fn oversized_frame_cleans_up_like_every_other_disconnect() {
+    let config = ServerConfig { max_message_size: 1024, ..ServerConfig::default() };
+    assert_disconnect_cleanup("oversized", config, DisconnectReason::Error, |_, mut bob| {
+        bob.send_raw(&[b'x'; 2048]);
+        bob.expect_closed();
+        None
+    });
+}
@@ -0,0 +1,102 @@
+#![allow(dead_code)]
+
+use p2p::common::{deserialize_message, serialize_message, Message, MessageType, BROADCAST_TARGET};
+use p2p::server::{P2PServer, ServerConfig, ServerThread, ShutdownHandle};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+
+/// 在后台线程启动服务器，返回实际地址、关闭句柄和线程句柄
+pub fn spawn_server(config: ServerConfig) -> (SocketAddr, ShutdownHandle, ServerThread) {
+    let (handle, addr, shutdown) = P2PServer::spawn_ephemeral_with_config(config).unwrap();
+    (addr, shutdown, handle)
+}
+
+/// 直接使用阻塞 TcpStream 的测试客户端，按行收发消息
+pub struct TestClient {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl TestClient {
+    pub fn connect(addr: SocketAddr) -> Self {
+        Self::from_stream(TcpStream::connect(addr).unwrap())
+    }
+
+    /// 接受一条入站连接（模拟对等节点的 P2P 监听端口）
+    pub fn accept(listener: &TcpListener) -> Self {
+        Self::from_stream(listener.accept().unwrap().0)
+    }
+
+    fn from_stream(stream: TcpStream) -> Self {
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let reader = BufReader::new(stream.try_clone().unwrap());
+        Self { writer: stream, reader }
+    }
+
+    /// 连接并发送 Join，等待服务器返回的对等节点列表和欢迎消息
+    pub fn join(addr: SocketAddr, user_id: &str) -> Self {
+        let mut client = Self::connect(addr);
+        client.send(&join_message(user_id, 9000));
+        client.expect(MessageType::PeerList);
+        client.expect(MessageType::Welcome);
+        client
+    }
+
+    pub fn send(&mut self, message: &Message) {
+        self.writer.write_all(&serialize_message(message).unwrap()).unwrap();
+    }
+
+    /// 发送任意字节（用于构造非法或超长帧）
+    pub fn send_raw(&mut self, bytes: &[u8]) {
+        self.writer.write_all(bytes).unwrap();
+    }
+
+    /// 读取下一条消息；连接关闭或超时返回 None
+    pub fn recv(&mut self) -> Option<Message> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(deserialize_message(line.trim_end().as_bytes()).unwrap()),
+        }
+    }
+
+    /// 跳过其他消息，直到收到指定类型的消息
+    pub fn expect(&mut self, msg_type: MessageType) -> Message {
+        loop {
+            match self.recv() {
+                Some(message) if message.msg_type == msg_type => return message,
+                Some(_) => continue,
+                None => panic!("connection closed while waiting for {:?}", msg_type),
+            }
+        }
+    }
+
+    /// 断言连接已被服务器关闭（跳过关闭前的剩余消息）
+    pub fn expect_closed(&mut self) {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Ok(0) => return,
+                Ok(_) => continue,
+                Err(e) => panic!("expected connection to be closed, got {}", e),
+            }
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Duration) {
+        self.writer.set_read_timeout(Some(timeout)).unwrap();
+    }
+}
+
+pub fn join_message(user_id: &str, port: u16) -> Message {
+    Message::join(user_id.to_string(), "127.0.0.1".to_string(), port)
+}
+
+/// 聊天消息；target 为 None 时发往所有人（`*`）
+pub fn chat_message(sender: &str, target: Option<&str>, content: &str) -> Message {
+    Message::new(MessageType::Chat, sender.to_string())
+        .with_content(content.to_string())
+        .with_target(target.unwrap_or(BROADCAST_TARGET).to_string())
+}
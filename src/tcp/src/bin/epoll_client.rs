@@ -1,177 +1,267 @@
-use mio::{Events, Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
 use mio::net::TcpStream;
-use std::io::{self, Read, Write};
-use std::net::SocketAddr;
-use std::str;
-use std::time::Duration;
+use std::io::{self, BufRead, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 const CLIENT: Token = Token(0);
+// 输入线程读到新的一行（或标准输入结束）时通过它唤醒事件循环
+const STDIN: Token = Token(1);
 const MAX_RETRY: u32 = 5;
 const RETRY_DELAY: Duration = Duration::from_secs(1);
+// 默认服务器地址，与 epoll_server 的默认监听地址相同，可以用 --addr 覆盖
+const DEFAULT_ADDR: &str = "127.0.0.1:18081";
+// 等待非阻塞 connect 完成的时间
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// 输入结束后等待剩余回显的时间
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
 
-fn main() -> io::Result<()> {
-    println!("EPOLL TCP Client starting...");
-    
-    // 解析地址
-    let address: SocketAddr = match "127.0.0.1:18081".parse() {
-        Ok(addr) => {
-            println!("Parsed address: {}", addr);
-            addr
-        },
-        Err(e) => {
-            eprintln!("Failed to parse address: {}", e);
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Failed to parse address: {}", e)));
-        }
-    };
+/// 命令行参数
+struct Args {
+    addr: SocketAddr,
+    // Some(n) 时不读标准输入，一次发出 n 条编号消息并校验回显
+    burst: Option<usize>,
+}
 
-    // 连接服务器
-    let mut stream = match connect_with_retry(&address) {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Failed to connect to server after {} attempts: {}", MAX_RETRY, e);
-            return Err(e);
+/// 解析 `--addr <addr>` 和 `--burst <n>`，未指定的使用默认值
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut addr = DEFAULT_ADDR.to_string();
+    let mut burst = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => addr = args.next().ok_or("--addr requires an address")?,
+            "--burst" => {
+                let value = args.next().ok_or("--burst requires a number")?;
+                burst = Some(value.parse().map_err(|e| format!("invalid --burst {}: {}", value, e))?);
+            }
+            other => return Err(format!("unknown argument {}", other)),
         }
-    };
-    println!("Successfully connected to server");
+    }
+    let addr = addr.parse().map_err(|e| format!("Failed to parse address {}: {}", addr, e))?;
+    Ok(Args { addr, burst })
+}
 
-    // 创建poll实例
-    let mut poll = match Poll::new() {
-        Ok(p) => p,
-        Err(e) => {
-            eprintln!("Failed to create Poll instance: {}", e);
-            return Err(e);
-        }
-    };
+/// 会话统计
+#[derive(Debug, Default)]
+struct Totals {
+    sent_messages: usize,
+    sent_bytes: usize,
+    received_messages: usize,
+    received_bytes: usize,
+}
+
+/// 与服务器的一条连接。每条消息以换行结尾，待发送的数据从 outbox 的 written 偏移处继续写，
+/// 收到的数据在 inbox 中凑成完整的行
+struct Session {
+    stream: TcpStream,
+    outbox: Vec<u8>,
+    written: usize,
+    inbox: Vec<u8>,
+    interest: Interest,
+    totals: Totals,
+    // 逐行打印收到的回显（交互模式）；否则保存下来供 --burst 校验
+    print: bool,
+    lines: Vec<String>,
+}
 
-    // 注册客户端流
-    if let Err(e) = poll.registry().register(
-        &mut stream,
-        CLIENT,
-        Interest::READABLE.add(Interest::WRITABLE),
-    ) {
-        eprintln!("Failed to register stream with poll: {}", e);
-        return Err(e);
+impl Session {
+    fn new(stream: TcpStream, print: bool) -> Self {
+        Self {
+            stream,
+            outbox: Vec::new(),
+            written: 0,
+            inbox: Vec::new(),
+            interest: Interest::READABLE,
+            totals: Totals::default(),
+            print,
+            lines: Vec::new(),
+        }
     }
-    println!("Stream registered with poll");
 
-    // 创建事件存储
-    let mut events = Events::with_capacity(128);
+    fn queue(&mut self, message: &str) {
+        self.outbox.extend_from_slice(message.as_bytes());
+        self.outbox.push(b'\n');
+        self.totals.sent_messages += 1;
+        self.totals.sent_bytes += message.len() + 1;
+    }
 
-    // 等待连接就绪（可写）
-    println!("Waiting for connection to be ready...");
-    poll.poll(&mut events, Some(Duration::from_secs(5)))?;
-    
-    let mut is_ready = false;
-    for event in events.iter() {
-        if event.token() == CLIENT && event.is_writable() {
-            is_ready = true;
-            break;
+    /// 尽量写出 outbox，WouldBlock 时保留剩余部分等下一次可写事件
+    fn flush(&mut self) -> io::Result<()> {
+        while self.written < self.outbox.len() {
+            match self.stream.write(&self.outbox[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
         }
+        self.outbox.clear();
+        self.written = 0;
+        Ok(())
     }
-    
-    if !is_ready {
-        eprintln!("Connection not ready for writing within timeout");
-        return Ok(());
+
+    fn flushed(&self) -> bool {
+        self.outbox.is_empty()
     }
 
-    // 发送消息
-    let message = "Hello from EPOLL TCP client!";
-    let mut retries = 3;
-    let mut sent = false;
-    
-    while retries > 0 && !sent {
-        match stream.write_all(message.as_bytes()) {
-            Ok(()) => {
-                println!("Sent: {}", message);
-                sent = true;
-                
-                // 刷新缓冲区确保数据被发送
-                if let Err(e) = stream.flush() {
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        eprintln!("Flush would block, retrying...");
-                        retries -= 1;
-                        std::thread::sleep(Duration::from_millis(100));
-                    } else {
-                        eprintln!("Failed to flush stream: {}", e);
-                        return Err(e);
-                    }
+    /// 读到 WouldBlock 为止，返回服务器是否已关闭连接
+    fn read_available(&mut self) -> io::Result<bool> {
+        let mut buffer = [0; 4096];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(true),
+                Ok(n) => {
+                    self.totals.received_bytes += n;
+                    self.inbox.extend_from_slice(&buffer[..n]);
+                    self.take_lines();
                 }
-            },
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                eprintln!("Write would block, retrying...");
-                retries -= 1;
-                std::thread::sleep(Duration::from_millis(100));
-            },
-            Err(e) => {
-                eprintln!("Failed to send message: {}", e);
-                return Err(e);
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
             }
         }
     }
-    
-    if !sent {
-        eprintln!("Failed to send message after multiple attempts");
-        return Err(io::Error::new(io::ErrorKind::TimedOut, "Failed to send message"));
-    }
 
-    // 等待响应
-    println!("Waiting for response...");
-    if let Err(e) = poll.poll(&mut events, Some(Duration::from_secs(5))) {
-        eprintln!("Poll error: {}", e);
-        return Err(e);
+    fn take_lines(&mut self) {
+        while let Some(pos) = self.inbox.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.inbox.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line[..pos]).into_owned();
+            self.totals.received_messages += 1;
+            if self.print {
+                println!("Received: {}", line);
+            } else {
+                self.lines.push(line);
+            }
+        }
     }
 
-    if events.is_empty() {
-        println!("Client timeout waiting for response");
-        return Ok(());
+    /// 有待发送数据时才关注 WRITABLE
+    fn update_interest(&mut self, registry: &Registry) -> io::Result<()> {
+        let interest = if self.flushed() { Interest::READABLE } else { Interest::READABLE.add(Interest::WRITABLE) };
+        if interest != self.interest {
+            registry.reregister(&mut self.stream, CLIENT, interest)?;
+            self.interest = interest;
+        }
+        Ok(())
     }
+}
 
-    println!("Received {} events", events.iter().count());
-    for event in events.iter() {
-        println!("Event: {:?}, token: {:?}", event, event.token());
-        match event.token() {
-            CLIENT => {
-                if event.is_readable() {
-                    println!("Client socket is readable");
-                    let mut buffer = [0; 1024];
-                    match stream.read(&mut buffer) {
-                        Ok(0) => {
-                            println!("Server closed connection");
-                            // 从poll中注销流
-                            if let Err(e) = poll.registry().deregister(&mut stream) {
-                                eprintln!("Failed to deregister stream: {}", e);
-                            }
-                        },
-                        Ok(n) => {
-                            let received = str::from_utf8(&buffer[..n])
-                                .unwrap_or("<invalid UTF-8>");
-                            println!("Received: {}", received.trim_end());
-                        },
-                        Err(e) => {
-                            eprintln!("Read error: {}", e);
-                        }
+fn main() -> io::Result<()> {
+    // 用法: epoll_client [--addr <addr>] [--burst <n>]
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: epoll_client [--addr <addr>] [--burst <n>]");
+            std::process::exit(2);
+        }
+    };
+    println!("EPOLL TCP Client starting...");
+
+    // 创建poll实例
+    let mut poll = Poll::new()?;
+    // 创建事件存储
+    let mut events = Events::with_capacity(128);
+
+    // 连接服务器
+    let stream = match connect_with_retry(&args.addr, &mut poll, &mut events) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Failed to connect to server after {} attempts: {}", MAX_RETRY, e);
+            return Err(e);
+        }
+    };
+    let mut session = Session::new(stream, args.burst.is_none());
+
+    // 交互模式：在单独线程中读取标准输入，每行一条消息，读到 EOF 或 /quit 时关闭通道
+    let (sender, receiver) = mpsc::channel::<String>();
+    match args.burst {
+        Some(count) => {
+            for i in 0..count {
+                session.queue(&format!("message {}", i));
+            }
+            drop(sender);
+        }
+        None => {
+            println!("Type a line to send it, /quit or EOF to exit");
+            let waker = Waker::new(poll.registry(), STDIN)?;
+            thread::spawn(move || {
+                for line in io::stdin().lock().lines() {
+                    let Ok(line) = line else { break };
+                    if line.trim() == "/quit" || sender.send(line).is_err() {
+                        break;
                     }
+                    let _ = waker.wake();
                 }
-                if event.is_writable() {
-                    println!("Client socket is writable");
-                    // 我们已经发送了数据，这里不需要额外处理
+                drop(sender);
+                let _ = waker.wake();
+            });
+        }
+    }
+
+    let started = Instant::now();
+    // 输入结束、数据全部写出后关闭写方向，服务器回显完剩余数据后关闭连接
+    let mut drain_deadline = None;
+    loop {
+        match receiver.try_recv() {
+            Ok(line) => {
+                session.queue(&line);
+                continue;
+            }
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => {
+                if drain_deadline.is_none() && session.flushed() {
+                    session.stream.shutdown(Shutdown::Write)?;
+                    drain_deadline = Some(Instant::now() + DRAIN_TIMEOUT);
                 }
-            },
-            _ => {
-                unreachable!()
             }
         }
+        session.flush()?;
+        session.update_interest(poll.registry())?;
+
+        match poll.poll(&mut events, Some(POLL_TIMEOUT)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+        let readable = events.iter().any(|event| event.token() == CLIENT && event.is_readable());
+        if readable && session.read_available()? {
+            println!("Server closed connection");
+            break;
+        }
+        if drain_deadline.is_some_and(|deadline| Instant::now() > deadline) {
+            eprintln!("Timed out waiting for the remaining echoes");
+            break;
+        }
     }
 
+    let totals = &session.totals;
+    println!("Sent {} messages ({} bytes), received {} messages ({} bytes) in {:.2?}",
+        totals.sent_messages, totals.sent_bytes, totals.received_messages, totals.received_bytes, started.elapsed());
+
+    if let Some(count) = args.burst {
+        if let Some((i, line)) = session.lines.iter().enumerate().find(|(i, line)| **line != format!("message {}", i)) {
+            return Err(io::Error::other(format!("echo {} was {:?}, expected \"message {}\"", i, line, i)));
+        }
+        if session.lines.len() != count {
+            return Err(io::Error::other(format!("received {} of {} echoes", session.lines.len(), count)));
+        }
+        println!("Burst of {} messages: all echoes verified", count);
+    }
     Ok(())
 }
 
-// 带重试的连接函数
-fn connect_with_retry(address: &SocketAddr) -> io::Result<TcpStream> {
+// 带重试的连接函数。非阻塞 connect 立即返回，连接结果要等到可写事件后才知道，
+// 因此每次尝试都等待连接完成，被拒绝或超时时再重试
+fn connect_with_retry(address: &SocketAddr, poll: &mut Poll, events: &mut Events) -> io::Result<TcpStream> {
     let mut retry_count = 0;
     loop {
         println!("Attempting to connect to {} (attempt {}/{})...", address, retry_count + 1, MAX_RETRY);
-        match TcpStream::connect(*address) {
+        match TcpStream::connect(*address).and_then(|stream| wait_connected(stream, poll, events)) {
             Ok(stream) => {
                 println!("Successfully connected to {}", address);
                 return Ok(stream);
@@ -187,4 +277,39 @@ fn connect_with_retry(address: &SocketAddr) -> io::Result<TcpStream> {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+/// 等待非阻塞 connect 完成。成功后改为只关注可读（有数据要发送时再关注可写）
+fn wait_connected(mut stream: TcpStream, poll: &mut Poll, events: &mut Events) -> io::Result<TcpStream> {
+    poll.registry().register(&mut stream, CLIENT, Interest::WRITABLE)?;
+    let deadline = Instant::now() + CONNECT_TIMEOUT;
+    let result = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break Err(io::Error::new(io::ErrorKind::TimedOut, "connection not ready within timeout"));
+        }
+        poll.poll(events, Some(remaining))?;
+        if !events.iter().any(|event| event.token() == CLIENT) {
+            continue;
+        }
+        if let Some(e) = stream.take_error()? {
+            break Err(e);
+        }
+        match stream.peer_addr() {
+            Ok(_) => break Ok(()),
+            // 虚假唤醒：连接仍在进行中
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => continue,
+            Err(e) => break Err(e),
+        }
+    };
+    match result {
+        Ok(()) => {
+            poll.registry().reregister(&mut stream, CLIENT, Interest::READABLE)?;
+            Ok(stream)
+        }
+        Err(e) => {
+            poll.registry().deregister(&mut stream)?;
+            Err(e)
+        }
+    }
+}
@@ -1,32 +1,179 @@
-use mio::{Events, Interest, Poll, Token};
+use mio::{Events, Interest, Poll, Registry, Token};
+use mio::event::Event;
 use mio::net::{TcpListener, TcpStream};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io::{self, Read, Write};
-use std::net::SocketAddr;
-use std::str;
+use std::net::{Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 // 定义token常量
 const SERVER: Token = Token(0);
+// 默认最大连接数，可以用 --max-conn 覆盖
 const MAX_CONN: usize = 1024;
+// 单次 poll 最多返回的事件数
+const EVENTS_CAPACITY: usize = 1024;
+// poll 超时，决定收到 Ctrl+C 后多久能退出
+const POLL_TIMEOUT: Duration = Duration::from_millis(100);
+// 默认监听地址，可以用 --addr 覆盖
+const DEFAULT_ADDR: &str = "127.0.0.1:18081";
+// 每次读取的缓冲区大小
+const READ_BUFFER_SIZE: usize = 4096;
+// 单个连接待回显数据的上限。客户端不读取时写不出去的数据会一直堆积，
+// 超过上限后暂停读取该连接（不再注册 READABLE），等写出一部分后再恢复
+const MAX_OUTBOX_BYTES: usize = 1024 * 1024;
+
+/// 一个客户端连接。收到的数据按顺序放进 outbox 等待回显，队首那块已经写出 written 字节
+struct Connection {
+    stream: TcpStream,
+    addr: SocketAddr,
+    outbox: VecDeque<Vec<u8>>,
+    written: usize,
+    // outbox 中尚未写出的字节数
+    pending: usize,
+    // 客户端已关闭写方向（读到 EOF），回显完剩余数据后关闭连接
+    read_closed: bool,
+    // 当前注册的关注事件，只在变化时 reregister
+    interest: Interest,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, addr: SocketAddr) -> Self {
+        Self {
+            stream,
+            addr,
+            outbox: VecDeque::new(),
+            written: 0,
+            pending: 0,
+            read_closed: false,
+            interest: Interest::READABLE,
+        }
+    }
+
+    /// 读到 WouldBlock 为止（边沿触发下没读完的数据不会再产生可读事件），
+    /// 收到的 n 字节原样放进 outbox；outbox 达到上限时先停止读取
+    fn read_available(&mut self) -> io::Result<()> {
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        while !self.read_closed && self.pending < MAX_OUTBOX_BYTES {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => {
+                    println!("Client {} closed its write side", self.addr);
+                    self.read_closed = true;
+                }
+                Ok(n) => {
+                    println!("Received {} bytes from {}", n, self.addr);
+                    self.outbox.push_back(buffer[..n].to_vec());
+                    self.pending += n;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// 按顺序写出 outbox，直到写完或 WouldBlock。写了一部分的块记下偏移，下次可写时从偏移处继续
+    fn flush_outbox(&mut self) -> io::Result<()> {
+        while let Some(front) = self.outbox.front() {
+            match self.stream.write(&front[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.written += n;
+                    self.pending -= n;
+                    if self.written == front.len() {
+                        self.outbox.pop_front();
+                        self.written = 0;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// 客户端已关闭写方向且数据全部回显完毕
+    fn finished(&self) -> bool {
+        self.read_closed && self.outbox.is_empty()
+    }
+
+    /// 有待写数据时关注 WRITABLE；outbox 未满且客户端还会发送时关注 READABLE。
+    /// 暂停读取期间到达的数据不会产生新的边沿，重新注册 READABLE 时内核会按当前状态重新报告可读
+    fn update_interest(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let readable = !self.read_closed && self.pending < MAX_OUTBOX_BYTES;
+        let writable = !self.outbox.is_empty();
+        let interest = match (readable, writable) {
+            (true, true) => Interest::READABLE.add(Interest::WRITABLE),
+            (false, true) => Interest::WRITABLE,
+            // finished() 的连接会被移除，这里只剩下 outbox 为空、仍可读的情况
+            _ => Interest::READABLE,
+        };
+        if interest != self.interest {
+            registry.reregister(&mut self.stream, token, interest)?;
+            self.interest = interest;
+        }
+        Ok(())
+    }
+}
+
+/// 命令行参数
+struct Args {
+    addr: SocketAddr,
+    max_conn: usize,
+}
+
+/// 解析 `--addr <addr>` 和 `--max-conn <n>`，未指定的使用默认值
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut addr = DEFAULT_ADDR.to_string();
+    let mut max_conn = MAX_CONN;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--addr" => addr = args.next().ok_or("--addr requires an address")?,
+            "--max-conn" => {
+                let value = args.next().ok_or("--max-conn requires a number")?;
+                max_conn = value.parse().map_err(|e| format!("invalid --max-conn {}: {}", value, e))?;
+                if max_conn == 0 {
+                    return Err("--max-conn must be at least 1".to_string());
+                }
+            }
+            other => return Err(format!("unknown argument {}", other)),
+        }
+    }
+    let addr = addr.parse().map_err(|e| format!("Failed to parse address {}: {}", addr, e))?;
+    Ok(Args { addr, max_conn })
+}
 
 fn main() -> io::Result<()> {
+    // 用法: epoll_server [--addr <addr>] [--max-conn <n>]，端口为 0 时由系统分配
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: epoll_server [--addr <addr>] [--max-conn <n>]");
+            std::process::exit(2);
+        }
+    };
+
+    // Ctrl+C 时只设置标志，由事件循环在下一轮检查后关闭所有连接再退出
+    let running = Arc::new(AtomicBool::new(true));
+    let flag = running.clone();
+    ctrlc::set_handler(move || flag.store(false, Ordering::SeqCst))
+        .map_err(|e| io::Error::other(format!("Failed to install Ctrl+C handler: {}", e)))?;
+
     // 创建poll实例
     let mut poll = Poll::new()?;
     // 创建事件存储
-    let mut events = Events::with_capacity(MAX_CONN);
+    let mut events = Events::with_capacity(EVENTS_CAPACITY);
 
-    // 绑定TCP监听
-    let addr: SocketAddr = match "127.0.0.1:18081".parse() {
-        Ok(a) => a,
-        Err(e) => {
-            eprintln!("Failed to parse address: {}", e);
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Failed to parse address: {}", e)));
-        }
-    };
-    let mut server = match TcpListener::bind(addr) {
+    // 绑定TCP监听。mio 在 unix 上绑定前会设置 SO_REUSEADDR，服务器重启时不会因为
+    // TIME_WAIT 状态的旧连接而绑定失败（Windows 上该选项允许抢占端口，mio 不设置）
+    let mut server = match TcpListener::bind(args.addr) {
         Ok(s) => s,
         Err(e) => {
-            eprintln!("Failed to bind to address {}: {}", addr, e);
+            eprintln!("Failed to bind to address {}: {}", args.addr, e);
             return Err(e);
         }
     };
@@ -39,15 +186,21 @@ fn main() -> io::Result<()> {
     )?;
 
     // 存储客户端连接
-    let mut connections = HashMap::new();
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
     let mut next_token = Token(1);
 
-    println!("EPOLL TCP Server running on 127.0.0.1:8081...");
+    // 输出实际监听的地址（端口为 0 时由系统分配）
+    println!("EPOLL TCP Server running on {}...", server.local_addr()?);
 
-    // 事件循环
-    loop {
+    // 事件循环。poll 使用有限的超时，保证收到 Ctrl+C 后最多一个超时周期内退出
+    while running.load(Ordering::SeqCst) {
         // 等待事件
-        poll.poll(&mut events, None)?;
+        match poll.poll(&mut events, Some(POLL_TIMEOUT)) {
+            Ok(()) => {}
+            // 信号可能打断 poll
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
 
         for event in events.iter() {
             match event.token() {
@@ -55,21 +208,24 @@ fn main() -> io::Result<()> {
                     // 接受新连接
                     match server.accept() {
                         Ok((mut stream, addr)) => {
+                            // 已达到连接上限：接受后立即关闭。不接受的话连接留在监听队列中，
+                            // 边沿触发下也不会再收到新的可读事件
+                            if connections.len() >= args.max_conn {
+                                println!("Connection limit {} reached, rejecting {}", args.max_conn, addr);
+                                let _ = stream.shutdown(Shutdown::Both);
+                                continue;
+                            }
                             println!("New connection: {}", addr);
 
                             // 为新连接分配token
                             let token = next_token;
                             next_token = Token(token.0 + 1);
 
-                            // 注册新连接
-                            poll.registry().register(
-                                &mut stream,
-                                token,
-                                Interest::READABLE.add(Interest::WRITABLE),
-                            )?;
+                            // 注册新连接。刚建立的连接没有待写数据，只关注可读
+                            poll.registry().register(&mut stream, token, Interest::READABLE)?;
 
                             // 存储连接
-                            connections.insert(token, stream);
+                            connections.insert(token, Connection::new(stream, addr));
                         }
                         Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                             break; // 没有更多连接
@@ -81,78 +237,46 @@ fn main() -> io::Result<()> {
                     }
                 },
                 token => {
-                            // 处理客户端连接事件
-                            // 标记是否需要移除连接
-                            let mut should_remove = false;
-
-                            if let Some(mut stream) = connections.get_mut(&token) {
-                                if event.is_readable() {
-                                    // 读取数据
-                                    let mut buffer = [0; 1024];
-                                    match stream.read(&mut buffer) {
-                                        Ok(0) => {
-                                            // 客户端关闭连接
-                                            println!("Client disconnected");
-                                            should_remove = true;
-                                        }
-                                        Ok(n) => {
-                                            let received = str::from_utf8(&buffer[..n])
-                                                .unwrap_or("<invalid UTF-8>");
-                                            println!("Received: {}", received.trim_end());
-
-                                            // 回显数据
-                                            // 尝试写入数据
-                                            let mut buf: Vec<u8>= "server reply ".as_bytes().to_vec();
-                                            buf.append(&mut buffer.to_vec());
-
-                                            match stream.write_all(&buf[..buf.len()]) {
-                                                Ok(()) => {
-                                                    println!("Sent: {}", received.trim_end());
-                                                }
-                                                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                                                    // 流暂时不可写，实际应用中应实现数据缓存机制
-                                                    eprintln!("Stream not writable, would block");
-                                                    // 不立即移除连接，而是等待下次可写事件
-                                                }
-                                                Err(e) => {
-                                                    eprintln!("Write error: {}", e);
-                                                    should_remove = true;
-                                                }
-                                            }
-                                            
-                                            // 确保数据被刷新
-                                            if let Err(e) = stream.flush() {
-                                                if e.kind() == io::ErrorKind::WouldBlock {
-                                                    // 刷新操作也可能阻塞
-                                                    eprintln!("Flush would block, will retry later");
-                                                } else {
-                                                    eprintln!("Flush error: {}", e);
-                                                    should_remove = true;
-                                                }
-                                            }
-                                        }
-                                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                                            continue;
-                                        }
-                                        Err(e) => {
-                                            eprintln!("Read error: {}", e);
-                                            should_remove = true;
-                                        }
-                                    }
-                                }
-
-                                if event.is_writable() {
-                                    // 这里可以处理写入事件（如果需要）
-                                    // 对于简单的回显服务器，我们不需要特别处理可写事件
-                                }
-                            }
-
-                            // 在可变引用作用域之外执行移除操作
-                            if should_remove {
-                                connections.remove(&token);
-                            }
+                    let Some(connection) = connections.get_mut(&token) else {
+                        continue;
+                    };
+                    // 在可变引用作用域之外执行移除操作
+                    let remove = match handle_connection_event(poll.registry(), token, connection, event) {
+                        Ok(()) => connection.finished(),
+                        Err(e) => {
+                            eprintln!("Connection {} error: {}", connection.addr, e);
+                            true
+                        }
+                    };
+                    if remove {
+                        println!("Client {} disconnected", connection.addr);
+                        connections.remove(&token);
+                    }
                 }
             }
         }
     }
-}
\ No newline at end of file
+
+    // 优雅关闭：注销并关闭所有连接和监听器，未回显完的数据直接丢弃
+    println!("Shutting down, closing {} connections...", connections.len());
+    for (_, mut connection) in connections.drain() {
+        let _ = poll.registry().deregister(&mut connection.stream);
+        let _ = connection.stream.shutdown(Shutdown::Both);
+    }
+    poll.registry().deregister(&mut server)?;
+    println!("Server stopped");
+    Ok(())
+}
+
+/// 处理客户端连接事件：先读取新数据，再尽量写出 outbox，最后按是否还有待写数据调整关注的事件
+fn handle_connection_event(registry: &Registry, token: Token, connection: &mut Connection, event: &Event) -> io::Result<()> {
+    if event.is_readable() {
+        connection.read_available()?;
+    }
+    // 可写事件，或刚读到新数据：写不完的部分留在 outbox，等下一次可写事件
+    connection.flush_outbox()?;
+    if connection.finished() {
+        return Ok(());
+    }
+    connection.update_interest(registry, token)
+}
@@ -0,0 +1,183 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// 运行中的 epoll_server 进程，Drop 时结束进程
+struct EchoServer {
+    child: Child,
+    addr: SocketAddr,
+}
+
+impl EchoServer {
+    /// 在系统分配的端口上启动服务器，从第一行输出中取得实际地址，其余输出在后台线程中丢弃
+    fn spawn(args: &[&str]) -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_epoll_server"))
+            .args(["--addr", "127.0.0.1:0"])
+            .args(args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let mut line = String::new();
+        stdout.read_line(&mut line).unwrap();
+        let addr = line.trim_end()
+            .trim_start_matches("EPOLL TCP Server running on ")
+            .trim_end_matches("...")
+            .parse()
+            .unwrap_or_else(|e| panic!("unexpected banner {:?}: {}", line, e));
+        thread::spawn(move || std::io::copy(&mut stdout, &mut std::io::sink()));
+        Self { child, addr }
+    }
+
+    /// 等待进程退出，超时则失败
+    fn wait_exit(&mut self) -> ExitStatus {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                return status;
+            }
+            assert!(Instant::now() < deadline, "server did not exit");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+}
+
+impl Drop for EchoServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    stream
+}
+
+#[test]
+fn echo_returns_exactly_the_bytes_received() {
+    let server = EchoServer::spawn(&[]);
+    let mut stream = connect(server.addr);
+
+    stream.write_all(b"hello").unwrap();
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"hello");
+
+    // 关闭写方向后服务器回显完剩余数据再关闭，不会多出填充的字节
+    stream.write_all(b" world").unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b" world");
+}
+
+#[test]
+fn echo_survives_a_client_that_pauses_reading() {
+    let server = EchoServer::spawn(&[]);
+    let mut stream = connect(server.addr);
+    // 远大于套接字缓冲区和服务器的 outbox 上限，服务器一定会遇到 WouldBlock 并暂停读取
+    let payload: Vec<u8> = (0..8 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+
+    let mut writer = stream.try_clone().unwrap();
+    let sent = payload.clone();
+    let sender = thread::spawn(move || {
+        writer.write_all(&sent).unwrap();
+        writer.shutdown(Shutdown::Write).unwrap();
+    });
+
+    let mut echoed = vec![0; 64 * 1024];
+    stream.read_exact(&mut echoed).unwrap();
+    // 读到一半停下，让服务器的写队列积压
+    thread::sleep(Duration::from_millis(500));
+    stream.read_to_end(&mut echoed).unwrap();
+    sender.join().unwrap();
+
+    assert_eq!(echoed.len(), payload.len());
+    assert!(echoed == payload, "echoed bytes differ from the payload");
+}
+
+/// 连接是否已被服务器关闭（读到 EOF 或连接被重置）
+fn closed_by_server(stream: &mut TcpStream) -> bool {
+    !matches!(stream.read(&mut [0; 1]), Ok(n) if n > 0)
+}
+
+#[test]
+fn connections_beyond_max_conn_are_rejected() {
+    let server = EchoServer::spawn(&["--max-conn", "1"]);
+    let mut first = connect(server.addr);
+    first.write_all(b"ping").unwrap();
+    first.read_exact(&mut [0; 4]).unwrap();
+
+    let mut second = connect(server.addr);
+    assert!(closed_by_server(&mut second));
+
+    // 第一个连接结束后空出名额
+    first.shutdown(Shutdown::Write).unwrap();
+    first.read_to_end(&mut Vec::new()).unwrap();
+    let mut third = connect(server.addr);
+    third.write_all(b"pong").unwrap();
+    let mut reply = [0; 4];
+    third.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"pong");
+}
+
+#[cfg(unix)]
+#[test]
+fn ctrl_c_closes_connections_and_exits_cleanly() {
+    let mut server = EchoServer::spawn(&[]);
+    let mut stream = connect(server.addr);
+    stream.write_all(b"ping").unwrap();
+    stream.read_exact(&mut [0; 4]).unwrap();
+
+    let status = Command::new("kill").args(["-INT", &server.child.id().to_string()]).status().unwrap();
+    assert!(status.success());
+    assert!(server.wait_exit().success());
+    assert!(closed_by_server(&mut stream));
+}
+
+#[test]
+fn invalid_arguments_exit_with_usage_error() {
+    for args in [&["--bogus"][..], &["--max-conn", "0"], &["--addr", "not-an-address"]] {
+        let output = Command::new(env!("CARGO_BIN_EXE_epoll_server")).args(args).output().unwrap();
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(String::from_utf8_lossy(&output.stderr).contains("Usage: epoll_server"));
+    }
+}
+
+fn run_client(addr: SocketAddr, args: &[&str], input: &str) -> std::process::Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_epoll_client"))
+        .args(["--addr", &addr.to_string()])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn client_burst_verifies_every_echo() {
+    let server = EchoServer::spawn(&[]);
+    let output = run_client(server.addr, &["--burst", "20000"], "");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("Burst of 20000 messages: all echoes verified"), "{}", stdout);
+    assert!(stdout.contains("Sent 20000 messages"), "{}", stdout);
+}
+
+#[test]
+fn client_session_echoes_stdin_lines_until_quit() {
+    let server = EchoServer::spawn(&[]);
+    let output = run_client(server.addr, &[], "hello\nworld\n/quit\nnot sent\n");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains("Received: hello\nReceived: world\n"), "{}", stdout);
+    assert!(!stdout.contains("not sent"), "{}", stdout);
+    assert!(stdout.contains("Sent 2 messages (12 bytes), received 2 messages (12 bytes)"), "{}", stdout);
+}